@@ -1,6 +1,6 @@
 use std::fs::File;
 
-use ftfrs_tracing::FtfLayer;
+use ftfrs_tracing::{ftf_instrument, FtfLayer};
 use tracing::{event, instrument, trace_span, Level};
 use tracing_subscriber::{self, layer::SubscriberExt};
 fn main() {
@@ -71,7 +71,7 @@ fn my_other_thing(id: u32, name: &str) -> u8 {
     id as u8
 }
 
-#[instrument(fields(ftf = true, category = "compute", type = "helper"))]
+#[ftf_instrument(category = "compute", fields(type = "helper"))]
 fn my_other(size: usize) -> u8 {
     let mut v: Vec<u16> = (1..1000).collect();
     
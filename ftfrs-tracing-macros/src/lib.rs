@@ -0,0 +1,62 @@
+//! Attribute macros re-exported by `ftfrs-tracing`.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Delimiter, TokenStream as TokenStream2, TokenTree};
+use quote::quote;
+
+/// Instrument a function with a span that is recorded by `FtfLayer`.
+///
+/// `#[ftf_instrument(category = "db")]` expands to
+/// `#[tracing::instrument(fields(ftf = true, category = "db"))]`. Every other
+/// argument, including `fields(...)`, is passed through to
+/// `tracing::instrument` unchanged, and `category` may be omitted.
+#[proc_macro_attribute]
+pub fn ftf_instrument(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item = TokenStream2::from(item);
+
+    let mut category = None;
+    let mut fields = None;
+    let mut passthrough = Vec::new();
+    for arg in split_args(attr.into()) {
+        match arg.as_slice() {
+            [TokenTree::Ident(ident), TokenTree::Punct(eq), value @ ..]
+                if ident == "category" && eq.as_char() == '=' && !value.is_empty() =>
+            {
+                category = Some(value.iter().cloned().collect::<TokenStream2>());
+            }
+            [TokenTree::Ident(ident), TokenTree::Group(group)]
+                if ident == "fields" && group.delimiter() == Delimiter::Parenthesis =>
+            {
+                fields = Some(group.stream());
+            }
+            _ => passthrough.push(arg.into_iter().collect::<TokenStream2>()),
+        }
+    }
+
+    let category = category.map(|category| quote!(, category = #category));
+    let fields = fields.map(|fields| quote!(, #fields));
+
+    quote!(
+        #[::tracing::instrument(#(#passthrough,)* fields(ftf = true #category #fields))]
+        #item
+    )
+    .into()
+}
+
+/// Split attribute arguments on top-level commas
+fn split_args(attr: TokenStream2) -> Vec<Vec<TokenTree>> {
+    let mut args = Vec::new();
+    let mut current = Vec::new();
+    for token in attr {
+        match &token {
+            TokenTree::Punct(punct) if punct.as_char() == ',' => {
+                args.push(std::mem::take(&mut current));
+            }
+            _ => current.push(token),
+        }
+    }
+    if !current.is_empty() {
+        args.push(current);
+    }
+    args
+}
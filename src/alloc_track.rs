@@ -0,0 +1,278 @@
+//! Allocation counting through a wrapping global allocator.
+//!
+//! Counts are kept per thread in a const-initialized thread local, which
+//! needs no allocation or registration, and process-wide in atomics.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Allocation counters, either for one thread or the whole process
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Allocations made, counting each reallocation as one
+    pub allocations: u64,
+    /// Bytes allocated
+    pub allocated_bytes: u64,
+    /// Bytes freed
+    pub freed_bytes: u64,
+}
+
+impl AllocStats {
+    const ZERO: Self = Self {
+        allocations: 0,
+        allocated_bytes: 0,
+        freed_bytes: 0,
+    };
+
+    /// Bytes allocated and not yet freed. Per thread this can be negative,
+    /// since memory is often freed by another thread than allocated it.
+    pub fn live_bytes(&self) -> i64 {
+        self.allocated_bytes as i64 - self.freed_bytes as i64
+    }
+
+    /// Add `other`'s counts to these
+    pub(crate) fn add(&mut self, other: &Self) {
+        self.allocations += other.allocations;
+        self.allocated_bytes += other.allocated_bytes;
+        self.freed_bytes += other.freed_bytes;
+    }
+
+    /// Counts accumulated since `earlier`
+    pub(crate) fn since(&self, earlier: &Self) -> Self {
+        Self {
+            allocations: self.allocations.wrapping_sub(earlier.allocations),
+            allocated_bytes: self.allocated_bytes.wrapping_sub(earlier.allocated_bytes),
+            freed_bytes: self.freed_bytes.wrapping_sub(earlier.freed_bytes),
+        }
+    }
+}
+
+thread_local! {
+    static THREAD_STATS: Cell<AllocStats> = const { Cell::new(AllocStats::ZERO) };
+    /// Trace time and counts as of the last allocation counter written for
+    /// this thread, or `None` before the first
+    static LAST_COUNTER: Cell<Option<(u64, AllocStats)>> = const { Cell::new(None) };
+}
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static FREED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+fn record(allocated: usize, freed: usize) {
+    if allocated > 0 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(allocated as u64, Ordering::Relaxed);
+    }
+    if freed > 0 {
+        FREED_BYTES.fetch_add(freed as u64, Ordering::Relaxed);
+    }
+
+    // Allocations made while the thread's locals are being torn down are
+    // only counted process-wide
+    let _ = THREAD_STATS.try_with(|stats| {
+        let mut current = stats.get();
+        if allocated > 0 {
+            current.allocations += 1;
+            current.allocated_bytes += allocated as u64;
+        }
+        current.freed_bytes += freed as u64;
+        stats.set(current);
+    });
+}
+
+/// Get the allocation counters of the calling thread.
+///
+/// Only allocations made through [`TrackingAllocator`] are counted.
+pub fn thread_alloc_stats() -> AllocStats {
+    THREAD_STATS.try_with(Cell::get).unwrap_or_default()
+}
+
+/// Get the allocation counters of the whole process.
+///
+/// Only allocations made through [`TrackingAllocator`] are counted.
+pub fn process_alloc_stats() -> AllocStats {
+    AllocStats {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        freed_bytes: FREED_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// Allocations made while a span was entered, for
+/// [`FtfLayerConfig::span_alloc_args`](crate::FtfLayerConfig::span_alloc_args)
+#[derive(Debug, Default)]
+pub(crate) struct SpanAllocs {
+    /// This thread's counts when the span was last entered, while it is
+    pub(crate) entered: Option<AllocStats>,
+    /// Counts accumulated over every time the span was entered
+    pub(crate) total: AllocStats,
+}
+
+impl SpanAllocs {
+    pub(crate) fn enter(&mut self) {
+        self.entered = Some(thread_alloc_stats());
+    }
+
+    pub(crate) fn exit(&mut self) {
+        if let Some(entered) = self.entered.take() {
+            self.total.add(&thread_alloc_stats().since(&entered));
+        }
+    }
+}
+
+/// Get this thread's counts since its last allocation counter if at least
+/// `interval_ns` has passed since then, starting the first interval on the
+/// first call
+pub(crate) fn take_thread_interval(now: u64, interval_ns: u64) -> Option<AllocStats> {
+    let current = thread_alloc_stats();
+    LAST_COUNTER
+        .try_with(|last| match last.get() {
+            Some((started, _)) if now.saturating_sub(started) < interval_ns => None,
+            Some((_, previous)) => {
+                last.set(Some((now, current)));
+                Some(current.since(&previous))
+            }
+            None => {
+                last.set(Some((now, current)));
+                None
+            }
+        })
+        .ok()
+        .flatten()
+}
+
+/// A global allocator that counts allocations per thread and for the whole
+/// process before passing them on to `A`.
+///
+/// ```
+/// use ftfrs_tracing::TrackingAllocator;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TrackingAllocator = TrackingAllocator::system();
+/// ```
+#[derive(Debug, Default)]
+pub struct TrackingAllocator<A = System> {
+    inner: A,
+}
+
+impl TrackingAllocator {
+    /// Count allocations made by the system allocator
+    pub const fn system() -> Self {
+        Self { inner: System }
+    }
+}
+
+impl<A> TrackingAllocator<A> {
+    /// Count allocations made by `inner`
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+// SAFETY: every call is forwarded to `inner` unchanged; counting neither
+// allocates nor unwinds
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            record(layout.size(), 0);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record(layout.size(), 0);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        record(0, layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            record(new_size, layout.size());
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[global_allocator]
+    static ALLOCATOR: TrackingAllocator = TrackingAllocator::system();
+
+    #[test]
+    fn allocations_are_counted_for_the_thread_and_the_process() {
+        let (thread_before, process_before) = (thread_alloc_stats(), process_alloc_stats());
+        let buffer = Vec::<u8>::with_capacity(1000);
+        let allocated = thread_alloc_stats().since(&thread_before);
+        drop(buffer);
+        let freed = thread_alloc_stats().since(&thread_before);
+        let process = process_alloc_stats().since(&process_before);
+
+        assert_eq!(allocated, AllocStats {
+            allocations: 1,
+            allocated_bytes: 1000,
+            freed_bytes: 0,
+        });
+        assert_eq!((freed.freed_bytes, freed.live_bytes()), (1000, 0));
+        assert!(process.allocations >= 1 && process.freed_bytes >= 1000);
+    }
+
+    #[test]
+    fn a_reallocation_counts_once() {
+        let mut buffer = Vec::<u8>::with_capacity(10);
+        let before = thread_alloc_stats();
+        buffer.reserve_exact(100);
+        let grown = thread_alloc_stats().since(&before);
+        drop(buffer);
+
+        assert_eq!(grown, AllocStats {
+            allocations: 1,
+            allocated_bytes: 100,
+            freed_bytes: 10,
+        });
+    }
+
+    #[test]
+    fn intervals_start_on_the_first_call() {
+        assert_eq!(take_thread_interval(1_000, 500), None);
+        let buffer = Vec::<u8>::with_capacity(64);
+        let early = take_thread_interval(1_400, 500);
+        let due = take_thread_interval(1_500, 500);
+        drop(buffer);
+
+        assert_eq!(early, None);
+        assert_eq!(due.map(|stats| (stats.allocations, stats.allocated_bytes)), Some((1, 64)));
+        assert_eq!(take_thread_interval(1_600, 500), None, "a new interval started at 1500");
+    }
+
+    #[test]
+    fn spans_count_only_while_entered() {
+        let mut span = SpanAllocs::default();
+        span.enter();
+        let inside = Vec::<u8>::with_capacity(64);
+        span.exit();
+        let outside = Vec::<u8>::with_capacity(32);
+        span.enter();
+        drop(inside);
+        span.exit();
+        drop(outside);
+
+        assert_eq!(span.entered, None);
+        assert_eq!(span.total, AllocStats {
+            allocations: 1,
+            allocated_bytes: 64,
+            freed_bytes: 64,
+        });
+    }
+}
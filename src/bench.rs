@@ -0,0 +1,89 @@
+//! Tracing micro-benchmarks.
+//!
+//! This crate doesn't depend on `criterion`, so rather than a custom
+//! measurement, [`BenchTracer::iter_custom`] has the shape of the routine
+//! Criterion's `Bencher::iter_custom` takes.
+
+use std::fs::{self, File};
+use std::hint::black_box;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tracing::{dispatcher, Dispatch};
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::testing::file_stem;
+use crate::{FlushGuard, FtfLayer, FtfLayerConfig, FtfLayerError, InitWriter, SyncedFile};
+
+/// Category of the spans wrapping each batch of iterations
+const BENCH_CATEGORY: &str = "bench";
+
+/// Traces one benchmark into its own file.
+///
+/// Each batch of iterations runs in a span named after the benchmark, in the
+/// `bench` category, with an `iterations` argument. The routine runs with the
+/// tracer's subscriber as the default, so its own spans and events are
+/// recorded inside the batch. The file is flushed when the tracer is dropped.
+///
+/// ```no_run
+/// use ftfrs_tracing::BenchTracer;
+///
+/// let tracer = BenchTracer::new("target/ftf", "parse/small").unwrap();
+/// // In a Criterion benchmark:
+/// // b.iter_custom(|iters| tracer.iter_custom(iters, || parse(INPUT)));
+/// let elapsed = tracer.iter_custom(100, || (0..1000u64).sum::<u64>());
+/// ```
+#[derive(Debug)]
+pub struct BenchTracer {
+    benchmark: String,
+    dispatch: Dispatch,
+    _guard: FlushGuard<InitWriter>,
+}
+
+impl BenchTracer {
+    /// Trace `benchmark` to `<dir>/<benchmark>.ftf`, using the default
+    /// configuration
+    pub fn new(dir: impl AsRef<Path>, benchmark: &str) -> Result<Self, FtfLayerError> {
+        Self::with_config(dir, benchmark, FtfLayerConfig::default())
+    }
+
+    /// Trace `benchmark` to `<dir>/<benchmark>.ftf`, creating `dir` if
+    /// needed. Characters other than letters, digits, `-`, `_` and `.` in the
+    /// benchmark's name, such as the `/` of a benchmark group, become `_` in
+    /// the file name.
+    pub fn with_config(dir: impl AsRef<Path>, benchmark: &str, config: FtfLayerConfig) -> Result<Self, FtfLayerError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let file = File::create(dir.join(format!("{}.ftf", file_stem(benchmark))))?;
+        let sync_file = file.try_clone()?;
+        let layer = FtfLayer::try_with_config(SyncedFile::for_config(file, &config)?, config)?;
+        let guard = layer.flush_guard().syncing(sync_file);
+
+        Ok(Self {
+            benchmark: benchmark.to_string(),
+            dispatch: Dispatch::new(tracing_subscriber::registry().with(layer)),
+            _guard: guard,
+        })
+    }
+
+    /// Run `routine` `iters` times in one traced batch, returning how long
+    /// the iterations took, not counting the batch span itself
+    pub fn iter_custom<O>(&self, iters: u64, mut routine: impl FnMut() -> O) -> Duration {
+        dispatcher::with_default(&self.dispatch, || {
+            let span = tracing::info_span!(
+                "batch",
+                ftf = true,
+                category = BENCH_CATEGORY,
+                ftf.name = self.benchmark.as_str(),
+                iterations = iters
+            );
+            let _entered = span.enter();
+
+            let start = Instant::now();
+            for _ in 0..iters {
+                black_box(routine());
+            }
+            start.elapsed()
+        })
+    }
+}
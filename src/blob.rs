@@ -0,0 +1,68 @@
+//! Blob records, which ftfrs doesn't write.
+//!
+//! Payloads that fit are written as a blob record: header, optional inline
+//! name, and the payload zero-padded to a whole word. Bigger ones, such as
+//! CPU profiles, go in a large blob record without metadata: header, format
+//! header, inline category and name if any, payload size, and the payload.
+
+use std::io;
+
+use crate::raw::{string_field, string_words, write_inline_string};
+
+/// FTF record type of blob records
+const BLOB_RECORD_TYPE: u64 = 5;
+
+/// FTF record type of large records, and the large record type of blobs
+const LARGE_RECORD_TYPE: u64 = 15;
+const LARGE_BLOB_TYPE: u64 = 0;
+
+/// Large blob format carrying no timestamp, thread or arguments
+const LARGE_BLOB_WITHOUT_METADATA: u64 = 1;
+
+/// Blob type of opaque data
+const BLOB_TYPE_DATA: u64 = 1;
+
+/// Longest payload a blob record's 15-bit size field can hold
+const MAX_BLOB_BYTES: usize = (1 << 15) - 1;
+
+/// Largest record, in words, that a record header's 12-bit size field holds
+const MAX_RECORD_WORDS: usize = (1 << 12) - 1;
+
+/// Write `payload` as a blob named `name`, in `category` if it needs a large
+/// blob record
+pub(crate) fn write_blob(
+    writer: &mut dyn io::Write,
+    category: &ftfrs::StringRef,
+    name: &ftfrs::StringRef,
+    payload: &[u8],
+) -> io::Result<()> {
+    let payload_words = payload.len().div_ceil(8);
+    let words = 1 + string_words(name) + payload_words;
+
+    if payload.len() <= MAX_BLOB_BYTES && words <= MAX_RECORD_WORDS {
+        let header = BLOB_RECORD_TYPE
+            | (words as u64) << 4
+            | string_field(name) << 16
+            | (payload.len() as u64) << 32
+            | BLOB_TYPE_DATA << 47;
+        writer.write_all(&header.to_le_bytes())?;
+        write_inline_string(writer, name)?;
+    } else {
+        let words = 2 + string_words(category) + string_words(name) + 1 + payload_words;
+        let words = u32::try_from(words)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "blob too large for an FTF record"))?;
+        let header = LARGE_RECORD_TYPE
+            | u64::from(words) << 4
+            | LARGE_BLOB_TYPE << 36
+            | LARGE_BLOB_WITHOUT_METADATA << 40;
+        let format_header = string_field(category) | string_field(name) << 16;
+        writer.write_all(&header.to_le_bytes())?;
+        writer.write_all(&format_header.to_le_bytes())?;
+        write_inline_string(writer, category)?;
+        write_inline_string(writer, name)?;
+        writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    }
+
+    writer.write_all(payload)?;
+    writer.write_all(&[0; 8][..payload_words * 8 - payload.len()])
+}
@@ -0,0 +1,24 @@
+//! Blocking sections, for finding code that stalls an async executor.
+
+use tracing::{dispatcher, Dispatch};
+
+use crate::future::ManualHooks;
+
+/// Run `f` as a blocking section, written as a duration named `reason` in
+/// the `blocking` category.
+///
+/// If it runs for longer than
+/// [`FtfLayerConfig::blocking_threshold`](crate::FtfLayerConfig::blocking_threshold),
+/// a warning is also printed. Usually called through [`ftf_blocking!`](crate::ftf_blocking).
+pub fn blocking_section<R>(reason: &'static str, f: impl FnOnce() -> R) -> R {
+    // Cloned so that `f` can use the dispatcher
+    let dispatch = dispatcher::get_default(Dispatch::clone);
+    let Some(hooks) = dispatch.downcast_ref::<ManualHooks>() else {
+        return f();
+    };
+
+    let start = (hooks.now)(&dispatch);
+    let result = f();
+    (hooks.blocking)(&dispatch, reason, start);
+    result
+}
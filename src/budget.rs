@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use parking_lot::{Mutex, RwLock};
+
+/// Nanoseconds in the one-second window a budget refills over
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Token buckets enforcing [`FtfLayerConfig::category_budgets`](crate::FtfLayerConfig::category_budgets).
+///
+/// The table only changes when a budget is set at runtime, so it is shared
+/// for reading and only the bucket of the category being recorded is locked.
+#[derive(Debug)]
+pub(crate) struct CategoryBudgets {
+    buckets: RwLock<HashMap<String, Mutex<Bucket>>>,
+    /// Whether any category has a budget, so unlimited layers skip the table
+    active: AtomicBool,
+    /// Records dropped because their category was over budget
+    throttled: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Records allowed per second, which is also the most that can be
+    /// saved up for a burst
+    per_second: u64,
+    /// Records that may be written now, in billionths of a record
+    tokens: u64,
+    /// Time the bucket was last refilled at
+    refilled: u64,
+}
+
+impl Bucket {
+    fn new(per_second: u32) -> Self {
+        let per_second = u64::from(per_second);
+        Self {
+            per_second,
+            tokens: per_second * NANOS_PER_SECOND,
+            refilled: 0,
+        }
+    }
+}
+
+impl CategoryBudgets {
+    pub(crate) fn new(budgets: &HashMap<String, u32>) -> Self {
+        let buckets: HashMap<_, _> = budgets
+            .iter()
+            .map(|(category, &per_second)| (category.clone(), Mutex::new(Bucket::new(per_second))))
+            .collect();
+        Self {
+            active: AtomicBool::new(!buckets.is_empty()),
+            buckets: RwLock::new(buckets),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Set the budget of `category` to `per_second` records, or lift it
+    pub(crate) fn set(&self, category: &str, per_second: Option<u32>) {
+        let mut buckets = self.buckets.write();
+        match per_second {
+            Some(per_second) => {
+                buckets.insert(category.to_string(), Mutex::new(Bucket::new(per_second)));
+            }
+            None => {
+                buckets.remove(category);
+            }
+        }
+        self.active.store(!buckets.is_empty(), Ordering::Release);
+    }
+
+    /// Each category's budget in records per second, sorted by category
+    pub(crate) fn budgets(&self) -> Vec<(String, u32)> {
+        let mut budgets: Vec<_> = self
+            .buckets
+            .read()
+            .iter()
+            .map(|(category, bucket)| (category.clone(), bucket.lock().per_second as u32))
+            .collect();
+        budgets.sort();
+        budgets
+    }
+
+    /// Whether a record in `category` may be written at monotonic time
+    /// `now`, using up one record of its budget if so
+    pub(crate) fn allow(&self, category: &str, now: u64) -> bool {
+        let buckets = self.buckets.read();
+        let Some(bucket) = buckets.get(category) else {
+            return true;
+        };
+
+        let mut bucket = bucket.lock();
+        let capacity = bucket.per_second * NANOS_PER_SECOND;
+        let elapsed = now.saturating_sub(bucket.refilled);
+        bucket.tokens = bucket.tokens.saturating_add(elapsed.saturating_mul(bucket.per_second)).min(capacity);
+        bucket.refilled = now;
+
+        if bucket.tokens >= NANOS_PER_SECOND {
+            bucket.tokens -= NANOS_PER_SECOND;
+            true
+        } else {
+            drop(bucket);
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    pub(crate) fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budgets(budgets: &[(&str, u32)]) -> CategoryBudgets {
+        CategoryBudgets::new(&budgets.iter().map(|&(category, per_second)| (category.to_string(), per_second)).collect())
+    }
+
+    #[test]
+    fn a_second_of_records_can_be_saved_for_a_burst() {
+        let budgets = budgets(&[("network", 2)]);
+        assert!(budgets.allow("network", 0));
+        assert!(budgets.allow("network", 0));
+        assert!(!budgets.allow("network", 0));
+
+        // Half a second refills one record, and a long pause no more than two
+        assert!(budgets.allow("network", NANOS_PER_SECOND / 2));
+        assert!(!budgets.allow("network", NANOS_PER_SECOND / 2));
+        let later = 10 * NANOS_PER_SECOND;
+        assert!(budgets.allow("network", later));
+        assert!(budgets.allow("network", later));
+        assert!(!budgets.allow("network", later));
+        assert_eq!(budgets.throttled(), 3);
+    }
+
+    #[test]
+    fn budgets_can_be_set_and_lifted() {
+        let budgets = budgets(&[]);
+        assert!(!budgets.is_active());
+        assert!(budgets.allow("db", 0));
+
+        budgets.set("db", Some(1));
+        budgets.set("network", Some(5));
+        assert!(budgets.is_active());
+        assert_eq!(budgets.budgets(), [("db".to_string(), 1), ("network".to_string(), 5)]);
+        assert!(budgets.allow("db", 0));
+        assert!(!budgets.allow("db", 0));
+        assert!(budgets.allow("other", 0));
+
+        budgets.set("db", None);
+        budgets.set("network", None);
+        assert!(!budgets.is_active());
+        assert!(budgets.allow("db", 0));
+    }
+}
@@ -0,0 +1,57 @@
+use crate::BytesEncoding;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode a byte field value as a string argument value
+pub(crate) fn encode(value: &[u8], encoding: BytesEncoding) -> String {
+    match encoding {
+        BytesEncoding::Hex => hex(value),
+        BytesEncoding::Base64 => base64(value),
+    }
+}
+
+fn hex(value: &[u8]) -> String {
+    let mut encoded = String::with_capacity(value.len() * 2);
+    for byte in value {
+        encoded.push(HEX_DIGITS[(byte >> 4) as usize] as char);
+        encoded.push(HEX_DIGITS[(byte & 0xF) as usize] as char);
+    }
+    encoded
+}
+
+/// Standard padded base64
+fn base64(value: &[u8]) -> String {
+    let mut encoded = String::with_capacity(value.len().div_ceil(3) * 4);
+    for chunk in value.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_encode_as_hex_and_padded_base64() {
+        assert_eq!(encode(&[0x00, 0x7f, 0xab], BytesEncoding::Hex), "007fab");
+        assert_eq!(encode(b"", BytesEncoding::Base64), "");
+        assert_eq!(encode(b"f", BytesEncoding::Base64), "Zg==");
+        assert_eq!(encode(b"fo", BytesEncoding::Base64), "Zm8=");
+        assert_eq!(encode(b"foo", BytesEncoding::Base64), "Zm9v");
+        assert_eq!(encode(&[0xfb, 0xff], BytesEncoding::Base64), "+/8=");
+    }
+}
@@ -0,0 +1,605 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::callsite::CallsiteRefs;
+use crate::debug_memo::DebugMemo;
+use crate::limits::{self, MAX_INLINE_NAME_BYTES, MAX_INLINE_VALUE_BYTES, MAX_STRING_BYTES};
+use crate::memory::{MemoryBudget, MemoryCharge, ENTRY_OVERHEAD};
+use crate::sink::RecordOutput;
+use crate::{CacheStats, FtfLayerConfig, ValueInterning};
+
+/// Largest index an FTF string record can carry
+const MAX_STRING_ID: u16 = 0x7FFF;
+
+/// Index that always refers to the empty string, so empty strings are
+/// neither interned nor written inline
+const EMPTY_STRING_ID: u16 = 0;
+
+/// Smallest value table allowed, so a single record's values (at most 15
+/// arguments) can never evict each other
+const MIN_VALUE_CAPACITY: usize = 16;
+
+/// Upper bound on values tracked for [`ValueInterning::AfterRepeats`] before
+/// the counts are reset
+const MAX_PENDING_VALUES: usize = 4096;
+
+/// Interned strings for one output.
+///
+/// Names (span, event, field and category names) are pinned for the life of
+/// the trace. Field values live in a bounded table evicted least-recently-used
+/// first; an evicted value's index is reused by writing a new string record
+/// for it, so value churn never displaces hot names or exhausts the index space.
+///
+/// When the layer's memory budget is full, values are evicted to make room,
+/// and names and values that still don't fit are written inline.
+#[derive(Debug)]
+pub(crate) struct StringCache {
+    names: HashMap<Arc<str>, u16>,
+    /// Interned values with the tick they were last used at
+    values: HashMap<Arc<str>, (u16, u64)>,
+    /// Interned values ordered by last use
+    value_order: BTreeMap<u64, Arc<str>>,
+    /// Interned names and values by index
+    by_id: HashMap<u16, Arc<str>>,
+    value_capacity: usize,
+    /// Largest index handed out, bounding names and values together
+    max_id: u16,
+    tick: u64,
+    /// First tick of the record being written, whose values are never
+    /// evicted so its earlier arguments keep their indices
+    record_start: u64,
+    next_id: u16,
+    /// Indices of values evicted only to free memory, reused before fresh ones
+    free_ids: Vec<u16>,
+    counters: Arc<CacheCounters>,
+    /// Bytes held by names and values
+    memory: MemoryCharge,
+    /// Bytes held by `pending_values`
+    pending_memory: MemoryCharge,
+    /// Resolved name and category references per callsite
+    pub(crate) callsites: CallsiteRefs,
+    /// Last Debug-formatted value per field, if memoized
+    pub(crate) debug_values: DebugMemo,
+    value_interning: ValueInterning,
+    /// Times each not-yet-interned value has been seen, for [`ValueInterning::AfterRepeats`]
+    pending_values: HashMap<String, u32>,
+}
+
+/// Interned thread references for one output.
+///
+/// Holds at most `capacity` threads, or as many as the layer's memory budget
+/// allows; once full, the least recently used thread's index is reused by
+/// writing a new thread record for it.
+#[derive(Debug)]
+pub(crate) struct ThreadCache {
+    /// Interned threads with the tick they were last used at
+    by_id: HashMap<(u64, u64), (u8, u64)>,
+    capacity: usize,
+    tick: u64,
+    /// Next fresh index, wider than an index so it can pass the last one
+    next_id: u16,
+    counters: Arc<CacheCounters>,
+    memory: MemoryCharge,
+}
+
+/// Bytes a name takes up in the table
+fn name_cost(value: &str) -> usize {
+    value.len() + ENTRY_OVERHEAD
+}
+
+/// Bytes a value takes up, kept both by value and in last-use order
+fn value_cost(value: &str) -> usize {
+    2 * (value.len() + ENTRY_OVERHEAD)
+}
+
+/// Counters of one intern table, kept outside its lock so they can be read
+/// without waiting on writers
+#[derive(Debug, Default)]
+pub(crate) struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    /// Strings that had characters escaped by [`limits::sanitize`]
+    sanitized: AtomicU64,
+}
+
+impl CacheCounters {
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn sanitized(&self) -> u64 {
+        self.sanitized.load(Ordering::Relaxed)
+    }
+}
+
+impl StringCache {
+    pub(crate) fn new(config: &FtfLayerConfig, memory: &Arc<MemoryBudget>) -> Self {
+        Self {
+            names: HashMap::new(),
+            values: HashMap::new(),
+            value_order: BTreeMap::new(),
+            by_id: HashMap::new(),
+            value_capacity: config.value_cache_capacity.max(MIN_VALUE_CAPACITY),
+            max_id: config.string_cache_capacity.clamp(1, MAX_STRING_ID as usize) as u16,
+            tick: 0,
+            record_start: 0,
+            next_id: 1,
+            free_ids: Vec::new(),
+            counters: Arc::default(),
+            memory: MemoryCharge::new(memory),
+            pending_memory: MemoryCharge::new(memory),
+            callsites: CallsiteRefs::new(),
+            debug_values: DebugMemo::new(memory),
+            value_interning: config.value_interning,
+            pending_values: HashMap::new(),
+        }
+    }
+
+    /// Start a new record, after which only values used before it may be
+    /// evicted
+    pub(crate) fn begin_record(&mut self) {
+        self.record_start = self.tick + 1;
+    }
+
+    /// Get a reference for a name, interning and pinning it on first use
+    pub(crate) fn get_or_create(&mut self, value: &str, output: &mut RecordOutput<'_>) -> Result<ftfrs::StringRef, ftfrs::FtfError> {
+        let Some(value) = self.sanitize(value) else {
+            return Ok(ftfrs::StringRef::Ref(EMPTY_STRING_ID));
+        };
+        let value = limits::truncate(&value, MAX_STRING_BYTES);
+        if let Some(&id) = self.names.get(value) {
+            CacheCounters::count(&self.counters.hits);
+            return Ok(ftfrs::StringRef::Ref(id));
+        }
+
+        // A value that turns out to also be a name is promoted so it is never evicted
+        if let Some((id, tick)) = self.values.remove(value) {
+            CacheCounters::count(&self.counters.hits);
+            self.value_order.remove(&tick);
+            self.memory.remove(value_cost(value) - name_cost(value));
+            self.names.insert(value.into(), id);
+            return Ok(ftfrs::StringRef::Ref(id));
+        }
+
+        CacheCounters::count(&self.counters.misses);
+
+        if !self.make_room(name_cost(value)) {
+            self.memory.budget().count_inlined();
+            return Ok(limits::inline_name(value));
+        }
+        let id = match self.allocate_id() {
+            Some(id) => id,
+            None => {
+                self.memory.remove(name_cost(value));
+                return Ok(limits::inline_name(value));
+            }
+        };
+        let value: Arc<str> = value.into();
+        self.names.insert(value.clone(), id);
+        self.by_id.insert(id, value.clone());
+
+        let record = ftfrs::Record::create_string(id, value.to_string());
+        output.write(record)?;
+
+        Ok(ftfrs::StringRef::Ref(id))
+    }
+
+    /// Get a reference for a field value, interning it only if the
+    /// [`ValueInterning`] policy allows and inlining it otherwise.
+    ///
+    /// Values too long for ftfrs to write inline are always interned.
+    pub(crate) fn get_or_create_value(&mut self, value: &str, output: &mut RecordOutput<'_>) -> Result<ftfrs::StringRef, ftfrs::FtfError> {
+        self.get_or_create_dynamic(value, MAX_INLINE_VALUE_BYTES, output)
+    }
+
+    /// Get a reference for a record name set at runtime, which may be as
+    /// varied as a field value, so it follows the same policy. Names can be
+    /// written inline at greater length than values.
+    pub(crate) fn get_or_create_dynamic_name(&mut self, value: &str, output: &mut RecordOutput<'_>) -> Result<ftfrs::StringRef, ftfrs::FtfError> {
+        self.get_or_create_dynamic(value, MAX_INLINE_NAME_BYTES, output)
+    }
+
+    /// Intern `value` in the value table if the [`ValueInterning`] policy
+    /// allows or it is longer than `max_inline` bytes, and inline it otherwise
+    fn get_or_create_dynamic(
+        &mut self,
+        value: &str,
+        max_inline: usize,
+        output: &mut RecordOutput<'_>,
+    ) -> Result<ftfrs::StringRef, ftfrs::FtfError> {
+        let Some(value) = self.sanitize(value) else {
+            return Ok(ftfrs::StringRef::Ref(EMPTY_STRING_ID));
+        };
+        let value = limits::truncate(&value, MAX_STRING_BYTES);
+        if let Some(&id) = self.names.get(value) {
+            CacheCounters::count(&self.counters.hits);
+            return Ok(ftfrs::StringRef::Ref(id));
+        }
+
+        self.tick += 1;
+        if let Some((id, tick)) = self.values.get_mut(value) {
+            CacheCounters::count(&self.counters.hits);
+            let previous = std::mem::replace(tick, self.tick);
+            if let Some(value) = self.value_order.remove(&previous) {
+                self.value_order.insert(self.tick, value);
+            }
+            return Ok(ftfrs::StringRef::Ref(*id));
+        }
+
+        CacheCounters::count(&self.counters.misses);
+        let intern = value.len() > max_inline || match self.value_interning {
+            ValueInterning::Always => true,
+            ValueInterning::MaxLength(max_len) => value.len() <= max_len,
+            ValueInterning::Never => false,
+            ValueInterning::AfterRepeats(repeats) => {
+                if let Some(seen) = self.pending_values.get_mut(value) {
+                    *seen += 1;
+                    *seen >= repeats
+                } else {
+                    let cost = name_cost(value);
+                    let mut tracked = self.pending_values.len() < MAX_PENDING_VALUES && self.pending_memory.try_add(cost);
+                    if !tracked {
+                        self.pending_values.clear();
+                        self.pending_memory.clear();
+                        // A count that doesn't fit even after starting over isn't kept
+                        tracked = self.pending_memory.try_add(cost);
+                    }
+                    if tracked {
+                        self.pending_values.insert(value.to_string(), 1);
+                    }
+                    repeats <= 1
+                }
+            }
+        };
+
+        if !intern {
+            return Ok(ftfrs::StringRef::Inline(value.to_string()));
+        }
+        if self.pending_values.remove(value).is_some() {
+            self.pending_memory.remove(name_cost(value));
+        }
+
+        let inline = || ftfrs::StringRef::Inline(limits::truncate(value, max_inline).to_string());
+        if !self.make_room(value_cost(value)) {
+            self.memory.budget().count_inlined();
+            return Ok(inline());
+        }
+        let id = if self.values.len() >= self.value_capacity {
+            self.evict_value()
+        } else {
+            self.allocate_id()
+        };
+        let id = match id {
+            Some(id) => id,
+            None => {
+                self.memory.remove(value_cost(value));
+                return Ok(inline());
+            }
+        };
+        let value: Arc<str> = value.into();
+        self.values.insert(value.clone(), (id, self.tick));
+        self.value_order.insert(self.tick, value.clone());
+        self.by_id.insert(id, value.clone());
+
+        let record = ftfrs::Record::create_string(id, value.to_string());
+        output.write(record)?;
+
+        Ok(ftfrs::StringRef::Ref(id))
+    }
+
+    /// Escape characters readers may reject, counting the strings changed.
+    /// Returns `None` for the empty string, which is never interned.
+    fn sanitize<'v>(&mut self, value: &'v str) -> Option<Cow<'v, str>> {
+        if value.is_empty() {
+            return None;
+        }
+        let sanitized = limits::sanitize(value);
+        if let Cow::Owned(_) = sanitized {
+            CacheCounters::count(&self.counters.sanitized);
+        }
+        Some(sanitized)
+    }
+
+
+    /// Write a string record for every interned name and value, so a new
+    /// output can keep using the references handed out so far
+    pub(crate) fn rewrite(&self, output: &mut RecordOutput<'_>) -> Result<(), ftfrs::FtfError> {
+        let mut strings: Vec<_> = self.by_id.iter().collect();
+        strings.sort_unstable_by_key(|(id, _)| **id);
+        for (&id, value) in strings {
+            output.write(ftfrs::Record::create_string(id, value.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Write a string record for each of `ids` this table has handed out, so
+    /// the references stay valid after other string records reassigned them
+    pub(crate) fn restore(&self, ids: &BTreeSet<u16>, output: &mut RecordOutput<'_>) -> Result<(), ftfrs::FtfError> {
+        for id in ids {
+            if let Some(value) = self.by_id.get(id) {
+                output.write(ftfrs::Record::create_string(*id, value.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Charge `bytes` to the memory budget, evicting the least recently
+    /// used values until they fit. Returns false, charging nothing, if they
+    /// don't fit even with every value the current record doesn't use evicted.
+    fn make_room(&mut self, bytes: usize) -> bool {
+        while !self.memory.try_add(bytes) {
+            match self.evict_value() {
+                Some(id) => self.free_ids.push(id),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Take a fresh or freed index, or reuse the least recently used value's
+    /// index once the index space is exhausted. Returns `None` if every
+    /// value left is used by the current record.
+    fn allocate_id(&mut self) -> Option<u16> {
+        if let Some(id) = self.free_ids.pop() {
+            return Some(id);
+        }
+        if self.next_id <= self.max_id {
+            let id = self.next_id;
+            self.next_id += 1;
+            return Some(id);
+        }
+
+        self.evict_value()
+    }
+
+    /// Evict the least recently used value and return its index, unless the
+    /// current record uses it
+    fn evict_value(&mut self) -> Option<u16> {
+        let (&tick, _) = self.value_order.first_key_value()?;
+        if tick >= self.record_start {
+            return None;
+        }
+        let (_, value) = self.value_order.pop_first()?;
+        CacheCounters::count(&self.counters.evictions);
+        self.memory.remove(value_cost(&value));
+        let (id, _) = self.values.remove(&value)?;
+        self.by_id.remove(&id);
+        Some(id)
+    }
+
+    /// The table's counters, which stay valid while it is locked by others
+    pub(crate) fn counters(&self) -> Arc<CacheCounters> {
+        self.counters.clone()
+    }
+}
+
+impl ThreadCache {
+    pub(crate) fn new(config: &FtfLayerConfig, memory: &Arc<MemoryBudget>) -> Self {
+        Self {
+            by_id: HashMap::new(),
+            capacity: config.thread_cache_capacity.clamp(1, u8::MAX as usize),
+            tick: 0,
+            next_id: 1,
+            counters: Arc::default(),
+            memory: MemoryCharge::new(memory),
+        }
+    }
+
+    pub(crate) fn get_or_create(
+        &mut self,
+        process_id: u64,
+        thread_id: u64,
+        output: &mut RecordOutput<'_>,
+    ) -> Result<ftfrs::ThreadRef, ftfrs::FtfError> {
+        let key = (process_id, thread_id);
+        self.tick += 1;
+        if let Some((id, tick)) = self.by_id.get_mut(&key) {
+            CacheCounters::count(&self.counters.hits);
+            *tick = self.tick;
+            return Ok(ftfrs::ThreadRef::Ref(*id));
+        }
+
+        CacheCounters::count(&self.counters.misses);
+        let id = if usize::from(self.next_id) <= self.capacity && self.memory.try_add(ENTRY_OVERHEAD) {
+            let id = self.next_id as u8;
+            self.next_id += 1;
+            id
+        } else if !self.by_id.is_empty() {
+            self.evict()
+        } else {
+            self.memory.budget().count_inlined();
+            return Ok(ftfrs::ThreadRef::Inline {
+                process_koid: process_id,
+                thread_koid: thread_id,
+            });
+        };
+        self.by_id.insert(key, (id, self.tick));
+
+        let record = ftfrs::Record::create_thread(id, process_id, thread_id);
+        output.write(record)?;
+
+        Ok(ftfrs::ThreadRef::Ref(id))
+    }
+
+    /// Write a thread record for each of `ids` this table has handed out, so
+    /// the references stay valid after other thread records reassigned them
+    pub(crate) fn restore(&self, ids: &BTreeSet<u8>, output: &mut RecordOutput<'_>) -> Result<(), ftfrs::FtfError> {
+        for (&(process_id, thread_id), &(id, _)) in &self.by_id {
+            if ids.contains(&id) {
+                output.write(ftfrs::Record::create_thread(id, process_id, thread_id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget every interned thread, so each is re-interned on next use
+    pub(crate) fn clear(&mut self) {
+        self.by_id.clear();
+        self.next_id = 1;
+        self.memory.clear();
+    }
+
+    /// Whether a thread is currently interned
+    pub(crate) fn contains(&self, process_id: u64, thread_id: u64) -> bool {
+        self.by_id.contains_key(&(process_id, thread_id))
+    }
+
+    /// Evict the least recently used thread and return its index
+    fn evict(&mut self) -> u8 {
+        let key = self
+            .by_id
+            .iter()
+            .min_by_key(|(_, (_, tick))| *tick)
+            .map(|(key, _)| *key)
+            .expect("a full thread cache is not empty");
+        CacheCounters::count(&self.counters.evictions);
+        self.by_id.remove(&key).map(|(id, _)| id).unwrap_or(1)
+    }
+
+    /// The table's counters, which stay valid while it is locked by others
+    pub(crate) fn counters(&self) -> Arc<CacheCounters> {
+        self.counters.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string_cache(config: FtfLayerConfig, memory_budget: Option<usize>) -> StringCache {
+        StringCache::new(&config, &MemoryBudget::new(memory_budget))
+    }
+
+    fn value_ref(cache: &mut StringCache, value: &str) -> ftfrs::StringRef {
+        let mut sink = std::io::sink();
+        let mut output = RecordOutput::new(&mut sink, &[], &[]);
+        cache.get_or_create_value(value, &mut output).unwrap()
+    }
+
+    fn name_ref(cache: &mut StringCache, value: &str) -> ftfrs::StringRef {
+        let mut sink = std::io::sink();
+        let mut output = RecordOutput::new(&mut sink, &[], &[]);
+        cache.get_or_create(value, &mut output).unwrap()
+    }
+
+    #[test]
+    fn values_of_one_record_keep_their_indices() {
+        let config = FtfLayerConfig {
+            string_cache_capacity: 3,
+            ..FtfLayerConfig::default()
+        };
+        let mut cache = string_cache(config, None);
+
+        cache.begin_record();
+        assert_eq!(name_ref(&mut cache, "name"), ftfrs::StringRef::Ref(1));
+        assert_eq!(value_ref(&mut cache, "first"), ftfrs::StringRef::Ref(2));
+        assert_eq!(value_ref(&mut cache, "second"), ftfrs::StringRef::Ref(3));
+        // Every index is taken by this record, so the next value is inlined
+        assert_eq!(value_ref(&mut cache, "third"), ftfrs::StringRef::Inline("third".to_string()));
+        assert_eq!(value_ref(&mut cache, "first"), ftfrs::StringRef::Ref(2));
+
+        // A later record may evict them
+        cache.begin_record();
+        assert_eq!(value_ref(&mut cache, "third"), ftfrs::StringRef::Ref(3));
+        assert_eq!(value_ref(&mut cache, "first"), ftfrs::StringRef::Ref(2));
+        assert_eq!(cache.counters.stats().evictions, 1);
+    }
+
+    #[test]
+    fn names_are_never_evicted() {
+        let config = FtfLayerConfig {
+            string_cache_capacity: 2,
+            ..FtfLayerConfig::default()
+        };
+        let mut cache = string_cache(config, None);
+
+        cache.begin_record();
+        assert_eq!(name_ref(&mut cache, "name"), ftfrs::StringRef::Ref(1));
+        assert_eq!(value_ref(&mut cache, "value"), ftfrs::StringRef::Ref(2));
+        cache.begin_record();
+        assert_eq!(name_ref(&mut cache, "other"), ftfrs::StringRef::Ref(2));
+        cache.begin_record();
+        assert!(matches!(name_ref(&mut cache, "third"), ftfrs::StringRef::Inline(_)));
+        assert_eq!(name_ref(&mut cache, "name"), ftfrs::StringRef::Ref(1));
+    }
+
+    #[test]
+    fn memory_budget_does_not_evict_the_current_record() {
+        // Room for one short value and not two
+        let mut cache = string_cache(FtfLayerConfig::default(), Some(value_cost("aaaa") + 10));
+
+        cache.begin_record();
+        assert_eq!(value_ref(&mut cache, "aaaa"), ftfrs::StringRef::Ref(1));
+        assert_eq!(value_ref(&mut cache, "bbbb"), ftfrs::StringRef::Inline("bbbb".to_string()));
+
+        cache.begin_record();
+        // The first value's index is freed for memory and taken by the second
+        assert_eq!(value_ref(&mut cache, "bbbb"), ftfrs::StringRef::Ref(1));
+        assert_eq!(cache.memory.budget().stats().inlined_entries, 1);
+        assert!(cache.memory.budget().stats().peak_bytes <= (value_cost("aaaa") + 10) as u64);
+    }
+
+    #[test]
+    fn after_repeats_interns_once_seen_enough() {
+        let config = FtfLayerConfig {
+            value_interning: ValueInterning::AfterRepeats(3),
+            ..FtfLayerConfig::default()
+        };
+        let mut cache = string_cache(config, None);
+        cache.begin_record();
+        assert!(matches!(value_ref(&mut cache, "value"), ftfrs::StringRef::Inline(_)));
+        assert!(matches!(value_ref(&mut cache, "value"), ftfrs::StringRef::Inline(_)));
+        assert_eq!(value_ref(&mut cache, "value"), ftfrs::StringRef::Ref(1));
+        assert!(cache.pending_values.is_empty());
+    }
+
+    fn thread_cache(capacity: usize) -> ThreadCache {
+        let config = FtfLayerConfig {
+            thread_cache_capacity: capacity,
+            ..FtfLayerConfig::default()
+        };
+        ThreadCache::new(&config, &MemoryBudget::new(None))
+    }
+
+    fn thread_id(cache: &mut ThreadCache, thread: u64) -> u8 {
+        let mut sink = std::io::sink();
+        let mut output = RecordOutput::new(&mut sink, &[], &[]);
+        match cache.get_or_create(1, thread, &mut output).unwrap() {
+            ftfrs::ThreadRef::Ref(id) => id,
+            other => panic!("thread {} not interned: {:?}", thread, other),
+        }
+    }
+
+    #[test]
+    fn thread_cache_reuses_indices_past_the_last() {
+        let mut cache = thread_cache(usize::MAX);
+        for thread in 0..300 {
+            let id = thread_id(&mut cache, thread);
+            assert_ne!(id, 0);
+        }
+        assert_eq!(cache.by_id.len(), 255);
+        assert_eq!(cache.counters.stats().evictions, 45);
+    }
+
+    #[test]
+    fn thread_cache_evicts_least_recently_used() {
+        let mut cache = thread_cache(2);
+        let first = thread_id(&mut cache, 1);
+        let second = thread_id(&mut cache, 2);
+        assert_eq!(thread_id(&mut cache, 1), first);
+        assert_eq!(thread_id(&mut cache, 3), second);
+        assert!(cache.contains(1, 1));
+        assert!(!cache.contains(1, 2));
+    }
+}
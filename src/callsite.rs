@@ -0,0 +1,147 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use tracing_core::Metadata;
+
+/// Callsites whose references can be cached. Records of callsites past it
+/// resolve their references through the string table each time.
+const CALLSITE_CAPACITY: usize = 1024;
+
+/// References resolved for a callsite
+#[derive(Debug)]
+struct Refs {
+    category: Box<str>,
+    name_id: u16,
+    category_id: u16,
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    /// Address of the metadata of the callsite holding the slot, or 0 while
+    /// it is free
+    callsite: AtomicUsize,
+    refs: OnceLock<Refs>,
+}
+
+/// Cache of resolved `(name, category)` string references per callsite.
+///
+/// Callsites are kept in a fixed-size open-addressed table of atomic slots,
+/// keyed by the address of their `'static` metadata, so a lookup takes no
+/// lock and hashes into no map. A slot keeps the category its callsite was
+/// first resolved for; records of the callsite in another category, which
+/// only a category set at runtime gives, miss and are resolved through the
+/// string table.
+#[derive(Debug, Default)]
+pub(crate) struct CallsiteRefs {
+    /// Allocated on first insert
+    slots: OnceLock<Box<[Slot]>>,
+}
+
+impl CallsiteRefs {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the cached `(name, category)` ids for `metadata` in `category`
+    pub(crate) fn get(&self, metadata: &'static Metadata<'static>, category: &str) -> Option<(u16, u16)> {
+        let key = callsite_key(metadata);
+        for slot in probe(self.slots.get()?, key) {
+            match slot.callsite.load(Ordering::Acquire) {
+                0 => return None,
+                callsite if callsite == key => {
+                    let refs = slot.refs.get()?;
+                    return (*refs.category == *category).then_some((refs.name_id, refs.category_id));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Cache the `(name, category)` ids for `metadata` in `category`, unless
+    /// the callsite already has ids cached or the table is full
+    pub(crate) fn insert(&self, metadata: &'static Metadata<'static>, category: &str, name_id: u16, category_id: u16) {
+        let key = callsite_key(metadata);
+        let slots = self.slots.get_or_init(|| (0..CALLSITE_CAPACITY).map(|_| Slot::default()).collect());
+        for slot in probe(slots, key) {
+            match slot.callsite.compare_exchange(0, key, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => {}
+                Err(callsite) if callsite == key => {}
+                Err(_) => continue,
+            }
+            let _ = slot.refs.set(Refs {
+                category: category.into(),
+                name_id,
+                category_id,
+            });
+            return;
+        }
+    }
+}
+
+/// The slots the callsite `key` may hold, in the order they are tried
+fn probe(slots: &[Slot], key: usize) -> impl Iterator<Item = &Slot> {
+    // Fibonacci hashing spreads the word-aligned addresses over the table
+    let start = (key as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - CALLSITE_CAPACITY.trailing_zeros());
+    slots.iter().cycle().skip(start as usize).take(slots.len())
+}
+
+fn callsite_key(metadata: &'static Metadata<'static>) -> usize {
+    metadata as *const Metadata<'static> as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_core::field::FieldSet;
+    use tracing_core::{Kind, Level};
+
+    use super::*;
+
+    /// Metadata of two distinct callsites
+    fn callsites() -> (&'static Metadata<'static>, &'static Metadata<'static>) {
+        tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+            let metadata = |span: tracing::Span| span.metadata().expect("an enabled span has metadata");
+            (metadata(tracing::info_span!("first")), metadata(tracing::info_span!("second")))
+        })
+    }
+
+    #[test]
+    fn callsite_refs_match_the_whole_category() {
+        let (first, second) = callsites();
+        let refs = CallsiteRefs::new();
+        refs.insert(first, "db", 1, 2);
+
+        assert_eq!(refs.get(first, "db"), Some((1, 2)));
+        assert_eq!(refs.get(first, "dbx"), None);
+        assert_eq!(refs.get(second, "db"), None);
+
+        // The category first resolved for a callsite keeps its slot
+        refs.insert(first, "net", 3, 4);
+        assert_eq!(refs.get(first, "net"), None);
+        assert_eq!(refs.get(first, "db"), Some((1, 2)));
+
+        refs.insert(second, "net", 3, 4);
+        assert_eq!(refs.get(second, "net"), Some((3, 4)));
+    }
+
+    #[test]
+    fn callsites_past_the_capacity_are_not_cached() {
+        let (first, _) = callsites();
+        let metadata: Vec<&'static Metadata<'static>> = (0..=CALLSITE_CAPACITY)
+            .map(|_| {
+                let fields = FieldSet::new(&[], first.callsite());
+                let metadata = Metadata::new("leaked", "test", Level::INFO, None, None, None, fields, Kind::SPAN);
+                &*Box::leak(Box::new(metadata))
+            })
+            .collect();
+
+        let refs = CallsiteRefs::new();
+        for (i, metadata) in metadata.iter().enumerate() {
+            refs.insert(metadata, "db", i as u16, 0);
+        }
+        for (i, metadata) in metadata[..CALLSITE_CAPACITY].iter().enumerate() {
+            assert_eq!(refs.get(metadata, "db"), Some((i as u16, 0)));
+        }
+        assert_eq!(refs.get(metadata[CALLSITE_CAPACITY], "db"), None);
+    }
+}
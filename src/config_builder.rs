@@ -0,0 +1,455 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing_core::Level;
+
+use crate::{
+    BytesEncoding, DefaultCategory, DurationCounters, FtfLayerConfig, Preallocation, RecordMiddleware, RecordSink,
+    Rotation, SpanEnd, SyncPolicy, TailSampling, TimestampEpoch, TraceClock, ValueInterning, WideIntEncoding,
+    WriteFailureHandler,
+};
+
+impl FtfLayerConfig {
+    /// Start from the default configuration, setting only what differs:
+    ///
+    /// ```
+    /// use ftfrs_tracing::FtfLayerConfig;
+    ///
+    /// let config = FtfLayerConfig::builder()
+    ///     .metadata([("service", "api"), ("version", "1.4.2")])
+    ///     .max_debug_len(1024)
+    ///     .build();
+    /// ```
+    pub fn builder() -> FtfLayerConfigBuilder {
+        FtfLayerConfigBuilder::default()
+    }
+}
+
+/// Builder for an [`FtfLayerConfig`].
+///
+/// Each method sets the field of the same name; see the field for what it
+/// does. Optional fields take the value itself, and collections are added
+/// to rather than replaced.
+#[derive(Debug, Clone, Default)]
+pub struct FtfLayerConfigBuilder {
+    config: FtfLayerConfig,
+}
+
+impl FtfLayerConfigBuilder {
+    /// Set the provider information ID
+    pub fn provider_id(mut self, provider_id: u32) -> Self {
+        self.config.provider_id = provider_id;
+        self
+    }
+
+    /// Set whether the provider ID is derived from the process id and
+    /// random bits instead
+    pub fn unique_provider_id(mut self, unique: bool) -> Self {
+        self.config.unique_provider_id = unique;
+        self
+    }
+
+    /// Set the provider name
+    pub fn provider_name(mut self, name: impl Into<String>) -> Self {
+        self.config.provider_name = name.into();
+        self
+    }
+
+    /// Add key-value pairs describing the trace, e.g. `("service", "api")`
+    pub fn metadata<K, V>(mut self, metadata: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.config
+            .metadata
+            .extend(metadata.into_iter().map(|(key, value)| (key.into(), value.into())));
+        self
+    }
+
+    /// Set whether the hostname, pid, executable path and command-line
+    /// arguments are added to the metadata
+    pub fn process_metadata(mut self, process_metadata: bool) -> Self {
+        self.config.process_metadata = process_metadata;
+        self
+    }
+
+    /// Set the process id records are written under, instead of detecting it
+    pub fn process_id(mut self, process_id: u64) -> Self {
+        self.config.process_id = Some(process_id);
+        self
+    }
+
+    /// Set what record timestamps are measured from
+    pub fn timestamp_epoch(mut self, epoch: TimestampEpoch) -> Self {
+        self.config.timestamp_epoch = epoch;
+        self
+    }
+
+    /// Set the clock trace time is read from
+    pub fn clock(mut self, clock: Arc<dyn TraceClock>) -> Self {
+        self.config.clock = Some(clock);
+        self
+    }
+
+    /// Add categories written to files of their own, by category
+    pub fn category_files<K, P>(mut self, files: impl IntoIterator<Item = (K, P)>) -> Self
+    where
+        K: Into<String>,
+        P: Into<PathBuf>,
+    {
+        self.config
+            .category_files
+            .extend(files.into_iter().map(|(category, path)| (category.into(), path.into())));
+        self
+    }
+
+    /// Set whether the writer routes records to different outputs by level or
+    /// target
+    pub fn route_by_metadata(mut self, route: bool) -> Self {
+        self.config.route_by_metadata = route;
+        self
+    }
+
+    /// Add categories that are never recorded
+    pub fn denied_categories(mut self, categories: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.denied_categories.extend(categories.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add categories whose events are attributed to the process rather than
+    /// a thread
+    pub fn process_categories(mut self, categories: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.process_categories.extend(categories.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add fields that are never written as arguments
+    pub fn denied_fields(mut self, fields: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.denied_fields.extend(fields.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set the longest Debug-formatted value written before truncating
+    pub fn max_debug_len(mut self, len: usize) -> Self {
+        self.config.max_debug_len = Some(len);
+        self
+    }
+
+    /// Set whether Debug-formatted values are memoized
+    pub fn memoize_debug_values(mut self, memoize: bool) -> Self {
+        self.config.memoize_debug_values = memoize;
+        self
+    }
+
+    /// Set how `i128` and `u128` values are written
+    pub fn wide_int_encoding(mut self, encoding: WideIntEncoding) -> Self {
+        self.config.wide_int_encoding = encoding;
+        self
+    }
+
+    /// Set how byte slice values are written
+    pub fn bytes_encoding(mut self, encoding: BytesEncoding) -> Self {
+        self.config.bytes_encoding = encoding;
+        self
+    }
+
+    /// Set the longest byte slice value written before truncating
+    pub fn max_bytes_len(mut self, len: usize) -> Self {
+        self.config.max_bytes_len = Some(len);
+        self
+    }
+
+    /// Set the rollover schedule of a rolling file writer
+    pub fn rotation(mut self, rotation: Rotation) -> Self {
+        self.config.rotation = Some(rotation);
+        self
+    }
+
+    /// Set how often the trace starts a new self-contained chunk
+    pub fn chunk_interval(mut self, interval: Duration) -> Self {
+        self.config.chunk_interval = Some(interval);
+        self
+    }
+
+    /// Set how many bytes a chunk may grow to before a new one starts
+    pub fn chunk_bytes(mut self, bytes: u64) -> Self {
+        self.config.chunk_bytes = Some(bytes);
+        self
+    }
+
+    /// Set whether each chunk ends with a trailer holding its length and
+    /// checksum
+    pub fn chunk_trailers(mut self, trailers: bool) -> Self {
+        self.config.chunk_trailers = trailers;
+        self
+    }
+
+    /// Set how often the file [`init`](crate::init) writes to is synced to disk
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.config.sync_policy = policy;
+        self
+    }
+
+    /// Set the disk space reserved ahead of the trace in the file
+    /// [`init`](crate::init) writes to
+    pub fn preallocation(mut self, preallocation: Preallocation) -> Self {
+        self.config.preallocation = Some(preallocation);
+        self
+    }
+
+    /// Set whether span begin and end events are also written to ftrace's
+    /// `trace_marker`
+    pub fn trace_marker(mut self, trace_marker: bool) -> Self {
+        self.config.trace_marker = trace_marker;
+        self
+    }
+
+    /// Set when a recorded span's duration ends
+    pub fn span_end(mut self, span_end: SpanEnd) -> Self {
+        self.config.span_end = span_end;
+        self
+    }
+
+    /// Set whether span durations are written as counters
+    pub fn span_duration_counters(mut self, counters: DurationCounters) -> Self {
+        self.config.span_duration_counters = counters;
+        self
+    }
+
+    /// Set whether span durations are summarized per span name
+    pub fn latency_summary(mut self, summary: bool) -> Self {
+        self.config.latency_summary = summary;
+        self
+    }
+
+    /// Set how many of the slowest span instances are kept and written
+    pub fn slowest_spans(mut self, count: usize) -> Self {
+        self.config.slowest_spans = count;
+        self
+    }
+
+    /// Set whether the categories and names seen are written as a `schema`
+    /// blob
+    pub fn schema_record(mut self, schema_record: bool) -> Self {
+        self.config.schema_record = schema_record;
+        self
+    }
+
+    /// Set how long a span may stay open before it is reported
+    pub fn long_running_span_threshold(mut self, threshold: Duration) -> Self {
+        self.config.long_running_span_threshold = Some(threshold);
+        self
+    }
+
+    /// Set whether long-running spans are also reported as instant events
+    pub fn long_running_span_events(mut self, events: bool) -> Self {
+        self.config.long_running_span_events = events;
+        self
+    }
+
+    /// Set how often a long-running span is reported again
+    pub fn long_running_span_repeat(mut self, repeat: Duration) -> Self {
+        self.config.long_running_span_repeat = Some(repeat);
+        self
+    }
+
+    /// Set how long a section run with [`ftf_blocking!`](crate::ftf_blocking)
+    /// may take before a warning is printed
+    pub fn blocking_threshold(mut self, threshold: Duration) -> Self {
+        self.config.blocking_threshold = Some(threshold);
+        self
+    }
+
+    /// Set how many writes to the main output may fail in a row before
+    /// recording is turned off
+    pub fn max_write_failures(mut self, failures: u32) -> Self {
+        self.config.max_write_failures = Some(failures);
+        self
+    }
+
+    /// Set the most bytes written to the main output before recording is
+    /// turned off
+    pub fn max_trace_bytes(mut self, bytes: u64) -> Self {
+        self.config.max_trace_bytes = Some(bytes);
+        self
+    }
+
+    /// Set what is called when too many failed writes turn recording off
+    pub fn write_failure_handler(mut self, handler: Arc<dyn WriteFailureHandler>) -> Self {
+        self.config.write_failure_handler = Some(handler);
+        self
+    }
+
+    /// Set whether the layer times its own hooks
+    pub fn measure_overhead(mut self, measure: bool) -> Self {
+        self.config.measure_overhead = measure;
+        self
+    }
+
+    /// Set how often the measured overhead is written as counters
+    pub fn overhead_counter_interval(mut self, interval: Duration) -> Self {
+        self.config.overhead_counter_interval = Some(interval);
+        self
+    }
+
+    /// Set how often allocation counters are written
+    #[cfg(feature = "alloc-track")]
+    pub fn alloc_counter_interval(mut self, interval: Duration) -> Self {
+        self.config.alloc_counter_interval = Some(interval);
+        self
+    }
+
+    /// Set whether span end records carry the allocations made while the
+    /// span was entered
+    #[cfg(feature = "alloc-track")]
+    pub fn span_alloc_args(mut self, args: bool) -> Self {
+        self.config.span_alloc_args = args;
+        self
+    }
+
+    /// Set whether span trees are held until their root closes and written
+    /// only if something in them was interesting
+    pub fn tail_sampling(mut self, sampling: TailSampling) -> Self {
+        self.config.tail_sampling = sampling;
+        self
+    }
+
+    /// Set whether spans are written as duration records
+    pub fn record_spans(mut self, record: bool) -> Self {
+        self.config.record_spans = record;
+        self
+    }
+
+    /// Set whether events are written as instant records
+    pub fn record_events(mut self, record: bool) -> Self {
+        self.config.record_events = record;
+        self
+    }
+
+    /// Add names of spans recorded as if they had `ftf = true`
+    pub fn record_span_names(mut self, names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.record_span_names.extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add a step every record passes through before it is written, after
+    /// those added before it
+    pub fn middleware(mut self, middleware: Arc<dyn RecordMiddleware>) -> Self {
+        self.config.middleware.push(middleware);
+        self
+    }
+
+    /// Add a consumer of every record written to the main output
+    pub fn sink(mut self, sink: Arc<dyn RecordSink>) -> Self {
+        self.config.sinks.push(sink);
+        self
+    }
+
+    /// Set whether frame marks also write a counter of the frame number
+    pub fn frame_counter(mut self, counter: bool) -> Self {
+        self.config.frame_counter = counter;
+        self
+    }
+
+    /// Add the most verbose level recorded for categories
+    pub fn category_levels<K: Into<String>>(mut self, levels: impl IntoIterator<Item = (K, Level)>) -> Self {
+        self.config
+            .category_levels
+            .extend(levels.into_iter().map(|(category, level)| (category.into(), level)));
+        self
+    }
+
+    /// Add the most span and event records written per second for categories
+    pub fn category_budgets<K: Into<String>>(mut self, budgets: impl IntoIterator<Item = (K, u32)>) -> Self {
+        self.config
+            .category_budgets
+            .extend(budgets.into_iter().map(|(category, budget)| (category.into(), budget)));
+        self
+    }
+
+    /// Set the most verbose level recorded in any category
+    pub fn max_level(mut self, level: Level) -> Self {
+        self.config.max_level = Some(level);
+        self
+    }
+
+    /// Set the category of records that don't set one
+    pub fn default_category(mut self, category: DefaultCategory) -> Self {
+        self.config.default_category = category;
+        self
+    }
+
+    /// Add categories to rewrite, from their name to the one written
+    pub fn category_aliases<K, V>(mut self, aliases: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.config
+            .category_aliases
+            .extend(aliases.into_iter().map(|(from, to)| (from.into(), to.into())));
+        self
+    }
+
+    /// Set when string field values are interned
+    pub fn value_interning(mut self, interning: ValueInterning) -> Self {
+        self.config.value_interning = interning;
+        self
+    }
+
+    /// Set how many interned field values are kept before evicting
+    pub fn value_cache_capacity(mut self, capacity: usize) -> Self {
+        self.config.value_cache_capacity = capacity;
+        self
+    }
+
+    /// Set how many string indices may be handed out
+    pub fn string_cache_capacity(mut self, capacity: usize) -> Self {
+        self.config.string_cache_capacity = capacity;
+        self
+    }
+
+    /// Set how many thread indices may be handed out before evicting
+    pub fn thread_cache_capacity(mut self, capacity: usize) -> Self {
+        self.config.thread_cache_capacity = capacity;
+        self
+    }
+
+    /// Set the approximate bytes the layer's tables and buffers may hold
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.config.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Finish the configuration
+    pub fn build(self) -> FtfLayerConfig {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collections_are_added_to() {
+        let config = FtfLayerConfig::builder()
+            .metadata([("service", "api")])
+            .metadata([("version".to_string(), "1.4.2".to_string())])
+            .denied_fields(["password"])
+            .denied_fields(["authorization"])
+            .category_levels([("db", Level::DEBUG)])
+            .max_debug_len(1024)
+            .build();
+        assert_eq!(config.metadata, [
+            ("service".to_string(), "api".to_string()),
+            ("version".to_string(), "1.4.2".to_string()),
+        ]);
+        assert_eq!(config.denied_fields.len(), 2);
+        assert_eq!(config.category_levels.get("db"), Some(&Level::DEBUG));
+        assert_eq!(config.max_debug_len, Some(1024));
+        assert_eq!(config.provider_name, FtfLayerConfig::default().provider_name);
+    }
+}
@@ -0,0 +1,448 @@
+//! A line-based control server for steering tracing on a live process.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::FtfHandle;
+
+/// How often the server checks whether its guard was dropped while waiting
+/// for a connection or a command
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest command line accepted, so a stray client can't grow the buffer
+/// without bound
+const MAX_LINE_BYTES: usize = 4096;
+
+/// Clients served at once, each on its own thread; more are turned away
+const MAX_CONNECTIONS: usize = 8;
+
+const HELP: &str = "commands: status, start, stop, deny <category>, allow <category>, \
+                    budget <category> <per-second|off>, flush, dump [args], help";
+
+/// Called for the `dump` command with the rest of its line, returning the
+/// reply, e.g. after writing a buffered trace to a file
+type DumpHandler = Arc<dyn Fn(&str) -> io::Result<String> + Send + Sync>;
+
+/// Serves commands that start and stop recording, change the denied
+/// categories and budgets, and dump the trace, over TCP or a Unix socket.
+///
+/// Clients send one command per line and get one line back, starting with
+/// `ok` or `error`, so `nc` or `socat` is enough to drive it:
+///
+/// ```text
+/// $ echo "deny gfx*" | nc localhost 7878
+/// ok
+/// ```
+///
+/// Each connection is served on its own thread, so an idle client doesn't
+/// hold up others, up to a handful at once. There is no authentication, so
+/// listen on a loopback address or on a Unix socket in a private directory.
+///
+/// Sampling is adjusted through category budgets, which cap how many records
+/// a category writes per second;
+/// [`FtfLayerConfig::tail_sampling`](crate::FtfLayerConfig::tail_sampling) is
+/// fixed when the layer is built.
+///
+/// ```no_run
+/// use ftfrs_tracing::{ControlServer, FtfLayer, MemoryWriter};
+///
+/// let layer = FtfLayer::new(MemoryWriter::new());
+/// let _control = ControlServer::new(layer.handle()).listen_tcp("127.0.0.1:7878").unwrap();
+/// ```
+pub struct ControlServer<W: for<'a> MakeWriter<'a>> {
+    handle: FtfHandle<W>,
+    dump: Option<DumpHandler>,
+}
+
+impl<W: for<'a> MakeWriter<'a>> std::fmt::Debug for ControlServer<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ControlServer").finish_non_exhaustive()
+    }
+}
+
+impl<W> ControlServer<W>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    /// Control the layer behind `handle`
+    pub fn new(handle: FtfHandle<W>) -> Self {
+        Self { handle, dump: None }
+    }
+
+    /// Handle the `dump` command with `handler`, which gets the rest of the
+    /// command's line and returns the reply's message.
+    ///
+    /// For a flight recorder, have the layer write to a buffer, such as a
+    /// [`MemoryWriter`](crate::MemoryWriter), and write it out here.
+    pub fn on_dump(mut self, handler: impl Fn(&str) -> io::Result<String> + Send + Sync + 'static) -> Self {
+        self.dump = Some(Arc::new(handler));
+        self
+    }
+
+    /// Run one command line, returning the reply without its newline
+    pub fn execute(&self, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        match (command, args.as_slice()) {
+            ("status", []) => self.status(),
+            ("start", []) => {
+                self.handle.resume_recording();
+                "ok".to_string()
+            }
+            ("stop", []) => {
+                self.handle.stop_recording();
+                "ok".to_string()
+            }
+            ("deny", [category]) => {
+                self.handle.deny_category(category);
+                "ok".to_string()
+            }
+            ("allow", [category]) => {
+                if self.handle.allow_category(category) {
+                    "ok".to_string()
+                } else {
+                    format!("error {} is not denied", category)
+                }
+            }
+            ("budget", [category, "off"]) => {
+                self.handle.set_category_budget(category, None);
+                "ok".to_string()
+            }
+            ("budget", [category, per_second]) => match per_second.parse() {
+                Ok(per_second) => {
+                    self.handle.set_category_budget(category, Some(per_second));
+                    "ok".to_string()
+                }
+                Err(_) => format!("error invalid budget {}", per_second),
+            },
+            ("flush", []) => match self.handle.flush() {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("error {}", e),
+            },
+            ("dump", _) => match &self.dump {
+                Some(dump) => match dump(&args.join(" ")) {
+                    Ok(message) if message.is_empty() => "ok".to_string(),
+                    Ok(message) => format!("ok {}", message.replace('\n', " ")),
+                    Err(e) => format!("error {}", e),
+                },
+                None => "error no dump handler".to_string(),
+            },
+            ("help", []) => format!("ok {}", HELP),
+            ("", []) => "error empty command".to_string(),
+            _ => format!("error unknown command {:?}; {}", line.trim(), HELP),
+        }
+    }
+
+    fn status(&self) -> String {
+        let budgets: Vec<String> = self
+            .handle
+            .category_budgets()
+            .into_iter()
+            .map(|(category, per_second)| format!("{}={}", category, per_second))
+            .collect();
+        format!(
+            "ok recording={} denied={} budgets={}",
+            if self.handle.is_recording() { "on" } else { "off" },
+            self.handle.denied_categories().join(","),
+            budgets.join(",")
+        )
+    }
+
+    /// Start serving on a TCP address, e.g. `127.0.0.1:0` for any free port,
+    /// which the guard's [`ControlServerGuard::local_addr`] reports
+    pub fn listen_tcp(self, addr: impl ToSocketAddrs) -> io::Result<ControlServerGuard> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let mut guard = self.spawn(move || match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                Ok(Some(Box::new(stream) as Box<dyn Connection>))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        })?;
+        guard.local_addr = Some(local_addr);
+        Ok(guard)
+    }
+
+    /// Start serving on a Unix socket at `path`, which is removed when the
+    /// guard is dropped
+    #[cfg(unix)]
+    pub fn listen_unix(self, path: impl AsRef<Path>) -> io::Result<ControlServerGuard> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        let mut guard = self.spawn(move || match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                Ok(Some(Box::new(stream) as Box<dyn Connection>))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        })?;
+        guard.socket_path = Some(path);
+        Ok(guard)
+    }
+
+    /// Run the server on its own thread, taking connections from `accept`
+    /// until the guard is dropped
+    fn spawn<A>(self, mut accept: A) -> io::Result<ControlServerGuard>
+    where
+        A: FnMut() -> io::Result<Option<Box<dyn Connection>>> + Send + 'static,
+    {
+        let server = Arc::new(self);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("ftfrs-tracing-control".to_string())
+            .spawn(move || {
+                let mut clients: Vec<JoinHandle<()>> = Vec::new();
+                while !thread_stop.load(Ordering::Acquire) {
+                    match accept() {
+                        Ok(Some(mut connection)) => {
+                            clients.retain(|client| !client.is_finished());
+                            if clients.len() >= MAX_CONNECTIONS {
+                                let _ = connection.write_all(b"error too many connections\n");
+                            } else if let Some(client) = server.clone().serve_on_thread(connection, &thread_stop) {
+                                clients.push(client);
+                            }
+                        }
+                        Ok(None) => std::thread::sleep(POLL_INTERVAL),
+                        Err(e) => {
+                            eprintln!("Error accepting FTF control connection: {}", e);
+                            std::thread::sleep(POLL_INTERVAL);
+                        }
+                    }
+                }
+
+                for client in clients {
+                    if client.join().is_err() {
+                        eprintln!("FTF control connection thread panicked");
+                    }
+                }
+            })?;
+
+        Ok(ControlServerGuard {
+            stop,
+            thread: Some(thread),
+            local_addr: None,
+            #[cfg(unix)]
+            socket_path: None,
+        })
+    }
+
+    /// Serve `connection` on a thread of its own
+    fn serve_on_thread(self: Arc<Self>, connection: Box<dyn Connection>, stop: &Arc<AtomicBool>) -> Option<JoinHandle<()>> {
+        let stop = stop.clone();
+        let spawned = std::thread::Builder::new()
+            .name("ftfrs-tracing-control-client".to_string())
+            .spawn(move || {
+                if let Err(e) = self.serve(connection, &stop) {
+                    eprintln!("Error serving FTF control connection: {}", e);
+                }
+            });
+        match spawned {
+            Ok(client) => Some(client),
+            Err(e) => {
+                eprintln!("Error starting FTF control connection thread: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Answer each command line on `connection` until the client hangs up
+    fn serve(&self, connection: Box<dyn Connection>, stop: &AtomicBool) -> io::Result<()> {
+        let mut reader = BufReader::new(connection);
+        let mut line = Vec::new();
+        while !stop.load(Ordering::Acquire) {
+            let limit = (MAX_LINE_BYTES + 1 - line.len()) as u64;
+            match (&mut reader).take(limit).read_until(b'\n', &mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                // The part of the line read so far stays in `line`
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                Err(e) => return Err(e),
+            }
+
+            if line.len() > MAX_LINE_BYTES {
+                return reader.get_mut().write_all(b"error command too long\n");
+            }
+            let reply = self.execute(&String::from_utf8_lossy(&line));
+            line.clear();
+            let connection = reader.get_mut();
+            connection.write_all(reply.as_bytes())?;
+            connection.write_all(b"\n")?;
+            connection.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// A client connection over either kind of socket
+trait Connection: io::Read + io::Write + Send {}
+
+impl<T: io::Read + io::Write + Send> Connection for T {}
+
+/// Keeps a [`ControlServer`] running.
+///
+/// Dropping the guard stops the server, waiting for the commands in
+/// progress, and joins its threads.
+#[must_use = "dropping the guard immediately stops the control server"]
+#[derive(Debug)]
+pub struct ControlServerGuard {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    local_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    socket_path: Option<PathBuf>,
+}
+
+impl ControlServerGuard {
+    /// The address a TCP server is listening on
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+}
+
+impl Drop for ControlServerGuard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("FTF control server thread panicked");
+            }
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::{FtfLayer, MemoryWriter};
+
+    fn server() -> ControlServer<MemoryWriter> {
+        ControlServer::new(FtfLayer::new(MemoryWriter::new()).handle())
+    }
+
+    #[test]
+    fn commands_steer_the_layer() {
+        let server = server();
+        assert_eq!(server.execute("status"), "ok recording=on denied= budgets=");
+
+        assert_eq!(server.execute("stop\n"), "ok");
+        assert_eq!(server.execute("status"), "ok recording=off denied= budgets=");
+        assert_eq!(server.execute("start"), "ok");
+        assert!(server.handle.is_recording());
+
+        assert_eq!(server.execute("deny gfx*"), "ok");
+        assert_eq!(server.execute("  budget  network 5 "), "ok");
+        assert_eq!(server.execute("status"), "ok recording=on denied=gfx* budgets=network=5");
+        assert_eq!(server.execute("allow gfx*"), "ok");
+        assert_eq!(server.execute("budget network off"), "ok");
+        assert_eq!(server.execute("status"), "ok recording=on denied= budgets=");
+
+        assert_eq!(server.execute("flush"), "ok");
+        assert_eq!(server.execute("help"), format!("ok {}", HELP));
+    }
+
+    #[test]
+    fn malformed_commands_are_errors() {
+        let server = server();
+        assert_eq!(server.execute(""), "error empty command");
+        assert_eq!(server.execute(" \r\n"), "error empty command");
+        assert_eq!(server.execute("allow gfx"), "error gfx is not denied");
+        assert_eq!(server.execute("budget network fast"), "error invalid budget fast");
+        assert_eq!(server.execute("budget network -1"), "error invalid budget -1");
+        assert_eq!(server.execute("dump"), "error no dump handler");
+
+        for line in ["launch", "status now", "stop all", "deny", "deny a b", "allow", "budget network", "budget a b c", "help me"] {
+            assert_eq!(server.execute(line), format!("error unknown command {:?}; {}", line, HELP));
+        }
+        assert!(server.handle.is_recording());
+        assert!(server.handle.denied_categories().is_empty());
+        assert!(server.handle.category_budgets().is_empty());
+    }
+
+    #[test]
+    fn dump_gets_the_rest_of_its_line() {
+        let server = server().on_dump(|args| match args {
+            "" => Ok(String::new()),
+            "fail" => Err(io::Error::other("disk full")),
+            args => Ok(format!("wrote\n{}", args)),
+        });
+        assert_eq!(server.execute("dump"), "ok");
+        assert_eq!(server.execute("dump  /tmp/a.ftf  now"), "ok wrote /tmp/a.ftf now");
+        assert_eq!(server.execute("dump fail"), "error disk full");
+    }
+
+    fn command(stream: &mut TcpStream, line: &str) -> String {
+        stream.write_all(line.as_bytes()).unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn idle_clients_do_not_hold_up_others() {
+        let guard = server().listen_tcp("127.0.0.1:0").unwrap();
+        let addr = guard.local_addr().unwrap();
+
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"sta").unwrap();
+        let mut active = TcpStream::connect(addr).unwrap();
+        active.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(command(&mut active, "stop\n"), "ok\n");
+
+        idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(command(&mut idle, "tus\n"), "ok recording=off denied= budgets=\n");
+        drop(guard);
+    }
+
+    #[test]
+    fn clients_past_the_limit_are_turned_away() {
+        let guard = server().listen_tcp("127.0.0.1:0").unwrap();
+        let addr = guard.local_addr().unwrap();
+
+        let mut clients: Vec<TcpStream> = (0..MAX_CONNECTIONS).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for client in &mut clients {
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            assert_eq!(command(client, "start\n"), "ok\n");
+        }
+
+        let mut extra = TcpStream::connect(addr).unwrap();
+        extra.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(command(&mut extra, ""), "error too many connections\n");
+
+        // Slots free up once their clients hang up and their threads finish
+        drop(clients);
+        let reply = (0..50)
+            .map(|_| {
+                std::thread::sleep(POLL_INTERVAL);
+                let mut client = TcpStream::connect(addr).unwrap();
+                client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                command(&mut client, "start\n")
+            })
+            .find(|reply| reply == "ok\n");
+        assert!(reply.is_some());
+    }
+}
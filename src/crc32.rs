@@ -0,0 +1,48 @@
+//! CRC-32, as gzip members and chunk trailers use it.
+
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    update(0, data)
+}
+
+/// Extend `crc`, the checksum of the bytes before `data`, over `data`
+pub(crate) fn update(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, &byte| TABLE[((crc ^ u32::from(byte)) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn update_extends_the_checksum() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        assert_eq!(crc32(data), 0x414F_A339);
+        for split in 0..=data.len() {
+            assert_eq!(update(crc32(&data[..split]), &data[split..]), crc32(data));
+        }
+    }
+}
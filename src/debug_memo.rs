@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use tracing_core::Field;
+
+use crate::memory::{MemoryBudget, MemoryCharge, ENTRY_OVERHEAD};
+
+/// Fields remembered before the memo is reset
+const MAX_MEMOIZED_FIELDS: usize = 1024;
+
+/// The last Debug-formatted value of each field, for
+/// [`FtfLayerConfig::memoize_debug_values`](crate::FtfLayerConfig::memoize_debug_values).
+///
+/// A repeated value is recognised by formatting it against the remembered
+/// string, which stops at the first difference and allocates nothing, so
+/// identical values skip building a new string. Values the layer's memory
+/// budget can't cover aren't remembered.
+#[derive(Debug)]
+pub(crate) struct DebugMemo {
+    values: HashMap<Field, String>,
+    memory: MemoryCharge,
+}
+
+impl DebugMemo {
+    pub(crate) fn new(memory: &Arc<MemoryBudget>) -> Self {
+        Self {
+            values: HashMap::new(),
+            memory: MemoryCharge::new(memory),
+        }
+    }
+
+    /// Take the remembered value of `field` if `value` formats the same
+    pub(crate) fn take_if_same(&mut self, field: &Field, value: &dyn fmt::Debug) -> Option<String> {
+        let remembered = self.values.get(field)?;
+        let mut compare = SameAs {
+            expected: remembered,
+            matched: 0,
+        };
+        let same = fmt::write(&mut compare, format_args!("{:?}", value)).is_ok() && compare.matched == remembered.len();
+        if same {
+            self.memory.remove(remembered.len() + ENTRY_OVERHEAD);
+            self.values.remove(field)
+        } else {
+            None
+        }
+    }
+
+    /// Remember `value` as the last value of `field`
+    pub(crate) fn insert(&mut self, field: &Field, value: String) {
+        if self.values.len() >= MAX_MEMOIZED_FIELDS && !self.values.contains_key(field) {
+            self.values.clear();
+            self.memory.clear();
+        }
+        if !self.memory.try_add(value.len() + ENTRY_OVERHEAD) {
+            return;
+        }
+        if let Some(previous) = self.values.insert(field.clone(), value) {
+            self.memory.remove(previous.len() + ENTRY_OVERHEAD);
+        }
+    }
+}
+
+/// A writer that only accepts exactly the text of `expected`, in pieces
+struct SameAs<'a> {
+    expected: &'a str,
+    matched: usize,
+}
+
+impl fmt::Write for SameAs<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.matched + s.len();
+        if self.expected.as_bytes().get(self.matched..end) != Some(s.as_bytes()) {
+            return Err(fmt::Error);
+        }
+        self.matched = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fields named `first` and `second`
+    fn fields() -> (Field, Field) {
+        let metadata = tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+            tracing::info_span!("memo", first = 1, second = 2).metadata().expect("span has metadata")
+        });
+        let field = |name| metadata.fields().field(name).expect("field exists");
+        (field("first"), field("second"))
+    }
+
+    #[test]
+    fn only_the_same_value_is_reused() {
+        let (first, second) = fields();
+        let budget = MemoryBudget::new(Some(1024));
+        let mut memo = DebugMemo::new(&budget);
+        memo.insert(&first, format!("{:?}", [1, 2, 3]));
+        assert_eq!(memo.take_if_same(&second, &[1, 2, 3]), None);
+        assert_eq!(memo.take_if_same(&first, &[1, 2]), None);
+        assert_eq!(memo.take_if_same(&first, &[1, 2, 3, 4]), None);
+        assert_eq!(memo.take_if_same(&first, &[1, 2, 3]).as_deref(), Some("[1, 2, 3]"));
+
+        // The value is taken, to be remembered again once it is written
+        assert_eq!(memo.take_if_same(&first, &[1, 2, 3]), None);
+        assert_eq!(budget.stats().used_bytes, 0);
+    }
+
+    #[test]
+    fn values_the_budget_cannot_cover_are_not_remembered() {
+        let (first, second) = fields();
+        let budget = MemoryBudget::new(Some(2 * ENTRY_OVERHEAD + 12));
+        let mut memo = DebugMemo::new(&budget);
+        let long = "x".repeat(100);
+        memo.insert(&first, long.clone());
+        assert_eq!(memo.take_if_same(&first, &format_args!("{}", long)), None);
+
+        // A new value is charged before the one it replaces is given back
+        memo.insert(&second, "short".to_string());
+        assert_eq!(budget.stats().used_bytes, (ENTRY_OVERHEAD + 5) as u64);
+        memo.insert(&second, "shorter".to_string());
+        assert_eq!(budget.stats().used_bytes, (ENTRY_OVERHEAD + 7) as u64);
+    }
+}
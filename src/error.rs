@@ -0,0 +1,52 @@
+use std::{error, fmt, io};
+
+use tracing::subscriber::SetGlobalDefaultError;
+
+/// Errors surfaced while setting up an [`FtfLayer`](crate::FtfLayer).
+#[derive(Debug)]
+pub enum FtfLayerError {
+    /// The output could not be written to or flushed
+    Io(io::Error),
+    /// A record could not be encoded or written
+    Ftf(ftfrs::FtfError),
+    /// A global default subscriber was already installed
+    Init(SetGlobalDefaultError),
+}
+
+impl fmt::Display for FtfLayerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error writing FTF output: {}", e),
+            Self::Ftf(e) => write!(f, "error writing FTF record: {}", e),
+            Self::Init(e) => write!(f, "failed to install FTF subscriber: {}", e),
+        }
+    }
+}
+
+impl error::Error for FtfLayerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Ftf(e) => Some(e),
+            Self::Init(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for FtfLayerError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ftfrs::FtfError> for FtfLayerError {
+    fn from(e: ftfrs::FtfError) -> Self {
+        Self::Ftf(e)
+    }
+}
+
+impl From<SetGlobalDefaultError> for FtfLayerError {
+    fn from(e: SetGlobalDefaultError) -> Self {
+        Self::Init(e)
+    }
+}
@@ -0,0 +1,319 @@
+//! Event records encoded by hand.
+//!
+//! ftfrs takes an event's arguments as a `Vec` and builds a fresh `Vec` for
+//! the header of every record it writes, so each event written through it
+//! costs allocations. An [`EventRecord`] borrows its arguments instead and
+//! is written here following the FTF event record layout: header,
+//! timestamp, optional inline thread, category and name, arguments, and an
+//! optional trailing word. It only becomes an [`ftfrs::Record`] when
+//! middleware or sinks need one.
+
+use std::io;
+
+use crate::limits::argument_words;
+use crate::raw::{string_field, string_words, write_inline_string};
+
+/// FTF record type of event records
+const EVENT_RECORD_TYPE: u64 = 4;
+
+/// Kind of event, with the trailing word the kind carries
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum EventKind {
+    Instant,
+    Counter { counter_id: u64 },
+    DurationBegin,
+    DurationEnd,
+    DurationComplete { end_timestamp: u64 },
+}
+
+impl EventKind {
+    /// Event type as encoded in the record header
+    fn event_type(self) -> u64 {
+        match self {
+            Self::Instant => 0,
+            Self::Counter { .. } => 1,
+            Self::DurationBegin => 2,
+            Self::DurationEnd => 3,
+            Self::DurationComplete { .. } => 4,
+        }
+    }
+
+    /// Word written after the arguments, if the kind has one
+    fn extra_word(self) -> Option<u64> {
+        match self {
+            Self::Counter { counter_id } => Some(counter_id),
+            Self::DurationComplete { end_timestamp } => Some(end_timestamp),
+            Self::Instant | Self::DurationBegin | Self::DurationEnd => None,
+        }
+    }
+}
+
+/// An event record borrowing its arguments
+pub(crate) struct EventRecord<'a> {
+    pub(crate) kind: EventKind,
+    pub(crate) timestamp: u64,
+    pub(crate) thread: ftfrs::ThreadRef,
+    pub(crate) category: ftfrs::StringRef,
+    pub(crate) name: ftfrs::StringRef,
+    pub(crate) arguments: &'a [ftfrs::Argument],
+}
+
+impl<'a> EventRecord<'a> {
+    pub(crate) fn instant(
+        timestamp: u64,
+        thread: ftfrs::ThreadRef,
+        category: ftfrs::StringRef,
+        name: ftfrs::StringRef,
+        arguments: &'a [ftfrs::Argument],
+    ) -> Self {
+        Self { kind: EventKind::Instant, timestamp, thread, category, name, arguments }
+    }
+
+    pub(crate) fn counter(
+        timestamp: u64,
+        thread: ftfrs::ThreadRef,
+        category: ftfrs::StringRef,
+        name: ftfrs::StringRef,
+        arguments: &'a [ftfrs::Argument],
+        counter_id: u64,
+    ) -> Self {
+        Self { kind: EventKind::Counter { counter_id }, timestamp, thread, category, name, arguments }
+    }
+
+    pub(crate) fn duration_begin(
+        timestamp: u64,
+        thread: ftfrs::ThreadRef,
+        category: ftfrs::StringRef,
+        name: ftfrs::StringRef,
+        arguments: &'a [ftfrs::Argument],
+    ) -> Self {
+        Self { kind: EventKind::DurationBegin, timestamp, thread, category, name, arguments }
+    }
+
+    pub(crate) fn duration_end(
+        timestamp: u64,
+        thread: ftfrs::ThreadRef,
+        category: ftfrs::StringRef,
+        name: ftfrs::StringRef,
+        arguments: &'a [ftfrs::Argument],
+    ) -> Self {
+        Self { kind: EventKind::DurationEnd, timestamp, thread, category, name, arguments }
+    }
+
+    pub(crate) fn duration_complete(
+        timestamp: u64,
+        thread: ftfrs::ThreadRef,
+        category: ftfrs::StringRef,
+        name: ftfrs::StringRef,
+        arguments: &'a [ftfrs::Argument],
+        end_timestamp: u64,
+    ) -> Self {
+        Self { kind: EventKind::DurationComplete { end_timestamp }, timestamp, thread, category, name, arguments }
+    }
+
+    /// Write this record to `writer`
+    pub(crate) fn write(&self, writer: &mut dyn io::Write) -> io::Result<()> {
+        write_event(
+            writer,
+            self.kind.event_type(),
+            self.timestamp,
+            &self.thread,
+            &self.category,
+            &self.name,
+            self.arguments,
+            self.kind.extra_word(),
+        )
+    }
+
+    /// The same record as ftfrs represents it, copying the arguments
+    pub(crate) fn into_record(self) -> ftfrs::Record {
+        let Self { kind, timestamp, thread, category, name, arguments } = self;
+        let arguments = arguments.to_vec();
+        match kind {
+            EventKind::Instant => ftfrs::Record::create_instant_event(timestamp, thread, category, name, arguments),
+            EventKind::Counter { counter_id } => {
+                ftfrs::Record::create_counter_event(timestamp, thread, category, name, arguments, counter_id)
+            }
+            EventKind::DurationBegin => {
+                ftfrs::Record::create_duration_begin_event(timestamp, thread, category, name, arguments)
+            }
+            EventKind::DurationEnd => ftfrs::Record::create_duration_end_event(timestamp, thread, category, name, arguments),
+            EventKind::DurationComplete { end_timestamp } => {
+                ftfrs::Record::create_duration_complete_event(timestamp, thread, category, name, arguments, end_timestamp)
+            }
+        }
+    }
+}
+
+/// Write an event record of `event_type`, ending in `extra_word` if given
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_event(
+    writer: &mut dyn io::Write,
+    event_type: u64,
+    timestamp: u64,
+    thread: &ftfrs::ThreadRef,
+    category: &ftfrs::StringRef,
+    name: &ftfrs::StringRef,
+    arguments: &[ftfrs::Argument],
+    extra_word: Option<u64>,
+) -> io::Result<()> {
+    let (thread_field, thread_words) = match thread {
+        ftfrs::ThreadRef::Ref(id) => (*id as u64, 0),
+        ftfrs::ThreadRef::Inline { .. } => (0, 2),
+    };
+    let words = 2
+        + thread_words
+        + string_words(category)
+        + string_words(name)
+        + arguments.iter().map(argument_words).sum::<usize>()
+        + extra_word.is_some() as usize;
+
+    let header = EVENT_RECORD_TYPE
+        | (words as u64) << 4
+        | event_type << 16
+        | (arguments.len() as u64) << 20
+        | thread_field << 24
+        | string_field(category) << 32
+        | string_field(name) << 48;
+
+    writer.write_all(&header.to_le_bytes())?;
+    writer.write_all(&timestamp.to_le_bytes())?;
+
+    if let ftfrs::ThreadRef::Inline { process_koid, thread_koid } = thread {
+        writer.write_all(&process_koid.to_le_bytes())?;
+        writer.write_all(&thread_koid.to_le_bytes())?;
+    }
+
+    write_inline_string(writer, category)?;
+    write_inline_string(writer, name)?;
+
+    for argument in arguments {
+        write_argument(writer, argument)?;
+    }
+
+    match extra_word {
+        Some(word) => writer.write_all(&word.to_le_bytes()),
+        None => Ok(()),
+    }
+}
+
+/// Write one argument: header, inline name, then its value
+fn write_argument(writer: &mut dyn io::Write, argument: &ftfrs::Argument) -> io::Result<()> {
+    use ftfrs::Argument::*;
+
+    let (argument_type, name, data, wide) = match argument {
+        Null(name) => (0, name, 0, None),
+        Int32(name, value) => (1, name, *value as u32 as u64, None),
+        UInt32(name, value) => (2, name, *value as u64, None),
+        Int64(name, value) => (3, name, 0, Some(*value as u64)),
+        UInt64(name, value) => (4, name, 0, Some(*value)),
+        Float(name, value) => (5, name, 0, Some(value.to_bits())),
+        Str(name, value) => (6, name, string_field(value), None),
+        Pointer(name, value) => (7, name, 0, Some(*value)),
+        KernelObjectId(name, value) => (8, name, 0, Some(*value)),
+        Boolean(name, value) => (9, name, *value as u64, None),
+    };
+
+    let header = argument_type | (argument_words(argument) as u64) << 4 | string_field(name) << 16 | data << 32;
+    writer.write_all(&header.to_le_bytes())?;
+    write_inline_string(writer, name)?;
+
+    if let Some(word) = wide {
+        writer.write_all(&word.to_le_bytes())?;
+    }
+    if let Str(_, value) = argument {
+        write_inline_string(writer, value)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inline(s: &str) -> ftfrs::StringRef {
+        ftfrs::StringRef::Inline(s.to_string())
+    }
+
+    fn ftfrs_bytes(record: ftfrs::Record) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        record.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn hand_bytes(record: &EventRecord<'_>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        record.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Every argument kind, with names both inline and interned. Inline
+    /// string values stay within a word, since ftfrs sizes them as one word
+    /// whatever their length.
+    fn every_argument() -> Vec<ftfrs::Argument> {
+        vec![
+            ftfrs::Argument::Null(inline("null")),
+            ftfrs::Argument::Int32(ftfrs::StringRef::Ref(1), -7),
+            ftfrs::Argument::UInt32(inline("a longer name"), 7),
+            ftfrs::Argument::Int64(ftfrs::StringRef::Ref(2), -1 << 40),
+            ftfrs::Argument::UInt64(inline("u64"), 1 << 40),
+            ftfrs::Argument::Float(ftfrs::StringRef::Ref(3), 1.5),
+            ftfrs::Argument::Str(inline("str"), inline("value")),
+            ftfrs::Argument::Str(ftfrs::StringRef::Ref(4), ftfrs::StringRef::Ref(5)),
+            ftfrs::Argument::Pointer(inline("ptr"), 0xdead_beef),
+            ftfrs::Argument::KernelObjectId(ftfrs::StringRef::Ref(6), 42),
+            ftfrs::Argument::Boolean(inline("bool"), true),
+            ftfrs::Argument::Boolean(ftfrs::StringRef::Ref(7), false),
+        ]
+    }
+
+    #[test]
+    fn events_encode_as_ftfrs_does() {
+        let arguments = every_argument();
+        let threads = [
+            ftfrs::ThreadRef::Ref(3),
+            ftfrs::ThreadRef::Inline { process_koid: 10, thread_koid: 11 },
+        ];
+        let names = [(inline("category"), inline("event name")), (ftfrs::StringRef::Ref(8), ftfrs::StringRef::Ref(9))];
+        let kinds = [
+            EventKind::Instant,
+            EventKind::Counter { counter_id: 5 },
+            EventKind::DurationBegin,
+            EventKind::DurationEnd,
+            EventKind::DurationComplete { end_timestamp: 2000 },
+        ];
+
+        for thread in threads {
+            for (category, name) in &names {
+                for kind in kinds {
+                    for arguments in [&arguments[..], &arguments[..1], &[]] {
+                        let record = EventRecord {
+                            kind,
+                            timestamp: 1000,
+                            thread,
+                            category: category.clone(),
+                            name: name.clone(),
+                            arguments,
+                        };
+                        let bytes = hand_bytes(&record);
+                        assert_eq!(bytes, ftfrs_bytes(record.into_record()), "{kind:?} {thread:?} {category:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn long_inline_string_values_are_sized_in_full() {
+        let arguments = [ftfrs::Argument::Str(ftfrs::StringRef::Ref(1), inline("longer than a word"))];
+        let record = EventRecord::instant(0, ftfrs::ThreadRef::Ref(1), ftfrs::StringRef::Ref(2), ftfrs::StringRef::Ref(3), &arguments);
+        let bytes = hand_bytes(&record);
+
+        assert_eq!(bytes.len(), 8 * (2 + 1 + 3));
+        assert_eq!(crate::raw::declared_record_len(&bytes), Some(bytes.len()));
+
+        let mut reader = &bytes[..];
+        assert_eq!(ftfrs::Record::from_bytes(&mut reader).unwrap(), record.into_record());
+        assert!(reader.is_empty());
+    }
+}
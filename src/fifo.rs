@@ -0,0 +1,299 @@
+//! Writing a trace to a named pipe that readers come and go from.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, MutexGuard};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::replay::Replay;
+
+/// Bytes buffered while no reader is attached before further records are
+/// dropped
+const DEFAULT_BACKLOG_BYTES: usize = 4 * 1024 * 1024;
+
+/// How often opening the pipe is retried while no reader is attached
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A writer to a named pipe (FIFO) that an external consumer can attach to
+/// and detach from at any time, e.g. a live viewer.
+///
+/// While no reader is attached, records are buffered up to a cap and later
+/// ones dropped. When a reader attaches, it first gets the trace header and
+/// the current string and thread tables, then the buffered records, so it
+/// can decode the trace from there on. The pipe is created if it doesn't
+/// exist. Writes block while an attached reader falls behind, as with any
+/// pipe.
+///
+/// ```no_run
+/// use ftfrs_tracing::{FifoWriter, FtfLayer};
+///
+/// let layer = FtfLayer::new(FifoWriter::new("/tmp/trace.fifo").unwrap());
+/// ```
+#[derive(Debug)]
+pub struct FifoWriter {
+    state: Mutex<FifoState>,
+}
+
+#[derive(Debug)]
+struct FifoState {
+    path: PathBuf,
+    /// The pipe, while a reader is attached
+    pipe: Option<File>,
+    /// When opening the pipe was last tried
+    last_connect: Option<Instant>,
+    /// Records written while no reader was attached
+    backlog: Vec<u8>,
+    backlog_bytes: usize,
+    dropped_bytes: u64,
+    /// Bytes of the record being written
+    pending: Vec<u8>,
+    replay: Replay,
+}
+
+impl FifoWriter {
+    /// Write to the named pipe at `path`, creating it if needed
+    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_backlog_bytes(path, DEFAULT_BACKLOG_BYTES)
+    }
+
+    /// Like [`FifoWriter::new`], buffering at most `backlog_bytes` while no
+    /// reader is attached
+    pub fn with_backlog_bytes(path: impl AsRef<Path>, backlog_bytes: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if !path.exists() {
+            make_fifo(&path)?;
+        }
+
+        Ok(Self {
+            state: Mutex::new(FifoState {
+                path,
+                pipe: None,
+                last_connect: None,
+                backlog: Vec::new(),
+                backlog_bytes,
+                dropped_bytes: 0,
+                pending: Vec::new(),
+                replay: Replay::default(),
+            }),
+        })
+    }
+
+    /// Whether a reader is attached, as of the last write
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().pipe.is_some()
+    }
+
+    /// Bytes of records dropped because the backlog was full
+    pub fn dropped_bytes(&self) -> u64 {
+        self.state.lock().dropped_bytes
+    }
+}
+
+fn make_fifo(path: &Path) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "FIFO path contains a NUL byte"))?;
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl FifoState {
+    /// Deliver the complete records in `pending` to the reader, or to the
+    /// backlog if none is attached
+    fn deliver(&mut self) {
+        let records = std::mem::take(&mut self.pending);
+        self.replay.observe(&records);
+
+        if self.pipe.is_none() && self.connect() {
+            let mut replay = self.replay.encode();
+            replay.append(&mut self.backlog);
+            self.send(&replay);
+        }
+        self.send(&records);
+    }
+
+    /// Write `bytes` to the reader, falling back to the backlog if it has gone
+    fn send(&mut self, bytes: &[u8]) {
+        if let Some(pipe) = &mut self.pipe {
+            match pipe.write_all(bytes) {
+                Ok(()) => return,
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => self.pipe = None,
+                Err(e) => {
+                    eprintln!("Error writing to FTF FIFO {}: {}", self.path.display(), e);
+                    self.pipe = None;
+                }
+            }
+        }
+
+        if self.backlog.len() + bytes.len() <= self.backlog_bytes {
+            self.backlog.extend_from_slice(bytes);
+        } else {
+            self.dropped_bytes += bytes.len() as u64;
+        }
+    }
+
+    /// Open the pipe if a reader is waiting on it, trying at most once per
+    /// [`RECONNECT_INTERVAL`]
+    fn connect(&mut self) -> bool {
+        let now = Instant::now();
+        if self.last_connect.is_some_and(|last| now.duration_since(last) < RECONNECT_INTERVAL) {
+            return false;
+        }
+        self.last_connect = Some(now);
+
+        // Opening for writing without blocking fails with ENXIO while no
+        // reader has the pipe open
+        let pipe = match OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(&self.path) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                if e.raw_os_error() != Some(libc::ENXIO) {
+                    eprintln!("Error opening FTF FIFO {}: {}", self.path.display(), e);
+                }
+                return false;
+            }
+        };
+
+        // Block on a full pipe rather than dropping part of a record
+        let fd = pipe.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+            eprintln!("Error configuring FTF FIFO {}: {}", self.path.display(), io::Error::last_os_error());
+            return false;
+        }
+        self.pipe = Some(pipe);
+        true
+    }
+}
+
+/// Writer handed out by [`FifoWriter`], holding its lock for one record.
+#[derive(Debug)]
+pub struct FifoWriterGuard<'a> {
+    state: MutexGuard<'a, FifoState>,
+}
+
+impl io::Write for FifoWriterGuard<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.deliver();
+        match &mut self.state.pipe {
+            Some(pipe) => pipe.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FifoWriterGuard<'_> {
+    fn drop(&mut self) {
+        if !self.state.pending.is_empty() {
+            self.state.deliver();
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for FifoWriter {
+    type Writer = FifoWriterGuard<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        FifoWriterGuard {
+            state: self.state.lock(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::FileTypeExt;
+
+    use super::*;
+
+    fn fifo_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ftfrs-tracing-fifo-{}-{}", name, std::process::id()))
+    }
+
+    fn encode(record: ftfrs::Record) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        record.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Write one record the way the layer does
+    fn write_record(writer: &FifoWriter, bytes: &[u8]) {
+        let mut guard = writer.make_writer();
+        guard.write_all(bytes).unwrap();
+        guard.flush().unwrap();
+    }
+
+    #[test]
+    fn writes_without_a_reader_do_not_block() {
+        let path = fifo_path("unread");
+        let writer = FifoWriter::new(&path).unwrap();
+        assert!(fs::metadata(&path).unwrap().file_type().is_fifo());
+
+        write_record(&writer, &encode(ftfrs::Record::create_string(1, "name".to_string())));
+        assert!(!writer.is_connected());
+        assert_eq!(writer.dropped_bytes(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn records_past_the_backlog_cap_are_dropped() {
+        let path = fifo_path("capped");
+        let string = encode(ftfrs::Record::create_string(1, "name".to_string()));
+        let writer = FifoWriter::with_backlog_bytes(&path, string.len()).unwrap();
+
+        write_record(&writer, &string);
+        assert_eq!(writer.dropped_bytes(), 0);
+        write_record(&writer, &string);
+        assert_eq!(writer.dropped_bytes(), string.len() as u64);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_new_reader_gets_the_tables_then_the_backlog() {
+        let path = fifo_path("replayed");
+        let writer = FifoWriter::new(&path).unwrap();
+        let string = encode(ftfrs::Record::create_string(1, "name".to_string()));
+        let initialization = encode(ftfrs::Record::create_initialization(1_000_000_000));
+        write_record(&writer, &string);
+        write_record(&writer, &initialization);
+
+        // A reader can open the pipe without waiting for a writer
+        let mut reader = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(&path).unwrap();
+        std::thread::sleep(RECONNECT_INTERVAL);
+        let later = encode(ftfrs::Record::create_string(2, "later".to_string()));
+        write_record(&writer, &later);
+        assert!(writer.is_connected());
+
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        fs::remove_file(&path).unwrap();
+
+        let magic = encode(ftfrs::Record::create_magic_number());
+        assert!(received.starts_with(&[magic, string].concat()), "{:?}", received);
+        let backlog = received.windows(initialization.len()).position(|window| window == initialization);
+        assert!(backlog.is_some(), "the backlog follows the tables");
+        assert!(received.ends_with(&later));
+    }
+}
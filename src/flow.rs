@@ -0,0 +1,64 @@
+//! Flow and async event records, which ftfrs doesn't write.
+//!
+//! A flow begin and flow end sharing an id draw an arrow between the slices
+//! that enclose them; an async begin and end sharing an id draw a slice of
+//! their own, apart from any thread. They are encoded by hand like other
+//! events, with no arguments and a trailing correlation id.
+
+use std::io;
+
+use crate::event::write_event;
+
+/// Kind of flow event, as encoded in the event record header
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum FlowPhase {
+    Begin = 8,
+    End = 10,
+}
+
+/// Kind of async event, as encoded in the event record header
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum AsyncPhase {
+    Begin = 5,
+    End = 7,
+}
+
+/// Write a flow event record for `flow_id` at `timestamp`
+pub(crate) fn write_flow_event(
+    writer: &mut dyn io::Write,
+    phase: FlowPhase,
+    timestamp: u64,
+    thread: &ftfrs::ThreadRef,
+    category: &ftfrs::StringRef,
+    name: &ftfrs::StringRef,
+    flow_id: u64,
+) -> io::Result<()> {
+    write_correlated_event(writer, phase as u64, timestamp, thread, category, name, flow_id)
+}
+
+/// Write an async event record for `async_id` at `timestamp`
+pub(crate) fn write_async_event(
+    writer: &mut dyn io::Write,
+    phase: AsyncPhase,
+    timestamp: u64,
+    thread: &ftfrs::ThreadRef,
+    category: &ftfrs::StringRef,
+    name: &ftfrs::StringRef,
+    async_id: u64,
+) -> io::Result<()> {
+    write_correlated_event(writer, phase as u64, timestamp, thread, category, name, async_id)
+}
+
+/// Write an event record of `event_type` with no arguments, ending in
+/// `correlation_id`
+fn write_correlated_event(
+    writer: &mut dyn io::Write,
+    event_type: u64,
+    timestamp: u64,
+    thread: &ftfrs::ThreadRef,
+    category: &ftfrs::StringRef,
+    name: &ftfrs::StringRef,
+    correlation_id: u64,
+) -> io::Result<()> {
+    write_event(writer, event_type, timestamp, thread, category, name, &[], Some(correlation_id))
+}
@@ -0,0 +1,70 @@
+//! Fork/join correlation for work handed to a thread pool such as rayon's.
+//!
+//! Wrap the closures passed to `rayon::join`, `rayon::scope`'s `spawn`, or a
+//! parallel iterator's `map` so that each piece of work runs in a `task` span
+//! on its worker, tied to the span it was dispatched from by a flow arrow.
+//!
+//! ```
+//! # fn join<A: FnOnce() -> RA, B: FnOnce() -> RB, RA, RB>(a: A, b: B) -> (RA, RB) { (a(), b()) }
+//! use ftfrs_tracing::wrap_task;
+//!
+//! let _request = tracing::info_span!("request", ftf = true).entered();
+//! let (left, right) = join(wrap_task(|| 1 + 1), wrap_task(|| 2 + 2));
+//! # assert_eq!((left, right), (2, 4));
+//! ```
+//!
+//! Neither function depends on rayon; any pool that runs closures on other
+//! threads works the same way.
+
+use tracing::{dispatcher, Dispatch, Span};
+
+use crate::link::{begin_flow, end_flow};
+use crate::FtfExt;
+
+/// Wrap a closure that a pool runs once on a worker thread.
+///
+/// A flow begins now, in the current span, and ends when the closure starts
+/// on its worker, inside a new `task` span that is a child of the current
+/// span. The worker also uses the current dispatcher while running it.
+pub fn wrap_task<F, R>(f: F) -> impl FnOnce() -> R + Send
+where
+    F: FnOnce() -> R + Send,
+{
+    let dispatch = dispatcher::get_default(Dispatch::clone);
+    let parent = Span::current();
+    let flow_id = begin_flow(&parent);
+
+    move || {
+        dispatcher::with_default(&dispatch, || {
+            let task = tracing::info_span!(parent: &parent, "task", ftf = true);
+            let _entered = task.enter();
+            if let Some(flow_id) = flow_id {
+                end_flow(&task, flow_id);
+            }
+            f()
+        })
+    }
+}
+
+/// Wrap a closure that a pool calls once per item, like the one passed to a
+/// parallel iterator's `map`.
+///
+/// Each call runs in a new `task` span, a child of the span current when the
+/// closure was wrapped, and is linked to that span by a flow arrow drawn from
+/// its start.
+pub fn wrap_task_fn<F, T, R>(f: F) -> impl Fn(T) -> R + Send + Sync
+where
+    F: Fn(T) -> R + Send + Sync,
+{
+    let dispatch = dispatcher::get_default(Dispatch::clone);
+    let parent = Span::current();
+
+    move |item| {
+        dispatcher::with_default(&dispatch, || {
+            let task = tracing::info_span!(parent: &parent, "task", ftf = true);
+            parent.link_spans(&task);
+            let _entered = task.enter();
+            f(item)
+        })
+    }
+}
@@ -0,0 +1,93 @@
+//! Manual instrumentation of futures, without spans.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tracing::{dispatcher, Dispatch};
+
+/// Extension trait for tracing a [`Future`] into an
+/// [`FtfLayer`](crate::FtfLayer) without `#[instrument]` or span fields.
+pub trait FtfFutureExt: Future + Sized {
+    /// Trace this future as an async slice named `name` in `category`.
+    ///
+    /// An async begin is written when the future is first polled and an
+    /// async end when it completes, so the slice spans every thread it ran
+    /// on. Each poll is also written as a duration on the polling thread.
+    /// Nothing is written unless the current subscriber has an `FtfLayer`.
+    ///
+    /// ```
+    /// use ftfrs_tracing::FtfFutureExt;
+    ///
+    /// async fn fetch() -> u32 {
+    ///     42
+    /// }
+    ///
+    /// let traced = fetch().ftf_traced("http", "fetch");
+    /// ```
+    fn ftf_traced(self, category: &'static str, name: &'static str) -> FtfTraced<Self> {
+        FtfTraced {
+            inner: self,
+            category,
+            name,
+            async_id: None,
+        }
+    }
+}
+
+impl<F: Future> FtfFutureExt for F {}
+
+/// Future returned by [`FtfFutureExt::ftf_traced`]
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FtfTraced<F> {
+    inner: F,
+    category: &'static str,
+    name: &'static str,
+    /// Set on the first poll that reached an `FtfLayer`
+    async_id: Option<u64>,
+}
+
+impl<F: Future> Future for FtfTraced<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: `inner` is pinned structurally; it is never moved out of
+        // `self` and `FtfTraced` has no `Drop` impl
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+
+        // Cloned so that the future can use the dispatcher while polled
+        let dispatch = dispatcher::get_default(Dispatch::clone);
+        let Some(hooks) = dispatch.downcast_ref::<ManualHooks>() else {
+            return inner.poll(cx);
+        };
+
+        let async_id = *this
+            .async_id
+            .get_or_insert_with(|| (hooks.begin)(&dispatch, this.category, this.name));
+        let start = (hooks.now)(&dispatch);
+        let poll = inner.poll(cx);
+        (hooks.poll)(&dispatch, this.category, this.name, start);
+        if poll.is_ready() {
+            (hooks.end)(&dispatch, this.category, this.name, async_id);
+        }
+        poll
+    }
+}
+
+/// Entry points exposed through `Layer::downcast_raw` so traced futures,
+/// streams and blocking sections can reach the layer without knowing its writer type
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ManualHooks {
+    pub(crate) now: fn(&Dispatch) -> u64,
+    /// Write an async begin at the current time, returning its id
+    pub(crate) begin: fn(&Dispatch, &str, &str) -> u64,
+    /// Write a poll from `start` to the current time
+    pub(crate) poll: fn(&Dispatch, &str, &str, u64),
+    pub(crate) end: fn(&Dispatch, &str, &str, u64),
+    /// Write an instant for the item with the given index
+    pub(crate) item: fn(&Dispatch, &str, &str, u64),
+    /// Write a blocking section from `start` to the current time
+    pub(crate) blocking: fn(&Dispatch, &str, u64),
+}
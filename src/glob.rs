@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+
+use parking_lot::RwLock;
+use tracing_core::callsite::Identifier;
+use tracing_core::{Level, Metadata};
+
+use crate::FtfLayerConfig;
+
+/// A glob pattern, split into characters once to match many texts against.
+/// `*` matches any run of characters, including none, and `?` matches any
+/// one character.
+#[derive(Debug, Clone)]
+pub(crate) struct Glob {
+    pattern: Vec<char>,
+}
+
+impl Glob {
+    pub(crate) fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.chars().collect(),
+        }
+    }
+
+    /// Whether `text` matches the pattern
+    pub(crate) fn matches(&self, text: &str) -> bool {
+        let pattern = &self.pattern;
+        // Positions in `text` are byte offsets of characters
+        let (mut p, mut t) = (0, 0);
+        // Position of the last `*` and of the text it was tried against, to
+        // backtrack to when the rest fails to match
+        let mut star = None;
+
+        while let Some(c) = text[t..].chars().next() {
+            match pattern.get(p) {
+                Some('*') => {
+                    star = Some((p, t));
+                    p += 1;
+                }
+                Some(&pc) if pc == '?' || pc == c => {
+                    p += 1;
+                    t += c.len_utf8();
+                }
+                _ => match star {
+                    Some((star_p, star_t)) => {
+                        let skipped = text[star_t..].chars().next().map_or(0, char::len_utf8);
+                        p = star_p + 1;
+                        t = star_t + skipped;
+                        star = Some((star_p, t));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        pattern[p..].iter().all(|&c| c == '*')
+    }
+}
+
+/// Span names recorded through [`FtfLayerConfig::record_span_names`],
+/// matched once per callsite
+#[derive(Debug)]
+pub(crate) struct SpanNames {
+    patterns: Vec<Glob>,
+    callsites: RwLock<HashMap<Identifier, bool>>,
+}
+
+impl SpanNames {
+    pub(crate) fn new(patterns: &[String]) -> Self {
+        Self {
+            patterns: patterns.iter().map(|pattern| Glob::new(pattern)).collect(),
+            callsites: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Whether spans from the callsite of `metadata` are recorded
+    pub(crate) fn contains(&self, metadata: &Metadata<'_>) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+        let callsite = metadata.callsite();
+        if let Some(&recorded) = self.callsites.read().get(&callsite) {
+            return recorded;
+        }
+        let recorded = self.patterns.iter().any(|pattern| pattern.matches(metadata.name()));
+        self.callsites.write().insert(callsite, recorded);
+        recorded
+    }
+}
+
+/// Whether `name` has wildcards, and so is matched as a [`Glob`]
+fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+/// Values keyed by category names or glob patterns.
+///
+/// Names are looked up directly, so tables without patterns cost no more
+/// than a map. Patterns are only tried when no name matches, longest first,
+/// so `gfx:*` wins over `gfx*` for `gfx:detail`.
+#[derive(Debug)]
+pub(crate) struct CategoryPatterns<V> {
+    names: HashMap<String, V>,
+    patterns: Vec<(String, Glob, V)>,
+}
+
+impl<V: Clone> CategoryPatterns<V> {
+    pub(crate) fn new<'a>(entries: impl IntoIterator<Item = (&'a String, &'a V)>) -> Self
+    where
+        V: 'a,
+    {
+        let mut names = HashMap::new();
+        let mut patterns = Vec::new();
+        for (key, value) in entries {
+            if is_pattern(key) {
+                patterns.push((key.clone(), Glob::new(key), value.clone()));
+            } else {
+                names.insert(key.clone(), value.clone());
+            }
+        }
+        let mut table = Self { names, patterns };
+        table.sort();
+        table
+    }
+
+    fn sort(&mut self) {
+        self.patterns.sort_by(|(a, _, _), (b, _, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    }
+
+    /// Add or replace the entry for a name or pattern
+    pub(crate) fn insert(&mut self, key: &str, value: V) {
+        if !is_pattern(key) {
+            self.names.insert(key.to_string(), value);
+            return;
+        }
+        match self.patterns.iter_mut().find(|(pattern, _, _)| pattern == key) {
+            Some((_, _, existing)) => *existing = value,
+            None => {
+                self.patterns.push((key.to_string(), Glob::new(key), value));
+                self.sort();
+            }
+        }
+    }
+
+    /// Remove the entry for a name or pattern, returning whether there was one
+    pub(crate) fn remove(&mut self, key: &str) -> bool {
+        if !is_pattern(key) {
+            return self.names.remove(key).is_some();
+        }
+        let len = self.patterns.len();
+        self.patterns.retain(|(pattern, _, _)| pattern != key);
+        self.patterns.len() != len
+    }
+
+    /// The names and patterns with entries, sorted
+    pub(crate) fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.names.keys().chain(self.patterns.iter().map(|(pattern, _, _)| pattern)).cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    pub(crate) fn get(&self, category: &str) -> Option<&V> {
+        self.names.get(category).or_else(|| {
+            self.patterns
+                .iter()
+                .find(|(_, glob, _)| glob.matches(category))
+                .map(|(_, _, value)| value)
+        })
+    }
+
+    pub(crate) fn contains(&self, category: &str) -> bool {
+        self.get(category).is_some()
+    }
+}
+
+impl CategoryPatterns<()> {
+    pub(crate) fn from_set(categories: &HashSet<String>) -> Self {
+        Self::new(categories.iter().map(|category| (category, &())))
+    }
+}
+
+/// The category and span name tables of an [`FtfLayerConfig`] that accept
+/// glob patterns. The deny-list can be changed at runtime.
+#[derive(Debug)]
+pub(crate) struct CategoryFilters {
+    pub(crate) denied: RwLock<CategoryPatterns<()>>,
+    pub(crate) levels: CategoryPatterns<Level>,
+    pub(crate) process: CategoryPatterns<()>,
+    pub(crate) span_names: SpanNames,
+}
+
+impl CategoryFilters {
+    pub(crate) fn new(config: &FtfLayerConfig) -> Self {
+        Self {
+            denied: RwLock::new(CategoryPatterns::from_set(&config.denied_categories)),
+            levels: CategoryPatterns::new(&config.category_levels),
+            process: CategoryPatterns::from_set(&config.process_categories),
+            span_names: SpanNames::new(&config.record_span_names),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob_match(pattern: &str, text: &str) -> bool {
+        Glob::new(pattern).matches(text)
+    }
+
+    #[test]
+    fn literal_patterns() {
+        assert!(glob_match("render", "render"));
+        assert!(!glob_match("render", "renders"));
+        assert!(!glob_match("render", "rende"));
+        assert!(!glob_match("render", ""));
+    }
+
+    #[test]
+    fn empty_pattern_matches_only_empty_text() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn star_matches_any_run() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("**", "anything"));
+        assert!(glob_match("gfx:*", "gfx:"));
+        assert!(glob_match("gfx:*", "gfx:detail"));
+        assert!(!glob_match("gfx:*", "gfx"));
+        assert!(glob_match("*_handler", "http_handler"));
+        assert!(!glob_match("*_handler", "http_handlers"));
+        assert!(glob_match("a*b*c", "abc"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        // Backtracking past a partial match of what follows the star
+        assert!(glob_match("a*bc", "abcbbc"));
+        assert!(!glob_match("a*bc", "abcbb"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(glob_match("db?", "db1"));
+        assert!(!glob_match("db?", "db"));
+        assert!(!glob_match("db?", "db12"));
+        assert!(glob_match("?", "x"));
+        assert!(!glob_match("?", ""));
+        assert!(glob_match("?*", "x"));
+        assert!(!glob_match("?*", ""));
+    }
+
+    #[test]
+    fn multibyte_characters() {
+        assert!(glob_match("r?nder", "rénder"));
+        assert!(glob_match("?", "é"));
+        assert!(glob_match("描画*", "描画:詳細"));
+        assert!(glob_match("*:詳?", "描画:詳細"));
+        assert!(!glob_match("??", "é"));
+    }
+
+    #[test]
+    fn category_patterns_prefer_names_then_longer_patterns() {
+        let entries: HashMap<String, u8> =
+            [("gfx".to_string(), 1), ("gfx*".to_string(), 2), ("gfx:*".to_string(), 3)].into_iter().collect();
+        let mut table = CategoryPatterns::new(&entries);
+        assert_eq!(table.get("gfx"), Some(&1));
+        assert_eq!(table.get("gfx:detail"), Some(&3));
+        assert_eq!(table.get("gfxdetail"), Some(&2));
+        assert_eq!(table.get("net"), None);
+
+        table.insert("gfx:*", 4);
+        assert_eq!(table.get("gfx:detail"), Some(&4));
+        assert!(table.remove("gfx:*"));
+        assert!(!table.remove("gfx:*"));
+        assert_eq!(table.get("gfx:detail"), Some(&2));
+        assert_eq!(table.keys(), ["gfx", "gfx*"]);
+    }
+
+    #[test]
+    fn span_names_are_matched_per_callsite() {
+        let names = SpanNames::new(&["render_*".to_string()]);
+        let (render, other) = tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+            let metadata = |span: tracing::Span| span.metadata().expect("an enabled span has metadata");
+            (metadata(tracing::info_span!("render_frame")), metadata(tracing::info_span!("update")))
+        });
+        assert!(names.contains(render));
+        assert!(!names.contains(other));
+        assert!(names.contains(render));
+        assert_eq!(names.callsites.read().len(), 2);
+
+        let none = SpanNames::new(&[]);
+        assert!(!none.contains(render));
+        assert!(none.callsites.read().is_empty());
+    }
+}
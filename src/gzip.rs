@@ -0,0 +1,304 @@
+//! A small gzip encoder for uploading trace chunks.
+//!
+//! Traces repeat the same record headers, string references and arguments
+//! over and over, so LZ77 matching with deflate's fixed Huffman codes gets
+//! most of the way without building per-block code tables.
+
+use crate::crc32::crc32;
+
+/// Bytes back a match may refer to
+const WINDOW: usize = 32 * 1024;
+/// Shortest and longest matches deflate can encode
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Candidates tried per position before settling for the best so far
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Compress `data` into a gzip member
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no modification time, no extra flags,
+    // unknown OS
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    let mut bits = BitWriter { out, bits: 0, count: 0 };
+    deflate(data, &mut bits);
+    out = bits.finish();
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Encode `data` as a single final deflate block with the fixed codes
+fn deflate(data: &[u8], bits: &mut BitWriter) {
+    bits.write(1, 1);
+    bits.write(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW];
+    let mut pos = 0;
+    while pos < data.len() {
+        let (length, distance) = longest_match(data, pos, &head, &prev);
+        let step = if length >= MIN_MATCH {
+            write_match(bits, length, distance);
+            length
+        } else {
+            write_literal(bits, u16::from(data[pos]));
+            1
+        };
+        for i in pos..(pos + step).min(data.len().saturating_sub(MIN_MATCH - 1)) {
+            let hash = hash(&data[i..]);
+            prev[i % WINDOW] = head[hash];
+            head[hash] = i;
+        }
+        pos += step;
+    }
+    write_literal(bits, 256);
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+/// The longest earlier occurrence of the bytes at `pos`, as a length and
+/// distance back
+fn longest_match(data: &[u8], pos: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    if pos + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+    let max = (data.len() - pos).min(MAX_MATCH);
+    let (mut best_length, mut best_distance) = (0, 0);
+    let mut candidate = head[hash(&data[pos..])];
+    for _ in 0..MAX_CHAIN {
+        if candidate == usize::MAX || candidate >= pos || pos - candidate > WINDOW {
+            break;
+        }
+        let length = data[candidate..].iter().zip(&data[pos..pos + max]).take_while(|(a, b)| a == b).count();
+        if length > best_length {
+            best_length = length;
+            best_distance = pos - candidate;
+            if length == max {
+                break;
+            }
+        }
+        let next = prev[candidate % WINDOW];
+        // The slot may have been reused by a later position
+        if next != usize::MAX && next >= candidate {
+            break;
+        }
+        candidate = next;
+    }
+    (best_length, best_distance)
+}
+
+fn write_literal(bits: &mut BitWriter, value: u16) {
+    let (code, length) = match value {
+        0..=143 => (0x30 + value, 8),
+        144..=255 => (0x190 + value - 144, 9),
+        256..=279 => (value - 256, 7),
+        _ => (0xC0 + value - 280, 8),
+    };
+    bits.write_code(code, length);
+}
+
+fn write_match(bits: &mut BitWriter, length: usize, distance: usize) {
+    let index = LENGTH_BASE.iter().rposition(|&base| usize::from(base) <= length).expect("length of at least 3");
+    write_literal(bits, 257 + index as u16);
+    bits.write((length - usize::from(LENGTH_BASE[index])) as u32, LENGTH_EXTRA[index]);
+
+    let index = DISTANCE_BASE.iter().rposition(|&base| usize::from(base) <= distance).expect("distance of at least 1");
+    bits.write_code(index as u16, 5);
+    bits.write((distance - usize::from(DISTANCE_BASE[index])) as u32, DISTANCE_EXTRA[index]);
+}
+
+/// Packs bits least significant first, as deflate stores everything but
+/// Huffman codes
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u8) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Write a Huffman code, which is stored most significant bit first
+    fn write_code(&mut self, code: u16, length: u8) {
+        let reversed = code.reverse_bits() >> (16 - length);
+        self.write(u32::from(reversed), length);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits least significant first
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> u32 {
+            let bit = self.data[self.pos / 8] >> (self.pos % 8) & 1;
+            self.pos += 1;
+            u32::from(bit)
+        }
+
+        fn bits(&mut self, count: u8) -> u32 {
+            (0..count).fold(0, |value, i| value | self.bit() << i)
+        }
+
+        /// Read a Huffman code, stored most significant bit first
+        fn code(&mut self, length: u8) -> u32 {
+            (0..length).fold(0, |code, _| code << 1 | self.bit())
+        }
+
+        /// Read a literal or length symbol with the fixed codes
+        fn symbol(&mut self) -> u16 {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return 256 + code as u16;
+            }
+            let code = code << 1 | self.bit();
+            match code {
+                0x30..=0xBF => (code - 0x30) as u16,
+                0xC0..=0xC7 => (280 + code - 0xC0) as u16,
+                _ => (144 + (code << 1 | self.bit()) - 0x190) as u16,
+            }
+        }
+    }
+
+    /// Decompress a gzip member of one fixed-code block, checking its
+    /// trailer, and return the data with the longest match length and
+    /// distance used
+    fn decompress(gzip: &[u8]) -> (Vec<u8>, usize, usize) {
+        assert_eq!(gzip[..4], [0x1f, 0x8b, 8, 0]);
+        let mut bits = BitReader { data: &gzip[10..], pos: 0 };
+        assert_eq!(bits.bits(1), 1, "final block");
+        assert_eq!(bits.bits(2), 1, "fixed codes");
+
+        let (mut out, mut max_length, mut max_distance) = (Vec::new(), 0, 0);
+        loop {
+            let symbol = bits.symbol();
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => break,
+                _ => {
+                    let index = usize::from(symbol - 257);
+                    let length = usize::from(LENGTH_BASE[index]) + bits.bits(LENGTH_EXTRA[index]) as usize;
+                    let index = bits.code(5) as usize;
+                    let distance = usize::from(DISTANCE_BASE[index]) + bits.bits(DISTANCE_EXTRA[index]) as usize;
+                    assert!(distance <= out.len(), "distance {} before the start", distance);
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                    max_length = max_length.max(length);
+                    max_distance = max_distance.max(distance);
+                }
+            }
+        }
+
+        let trailer = &gzip[10 + bits.pos.div_ceil(8)..];
+        assert_eq!(trailer.len(), 8);
+        assert_eq!(trailer[..4], crc32(&out).to_le_bytes());
+        assert_eq!(trailer[4..], (out.len() as u32).to_le_bytes());
+        (out, max_length, max_distance)
+    }
+
+    /// Bytes from a xorshift generator, which don't compress
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(data: &[u8]) -> (usize, usize) {
+        let (out, max_length, max_distance) = decompress(&compress(data));
+        assert_eq!(out, data);
+        (max_length, max_distance)
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(round_trip(&[]), (0, 0));
+    }
+
+    #[test]
+    fn incompressible_input() {
+        let data = noise(10_000);
+        round_trip(&data);
+        // Literals cost at most 9 bits
+        assert!(compress(&data).len() <= data.len() * 9 / 8 + 32);
+    }
+
+    #[test]
+    fn long_runs_use_the_longest_match() {
+        let data = vec![b'a'; 10_000];
+        let (max_length, max_distance) = round_trip(&data);
+        assert_eq!(max_length, MAX_MATCH);
+        assert_eq!(max_distance, 1);
+        assert!(compress(&data).len() < 200);
+    }
+
+    #[test]
+    fn matches_reach_the_whole_window() {
+        let mut data = noise(WINDOW);
+        data.extend_from_within(..1000);
+        let (max_length, max_distance) = round_trip(&data);
+        assert_eq!(max_distance, WINDOW);
+        assert_eq!(max_length, MAX_MATCH);
+    }
+
+    #[test]
+    fn input_longer_than_the_window() {
+        let mut data = Vec::new();
+        for i in 0..20_000 {
+            data.extend_from_slice(format!("record {} of the trace\n", i % 977).as_bytes());
+        }
+        data.extend_from_slice(&noise(3 * WINDOW));
+        assert!(data.len() > 4 * WINDOW);
+        let (_, max_distance) = round_trip(&data);
+        assert!(max_distance <= WINDOW);
+    }
+
+    #[test]
+    fn short_inputs() {
+        for len in 0..8 {
+            round_trip(&noise(len));
+            round_trip(&vec![7; len]);
+        }
+    }
+}
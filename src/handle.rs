@@ -0,0 +1,465 @@
+#[cfg(all(feature = "sched", any(target_os = "linux", target_os = "android")))]
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::event::EventRecord;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::io_sampler::{IoSample, IoSamplerGuard};
+#[cfg(feature = "net-sampler")]
+use crate::net_sampler::{NetCounters, NetSamplerGuard};
+#[cfg(all(feature = "sched", any(target_os = "linux", target_os = "android")))]
+use crate::sched::{SchedSamplerGuard, ThreadSample};
+#[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+use crate::ProfilerGuard;
+use crate::schema::SchemaKind;
+use crate::{FtfLayer, FtfTrack, WatchdogGuard};
+
+/// Category and name of the records written by [`FtfHandle::frame_mark`]
+const FRAME_CATEGORY: &str = "frame";
+
+/// First koid handed out to tracks, far above the ids given to real threads
+const TRACK_KOID_BASE: u64 = 1 << 48;
+
+/// Thread a directly emitted record is attributed to
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Scope<'a> {
+    /// The calling thread
+    Thread,
+    /// The process as a whole, written as an inline thread with koid 0 so no
+    /// thread is interned
+    Process,
+    /// A named track, written as a synthetic thread of the process
+    Track { koid: u64, name: &'a str },
+}
+
+/// Handle for writing records that don't come from `tracing` spans or events
+/// into an [`FtfLayer`]'s output.
+///
+/// Obtained from [`FtfLayer::handle`]. Records go through the same intern
+/// tables, category aliases, deny-lists and output files as the layer's own.
+#[derive(Debug)]
+pub struct FtfHandle<W: for<'a> MakeWriter<'a>> {
+    layer: FtfLayer<W>,
+}
+
+/// Value of an argument passed to [`FtfHandle`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgValue<'a> {
+    /// Written as an `Int64` argument
+    I64(i64),
+    /// Written as a `UInt64` argument
+    U64(u64),
+    /// Written as a `Float` argument
+    F64(f64),
+    /// Written as a `Boolean` argument
+    Bool(bool),
+    /// Written as a `Str` argument, interned like a string field value
+    Str(&'a str),
+}
+
+impl From<i64> for ArgValue<'_> {
+    fn from(value: i64) -> Self {
+        Self::I64(value)
+    }
+}
+
+impl From<u64> for ArgValue<'_> {
+    fn from(value: u64) -> Self {
+        Self::U64(value)
+    }
+}
+
+impl From<f64> for ArgValue<'_> {
+    fn from(value: f64) -> Self {
+        Self::F64(value)
+    }
+}
+
+impl From<bool> for ArgValue<'_> {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl<'a> From<&'a str> for ArgValue<'a> {
+    fn from(value: &'a str) -> Self {
+        Self::Str(value)
+    }
+}
+
+impl<W: for<'a> MakeWriter<'a>> Clone for FtfHandle<W> {
+    fn clone(&self) -> Self {
+        Self::new(self.layer.share())
+    }
+}
+
+impl<W> FtfHandle<W>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    /// Start a thread that calls [`FtfHandle::check_open_spans`] every
+    /// `interval` until the returned guard is dropped
+    pub fn spawn_watchdog(&self, interval: Duration) -> WatchdogGuard {
+        WatchdogGuard::spawn(self.clone(), interval)
+    }
+
+    /// Start a thread that writes an `io` counter in the `io` category every
+    /// `interval` until the returned guard is dropped.
+    ///
+    /// The counter carries the process's `open_fds`, and the bytes passed to
+    /// read and write calls (`read_chars`, `written_chars`) and actually
+    /// moved to or from storage (`read_bytes`, `write_bytes`) since the last
+    /// sample, as reported by `/proc/self/io`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn spawn_io_sampler(&self, interval: Duration) -> IoSamplerGuard {
+        IoSamplerGuard::spawn(self.clone(), interval)
+    }
+
+    /// Start a thread that writes a counter per network interface in the
+    /// `net` category every `interval` until the returned guard is dropped.
+    ///
+    /// Interfaces are read from `/proc/self/net/dev`, which covers the
+    /// process's network namespace. Each counter is named after its
+    /// interface and carries the `rx_bytes`, `tx_bytes`, `rx_packets` and
+    /// `tx_packets` since the last sample.
+    #[cfg(all(feature = "net-sampler", any(target_os = "linux", target_os = "android")))]
+    pub fn spawn_net_sampler(&self, interval: Duration) -> NetSamplerGuard {
+        NetSamplerGuard::spawn(self.clone(), interval, Box::new(crate::net_sampler::read_proc_net_dev))
+    }
+
+    /// Like [`FtfHandle::spawn_net_sampler`], reading each interface's
+    /// running totals from `source` instead, e.g. a connection pool's own
+    /// byte counts.
+    #[cfg(feature = "net-sampler")]
+    pub fn spawn_net_sampler_with<F>(&self, interval: Duration, source: F) -> NetSamplerGuard
+    where
+        F: FnMut() -> io::Result<Vec<(String, NetCounters)>> + Send + 'static,
+    {
+        NetSamplerGuard::spawn(self.clone(), interval, Box::new(source))
+    }
+
+    /// Start a thread that samples the scheduling of this process's threads
+    /// from procfs every `interval` until the returned guard is dropped.
+    ///
+    /// Threads starting or stopping running between samples are written as
+    /// context switch records, and each thread that was scheduled gets a
+    /// `sched` counter with its CPU time, wait time and switches since the
+    /// last sample.
+    #[cfg(all(feature = "sched", any(target_os = "linux", target_os = "android")))]
+    pub fn spawn_sched_sampler(&self, interval: Duration) -> SchedSamplerGuard {
+        SchedSamplerGuard::spawn(self.clone(), interval)
+    }
+
+    /// Start a sampling profiler that captures the stack of each of this
+    /// process's running threads every `interval` until the returned guard
+    /// is dropped.
+    ///
+    /// Each stack is written as a `sample` instant event in the `profiler`
+    /// category on the sampled thread, with a `stack` argument holding the
+    /// frames outermost first, separated by `;`.
+    /// Only one profiler can run in a process at a time; it uses `SIGPROF`.
+    #[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+    pub fn spawn_profiler(&self, interval: Duration) -> ProfilerGuard {
+        ProfilerGuard::spawn(self.clone(), interval)
+    }
+}
+
+impl<W: for<'a> MakeWriter<'a>> FtfHandle<W> {
+    pub(crate) fn new(layer: FtfLayer<W>) -> Self {
+        Self { layer }
+    }
+
+    /// Get the current trace time, in nanoseconds since the
+    /// [`timestamp_epoch`](crate::FtfLayerConfig::timestamp_epoch), for
+    /// converting external timestamps
+    pub fn now(&self) -> u64 {
+        self.layer.now()
+    }
+
+    /// Write an instant event at `ts_ns`, in nanoseconds since the timestamp
+    /// epoch, on the calling thread
+    pub fn emit_instant_at(&self, ts_ns: u64, category: &str, name: &str, args: &[(&str, ArgValue<'_>)]) {
+        self.emit_instant(Scope::Thread, ts_ns, category, name, args);
+    }
+
+    /// Write an instant event at `ts_ns` attributed to the process as a whole
+    /// rather than the calling thread, for markers such as config reloads
+    pub fn emit_process_instant_at(&self, ts_ns: u64, category: &str, name: &str, args: &[(&str, ArgValue<'_>)]) {
+        self.emit_instant(Scope::Process, ts_ns, category, name, args);
+    }
+
+    /// Write a complete duration event from `start_ns` to `end_ns`, in
+    /// nanoseconds since the timestamp epoch, on the calling thread.
+    ///
+    /// An `end_ns` before `start_ns` is clamped so the slice has zero length.
+    pub fn emit_duration_at(
+        &self,
+        start_ns: u64,
+        end_ns: u64,
+        category: &str,
+        name: &str,
+        args: &[(&str, ArgValue<'_>)],
+    ) {
+        self.emit_duration(Scope::Thread, start_ns, end_ns, category, name, args);
+    }
+
+    /// Create a named virtual track, such as `"GPU queue"`, that events can
+    /// be emitted to independently of any real thread.
+    ///
+    /// Tracks are written as synthetic threads of the process, named with a
+    /// kernel object record the first time they are used in each output.
+    pub fn create_track(&self, name: &str) -> FtfTrack<W> {
+        let koid = TRACK_KOID_BASE + self.layer.next_track_id.fetch_add(1, Ordering::Relaxed);
+        FtfTrack::new(self.clone(), koid, name.to_string())
+    }
+
+    /// Mark the start of frame `frame_number` with a process-scoped instant
+    /// event in the `frame` category, so viewers can align work to frames.
+    ///
+    /// With [`FtfLayerConfig::frame_counter`](crate::FtfLayerConfig::frame_counter)
+    /// set, a counter record tracking the frame number is written as well.
+    pub fn frame_mark(&self, frame_number: u64) {
+        let ts_ns = self.now();
+        let args = [(FRAME_CATEGORY, ArgValue::U64(frame_number))];
+
+        self.emit(Scope::Process, SchemaKind::Event, FRAME_CATEGORY, FRAME_CATEGORY, &args, |thread_ref, category_ref, name_ref, arguments| {
+            EventRecord::instant(ts_ns, thread_ref, category_ref, name_ref, arguments)
+        });
+
+        if self.layer.config.frame_counter {
+            self.emit(Scope::Process, SchemaKind::Counter, FRAME_CATEGORY, FRAME_CATEGORY, &args, |thread_ref, category_ref, name_ref, arguments| {
+                EventRecord::counter(ts_ns, thread_ref, category_ref, name_ref, arguments, 0)
+            });
+        }
+    }
+
+    /// End every recorded span that is still open, e.g. just before the
+    /// process exits, so their durations aren't lost.
+    ///
+    /// Each gets an end record at the current time with a `truncated = true`
+    /// argument, and the main writer is flushed. Open spans keep their
+    /// subscriber alive, so this can't wait for the layer to be dropped.
+    pub fn end_open_spans(&self) {
+        self.layer.end_open_spans();
+    }
+
+    /// Write `payload` to the main output as a blob record named `name`,
+    /// e.g. an encoded CPU profile to keep alongside the trace.
+    ///
+    /// Payloads over 32 KiB are written as a large blob record in the
+    /// `blob` category.
+    pub fn write_blob(&self, name: &str, payload: &[u8]) {
+        self.layer.write_blob(name, payload);
+    }
+
+    /// Write a `latency summary` instant event in the `ftfrs` category for
+    /// each span name closed since the last summary, with its count and
+    /// min, mean, p50, p95, p99 and max durations, if
+    /// [`FtfLayerConfig::latency_summary`](crate::FtfLayerConfig::latency_summary)
+    /// is set. Dropping a [`FlushGuard`](crate::FlushGuard) does this too.
+    pub fn write_latency_summary(&self) {
+        self.layer.write_latency_summary();
+    }
+
+    /// Write a `slowest span` instant event in the `ftfrs` category for each
+    /// of the slowest spans closed since the last report, if
+    /// [`FtfLayerConfig::slowest_spans`](crate::FtfLayerConfig::slowest_spans)
+    /// is set. Each sits at its span's start on the span's thread and carries
+    /// `rank`, `span`, `category` and `duration_ns` arguments. Dropping a
+    /// [`FlushGuard`](crate::FlushGuard) does this too.
+    pub fn write_slowest_spans(&self) {
+        self.layer.write_slowest_spans();
+    }
+
+    /// Write a `schema` blob listing the categories, span names, event names
+    /// and counter names seen so far as a JSON object of sorted arrays, if
+    /// [`FtfLayerConfig::schema_record`](crate::FtfLayerConfig::schema_record)
+    /// is set. Dropping a [`FlushGuard`](crate::FlushGuard) does this too.
+    pub fn write_schema(&self) {
+        self.layer.write_schema();
+    }
+
+    /// End the current chunk of the main output with a trailer, if
+    /// [`FtfLayerConfig::chunk_trailers`](crate::FtfLayerConfig::chunk_trailers)
+    /// is set, so everything recorded so far survives a crash that tears a
+    /// later write. The next record starts a new chunk. Dropping a
+    /// [`FlushGuard`](crate::FlushGuard) does this too.
+    pub fn end_chunk(&self) {
+        self.layer.end_chunk();
+    }
+
+    /// Flush the layer's main output
+    pub fn flush(&self) -> io::Result<()> {
+        self.layer.flush()
+    }
+
+    /// Whether the layer is recording, i.e. hasn't been turned off by
+    /// [`FtfLayerConfig::max_write_failures`](crate::FtfLayerConfig::max_write_failures),
+    /// [`FtfLayerConfig::max_trace_bytes`](crate::FtfLayerConfig::max_trace_bytes)
+    /// or [`FtfHandle::stop_recording`]
+    pub fn is_recording(&self) -> bool {
+        self.layer.is_recording()
+    }
+
+    /// Turn recording back on after too many write failures,
+    /// [`FtfLayerConfig::max_trace_bytes`](crate::FtfLayerConfig::max_trace_bytes)
+    /// or [`FtfHandle::stop_recording`] turned it off, e.g. once disk space
+    /// has been freed. The byte budget starts over.
+    ///
+    /// The next record is preceded by the header and the string table, so
+    /// the trace reads correctly from there even if a record was cut short.
+    /// Spans that began or ended while recording was off are missing their
+    /// begin or end.
+    pub fn resume_recording(&self) {
+        self.layer.resume_recording();
+    }
+
+    /// Turn recording off, e.g. to end a trace session on a live process,
+    /// until [`FtfHandle::resume_recording`] turns it back on. Spans open
+    /// while it is off are missing their end.
+    pub fn stop_recording(&self) {
+        self.layer.stop_recording();
+    }
+
+    /// Stop recording spans and events in `category` from now on, as if it
+    /// were in [`FtfLayerConfig::denied_categories`](crate::FtfLayerConfig::denied_categories).
+    /// It may be a glob pattern.
+    pub fn deny_category(&self, category: &str) {
+        self.layer.deny_category(category);
+    }
+
+    /// Take a category or pattern off the deny-list, whether it was put
+    /// there by the config or by [`FtfHandle::deny_category`], returning
+    /// whether it was on it. Categories denied by other patterns stay denied.
+    pub fn allow_category(&self, category: &str) -> bool {
+        self.layer.allow_category(category)
+    }
+
+    /// The categories and patterns currently denied, sorted
+    pub fn denied_categories(&self) -> Vec<String> {
+        self.layer.denied_categories()
+    }
+
+    /// Limit `category` to `per_second` span and event records, as
+    /// [`FtfLayerConfig::category_budgets`](crate::FtfLayerConfig::category_budgets)
+    /// does, or lift its limit with `None`. A new budget starts full.
+    pub fn set_category_budget(&self, category: &str, per_second: Option<u32>) {
+        self.layer.set_category_budget(category, per_second);
+    }
+
+    /// Each category's record budget per second, sorted by category
+    pub fn category_budgets(&self) -> Vec<(String, u32)> {
+        self.layer.category_budgets()
+    }
+
+    /// Report recorded spans open for longer than
+    /// [`FtfLayerConfig::long_running_span_threshold`](crate::FtfLayerConfig::long_running_span_threshold),
+    /// returning how many were found.
+    ///
+    /// Each span is reported once: counted in
+    /// [`FtfLayerStats::long_running_spans`](crate::FtfLayerStats::long_running_spans)
+    /// and, if enabled, written as an instant event. With
+    /// [`FtfLayerConfig::long_running_span_repeat`](crate::FtfLayerConfig::long_running_span_repeat)
+    /// set, it is reported again each time that interval passes.
+    pub fn check_open_spans(&self) -> usize {
+        self.layer.check_open_spans()
+    }
+
+    /// Write an interface's traffic since the last network sample
+    #[cfg(feature = "net-sampler")]
+    pub(crate) fn write_net_sample(&self, interface: &str, delta: &NetCounters) {
+        self.layer.write_net_sample(interface, delta);
+    }
+
+    /// Write what changed between two I/O samples
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn write_io_sample(&self, previous: &IoSample, current: &IoSample) {
+        self.layer.write_io_sample(previous, current);
+    }
+
+    /// Write a stack sample of another thread, captured at `timestamp`
+    #[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+    pub(crate) fn write_stack_sample(&self, timestamp: u64, thread_id: u64, frames: &[&str]) {
+        self.layer.write_stack_sample(timestamp, thread_id, frames);
+    }
+
+    /// Write what changed between two scheduling samples
+    #[cfg(all(feature = "sched", any(target_os = "linux", target_os = "android")))]
+    pub(crate) fn write_sched_samples(
+        &self,
+        previous: &HashMap<u64, ThreadSample>,
+        current: &HashMap<u64, ThreadSample>,
+    ) {
+        self.layer.write_sched_samples(previous, current);
+    }
+
+    /// Write an instant event at `ts_ns` attributed to `scope`
+    pub(crate) fn emit_instant(&self, scope: Scope<'_>, ts_ns: u64, category: &str, name: &str, args: &[(&str, ArgValue<'_>)]) {
+        self.emit(scope, SchemaKind::Event, category, name, args, |thread_ref, category_ref, name_ref, arguments| {
+            EventRecord::instant(ts_ns, thread_ref, category_ref, name_ref, arguments)
+        });
+    }
+
+    /// Write a complete duration event attributed to `scope`, clamping an
+    /// end before the start
+    pub(crate) fn emit_duration(
+        &self,
+        scope: Scope<'_>,
+        start_ns: u64,
+        end_ns: u64,
+        category: &str,
+        name: &str,
+        args: &[(&str, ArgValue<'_>)],
+    ) {
+        self.emit(scope, SchemaKind::Span, category, name, args, |thread_ref, category_ref, name_ref, arguments| {
+            EventRecord::duration_complete(
+                start_ns,
+                thread_ref,
+                category_ref,
+                name_ref,
+                arguments,
+                end_ns.max(start_ns),
+            )
+        });
+    }
+
+    /// Resolve the references and arguments for a `kind` record in
+    /// `category` and write the record `build` makes from them
+    fn emit(
+        &self,
+        scope: Scope<'_>,
+        kind: SchemaKind,
+        category: &str,
+        name: &str,
+        args: &[(&str, ArgValue<'_>)],
+        build: impl FnOnce(ftfrs::ThreadRef, ftfrs::StringRef, ftfrs::StringRef, &[ftfrs::Argument]) -> EventRecord<'_>,
+    ) {
+        let category = self.layer.alias_category(category.into());
+        if self.layer.is_category_denied(&category) {
+            return;
+        }
+
+        self.layer.note_schema(kind, &category, name);
+        self.layer.with_destination(&category, |dest| {
+            let category_ref = dest.string_ref(&category);
+            let name_ref = dest.string_ref(name);
+            let thread_ref = match scope {
+                Scope::Thread => dest.thread_ref(self.layer.process_id(), self.layer.thread_id()),
+                Scope::Process => self.layer.process_thread_ref(),
+                Scope::Track { koid, name } => dest.named_thread_ref(self.layer.process_id(), koid, name),
+            };
+
+            let arguments = self.layer.arguments(dest, |visitor| {
+                for (name, value) in args {
+                    visitor.push_arg(name, value);
+                }
+            });
+
+            dest.write_event(build(thread_ref, category_ref, name_ref, &arguments));
+        });
+    }
+}
@@ -0,0 +1,194 @@
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Called when recording is disabled after
+/// [`FtfLayerConfig::max_write_failures`](crate::FtfLayerConfig::max_write_failures)
+/// consecutive failed writes, e.g. because the disk is full or the socket
+/// being written to has closed.
+///
+/// It runs with the main output locked, on whichever thread hit the last
+/// failure. Spans and events recorded from it are dropped, since recording is
+/// already off by then.
+///
+/// Any `Fn(&io::Error)` closure is a handler:
+///
+/// ```
+/// use std::io;
+/// use std::sync::Arc;
+///
+/// let config = ftfrs_tracing::FtfLayerConfig::builder()
+///     .max_write_failures(16)
+///     .write_failure_handler(Arc::new(|e: &io::Error| {
+///         eprintln!("tracing turned off: {}", e);
+///     }))
+///     .build();
+/// ```
+pub trait WriteFailureHandler: Send + Sync {
+    fn recording_disabled(&self, error: &io::Error);
+}
+
+impl<F> WriteFailureHandler for F
+where
+    F: Fn(&io::Error) + Send + Sync,
+{
+    fn recording_disabled(&self, error: &io::Error) {
+        self(error)
+    }
+}
+
+impl fmt::Debug for dyn WriteFailureHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WriteFailureHandler")
+    }
+}
+
+/// Consecutive write failures of and bytes written to the main output, and
+/// whether either has turned recording off
+#[derive(Debug)]
+pub(crate) struct WriteHealth {
+    limit: Option<u32>,
+    handler: Option<Arc<dyn WriteFailureHandler>>,
+    failures: AtomicU32,
+    max_bytes: Option<u64>,
+    /// Whether bytes written are counted, for a byte budget or chunk size
+    count_bytes: bool,
+    written: AtomicU64,
+    /// Whether the final record for an exceeded byte budget has been claimed
+    over_budget: AtomicBool,
+    disabled: AtomicBool,
+    /// Set when recording is resumed, so the next write starts with a fresh
+    /// header and string table
+    resync: AtomicBool,
+}
+
+impl WriteHealth {
+    pub(crate) fn new(
+        limit: Option<u32>,
+        handler: Option<Arc<dyn WriteFailureHandler>>,
+        max_bytes: Option<u64>,
+        count_bytes: bool,
+    ) -> Self {
+        Self {
+            limit,
+            handler,
+            failures: AtomicU32::new(0),
+            max_bytes,
+            count_bytes: count_bytes || max_bytes.is_some(),
+            written: AtomicU64::new(0),
+            over_budget: AtomicBool::new(false),
+            disabled: AtomicBool::new(false),
+            resync: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn is_recording(&self) -> bool {
+        !self.disabled.load(Ordering::Acquire)
+    }
+
+    /// Bytes written since recording started or was last resumed, if they
+    /// are counted
+    pub(crate) fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// Turn recording off until it is resumed
+    pub(crate) fn stop(&self) {
+        self.disabled.store(true, Ordering::Release);
+    }
+
+    /// Turn recording back on after it was disabled, with a fresh byte budget
+    pub(crate) fn resume(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.written.store(0, Ordering::Relaxed);
+        self.over_budget.store(false, Ordering::Release);
+        if self.disabled.swap(false, Ordering::AcqRel) {
+            self.resync.store(true, Ordering::Release);
+        }
+    }
+
+    /// Whether recording was resumed since the last call
+    pub(crate) fn take_resync(&self) -> bool {
+        self.resync.load(Ordering::Acquire) && self.resync.swap(false, Ordering::AcqRel)
+    }
+
+    /// Whether the byte budget has just been used up, in which case
+    /// recording is turned off and the caller should write the final record.
+    /// Only the first caller after the budget runs out gets `true`.
+    pub(crate) fn take_budget_exceeded(&self) -> bool {
+        let Some(max_bytes) = self.max_bytes else {
+            return false;
+        };
+        if self.written.load(Ordering::Relaxed) < max_bytes || self.over_budget.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.disabled.store(true, Ordering::Release);
+        true
+    }
+
+    fn succeeded(&self, bytes: usize) {
+        if self.count_bytes {
+            self.written.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        if self.failures.load(Ordering::Relaxed) != 0 {
+            self.failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn failed(&self, error: &io::Error) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+        if error.kind() == io::ErrorKind::Interrupted {
+            return;
+        }
+
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < limit || self.disabled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        match &self.handler {
+            Some(handler) => handler.recording_disabled(error),
+            None => eprintln!("Disabling FTF recording after {} consecutive write failures: {}", failures, error),
+        }
+    }
+}
+
+/// A writer that reports the outcome of every write to a [`WriteHealth`]
+pub(crate) struct MonitoredWriter<'a> {
+    inner: &'a mut dyn io::Write,
+    health: &'a WriteHealth,
+}
+
+impl<'a> MonitoredWriter<'a> {
+    pub(crate) fn new(inner: &'a mut dyn io::Write, health: &'a WriteHealth) -> Self {
+        Self { inner, health }
+    }
+
+    /// Report `result`, which wrote `bytes` if it succeeded
+    fn observe<T>(&self, result: io::Result<T>, bytes: impl FnOnce(&T) -> usize) -> io::Result<T> {
+        match &result {
+            Ok(value) => self.health.succeeded(bytes(value)),
+            Err(e) => self.health.failed(e),
+        }
+        result
+    }
+}
+
+impl io::Write for MonitoredWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.observe(result, |written| *written)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let result = self.inner.write_all(buf);
+        self.observe(result, |_| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.inner.flush();
+        self.observe(result, |_| 0)
+    }
+}
@@ -0,0 +1,757 @@
+//! Uploading trace chunks and files to an HTTP endpoint.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use parking_lot::{Condvar, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::raw::{declared_record_len, record_len};
+use crate::{gzip, MAGIC_NUMBER_RECORD};
+
+/// Completed chunks waiting to be uploaded before the oldest is dropped
+const DEFAULT_QUEUED_CHUNKS: usize = 16;
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A snapshot of the uploader's counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UploadMetrics {
+    /// Chunks the endpoint accepted
+    pub uploaded_chunks: u64,
+    /// Bytes of trace in the accepted chunks, before compression
+    pub uploaded_bytes: u64,
+    /// Failed attempts that were retried
+    pub retries: u64,
+    /// Chunks given up on after their last retry or a client error
+    pub failed_chunks: u64,
+    /// Chunks dropped because the queue was full
+    pub dropped_chunks: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    uploaded_chunks: AtomicU64,
+    uploaded_bytes: AtomicU64,
+    retries: AtomicU64,
+    failed_chunks: AtomicU64,
+    dropped_chunks: AtomicU64,
+}
+
+/// Where chunks are posted
+#[derive(Debug, Clone)]
+struct Endpoint {
+    /// The host name or address, without the brackets of an IPv6 address
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", message, url));
+        if url.starts_with("https://") {
+            return Err(invalid("HTTPS upload URLs are not supported, use an http:// URL"));
+        }
+        let rest = url.strip_prefix("http://").ok_or_else(|| invalid("upload URL must start with http://"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        // IPv6 addresses are bracketed, since they contain colons themselves
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed.split_once(']').ok_or_else(|| invalid("unclosed bracket in upload URL"))?;
+                match rest {
+                    "" => (host, None),
+                    _ => (host, Some(rest.strip_prefix(':').ok_or_else(|| invalid("invalid port in upload URL"))?)),
+                }
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid("invalid port in upload URL"))?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host in upload URL"));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// The value of a request's `Host` header
+    fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Sends requests to the endpoint with the builder's settings
+#[derive(Debug)]
+struct Client {
+    endpoint: Endpoint,
+    headers: Vec<(String, String)>,
+    gzip: bool,
+    max_retries: u32,
+    retry_backoff: Duration,
+    timeout: Duration,
+}
+
+impl Client {
+    /// Send `data` to `path`, compressed if configured, retrying server
+    /// errors and failed connections with exponential backoff. Returns
+    /// whether the endpoint accepted it.
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        what: &str,
+        extra_header: (&str, &str),
+        data: &[u8],
+        retries: &AtomicU64,
+    ) -> bool {
+        let body = if self.gzip { gzip::compress(data) } else { data.to_vec() };
+        let mut backoff = self.retry_backoff;
+        for attempt in 0..=self.max_retries {
+            if attempt > 0 {
+                retries.fetch_add(1, Ordering::Relaxed);
+                std::thread::sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+            }
+
+            let error = match self.request(method, path, extra_header, &body) {
+                Ok(status) if (200..300).contains(&status) => return true,
+                Ok(status) if status == 429 || status >= 500 => format!("HTTP status {}", status),
+                Ok(status) => {
+                    eprintln!("{} rejected by upload endpoint with HTTP status {}", what, status);
+                    return false;
+                }
+                Err(e) => e.to_string(),
+            };
+            if attempt == self.max_retries {
+                eprintln!("Error uploading {}: {}", what, error);
+            }
+        }
+        false
+    }
+
+    /// Send one request, returning the response's status code
+    fn request(&self, method: &str, path: &str, extra_header: (&str, &str), body: &[u8]) -> io::Result<u16> {
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n{}: {}\r\nConnection: close\r\n",
+            method,
+            path,
+            self.endpoint.authority(),
+            body.len(),
+            extra_header.0,
+            extra_header.1
+        );
+        if self.gzip {
+            request.push_str("Content-Encoding: gzip\r\n");
+        }
+        for (name, value) in &self.headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        parse_status_line(&status_line)
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in (self.endpoint.host.as_str(), self.endpoint.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "upload host has no addresses")))
+    }
+}
+
+/// The status code of an HTTP response's status line
+fn parse_status_line(line: &str) -> io::Result<u16> {
+    let mut parts = line.split_whitespace();
+    let version = parts.next().filter(|version| version.starts_with("HTTP/"));
+    version
+        .and(parts.next())
+        .filter(|status| status.len() == 3)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed HTTP status line {:?}", line)))
+}
+
+#[derive(Debug)]
+struct Queue {
+    /// Completed chunks and their sequence numbers
+    chunks: VecDeque<(u64, Vec<u8>)>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Current {
+    /// The chunk being written
+    chunk: Vec<u8>,
+    /// The start of a record whose rest hasn't been appended yet
+    tail: Vec<u8>,
+    next_sequence: u64,
+}
+
+#[derive(Debug)]
+struct Shared {
+    current: Mutex<Current>,
+    queue: Mutex<Queue>,
+    not_empty: Condvar,
+    client: Client,
+    queued_chunks: usize,
+    counters: Counters,
+}
+
+impl Shared {
+    /// Add whole records to the current chunk, queueing it for upload when
+    /// one of them starts the next chunk. A record cut short at the end of
+    /// `records` is held back until its rest is appended.
+    fn append(&self, records: &[u8]) {
+        let mut current = self.current.lock();
+        let joined;
+        let mut records = if current.tail.is_empty() {
+            records
+        } else {
+            let mut tail = std::mem::take(&mut current.tail);
+            tail.extend_from_slice(records);
+            joined = tail;
+            &joined[..]
+        };
+        while !records.is_empty() {
+            if declared_record_len(records).is_none_or(|len| len > records.len()) {
+                current.tail = records.to_vec();
+                break;
+            }
+            let len = record_len(records);
+            let record = &records[..len];
+            if record.starts_with(&MAGIC_NUMBER_RECORD) && !current.chunk.is_empty() {
+                self.complete(&mut current);
+            }
+            current.chunk.extend_from_slice(record);
+            records = &records[len..];
+        }
+    }
+
+    /// Queue the current chunk for upload
+    fn complete(&self, current: &mut Current) {
+        if current.chunk.is_empty() {
+            return;
+        }
+        let chunk = std::mem::take(&mut current.chunk);
+        let sequence = current.next_sequence;
+        current.next_sequence += 1;
+
+        let mut queue = self.queue.lock();
+        if queue.chunks.len() >= self.queued_chunks {
+            queue.chunks.pop_front();
+            self.counters.dropped_chunks.fetch_add(1, Ordering::Relaxed);
+        }
+        queue.chunks.push_back((sequence, chunk));
+        self.not_empty.notify_one();
+    }
+
+    fn close(&self) {
+        let mut current = self.current.lock();
+        // A record never finished is uploaded as far as it got
+        let tail = std::mem::take(&mut current.tail);
+        current.chunk.extend_from_slice(&tail);
+        self.complete(&mut current);
+        drop(current);
+        self.queue.lock().closed = true;
+        self.not_empty.notify_all();
+    }
+
+    fn run(&self) {
+        loop {
+            let next = {
+                let mut queue = self.queue.lock();
+                while queue.chunks.is_empty() && !queue.closed {
+                    self.not_empty.wait(&mut queue);
+                }
+                queue.chunks.pop_front()
+            };
+            match next {
+                Some((sequence, chunk)) => self.upload(sequence, &chunk),
+                None => return,
+            }
+        }
+    }
+
+    /// Post one chunk to the endpoint
+    fn upload(&self, sequence: u64, chunk: &[u8]) {
+        let sent = self.client.send(
+            "POST",
+            &self.client.endpoint.path,
+            &format!("FTF chunk {}", sequence),
+            ("X-Ftf-Chunk", &sequence.to_string()),
+            chunk,
+            &self.counters.retries,
+        );
+        if sent {
+            self.counters.uploaded_chunks.fetch_add(1, Ordering::Relaxed);
+            self.counters.uploaded_bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        } else {
+            self.counters.failed_chunks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn metrics(&self) -> UploadMetrics {
+        UploadMetrics {
+            uploaded_chunks: self.counters.uploaded_chunks.load(Ordering::Relaxed),
+            uploaded_bytes: self.counters.uploaded_bytes.load(Ordering::Relaxed),
+            retries: self.counters.retries.load(Ordering::Relaxed),
+            failed_chunks: self.counters.failed_chunks.load(Ordering::Relaxed),
+            dropped_chunks: self.counters.dropped_chunks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A [`MakeWriter`] that uploads the trace to an HTTP endpoint chunk by
+/// chunk, from a background thread.
+///
+/// The trace is split where the layer starts a new self-contained chunk, so
+/// set [`FtfLayerConfig::chunk_interval`](crate::FtfLayerConfig::chunk_interval)
+/// or [`chunk_bytes`](crate::FtfLayerConfig::chunk_bytes) to choose how often
+/// chunks are uploaded. Each chunk is posted on its own, so the endpoint can
+/// store and decode it independently. The last chunk is uploaded when the
+/// [`UploadGuard`] is dropped.
+#[derive(Debug, Clone)]
+pub struct HttpUploader {
+    shared: Arc<Shared>,
+}
+
+impl HttpUploader {
+    /// Get a snapshot of the upload counters
+    pub fn metrics(&self) -> UploadMetrics {
+        self.shared.metrics()
+    }
+}
+
+/// Per-record writer handed out by [`HttpUploader`].
+#[derive(Debug)]
+pub struct HttpUploaderWriter<'a> {
+    shared: &'a Shared,
+    buffer: Vec<u8>,
+}
+
+impl io::Write for HttpUploaderWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.shared.append(&std::mem::take(&mut self.buffer));
+        Ok(())
+    }
+}
+
+impl Drop for HttpUploaderWriter<'_> {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            self.shared.append(&self.buffer);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for HttpUploader {
+    type Writer = HttpUploaderWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        HttpUploaderWriter {
+            shared: &self.shared,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Keeps the upload thread alive.
+///
+/// Dropping the guard queues the chunk being written, waits for the queued
+/// chunks to be uploaded and joins the upload thread, so hold on to it until
+/// tracing is done.
+#[must_use = "dropping the guard immediately stops uploading"]
+#[derive(Debug)]
+pub struct UploadGuard {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        self.shared.close();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                eprintln!("FTF upload thread panicked");
+            }
+        }
+    }
+}
+
+/// Builder for an [`HttpUploader`].
+#[derive(Debug, Clone)]
+pub struct HttpUploaderBuilder {
+    url: String,
+    headers: Vec<(String, String)>,
+    gzip: bool,
+    queued_chunks: usize,
+    max_retries: u32,
+    retry_backoff: Duration,
+    timeout: Duration,
+    thread_name: String,
+}
+
+impl HttpUploaderBuilder {
+    /// Upload to `url`, which must be a plain `http://` URL. IPv6 hosts are
+    /// bracketed, as in `http://[::1]:8080/traces`.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            headers: Vec::new(),
+            gzip: true,
+            queued_chunks: DEFAULT_QUEUED_CHUNKS,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            timeout: DEFAULT_TIMEOUT,
+            thread_name: "ftfrs-tracing-upload".to_string(),
+        }
+    }
+
+    /// Add a header to every request, e.g. for authorization or to name the
+    /// host the trace comes from
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set whether chunks are gzip-compressed, which they are by default
+    pub fn gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+
+    /// Set how many completed chunks may wait for upload before the oldest
+    /// is dropped
+    pub fn queued_chunks(mut self, queued_chunks: usize) -> Self {
+        self.queued_chunks = queued_chunks.max(1);
+        self
+    }
+
+    /// Set how many times a failed upload is retried
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Set the wait before the first retry, which doubles on each one after
+    pub fn retry_backoff(mut self, retry_backoff: Duration) -> Self {
+        self.retry_backoff = retry_backoff;
+        self
+    }
+
+    /// Set how long connecting, sending and waiting for a response may take
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the name of the upload thread
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    fn client(&self) -> io::Result<Client> {
+        Ok(Client {
+            endpoint: Endpoint::parse(&self.url)?,
+            headers: self.headers.clone(),
+            gzip: self.gzip,
+            max_retries: self.max_retries,
+            retry_backoff: self.retry_backoff,
+            timeout: self.timeout,
+        })
+    }
+
+    /// Get a handler for
+    /// [`RollingFileBuilder::on_complete`](crate::RollingFileBuilder::on_complete) that uploads
+    /// each finished file whole, with a `PUT` to the URL's path followed by
+    /// the file's name, rather than streaming chunks.
+    ///
+    /// This suits object stores and gateways that accept uploads by `PUT`.
+    /// The queue and thread settings don't apply, since the handler runs on
+    /// the rolling file's own thread. Failed uploads leave the file in
+    /// place.
+    pub fn put_files(self) -> io::Result<impl Fn(&Path) + Send + Sync + 'static> {
+        let client = self.client()?;
+        let base = client.endpoint.path.trim_end_matches('/').to_string();
+        let retries = AtomicU64::new(0);
+        Ok(move |path: &Path| {
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                eprintln!("FTF file {} has no name to upload it under", path.display());
+                return;
+            };
+            match fs::read(path) {
+                Ok(data) => {
+                    let what = format!("FTF file {}", name);
+                    client.send("PUT", &format!("{}/{}", base, name), &what, ("X-Ftf-File", name), &data, &retries);
+                }
+                Err(e) => eprintln!("Error reading FTF file {} for upload: {}", path.display(), e),
+            }
+        })
+    }
+
+    /// Spawn the upload thread, failing if the URL is invalid
+    pub fn finish(self) -> io::Result<(HttpUploader, UploadGuard)> {
+        let shared = Arc::new(Shared {
+            current: Mutex::new(Current {
+                chunk: Vec::new(),
+                tail: Vec::new(),
+                next_sequence: 0,
+            }),
+            queue: Mutex::new(Queue {
+                chunks: VecDeque::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            client: self.client()?,
+            queued_chunks: self.queued_chunks,
+            counters: Counters::default(),
+        });
+
+        let worker = Arc::clone(&shared);
+        let handle = std::thread::Builder::new()
+            .name(self.thread_name)
+            .spawn(move || worker.run())?;
+
+        Ok((
+            HttpUploader {
+                shared: Arc::clone(&shared),
+            },
+            UploadGuard {
+                shared,
+                handle: Some(handle),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn status_lines() {
+        assert_eq!(parse_status_line("HTTP/1.1 200 OK\r\n").unwrap(), 200);
+        assert_eq!(parse_status_line("HTTP/1.0 503 Service Unavailable\r\n").unwrap(), 503);
+        assert_eq!(parse_status_line("HTTP/1.1 204\r\n").unwrap(), 204);
+        for line in ["", "\r\n", "garbage", "HTTP/1.1", "HTTP/1.1 OK 200", "HTTP/1.1 2000 OK", "SIP/2.0 200 OK"] {
+            let error = parse_status_line(line).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn endpoints() {
+        let endpoint = Endpoint::parse("http://collector:8080/traces/upload").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port, endpoint.path.as_str()), ("collector", 8080, "/traces/upload"));
+        let endpoint = Endpoint::parse("http://collector").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port, endpoint.path.as_str()), ("collector", 80, "/"));
+        assert_eq!(endpoint.authority(), "collector:80");
+
+        let endpoint = Endpoint::parse("http://[::1]:8080/traces").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port, endpoint.path.as_str()), ("::1", 8080, "/traces"));
+        assert_eq!(endpoint.authority(), "[::1]:8080");
+        let endpoint = Endpoint::parse("http://[fe80::1]").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port), ("fe80::1", 80));
+
+        let error = Endpoint::parse("https://collector/").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("HTTPS"), "{}", error);
+        for url in ["collector/", "http:///path", "http://collector:port/", "http://[::1/", "http://[::1]8080/", "http://[]:80/"] {
+            assert_eq!(Endpoint::parse(url).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", url);
+        }
+    }
+
+    /// A request as the test server received it
+    struct Request {
+        head: String,
+        body: Vec<u8>,
+    }
+
+    /// Serve one connection per status in `statuses`, answering each with
+    /// it, and return the address and the requests received
+    fn serve(statuses: Vec<u16>) -> (String, JoinHandle<Vec<Request>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    head.push_str(&line);
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                }
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .map_or(0, |length| length.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(stream, "HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+                requests.push(Request { head, body });
+            }
+            requests
+        });
+        (format!("http://{}/upload", addr), handle)
+    }
+
+    fn client(url: &str, gzip: bool, max_retries: u32) -> Client {
+        HttpUploaderBuilder::new(url)
+            .gzip(gzip)
+            .header("Authorization", "Bearer token")
+            .max_retries(max_retries)
+            .retry_backoff(Duration::from_millis(1))
+            .timeout(Duration::from_secs(5))
+            .client()
+            .unwrap()
+    }
+
+    fn send(client: &Client, retries: &AtomicU64) -> bool {
+        client.send("POST", "/upload", "test chunk", ("X-Ftf-Chunk", "7"), b"trace bytes", retries)
+    }
+
+    #[test]
+    fn requests_carry_headers_and_body() {
+        let (url, server) = serve(vec![200, 200]);
+        let retries = AtomicU64::new(0);
+        assert!(send(&client(&url, false, 0), &retries));
+        assert!(send(&client(&url, true, 0), &retries));
+
+        let requests = server.join().unwrap();
+        let plain = &requests[0];
+        assert!(plain.head.starts_with("POST /upload HTTP/1.1\r\n"));
+        assert!(plain.head.contains("\r\nX-Ftf-Chunk: 7\r\n"));
+        assert!(plain.head.contains("\r\nAuthorization: Bearer token\r\n"));
+        assert!(!plain.head.contains("Content-Encoding"));
+        assert_eq!(plain.body, b"trace bytes");
+
+        let compressed = &requests[1];
+        assert!(compressed.head.contains("\r\nContent-Encoding: gzip\r\n"));
+        assert_eq!(compressed.body, gzip::compress(b"trace bytes"));
+    }
+
+    #[test]
+    fn server_errors_are_retried() {
+        let (url, server) = serve(vec![503, 429, 200]);
+        let retries = AtomicU64::new(0);
+        assert!(send(&client(&url, false, 5), &retries));
+        assert_eq!(retries.load(Ordering::Relaxed), 2);
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let (url, server) = serve(vec![400]);
+        let retries = AtomicU64::new(0);
+        assert!(!send(&client(&url, false, 5), &retries));
+        assert_eq!(retries.load(Ordering::Relaxed), 0);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn retries_give_up() {
+        let (url, server) = serve(vec![500, 500, 500]);
+        let retries = AtomicU64::new(0);
+        assert!(!send(&client(&url, false, 2), &retries));
+        assert_eq!(retries.load(Ordering::Relaxed), 2);
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn failed_connections_are_retried() {
+        // Nothing listens on a port just released
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let retries = AtomicU64::new(0);
+        assert!(!send(&client(&format!("http://{}/", addr), false, 1), &retries));
+        assert_eq!(retries.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn uploader_posts_each_chunk() {
+        let (url, server) = serve(vec![200, 200]);
+        let (uploader, guard) = HttpUploaderBuilder::new(url).gzip(false).finish().unwrap();
+        let mut writer = uploader.make_writer();
+        for record in [&MAGIC_NUMBER_RECORD[..], &MAGIC_NUMBER_RECORD[..]] {
+            writer.write_all(record).unwrap();
+            writer.flush().unwrap();
+        }
+        drop(writer);
+        drop(guard);
+
+        let metrics = uploader.metrics();
+        assert_eq!(metrics.uploaded_chunks, 2);
+        assert_eq!(metrics.uploaded_bytes, 2 * MAGIC_NUMBER_RECORD.len() as u64);
+        let requests = server.join().unwrap();
+        assert!(requests[0].head.contains("\r\nX-Ftf-Chunk: 0\r\n"));
+        assert!(requests[1].head.contains("\r\nX-Ftf-Chunk: 1\r\n"));
+    }
+
+    #[test]
+    fn records_split_across_writes_stay_whole() {
+        let (url, server) = serve(vec![200, 200]);
+        let (uploader, guard) = HttpUploaderBuilder::new(url).gzip(false).finish().unwrap();
+        let trace = [&MAGIC_NUMBER_RECORD[..], &MAGIC_NUMBER_RECORD[..]].concat();
+        // Cut inside the first record's header, then inside the second record
+        for part in [&trace[..3], &trace[3..13], &trace[13..]] {
+            let mut writer = uploader.make_writer();
+            writer.write_all(part).unwrap();
+            writer.flush().unwrap();
+        }
+        drop(guard);
+
+        assert_eq!(uploader.metrics().uploaded_chunks, 2);
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].body, MAGIC_NUMBER_RECORD);
+        assert_eq!(requests[1].body, MAGIC_NUMBER_RECORD);
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs::File;
+use std::{error, fmt};
+use std::path::Path;
+#[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+use std::time::Duration;
+
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+
+#[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+use crate::pprof::CpuProfile;
+use crate::{FtfHandle, FtfLayer, FtfLayerConfig, FtfLayerError, SyncedFile};
+
+/// Writer used by the layer [`init`] installs
+pub type InitWriter = SyncedFile;
+
+/// Trace the whole process to the file at `path`, using the default
+/// configuration.
+///
+/// See [`init_with_config`].
+pub fn init(path: impl AsRef<Path>) -> Result<FlushGuard<InitWriter>, FtfLayerError> {
+    init_with_config(path, FtfLayerConfig::default())
+}
+
+/// Trace the whole process to the file at `path`.
+///
+/// Creates the file, writes the trace header and installs a registry with
+/// the layer as the global default subscriber. Output is buffered, so keep
+/// the returned guard alive until the end of `main`; dropping it ends open
+/// spans, flushes the file and syncs it to disk. In between, the file is
+/// synced as [`FtfLayerConfig::sync_policy`] says, with space reserved ahead
+/// of the trace if [`FtfLayerConfig::preallocation`] is set.
+pub fn init_with_config(path: impl AsRef<Path>, config: FtfLayerConfig) -> Result<FlushGuard<InitWriter>, FtfLayerError> {
+    let file = File::create(path)?;
+    let sync_file = file.try_clone()?;
+    let writer = SyncedFile::for_config(file, &config)?;
+    let layer = FtfLayer::try_with_config(writer, config)?;
+    let guard = layer.flush_guard().syncing(sync_file);
+
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))?;
+    Ok(guard)
+}
+
+/// Shuts a layer's output down cleanly when dropped.
+///
+/// Obtained from [`init`] or [`FtfLayer::flush_guard`]. Dropping it writes
+/// the [`FtfHandle::write_latency_summary`] and
+/// [`FtfHandle::write_slowest_spans`] reports and the
+/// [`FtfHandle::write_schema`] blob if they are collected, ends the
+/// layer's open spans as [`FtfHandle::end_open_spans`] does, ends the
+/// chunk with a trailer as [`FtfHandle::end_chunk`] does, flushes the main
+/// writer and, if given a file with [`FlushGuard::syncing`], syncs it to
+/// disk. When writing through [`non_blocking`](crate::non_blocking), drop
+/// this guard before the [`WorkerGuard`](crate::WorkerGuard).
+#[must_use = "dropping the guard immediately ends open spans and flushes"]
+pub struct FlushGuard<W: for<'a> MakeWriter<'a>> {
+    handle: FtfHandle<W>,
+    sync_file: Option<File>,
+    blobs: Vec<(String, BlobSource)>,
+}
+
+/// Produces a blob's payload when a [`FlushGuard`] is dropped
+type BlobSource = Box<dyn FnOnce() -> Result<Vec<u8>, Box<dyn error::Error + Send + Sync>> + Send>;
+
+impl<W: for<'a> MakeWriter<'a>> FlushGuard<W> {
+    pub(crate) fn new(handle: FtfHandle<W>) -> Self {
+        Self {
+            handle,
+            sync_file: None,
+            blobs: Vec::new(),
+        }
+    }
+
+    /// Also sync `file` to disk on drop, after flushing.
+    ///
+    /// Pass a [`File::try_clone`] of the file the layer writes to.
+    pub fn syncing(mut self, file: File) -> Self {
+        self.sync_file = Some(file);
+        self
+    }
+
+    /// Also write the payload `payload` returns as a blob record named
+    /// `name` on drop, before ending open spans.
+    ///
+    /// This attaches data only available at shutdown, such as a report from
+    /// another tool that ran alongside the trace, to the same file.
+    pub fn with_blob<F>(mut self, name: impl Into<String>, payload: F) -> Self
+    where
+        F: FnOnce() -> Result<Vec<u8>, Box<dyn error::Error + Send + Sync>> + Send + 'static,
+    {
+        self.blobs.push((name.into(), Box::new(payload)));
+        self
+    }
+
+    /// Start a CPU profile now, sampling the stack of each running thread
+    /// every `interval`, and on drop stop it and write it as a gzipped pprof
+    /// profile in a blob named `cpu.pprof`.
+    ///
+    /// The profile uses the same sampler as [`FtfHandle::spawn_profiler`],
+    /// so it can't run while one of those does.
+    #[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+    pub fn with_cpu_profile(self, interval: Duration) -> Self {
+        let profile = CpuProfile::start(interval);
+        self.with_blob("cpu.pprof", move || Ok(profile.finish()))
+    }
+}
+
+impl<W: for<'a> MakeWriter<'a> + fmt::Debug> fmt::Debug for FlushGuard<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlushGuard")
+            .field("handle", &self.handle)
+            .field("sync_file", &self.sync_file)
+            .field("blobs", &self.blobs.iter().map(|(name, _)| name).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl<W: for<'a> MakeWriter<'a>> Drop for FlushGuard<W> {
+    fn drop(&mut self) {
+        for (name, payload) in self.blobs.drain(..) {
+            match payload() {
+                Ok(payload) => self.handle.write_blob(&name, &payload),
+                Err(e) => eprintln!("Error producing FTF blob {:?}: {}", name, e),
+            }
+        }
+        self.handle.write_latency_summary();
+        self.handle.write_slowest_spans();
+        self.handle.write_schema();
+        self.handle.end_open_spans();
+        self.handle.end_chunk();
+        if let Err(e) = self.handle.flush() {
+            eprintln!("Error flushing FTF output: {}", e);
+        }
+        if let Some(file) = &self.sync_file {
+            if let Err(e) = file.sync_all() {
+                eprintln!("Error syncing FTF output: {}", e);
+            }
+        }
+    }
+}
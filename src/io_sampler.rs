@@ -0,0 +1,137 @@
+//! Process I/O samples, read from procfs.
+
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::{fs, io};
+
+use parking_lot::{Condvar, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::FtfHandle;
+
+/// The process's open file descriptors and I/O totals at one sample
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct IoSample {
+    pub(crate) open_fds: u64,
+    /// Bytes passed to `read`-like calls, whether or not they hit storage
+    pub(crate) read_chars: u64,
+    /// Bytes passed to `write`-like calls, whether or not they hit storage
+    pub(crate) written_chars: u64,
+    /// Bytes fetched from storage
+    pub(crate) read_bytes: u64,
+    /// Bytes sent to storage
+    pub(crate) write_bytes: u64,
+}
+
+/// Sample the process's open file descriptors and `/proc/self/io`
+pub(crate) fn read_io() -> io::Result<IoSample> {
+    let mut sample = IoSample {
+        open_fds: fs::read_dir("/proc/self/fd")?.count() as u64,
+        ..IoSample::default()
+    };
+
+    for line in fs::read_to_string("/proc/self/io")?.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let Ok(value) = value.trim().parse() else {
+            continue;
+        };
+        match key {
+            "rchar" => sample.read_chars = value,
+            "wchar" => sample.written_chars = value,
+            "read_bytes" => sample.read_bytes = value,
+            "write_bytes" => sample.write_bytes = value,
+            _ => {}
+        }
+    }
+    Ok(sample)
+}
+
+/// Keeps a sampler started by [`FtfHandle::spawn_io_sampler`] running.
+///
+/// Dropping the guard stops the sampler and joins its thread.
+#[must_use = "dropping the guard immediately stops the sampler"]
+#[derive(Debug)]
+pub struct IoSamplerGuard {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl IoSamplerGuard {
+    pub(crate) fn spawn<W>(handle: FtfHandle<W>, interval: Duration) -> Self
+    where
+        W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let spawned = std::thread::Builder::new()
+            .name("ftfrs-tracing-io".to_string())
+            .spawn(move || {
+                let mut previous = None;
+                let (stopped, condvar) = &*thread_stop;
+                let mut stopped = stopped.lock();
+                while !*stopped {
+                    match read_io() {
+                        Ok(current) => {
+                            if let Some(previous) = &previous {
+                                handle.write_io_sample(previous, &current);
+                            }
+                            previous = Some(current);
+                        }
+                        Err(e) => {
+                            eprintln!("Error reading process I/O stats: {}", e);
+                            return;
+                        }
+                    }
+                    condvar.wait_for(&mut stopped, interval);
+                }
+            });
+
+        let handle = match spawned {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                eprintln!("Error starting FTF I/O sampling thread: {}", e);
+                None
+            }
+        };
+        Self { stop, handle }
+    }
+}
+
+impl Drop for IoSamplerGuard {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock() = true;
+        condvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                eprintln!("FTF I/O sampling thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn open_files_and_writes_are_counted() {
+        let before = read_io().unwrap();
+        assert!(before.open_fds > 0, "at least stdin, stdout and stderr are open");
+
+        let path = std::env::temp_dir().join(format!("ftfrs-tracing-io-sampler-{}", std::process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        file.write_all(&[0; 4096]).unwrap();
+        let after = read_io().unwrap();
+        drop(file);
+        fs::remove_file(&path).unwrap();
+
+        // Other tests only ever add to the process totals
+        assert!(after.written_chars - before.written_chars >= 4096);
+    }
+}
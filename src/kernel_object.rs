@@ -0,0 +1,47 @@
+//! Kernel object records, which ftfrs doesn't write.
+//!
+//! These name the objects that events refer to by koid. Only thread objects
+//! are written here, following the FTF layout: header, koid, optional inline
+//! name, and a `process` koid argument tying the thread to its process.
+
+use std::io;
+
+use crate::raw::{string_field, string_words, write_inline_string};
+
+/// FTF record type of kernel object records
+const KERNEL_OBJECT_RECORD_TYPE: u64 = 7;
+
+/// Zircon object type of threads
+const OBJECT_TYPE_THREAD: u64 = 2;
+
+/// FTF argument type of kernel object id arguments
+const KOID_ARGUMENT_TYPE: u64 = 8;
+
+/// Write a kernel object record naming thread `thread_koid` of `process_koid`.
+///
+/// `process_arg` is the name of the argument carrying the process koid, which
+/// must be `process` for viewers to attribute the thread.
+pub(crate) fn write_thread_name(
+    writer: &mut dyn io::Write,
+    process_koid: u64,
+    thread_koid: u64,
+    name: &ftfrs::StringRef,
+    process_arg: &ftfrs::StringRef,
+) -> io::Result<()> {
+    let argument_words = 2 + string_words(process_arg);
+    let words = 2 + string_words(name) + argument_words;
+
+    let header = KERNEL_OBJECT_RECORD_TYPE
+        | (words as u64) << 4
+        | OBJECT_TYPE_THREAD << 16
+        | string_field(name) << 24
+        | 1 << 40;
+    writer.write_all(&header.to_le_bytes())?;
+    writer.write_all(&thread_koid.to_le_bytes())?;
+    write_inline_string(writer, name)?;
+
+    let argument_header = KOID_ARGUMENT_TYPE | (argument_words as u64) << 4 | string_field(process_arg) << 16;
+    writer.write_all(&argument_header.to_le_bytes())?;
+    write_inline_string(writer, process_arg)?;
+    writer.write_all(&process_koid.to_le_bytes())
+}
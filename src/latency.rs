@@ -0,0 +1,250 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+
+/// Sub-buckets each power of two is split into, bounding a percentile's
+/// error to about 1/8 of its value
+const SUB_BUCKETS: usize = 8;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+
+/// Buckets covering every `u64` duration
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Span names tracked before further names are left out of the summary
+const MAX_SUMMARY_NAMES: usize = 1024;
+
+/// Durations of closed spans per name, for
+/// [`FtfLayerConfig::latency_summary`](crate::FtfLayerConfig::latency_summary)
+#[derive(Debug, Default)]
+pub(crate) struct LatencySummary {
+    spans: Mutex<HashMap<String, Histogram>>,
+}
+
+/// Summary of one span name's durations, in nanoseconds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NameSummary {
+    pub(crate) name: String,
+    pub(crate) count: u64,
+    pub(crate) min_ns: u64,
+    pub(crate) max_ns: u64,
+    pub(crate) mean_ns: u64,
+    pub(crate) p50_ns: u64,
+    pub(crate) p95_ns: u64,
+    pub(crate) p99_ns: u64,
+}
+
+impl LatencySummary {
+    pub(crate) fn record(&self, name: &str, duration_ns: u64) {
+        let mut spans = self.spans.lock();
+        if let Some(histogram) = spans.get_mut(name) {
+            histogram.record(duration_ns);
+        } else if spans.len() < MAX_SUMMARY_NAMES {
+            let mut histogram = Histogram::new();
+            histogram.record(duration_ns);
+            spans.insert(name.to_string(), histogram);
+        }
+    }
+
+    /// Summarize every name seen, most time spent first, and start over
+    pub(crate) fn take(&self) -> Vec<NameSummary> {
+        let spans = std::mem::take(&mut *self.spans.lock());
+        let mut summaries: Vec<_> = spans.into_iter().map(|(name, histogram)| histogram.summary(name)).collect();
+        summaries.sort_by_key(|summary| std::cmp::Reverse(u128::from(summary.mean_ns) * u128::from(summary.count)));
+        summaries
+    }
+}
+
+/// Log-linear histogram of durations
+#[derive(Debug)]
+struct Histogram {
+    buckets: Box<[u64; BUCKETS]>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: Box::new([0; BUCKETS]),
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    fn record(&mut self, value: u64) {
+        self.buckets[bucket(value)] += 1;
+        self.count += 1;
+        self.sum += u128::from(value);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// The largest value in the bucket holding the `quantile`th value,
+    /// clamped to the values actually seen
+    fn quantile(&self, quantile: f64) -> u64 {
+        let rank = ((self.count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_max(index).clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+
+    fn summary(&self, name: String) -> NameSummary {
+        NameSummary {
+            name,
+            count: self.count,
+            min_ns: self.min,
+            max_ns: self.max,
+            mean_ns: (self.sum / u128::from(self.count.max(1))) as u64,
+            p50_ns: self.quantile(0.5),
+            p95_ns: self.quantile(0.95),
+            p99_ns: self.quantile(0.99),
+        }
+    }
+}
+
+/// Index of the bucket holding `value`. Values below [`SUB_BUCKETS`] get a
+/// bucket each; above that, each power of two is split evenly.
+fn bucket(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub_bucket = (value >> shift) as usize - SUB_BUCKETS;
+    (shift as usize + 1) * SUB_BUCKETS + sub_bucket
+}
+
+/// Largest value that falls in bucket `index`
+fn bucket_max(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub_bucket = (index % SUB_BUCKETS + SUB_BUCKETS) as u64;
+    // Wraps to `u64::MAX` for the last bucket
+    ((sub_bucket + 1) << shift).wrapping_sub(1)
+}
+
+/// The slowest span instances seen, for
+/// [`FtfLayerConfig::slowest_spans`](crate::FtfLayerConfig::slowest_spans)
+#[derive(Debug)]
+pub(crate) struct SlowestSpans {
+    capacity: usize,
+    /// Min-heap of the slowest spans, so the fastest of them is evicted first
+    spans: Mutex<BinaryHeap<Reverse<SlowSpan>>>,
+    /// Duration a span must exceed to make the list once it is full, read
+    /// without locking so most spans are turned away cheaply
+    threshold: AtomicU64,
+}
+
+/// One span instance in the [`SlowestSpans`] list
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct SlowSpan {
+    pub(crate) duration_ns: u64,
+    pub(crate) start: u64,
+    pub(crate) thread_id: u64,
+    pub(crate) name: String,
+    pub(crate) category: String,
+}
+
+impl SlowestSpans {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            spans: Mutex::new(BinaryHeap::with_capacity(capacity + 1)),
+            threshold: AtomicU64::new(0),
+        }
+    }
+
+    /// Offer a closed span, building its entry only if it makes the list
+    pub(crate) fn offer(&self, duration_ns: u64, span: impl FnOnce() -> SlowSpan) {
+        if duration_ns <= self.threshold.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut spans = self.spans.lock();
+        spans.push(Reverse(span()));
+        if spans.len() > self.capacity {
+            spans.pop();
+        }
+        if spans.len() == self.capacity {
+            if let Some(Reverse(fastest)) = spans.peek() {
+                self.threshold.store(fastest.duration_ns, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The slowest spans, slowest first, starting the list over
+    pub(crate) fn take(&self) -> Vec<SlowSpan> {
+        let spans = std::mem::take(&mut *self.spans.lock());
+        self.threshold.store(0, Ordering::Relaxed);
+        spans.into_sorted_vec().into_iter().map(|Reverse(span)| span).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_every_value() {
+        for value in [0, 1, 7, 8, 9, 15, 16, 1000, u64::MAX / 3, u64::MAX] {
+            let index = bucket(value);
+            assert!(index < BUCKETS);
+            assert!(value <= bucket_max(index), "{} is past its bucket", value);
+            if index > 0 {
+                assert!(value > bucket_max(index - 1), "{} fits an earlier bucket", value);
+            }
+        }
+        assert_eq!(bucket_max(BUCKETS - 1), u64::MAX);
+    }
+
+    #[test]
+    fn summaries_are_within_a_bucket_and_start_over() {
+        let summary = LatencySummary::default();
+        for duration in 1..=100 {
+            summary.record("slow", duration * 1000);
+        }
+        summary.record("fast", 5);
+        let summaries = summary.take();
+        assert_eq!(summaries.iter().map(|summary| summary.name.as_str()).collect::<Vec<_>>(), ["slow", "fast"]);
+
+        let slow = &summaries[0];
+        assert_eq!((slow.count, slow.min_ns, slow.max_ns, slow.mean_ns), (100, 1000, 100_000, 50_500));
+        for (percentile, exact) in [(slow.p50_ns, 50_000), (slow.p95_ns, 95_000), (slow.p99_ns, 99_000)] {
+            assert!(percentile >= exact && percentile <= exact + exact / 8, "{} for {}", percentile, exact);
+        }
+        assert_eq!(summaries[1].p99_ns, 5);
+        assert!(summary.take().is_empty());
+    }
+
+    #[test]
+    fn only_the_slowest_spans_are_kept() {
+        let slowest = SlowestSpans::new(2);
+        let span = |duration_ns| SlowSpan {
+            duration_ns,
+            start: 0,
+            thread_id: 1,
+            name: format!("span {}", duration_ns),
+            category: "test".to_string(),
+        };
+        for duration in [30, 10, 50, 20, 40] {
+            slowest.offer(duration, || span(duration));
+        }
+        // Spans at or under the fastest kept one aren't even built
+        slowest.offer(40, || unreachable!());
+        assert_eq!(slowest.take(), [span(50), span(40)]);
+        assert!(slowest.take().is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+use std::any::TypeId;
+
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::subscriber::Interest;
+use tracing_core::{Dispatch, Event, LevelFilter, Metadata, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::FtfLayer;
+
+/// A layer feeding the same trace as the [`FtfLayer`] it came from, for
+/// adding to another subscriber.
+///
+/// Obtained from [`FtfLayer::layer_handle`]. Every handle, and every clone
+/// of one, writes to the original layer's output through its intern tables,
+/// clock, budgets and counters, so records from all the subscribers they are
+/// added to land in one coherent trace. Spans still open in any of them are
+/// ended by the original layer's [`FlushGuard`](crate::FlushGuard).
+#[derive(Debug)]
+pub struct FtfLayerHandle<W: for<'a> MakeWriter<'a>> {
+    layer: FtfLayer<W>,
+}
+
+impl<W: for<'a> MakeWriter<'a>> FtfLayerHandle<W> {
+    pub(crate) fn new(layer: &FtfLayer<W>) -> Self {
+        Self { layer: layer.clone() }
+    }
+}
+
+/// Each clone is its own layer, to be added to a different subscriber
+impl<W: for<'a> MakeWriter<'a>> Clone for FtfLayerHandle<W> {
+    fn clone(&self) -> Self {
+        Self::new(&self.layer)
+    }
+}
+
+impl<W, S> Layer<S> for FtfLayerHandle<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        Layer::<S>::on_register_dispatch(&self.layer, subscriber);
+    }
+
+    fn on_layer(&mut self, subscriber: &mut S) {
+        self.layer.on_layer(subscriber);
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        Layer::<S>::register_callsite(&self.layer, metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        self.layer.enabled(metadata, ctx)
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        self.layer.on_new_span(attrs, id, ctx);
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Layer::<S>::max_level_hint(&self.layer)
+    }
+
+    fn on_record(&self, span: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        self.layer.on_record(span, values, ctx);
+    }
+
+    fn on_follows_from(&self, span: &Id, follows: &Id, ctx: Context<'_, S>) {
+        self.layer.on_follows_from(span, follows, ctx);
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: Context<'_, S>) -> bool {
+        self.layer.event_enabled(event, ctx)
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        self.layer.on_event(event, ctx);
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        self.layer.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        self.layer.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        self.layer.on_close(id, ctx);
+    }
+
+    fn on_id_change(&self, old: &Id, new: &Id, ctx: Context<'_, S>) {
+        self.layer.on_id_change(old, new, ctx);
+    }
+
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            return Some(self as *const Self as *const ());
+        }
+        // SAFETY: forwarded unchanged, so the inner layer's guarantees hold
+        unsafe { Layer::<S>::downcast_raw(&self.layer, id) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::{FtfLayerConfig, RecordSink};
+
+    use super::*;
+
+    /// Collects the records written to an output
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<ftfrs::Record>>);
+
+    impl RecordSink for Collect {
+        fn write_record(&self, record: &ftfrs::Record) {
+            self.0.lock().push(record.clone());
+        }
+    }
+
+    /// Count the duration events written, as begins, ends and ends marked
+    /// truncated. ftfrs doesn't export its event types, so they are told
+    /// apart by their debug output.
+    fn durations(records: &[ftfrs::Record]) -> (usize, usize, usize) {
+        let (mut begins, mut ends, mut truncated) = (0, 0, 0);
+        for record in records {
+            let debug = format!("{:?}", record);
+            if debug.starts_with("Event(DurationBegin(") {
+                begins += 1;
+            } else if debug.starts_with("Event(DurationEnd(") {
+                ends += 1;
+                if debug.contains("Boolean(") {
+                    truncated += 1;
+                }
+            }
+        }
+        (begins, ends, truncated)
+    }
+
+    #[test]
+    fn subscribers_share_one_output() {
+        let collect = Arc::new(Collect::default());
+        let layer = FtfLayer::with_config(io::sink, FtfLayerConfig {
+            sinks: vec![collect.clone()],
+            ..Default::default()
+        });
+        let first = tracing_subscriber::registry().with(layer.layer_handle());
+        let second = tracing_subscriber::registry().with(layer.layer_handle().clone());
+
+        // Both registries hand out the same span ids
+        let first_span = tracing::subscriber::with_default(first, || {
+            let closed = tracing::info_span!("closed", ftf = true);
+            drop(closed);
+            tracing::info_span!("left open", ftf = true)
+        });
+        let second_span = tracing::subscriber::with_default(second, || {
+            let closed = tracing::info_span!("closed", ftf = true);
+            drop(closed);
+            tracing::info_span!("left open", ftf = true)
+        });
+        assert_eq!(first_span.id(), second_span.id());
+
+        layer.handle().end_open_spans();
+        assert_eq!(durations(&collect.0.lock()), (4, 4, 2));
+
+        // Spans ended early write nothing more when they close
+        drop((first_span, second_span));
+        assert_eq!(durations(&collect.0.lock()), (4, 4, 2));
+    }
+}
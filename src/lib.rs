@@ -1,198 +1,1168 @@
-use std::collections::HashMap;
-use std::sync::atomic::Ordering;
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{fmt, io};
 
-use parking_lot::RwLock;
-use tracing_core::{field::{Field, Visit}, span, Event, Subscriber};
+use parking_lot::Mutex;
+use smallvec::SmallVec;
+use tracing_core::{field::{Field, Visit}, span, Dispatch, Event, Level, LevelFilter, Metadata, Subscriber};
 use tracing_subscriber::{fmt::MakeWriter, registry::LookupSpan, Layer};
 
+#[cfg(feature = "alloc-track")]
+mod alloc_track;
+#[cfg(feature = "bench")]
+mod bench;
+mod blob;
+mod blocking;
+mod budget;
+mod bytes;
+mod cache;
+mod callsite;
+mod config_builder;
+#[cfg(feature = "control")]
+mod control;
+mod crc32;
+mod debug_memo;
+mod error;
+mod event;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod fifo;
+mod flow;
+mod ftf_rayon;
+mod future;
+mod glob;
+#[cfg(any(feature = "http-upload", all(feature = "profiler", target_os = "linux", target_env = "gnu")))]
+mod gzip;
+mod handle;
+mod health;
+#[cfg(feature = "http-upload")]
+mod http_upload;
+mod init;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod io_sampler;
+mod kernel_object;
+mod latency;
+mod layer_handle;
+mod limits;
+mod link;
+mod macros;
+mod memory;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod multi_process;
+#[cfg(feature = "net-sampler")]
+mod net_sampler;
+mod non_blocking;
+mod overhead;
+mod platform;
+#[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+mod pprof;
+#[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+mod profiler;
+mod process_info;
+mod raw;
+mod replay;
+mod rolling;
+mod routing;
+mod sampling;
+#[cfg(all(feature = "sched", any(target_os = "linux", target_os = "android")))]
+mod sched;
+mod schema;
+mod scoped;
+mod shared_file;
+mod sink;
+mod span_store;
+mod stream;
+mod streaming;
+mod subscriber;
+mod synced_file;
+pub mod testing;
+mod thread_id;
+mod trace_clock;
+mod trace_id;
+mod trace_marker;
+mod track;
+mod trailer;
+mod tree_buffer;
+mod watchdog;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+mod wasm;
+mod writer;
+
+#[cfg(feature = "alloc-track")]
+pub use alloc_track::{process_alloc_stats, thread_alloc_stats, AllocStats, TrackingAllocator};
+#[cfg(feature = "bench")]
+pub use bench::BenchTracer;
+pub use blocking::blocking_section;
+pub use config_builder::FtfLayerConfigBuilder;
+#[cfg(feature = "control")]
+pub use control::{ControlServer, ControlServerGuard};
+pub use error::FtfLayerError;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use fifo::{FifoWriter, FifoWriterGuard};
+pub use ftf_rayon::{wrap_task, wrap_task_fn};
+pub use future::{FtfFutureExt, FtfTraced};
+pub use handle::{ArgValue, FtfHandle};
+pub use health::WriteFailureHandler;
+#[cfg(feature = "http-upload")]
+pub use http_upload::{HttpUploader, HttpUploaderBuilder, HttpUploaderWriter, UploadGuard, UploadMetrics};
+pub use init::{init, init_with_config, FlushGuard, InitWriter};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use io_sampler::IoSamplerGuard;
+pub use layer_handle::FtfLayerHandle;
+pub use link::FtfExt;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use multi_process::{MultiProcessFile, MultiProcessFileGuard};
+#[cfg(feature = "net-sampler")]
+pub use net_sampler::{NetCounters, NetSamplerGuard};
+#[cfg(feature = "attributes")]
+pub use ftfrs_tracing_macros::ftf_instrument;
+pub use non_blocking::{
+    non_blocking, BackpressureMetrics, BackpressurePolicy, NonBlocking, NonBlockingBuilder,
+    NonBlockingWriter, WorkerGuard,
+};
+pub use overhead::{HookStats, OverheadStats};
+#[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+pub use profiler::ProfilerGuard;
+pub use rolling::{RollingFile, RollingFileBuilder, RollingFileGuard, RollingFileWriter};
+pub use sampling::TailSampling;
+#[cfg(all(feature = "sched", any(target_os = "linux", target_os = "android")))]
+pub use sched::SchedSamplerGuard;
+pub use scoped::{in_thread_span, FtfScope};
+pub use sink::{RecordMiddleware, RecordSink};
+pub use stream::FtfStreamTracer;
+pub use streaming::{StreamFrame, StreamingGuard, StreamingSink, StreamingSinkBuilder};
+pub use subscriber::FtfSubscriber;
+pub use synced_file::{Preallocation, SyncPolicy, SyncedFile, SyncedFileWriter};
+pub use trace_clock::TraceClock;
+pub use track::FtfTrack;
+pub use trailer::verified_trace_len;
+pub use watchdog::WatchdogGuard;
+pub use writer::{MemoryWriter, MemoryWriterGuard, OwnedWriter, OwnedWriterGuard};
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub use wasm::{PostMessageWriter, PostMessageWriterGuard};
+
+#[doc(hidden)]
+pub use tracing as __tracing;
+
+/// Version of the compiler that built this crate, used by [`build_info!`]
+#[cfg(feature = "build-info")]
+#[doc(hidden)]
+pub const __RUSTC_VERSION: &str = env!("FTFRS_TRACING_RUSTC_VERSION");
+
+#[cfg(feature = "alloc-track")]
+use alloc_track::SpanAllocs;
+use budget::CategoryBudgets;
+use cache::{CacheCounters, StringCache, ThreadCache};
+use event::EventRecord;
+use flow::{AsyncPhase, FlowPhase};
+use future::ManualHooks;
+use glob::CategoryFilters;
+use health::{MonitoredWriter, WriteHealth};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use io_sampler::IoSample;
+use latency::{LatencySummary, SlowSpan, SlowestSpans};
+use link::LinkSpans;
+use limits::{MAX_PROVIDER_NAME_BYTES, MAX_STRING_BYTES};
+use memory::MemoryBudget;
+use overhead::{Hook, Overhead, Phase};
+use platform::Clock;
+use routing::CategoryRouter;
+#[cfg(all(feature = "sched", any(target_os = "linux", target_os = "android")))]
+use sched::{ThreadSample, ThreadState};
+use schema::{Schema, SchemaKind};
+use sink::RecordOutput;
+use span_store::SpanView;
+use trace_marker::TraceMarker;
+use trailer::{ChecksummedWriter, ChunkChecksum};
+use tree_buffer::SpanTreeBuffer;
+
+/// Fallback category used by [`DefaultCategory::default`]
+const DEFAULT_CATEGORY: &str = "default";
+
+/// Category and name of the instant event carrying [`FtfLayerConfig::metadata`]
+const METADATA_CATEGORY: &str = "trace";
+const METADATA_NAME: &str = "metadata";
+
+/// Metadata key carrying [`FtfLayer::trace_id`]
+const TRACE_ID_KEY: &str = "trace_id";
+
+/// Metadata key carrying the provider id chosen by
+/// [`FtfLayerConfig::unique_provider_id`]
+const PROVIDER_ID_KEY: &str = "provider_id";
+
+/// Reserved field overriding a record's timestamp, in nanoseconds since the
+/// [`FtfLayerConfig::timestamp_epoch`]
+const TIMESTAMP_FIELD: &str = "ftf.ts_ns";
+
+/// Reserved field overriding a record's name, e.g. with a request's path
+const NAME_FIELD: &str = "ftf.name";
+
+/// Appended to values cut short at [`FtfLayerConfig::max_debug_len`] or
+/// [`FtfLayerConfig::max_bytes_len`]
+const TRUNCATION_MARKER: &str = "…";
+
+/// Encoding of the FTF magic number record, which starts every trace, rolled
+/// file and chunk, for writers that split the output there
+const MAGIC_NUMBER_RECORD: [u8; 8] = 0x0016_5478_4604_0010u64.to_le_bytes();
+
 /// A tracing layer that outputs traces in Fuchsia Trace Format (FTF).
 ///
 /// This layer handles span creation, events, and closing of spans,
 /// and properly interns strings and thread references for efficient trace output.
+///
+/// The writer's [`MakeWriter::make_writer_for`] is called once per record,
+/// with the record's metadata, before any of the layer's locks are taken.
+/// Records refer to strings and threads interned earlier in the stream, so
+/// every writer it returns must append to the same stream unless
+/// [`FtfLayerConfig::route_by_metadata`] is set; use
+/// [`FtfLayerConfig::category_files`] to split categories into their own
+/// files.
+//
+// Lock ordering: a span tree's buffer, then the category router's table,
+// then a single output (`main` or one routed output). No other lock is held
+// while an output is locked, and no output lock is held while taking
+// another.
 #[derive(Debug)]
 pub struct FtfLayer<W: for<'a> MakeWriter<'a>> {
-    writer: Arc<RwLock<W>>,
-    start: Instant,
-    /// Cache for interned strings
-    string_cache: Arc<RwLock<StringCache>>,
-    /// Cache for interned thread references
-    thread_cache: Arc<RwLock<ThreadCache>>,
+    writer: Arc<W>,
+    /// Intern tables of the main writer
+    main: Arc<Mutex<MainOutput>>,
+    start: Clock,
+    /// Counters of the main output's string table, read without locking it
+    string_counters: Arc<CacheCounters>,
+    /// Counters of the main output's thread table, read without locking it
+    thread_counters: Arc<CacheCounters>,
+    /// Per-category output files, if any are configured
+    router: Option<Arc<CategoryRouter>>,
+    /// Per-category record budgets
+    budgets: Arc<CategoryBudgets>,
+    /// Deny-list, per-category levels and process categories, with glob patterns compiled
+    category_filters: Arc<CategoryFilters>,
+    config: Arc<FtfLayerConfig>,
+    /// Whether the magic number and provider info have been written
+    header_written: Arc<AtomicBool>,
+    /// Consecutive write failures of the main output, and whether they
+    /// turned recording off
+    health: Arc<WriteHealth>,
+    /// Debug-formatted values truncated at [`FtfLayerConfig::max_debug_len`]
+    truncated_debug_values: Arc<AtomicU64>,
+    /// Spans that closed on a different thread than they began on
+    migrated_spans: Arc<AtomicU64>,
+    /// Spans reported open for longer than [`FtfLayerConfig::long_running_span_threshold`]
+    long_running_spans: Arc<AtomicU64>,
+    /// Recorded spans that haven't closed yet, from every subscriber
+    open_spans: Arc<Mutex<HashMap<(u64, span::Id), OpenSpan>>>,
+    /// Which subscriber this layer feeds, since span ids are only unique
+    /// within one
+    subscriber: u64,
+    /// Next id for a clone added to another subscriber
+    next_subscriber: Arc<AtomicU64>,
+    /// Time spent in the layer's own hooks, if [`FtfLayerConfig::measure_overhead`] is set
+    overhead: Option<Arc<Overhead>>,
+    /// Span durations per name, if [`FtfLayerConfig::latency_summary`] is set
+    latency: Option<Arc<LatencySummary>>,
+    /// Slowest span instances, if [`FtfLayerConfig::slowest_spans`] is set
+    slowest: Option<Arc<SlowestSpans>>,
+    /// Categories and record names seen, if [`FtfLayerConfig::schema_record`] is set
+    schema: Option<Arc<Schema>>,
+    /// Correlation id of the next flow drawn by [`FtfExt::link_spans`]
+    next_flow_id: Arc<AtomicU64>,
+    /// Correlation id of the next future traced with [`FtfFutureExt::ftf_traced`]
+    next_async_id: Arc<AtomicU64>,
+    /// Number of tracks created through [`FtfHandle::create_track`]
+    next_track_id: Arc<AtomicU64>,
+    /// Set once the layer is added to a subscriber
+    link_spans: Option<LinkSpans>,
+    /// Set once the layer is added to a subscriber
+    manual_hooks: Option<ManualHooks>,
+    /// Unique id of this layer's trace, also written as metadata
+    trace_id: Arc<str>,
+    /// [`Rotation`] period the main output was last written in
+    rotation_period: Arc<AtomicU64>,
+    /// Trace time the current chunk of the main output started at
+    chunk_start: Arc<AtomicU64>,
+    /// Bytes written to the main output when the current chunk started
+    chunk_start_bytes: Arc<AtomicU64>,
+    /// Bytes held by the layer's tables and buffers against [`FtfLayerConfig::memory_budget`]
+    memory: Arc<MemoryBudget>,
+    /// Length and checksum of the current chunk, if [`FtfLayerConfig::chunk_trailers`] is set
+    chunk_checksum: Option<Arc<ChunkChecksum>>,
+    /// Open `trace_marker`, if [`FtfLayerConfig::trace_marker`] is set and it could be opened
+    trace_marker: Option<Arc<TraceMarker>>,
 }
 
-#[derive(Debug)]
-struct StringCache {
-    by_value: HashMap<String, u16>,
-    next_id: u16,
+/// A recorded span that hasn't closed yet, remembered so that it can still be
+/// ended if the layer goes away first
+#[derive(Debug, Clone)]
+struct OpenSpan {
+    metadata: &'static Metadata<'static>,
+    /// Name set through [`NAME_FIELD`]
+    name: Option<String>,
+    category: String,
+    origin: SpanOrigin,
+    /// Whether the span's records are held in a sampled tree
+    sampled: bool,
+    /// Time the span was last reported as long-running at
+    reported_at: Option<u64>,
 }
 
-#[derive(Debug)]
-struct ThreadCache {
-    by_id: HashMap<(u64, u64), u8>,
-    next_id: u8,
+impl OpenSpan {
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.metadata.name())
+    }
 }
 
-impl StringCache {
-    fn new() -> Self {
-        Self {
-            by_value: HashMap::new(),
-            next_id: 1, 
-        }
-    }
+/// Category of blob records too big for a plain blob record
+const BLOB_CATEGORY: &str = "blob";
 
-    fn get_or_create(&mut self, value: &str, writer: &mut impl io::Write) -> Result<ftfrs::StringRef, ftfrs::FtfError> {
-        if let Some(&id) = self.by_value.get(value) {
-            return Ok(ftfrs::StringRef::Ref(id));
-        }
+/// Argument of the counters written by [`FtfLayerConfig::span_duration_counters`]
+const DURATION_ARG: &str = "duration_ns";
 
-        let id = self.next_id;
-        self.next_id = self.next_id.wrapping_add(1);
-        if self.next_id == 0 {
-            self.next_id = 1; 
-        }
-        self.by_value.insert(value.to_string(), id);
+/// Argument marking the end records written by [`FtfLayer::end_open_spans`]
+const TRUNCATED_ARG: &str = "truncated";
 
-        let record = ftfrs::Record::create_string(id, value.to_string());
-        record.write(writer)?;
+/// Category and name of the counter records written by
+/// [`FtfLayerConfig::overhead_counter_interval`]
+const OVERHEAD_CATEGORY: &str = "ftfrs";
+const OVERHEAD_NAME: &str = "overhead";
 
-        Ok(ftfrs::StringRef::Ref(id))
-    }
-}
+/// Name of the instant event written by [`FtfLayerConfig::max_trace_bytes`],
+/// in the overhead category
+const BUDGET_EXCEEDED_NAME: &str = "budget exceeded";
 
-impl ThreadCache {
-    fn new() -> Self {
-        Self {
-            by_id: HashMap::new(),
-            next_id: 1, 
-        }
-    }
+/// Name of the instant events written by [`FtfLayerConfig::latency_summary`],
+/// in the overhead category
+const LATENCY_SUMMARY_NAME: &str = "latency summary";
 
-    fn get_or_create(
-        &mut self,
-        process_id: u64,
-        thread_id: u64,
-        writer: &mut impl io::Write,
-    ) -> Result<ftfrs::ThreadRef, ftfrs::FtfError> {
-        let key = (process_id, thread_id);
-        if let Some(&id) = self.by_id.get(&key) {
-            return Ok(ftfrs::ThreadRef::Ref(id));
-        }
+/// Name of the instant events written by [`FtfLayerConfig::slowest_spans`],
+/// in the overhead category
+const SLOWEST_SPAN_NAME: &str = "slowest span";
 
-        let id = self.next_id;
-        self.next_id = self.next_id.wrapping_add(1);
-        if self.next_id == 0 {
-            self.next_id = 1; 
-        }
-        self.by_id.insert(key, id);
+/// Name of the blob written by [`FtfLayerConfig::schema_record`]
+const SCHEMA_BLOB_NAME: &str = "schema";
+
+/// Name of the counter records written by
+/// [`FtfLayerConfig::alloc_counter_interval`], in the overhead category
+#[cfg(feature = "alloc-track")]
+const ALLOC_COUNTER_NAME: &str = "allocations";
+
+/// Category and name of the instant events reporting long-running spans
+const WATCHDOG_CATEGORY: &str = "watchdog";
+
+/// Category of the durations written by [`ftf_blocking!`]
+const BLOCKING_CATEGORY: &str = "blocking";
+const LONG_RUNNING_SPAN_NAME: &str = "long-running span";
+
+/// Category, name and argument of the instant events written by the profiler
+#[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+const PROFILER_CATEGORY: &str = "profiler";
+#[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+const PROFILER_SAMPLE_NAME: &str = "sample";
+#[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+const PROFILER_STACK_ARG: &str = "stack";
+
+/// Category and name of the counter records written by the I/O sampler
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IO_CATEGORY: &str = "io";
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IO_NAME: &str = "io";
+
+/// Category of the counter records written by the network sampler, which
+/// are named after their interface
+#[cfg(feature = "net-sampler")]
+const NET_CATEGORY: &str = "net";
+
+/// Category and name of the counter records written by the scheduler sampler
+#[cfg(all(feature = "sched", any(target_os = "linux", target_os = "android")))]
+const SCHED_CATEGORY: &str = "sched";
+#[cfg(all(feature = "sched", any(target_os = "linux", target_os = "android")))]
+const SCHED_NAME: &str = "sched";
 
-        let record = ftfrs::Record::create_thread(id, process_id, thread_id);
-        record.write(writer)?;
+/// When a recorded span was last exited, for [`SpanEnd::LastExit`]
+#[derive(Debug, Clone, Copy)]
+struct LastExit(u64);
 
-        Ok(ftfrs::ThreadRef::Ref(id))
+/// Adds the time until it is dropped to a hook's total
+struct HookTimer<'a, W: for<'w> MakeWriter<'w>> {
+    layer: &'a FtfLayer<W>,
+    hook: Hook,
+    started: u64,
+}
+
+impl<W: for<'w> MakeWriter<'w>> Drop for HookTimer<'_, W> {
+    fn drop(&mut self) {
+        if let Some(overhead) = &self.layer.overhead {
+            overhead.add_hook(self.hook, self.layer.overhead_now().saturating_sub(self.started));
+            self.layer.write_overhead_counters(overhead);
+        }
     }
 }
 
+/// Where and when a recorded span began, for drawing flows to and from it
+#[derive(Debug, Clone, Copy)]
+struct SpanOrigin {
+    timestamp: u64,
+    thread_id: u64,
+}
+
+/// Configuration for an [`FtfLayer`], made with [`FtfLayerConfig::builder`]
+/// or [`FtfLayerConfig::default`].
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct FtfLayerConfig {
     /// Provider information ID
     pub provider_id: u32,
+    /// Whether [`FtfLayerConfig::provider_id`] is replaced with one derived
+    /// from the process id and random bits, so traces from several processes
+    /// can be merged without assigning ids by hand. The id is also written as
+    /// the `provider_id` metadata argument.
+    pub unique_provider_id: bool,
     /// Provider name
     pub provider_name: String,
+    /// Key-value pairs describing the trace, e.g. `("service", "api")`.
+    ///
+    /// They are written as string arguments of a `metadata` instant event in
+    /// the `trace` category right after the header of every output, followed
+    /// by the generated [`FtfLayer::trace_id`] and, unless turned off with
+    /// [`FtfLayerConfig::process_metadata`], details of the process.
+    pub metadata: Vec<(String, String)>,
+    /// Whether to add the hostname, pid, executable path and command-line
+    /// arguments to the metadata. Turn this off if they may be sensitive.
+    pub process_metadata: bool,
     /// Optional process ID to use instead of auto-detection
     pub process_id: Option<u64>,
+    /// When timestamps count from. Give layers in one process the same
+    /// epoch for their traces to line up.
+    pub timestamp_epoch: TimestampEpoch,
+    /// Where trace time comes from instead of the system's monotonic clock,
+    /// e.g. a simulation's. Its readings are used as they are, so
+    /// [`FtfLayerConfig::timestamp_epoch`] has no effect. See [`TraceClock`].
+    pub clock: Option<Arc<dyn TraceClock>>,
+    /// Categories whose records go to their own file instead of the main writer.
+    ///
+    /// Each file gets its own header and intern tables, so it is a complete
+    /// trace on its own.
+    pub category_files: HashMap<String, PathBuf>,
+    /// Whether the writer routes records to different outputs by level or
+    /// target through [`MakeWriter::make_writer_for`], e.g. with
+    /// `MakeWriterExt::with_max_level`.
+    ///
+    /// Each span and event record is then written with intern tables of its
+    /// own, preceded by the string and thread records it refers to, so it
+    /// reads correctly in whichever output it reaches. A header is written
+    /// before the first record of each level and target. Traces grow
+    /// accordingly.
+    pub route_by_metadata: bool,
+    /// Categories that are never recorded, even when a span or event sets
+    /// `ftf = true`. Entries may be glob patterns such as `gfx*` or `*:detail`.
+    pub denied_categories: HashSet<String>,
+    /// Categories whose events are attributed to the process as a whole
+    /// rather than the thread that emitted them, e.g. config reloads.
+    ///
+    /// Such events carry an inline thread with koid 0, so no thread is
+    /// interned. Entries may be glob patterns.
+    pub process_categories: HashSet<String>,
+    /// Field names that are never written as arguments, e.g. `password`
+    pub denied_fields: HashSet<String>,
+    /// Longest Debug-formatted field value, in bytes, before it is cut short
+    /// and marked with a trailing `…`. Unlimited when `None`.
+    pub max_debug_len: Option<usize>,
+    /// Whether the last Debug-formatted value of each field is remembered,
+    /// so a field recorded with the same value over and over reuses it
+    /// instead of building and interning a new string each time.
+    ///
+    /// The value is still formatted, to compare it with the remembered one,
+    /// so this helps most with large values that rarely change.
+    pub memoize_debug_values: bool,
+    /// How `i128` and `u128` field values are written
+    pub wide_int_encoding: WideIntEncoding,
+    /// How byte slice field values are written
+    pub bytes_encoding: BytesEncoding,
+    /// Most bytes of a byte slice field value that are written before it is
+    /// cut short and marked with a trailing `…`. Unlimited when `None`.
+    pub max_bytes_len: Option<usize>,
+    /// How often the main writer rolls over to a new file, as with
+    /// `tracing_appender::rolling` or [`RollingFile`].
+    ///
+    /// When set, the first record written in each new period is preceded by
+    /// the header and the string table, so every rolled file is a trace that
+    /// parses on its own.
+    pub rotation: Option<Rotation>,
+    /// How often the main output starts a new self-contained chunk, for
+    /// consumers that start reading a live stream part way through.
+    ///
+    /// The first record written once the interval has passed is preceded by
+    /// the header, the string table and the trace metadata, and threads are
+    /// re-interned on their next use, so decoding can begin at any chunk.
+    pub chunk_interval: Option<Duration>,
+    /// How many bytes of the main output a chunk holds before the next
+    /// record starts a new one, as [`FtfLayerConfig::chunk_interval`] does
+    /// over time. Either limit starts a new chunk when both are set.
+    pub chunk_bytes: Option<u64>,
+    /// Whether each chunk of the main output ends with a trailer holding its
+    /// length and checksum, so [`verified_trace_len`] can find where a crash
+    /// cut the trace short.
+    ///
+    /// A trailer is written before every new chunk's header and when a
+    /// [`FlushGuard`] is dropped or on [`FtfHandle::end_chunk`]. With
+    /// [`FtfLayerConfig::rotation`], write to a [`RollingFile`] so each
+    /// file's last trailer lands in that file rather than the next.
+    pub chunk_trailers: bool,
+    /// How often the file [`init`] or [`testing::per_test`] writes to is
+    /// synced to disk, trading throughput for durability. Layers writing to a
+    /// [`SyncedFile`] of their own give it a policy directly.
+    pub sync_policy: SyncPolicy,
+    /// Disk space reserved ahead of the trace in the file [`init`] or
+    /// [`testing::per_test`] writes to. None is reserved when `None`.
+    pub preallocation: Option<Preallocation>,
+    /// Whether span begin and end events are also written to ftrace's
+    /// `trace_marker` (Linux only), so spans appear in system traces captured
+    /// by perfetto or `trace-cmd`.
+    ///
+    /// Writing the marker usually needs root or membership of the tracing group.
+    pub trace_marker: bool,
+    /// When a span's duration ends. Spans kept alive by a clone, e.g. one
+    /// stored in a future or shared with another task, only close once the
+    /// last clone is dropped; [`SpanEnd::LastExit`] ends them when they were
+    /// last exited instead.
+    pub span_end: SpanEnd,
+    /// Whether each span's duration is also, or only, written as a counter
+    /// record named after the span when it ends, so viewers chart its
+    /// latency over time
+    pub span_duration_counters: DurationCounters,
+    /// Whether span durations are collected per span name and written as a
+    /// summary when a [`FlushGuard`] is dropped or on
+    /// [`FtfHandle::write_latency_summary`]
+    pub latency_summary: bool,
+    /// Number of slowest span instances kept and written as `slowest span`
+    /// instant events when a [`FlushGuard`] is dropped or on
+    /// [`FtfHandle::write_slowest_spans`]. None are kept when 0.
+    pub slowest_spans: usize,
+    /// Whether the categories, span names, event names and counter names
+    /// seen are written as a JSON `schema` blob when a [`FlushGuard`] is
+    /// dropped or on [`FtfHandle::write_schema`]
+    pub schema_record: bool,
+    /// How long a span may stay open before [`FtfHandle::check_open_spans`]
+    /// or the watchdog reports it, e.g. because a guard was leaked.
+    /// Spans are never reported when `None`.
+    pub long_running_span_threshold: Option<Duration>,
+    /// Whether each reported long-running span is also written as a
+    /// `long-running span` instant event in the `watchdog` category, on the
+    /// thread the span began on
+    pub long_running_span_events: bool,
+    /// How often a span that is still open is reported again after its first
+    /// report, so a hung operation keeps showing up in a live stream.
+    /// Spans are reported only once when `None`.
+    pub long_running_span_repeat: Option<Duration>,
+    /// How long a section run with [`ftf_blocking!`] may take before a
+    /// warning is printed. Sections are never reported when `None`.
+    pub blocking_threshold: Option<Duration>,
+    /// Consecutive failed writes to the main output after which recording
+    /// is turned off, rather than failing and printing an error for every
+    /// record, e.g. once the disk is full. Recording never stops when `None`.
+    ///
+    /// [`FtfHandle::resume_recording`] turns it back on.
+    pub max_write_failures: Option<u32>,
+    /// Most bytes written to the main output before recording is turned off,
+    /// so a trace can't fill the disk. A final `budget exceeded` instant event
+    /// in the `ftfrs` category marks where the trace stops. Unlimited when
+    /// `None`.
+    ///
+    /// The budget is checked after each record, so the output may go over it
+    /// by one record. [`FtfHandle::resume_recording`] starts a fresh budget.
+    pub max_trace_bytes: Option<u64>,
+    /// Called instead of printing to stderr when
+    /// [`FtfLayerConfig::max_write_failures`] turns recording off. See
+    /// [`WriteFailureHandler`].
+    pub write_failure_handler: Option<Arc<dyn WriteFailureHandler>>,
+    /// Whether the layer times its own hooks, reporting the totals in
+    /// [`FtfLayerStats::overhead`]
+    pub measure_overhead: bool,
+    /// How often the measured overhead is also written as a counter record
+    /// named `overhead` in the `ftfrs` category, averaged over the interval.
+    /// Requires [`FtfLayerConfig::measure_overhead`].
+    pub overhead_counter_interval: Option<Duration>,
+    /// How often each thread's allocations, as counted by
+    /// [`TrackingAllocator`], are written as a counter record named
+    /// `allocations` in the `ftfrs` category on that thread.
+    ///
+    /// Counters are written from the layer's hooks, so a thread gets one at
+    /// its first span or event after the interval has passed.
+    #[cfg(feature = "alloc-track")]
+    pub alloc_counter_interval: Option<Duration>,
+    /// Whether span end records carry `alloc_count` and `alloc_bytes`
+    /// arguments with the allocations, as counted by [`TrackingAllocator`],
+    /// made while the span was entered, including in its children
+    #[cfg(feature = "alloc-track")]
+    pub span_alloc_args: bool,
+    /// Whether span trees are held in memory until their root span closes
+    /// and written only if something in them was interesting, e.g. an error.
+    ///
+    /// Records in [`FtfLayerConfig::category_files`] categories, events
+    /// outside any recorded span and flows drawn with [`FtfExt::link_spans`]
+    /// are always written straight away.
+    pub tail_sampling: TailSampling,
+    /// Whether spans are written as duration records. When off, spans are
+    /// still checked for `ftf` and `category`, so events inside them are
+    /// recorded as before.
+    pub record_spans: bool,
+    /// Whether events are written as instant records
+    pub record_events: bool,
+    /// Names of spans recorded as if they had `ftf = true`, such as those of
+    /// `#[instrument]`ed functions in other crates. `*` in a name matches
+    /// any run of characters and `?` any one character, e.g. `render_*`.
+    pub record_span_names: Vec<String>,
+    /// Steps every record passes through, in order, before it is written.
+    /// See [`RecordMiddleware`].
+    pub middleware: Vec<Arc<dyn RecordMiddleware>>,
+    /// Additional consumers of every record written to the main output,
+    /// such as metrics extraction or a live view. See [`RecordSink`].
+    pub sinks: Vec<Arc<dyn RecordSink>>,
+    /// Whether [`FtfHandle::frame_mark`] also writes a counter record
+    /// tracking the frame number
+    pub frame_counter: bool,
+    /// Most verbose level recorded for each category.
+    ///
+    /// Records in categories without an entry are recorded at any level.
+    /// Keys may be glob patterns; a category's own entry wins over them, and
+    /// the longest matching pattern over shorter ones.
+    pub category_levels: HashMap<String, Level>,
+    /// Most span and event records written per second for each category.
+    ///
+    /// Budgets are token buckets, so a quiet category can save up to one
+    /// second's worth for a burst. Records over budget are dropped and
+    /// counted in [`FtfLayerStats::throttled_records`]; a dropped span's
+    /// end is dropped with it. Categories without an entry are unlimited.
+    pub category_budgets: HashMap<String, u32>,
+    /// Most verbose level recorded in any category.
+    ///
+    /// This is reported through [`Layer::max_level_hint`], so callsites above
+    /// it are disabled for the whole subscriber rather than dispatched and
+    /// dropped by this layer.
+    pub max_level: Option<Level>,
+    /// Category used when neither a record nor its parent span sets one
+    pub default_category: DefaultCategory,
+    /// Categories to rewrite on the way out, e.g. `"sqlx::query" -> "db"`.
+    ///
+    /// Aliasing happens before the deny-list, level thresholds and output
+    /// routing are consulted, so those should use the rewritten names.
+    pub category_aliases: HashMap<String, String>,
+    /// When string field values are interned rather than written inline.
+    ///
+    /// Field, span and category names are always interned.
+    pub value_interning: ValueInterning,
+    /// Number of interned field values kept before the least recently used
+    /// ones are evicted. Names are never evicted.
+    pub value_cache_capacity: usize,
+    /// Number of string indices, shared by names and values, that may be
+    /// handed out. Capped at the 32767 indices FTF allows.
+    pub string_cache_capacity: usize,
+    /// Number of thread indices that may be handed out before the least
+    /// recently used thread is evicted. Capped at the 255 indices FTF allows.
+    pub thread_cache_capacity: usize,
+    /// Approximate bytes the string and thread tables, the
+    /// [`ValueInterning::AfterRepeats`] counts, memoized Debug values and
+    /// [`FtfLayerConfig::tail_sampling`] span trees may hold together, so a
+    /// long-lived service can't grow them without bound. Unlimited, and not
+    /// counted, when `None`.
+    ///
+    /// Once the budget is full, the least recently used field values are
+    /// evicted to make room. Names, values and threads that still don't fit
+    /// are written inline, repeat counts start over, Debug values aren't
+    /// memoized, and a span tree that outgrows it is dropped. See
+    /// [`FtfLayerStats::memory`].
+    pub memory_budget: Option<usize>,
+}
+
+/// Hit, miss and eviction counters for an intern table.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups answered from the table
+    pub hits: u64,
+    /// Lookups that had to intern (or inline) the entry
+    pub misses: u64,
+    /// Entries evicted to make room for new ones
+    pub evictions: u64,
+}
+
+impl std::ops::AddAssign for CacheStats {
+    fn add_assign(&mut self, other: Self) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.evictions += other.evictions;
+    }
+}
+
+/// Memory held against [`FtfLayerConfig::memory_budget`], which is only
+/// counted when a budget is set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Approximate bytes held now
+    pub used_bytes: u64,
+    /// Most bytes held at once
+    pub peak_bytes: u64,
+    /// Times a name, value or thread was written inline because the budget
+    /// was full
+    pub inlined_entries: u64,
+    /// Sampled span trees dropped because the budget was full
+    pub dropped_trees: u64,
+}
+
+/// Counters reported by [`FtfLayer::stats`], summed over every output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FtfLayerStats {
+    /// String table counters
+    pub strings: CacheStats,
+    /// Thread table counters
+    pub threads: CacheStats,
+    /// Debug-formatted values truncated at [`FtfLayerConfig::max_debug_len`]
+    pub truncated_debug_values: u64,
+    /// Names and values whose NUL characters were escaped as `\0`, since
+    /// some readers take a NUL as the end of a string
+    pub sanitized_strings: u64,
+    /// Spans that closed on a different thread than they began on, whose end
+    /// was recorded on the beginning thread
+    pub migrated_spans: u64,
+    /// Spans reported open for longer than
+    /// [`FtfLayerConfig::long_running_span_threshold`]
+    pub long_running_spans: u64,
+    /// Spans and events dropped because their category was over its
+    /// [`FtfLayerConfig::category_budgets`] budget
+    pub throttled_records: u64,
+    /// Time spent in the layer itself, if
+    /// [`FtfLayerConfig::measure_overhead`] is set
+    pub overhead: OverheadStats,
+    /// Memory held by the layer's tables and buffers, if
+    /// [`FtfLayerConfig::memory_budget`] is set
+    pub memory: MemoryStats,
+}
+
+/// Policy for interning string field values.
+///
+/// Interned strings stay in the string table for the life of the trace, so
+/// interning high-cardinality values such as ids or URLs grows the table and
+/// writes a string record for a value that may never be seen again.
+///
+/// ftfrs can only write string values of up to 8 bytes inline, so longer
+/// values are interned regardless of the policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValueInterning {
+    /// Intern every value
+    #[default]
+    Always,
+    /// Write values inline until the same value has been seen this many times
+    AfterRepeats(u32),
+    /// Intern values up to this many bytes long and write longer ones inline
+    MaxLength(usize),
+    /// Write values inline, as far as ftfrs can. Only names grow the string
+    /// table, except for values too long to write inline.
+    Never,
+}
+
+/// Encoding for `i128` and `u128` field values, which FTF has no argument type for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WideIntEncoding {
+    /// A decimal string, e.g. `"-42"`
+    #[default]
+    Decimal,
+    /// A hex string, e.g. `"-0x2a"`
+    Hex,
+    /// Two `u64` arguments, `<name>_hi` and `<name>_lo`, holding the upper and
+    /// lower 64 bits of the value's two's complement representation
+    Split,
+}
+
+/// Encoding for byte slice field values, which are written as string arguments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BytesEncoding {
+    /// Lowercase hex, two digits per byte
+    #[default]
+    Hex,
+    /// Standard padded base64
+    Base64,
+}
+
+/// Rollover schedule of a rolling file writer, matching `tracing_appender::rolling::Rotation`.
+///
+/// Periods start on UTC boundaries, as they do for `tracing_appender`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// A new file every minute
+    Minutely,
+    /// A new file every hour
+    Hourly,
+    /// A new file every day
+    Daily,
+}
+
+impl Rotation {
+    /// Length of a period in seconds
+    fn period_seconds(self) -> u64 {
+        match self {
+            Self::Minutely => 60,
+            Self::Hourly => 60 * 60,
+            Self::Daily => 24 * 60 * 60,
+        }
+    }
+
+    /// Index of the period containing the current time
+    fn current_period(self) -> u64 {
+        (platform::unix_nanos() / 1_000_000_000) as u64 / self.period_seconds()
+    }
+}
+
+/// When a recorded span's duration ends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpanEnd {
+    /// When the span closes, i.e. once its last handle is dropped, which
+    /// may be long after it was last entered if a clone is kept around
+    #[default]
+    Close,
+    /// When the span was last exited, falling back to its close for spans
+    /// that were never entered
+    LastExit,
+}
+
+/// Whether span durations are written as counters, for
+/// [`FtfLayerConfig::span_duration_counters`].
+///
+/// Counters carry a `duration_ns` argument, are attributed to the process
+/// rather than a thread, and share the span's name and category, so each
+/// span name gets one series.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DurationCounters {
+    /// Write spans only as duration records
+    #[default]
+    Off,
+    /// Write a counter as well as the duration records
+    AlongsideSpans,
+    /// Write only the counter, leaving spans out of the timeline
+    InsteadOfSpans,
+}
+
+/// What record timestamps are measured from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampEpoch {
+    /// When the layer was created
+    #[default]
+    LayerCreation,
+    /// When the process started. On Linux and Android this is read from
+    /// procfs; elsewhere, and in the browser where it is the page's time
+    /// origin, it falls back to when the first layer using it was created.
+    ProcessStart,
+    /// A caller-supplied instant, e.g. one taken at the start of `main` and
+    /// shared between components. Not supported with the `wasm` feature on
+    /// `wasm32`, where it falls back to layer creation.
+    Instant(Instant),
+}
+
+/// How the category is chosen for records that don't set one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DefaultCategory {
+    /// Use a fixed category name
+    Fixed(String),
+    /// Use the full metadata target, e.g. `my_crate::db::pool`
+    Target,
+    /// Use the first path segment of the metadata target, e.g. `my_crate`
+    TargetCrate,
+}
+
+impl Default for DefaultCategory {
+    fn default() -> Self {
+        Self::Fixed(DEFAULT_CATEGORY.to_string())
+    }
 }
 
 impl Default for FtfLayerConfig {
     fn default() -> Self {
         Self {
             provider_id: 1,
+            unique_provider_id: false,
             provider_name: "trace".to_string(),
+            metadata: Vec::new(),
+            process_metadata: true,
             process_id: None,
+            timestamp_epoch: TimestampEpoch::LayerCreation,
+            clock: None,
+            category_files: HashMap::new(),
+            route_by_metadata: false,
+            denied_categories: HashSet::new(),
+            process_categories: HashSet::new(),
+            denied_fields: HashSet::new(),
+            max_debug_len: None,
+            memoize_debug_values: false,
+            wide_int_encoding: WideIntEncoding::default(),
+            bytes_encoding: BytesEncoding::default(),
+            max_bytes_len: None,
+            rotation: None,
+            chunk_interval: None,
+            chunk_bytes: None,
+            chunk_trailers: false,
+            sync_policy: SyncPolicy::Never,
+            preallocation: None,
+            trace_marker: false,
+            span_end: SpanEnd::Close,
+            span_duration_counters: DurationCounters::Off,
+            latency_summary: false,
+            slowest_spans: 0,
+            schema_record: false,
+            long_running_span_threshold: None,
+            long_running_span_events: false,
+            long_running_span_repeat: None,
+            blocking_threshold: None,
+            max_write_failures: None,
+            max_trace_bytes: None,
+            write_failure_handler: None,
+            measure_overhead: false,
+            overhead_counter_interval: None,
+            #[cfg(feature = "alloc-track")]
+            alloc_counter_interval: None,
+            #[cfg(feature = "alloc-track")]
+            span_alloc_args: false,
+            tail_sampling: TailSampling::Off,
+            record_spans: true,
+            record_events: true,
+            record_span_names: Vec::new(),
+            middleware: Vec::new(),
+            sinks: Vec::new(),
+            frame_counter: false,
+            category_levels: HashMap::new(),
+            category_budgets: HashMap::new(),
+            max_level: None,
+            default_category: DefaultCategory::default(),
+            category_aliases: HashMap::new(),
+            value_interning: ValueInterning::default(),
+            value_cache_capacity: 4096,
+            string_cache_capacity: 0x7FFF,
+            thread_cache_capacity: 255,
+            memory_budget: None,
         }
     }
 }
 
+/// Arguments a record collects before spilling to the heap
+const INLINE_ARGUMENTS: usize = 8;
+
+/// A record's arguments, collected inline since most records have only a few
+type Arguments = SmallVec<[ftfrs::Argument; INLINE_ARGUMENTS]>;
+
 struct ArgumentVisitor<'a> {
-    arguments: Vec<ftfrs::Argument>,
+    arguments: Arguments,
     string_cache: &'a mut StringCache,
-    writer: &'a mut dyn io::Write,
+    output: RecordOutput<'a>,
+    config: &'a FtfLayerConfig,
+    /// Debug-formatted values cut short at [`FtfLayerConfig::max_debug_len`]
+    truncated_debug_values: u64,
 }
 
 impl<'a> ArgumentVisitor<'a> {
-    fn new(string_cache: &'a mut StringCache, writer: &'a mut dyn io::Write) -> Self {
+    fn new(
+        string_cache: &'a mut StringCache,
+        output: RecordOutput<'a>,
+        config: &'a FtfLayerConfig,
+    ) -> Self {
         Self {
-            arguments: Vec::new(),
+            arguments: SmallVec::new(),
             string_cache,
-            writer,
+            output,
+            config,
+            truncated_debug_values: 0,
         }
     }
 
+    /// Whether `field` is on the deny-list and must not be recorded
+    fn is_denied(&self, field: &Field) -> bool {
+        self.config.denied_fields.contains(field.name())
+    }
+
     fn get_string_ref(&mut self, value: &str) -> ftfrs::StringRef {
-        let mut buffer = Vec::new();
-        match self.string_cache.get_or_create(value, &mut buffer) {
-            Ok(string_ref) => {
-                if !buffer.is_empty() {
-                    if let Err(e) = self.writer.write_all(&buffer) {
-                        eprintln!("Error writing string record: {}", e);
-                    }
-                }
-                string_ref
-            }
-            Err(_) => {
-                ftfrs::StringRef::Inline(value.to_string())
-            }
+        self.intern(value, false)
+    }
+
+    fn get_value_ref(&mut self, value: &str) -> ftfrs::StringRef {
+        self.intern(value, true)
+    }
+
+    fn intern(&mut self, value: &str, is_value: bool) -> ftfrs::StringRef {
+        let result = if is_value {
+            self.string_cache.get_or_create_value(value, &mut self.output)
+        } else {
+            self.string_cache.get_or_create(value, &mut self.output)
+        };
+        match result {
+            Ok(string_ref) => string_ref,
+            Err(_) if is_value => limits::inline_value(value),
+            Err(_) => limits::inline_name(value),
+        }
+    }
+
+    /// Push a string argument, splitting values too long for one string
+    /// record into arguments named `name[0]`, `name[1]`, ...
+    fn push_str(&mut self, name: &str, value: &str) {
+        if value.len() <= MAX_STRING_BYTES {
+            let name_ref = self.get_string_ref(name);
+            let value_ref = self.get_value_ref(value);
+            self.arguments.push(ftfrs::Argument::Str(name_ref, value_ref));
+            return;
         }
+
+        for (i, chunk) in limits::chunks(value, MAX_STRING_BYTES).into_iter().enumerate() {
+            let name_ref = self.get_string_ref(&format!("{}[{}]", name, i));
+            let value_ref = self.get_value_ref(chunk);
+            self.arguments.push(ftfrs::Argument::Str(name_ref, value_ref));
+        }
+    }
+
+    /// Push an argument given directly rather than recorded from a field
+    fn push_arg(&mut self, name: &str, value: &ArgValue<'_>) {
+        if self.config.denied_fields.contains(name) {
+            return;
+        }
+
+        let argument = match *value {
+            ArgValue::I64(value) => ftfrs::Argument::Int64(self.get_string_ref(name), value),
+            ArgValue::U64(value) => ftfrs::Argument::UInt64(self.get_string_ref(name), value),
+            ArgValue::F64(value) => ftfrs::Argument::Float(self.get_string_ref(name), value),
+            ArgValue::Bool(value) => ftfrs::Argument::Boolean(self.get_string_ref(name), value),
+            ArgValue::Str(value) => return self.push_str(name, value),
+        };
+        self.arguments.push(argument);
+    }
+
+    /// Push a 128-bit value as `name_hi` and `name_lo` u64 arguments
+    fn push_split(&mut self, name: &str, value: u128) {
+        let hi_ref = self.get_string_ref(&format!("{}_hi", name));
+        self.arguments.push(ftfrs::Argument::UInt64(hi_ref, (value >> 64) as u64));
+
+        let lo_ref = self.get_string_ref(&format!("{}_lo", name));
+        self.arguments.push(ftfrs::Argument::UInt64(lo_ref, value as u64));
     }
 }
 
 impl Visit for ArgumentVisitor<'_> {
     fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
-        let name_ref = self.get_string_ref(field.name());
-        let value_str = format!("{:?}", value);
-        let value_ref = self.get_string_ref(&value_str);
-        
-        self.arguments.push(ftfrs::Argument::Str(name_ref, value_ref));
+        if self.is_denied(field) {
+            return;
+        }
+
+        let memoize = self.config.memoize_debug_values;
+        if memoize {
+            if let Some(value) = self.string_cache.debug_values.take_if_same(field, value) {
+                self.push_str(field.name(), &value);
+                self.string_cache.debug_values.insert(field, value);
+                return;
+            }
+        }
+
+        let (value, truncated) = match self.config.max_debug_len {
+            Some(max_len) => {
+                let (mut value, truncated) = limits::debug_string(value, max_len);
+                if truncated {
+                    value.push_str(TRUNCATION_MARKER);
+                    self.truncated_debug_values += 1;
+                }
+                (value, truncated)
+            }
+            None => (format!("{:?}", value), false),
+        };
+        self.push_str(field.name(), &value);
+        // Truncated values can't be compared with a later value in full
+        if memoize && !truncated {
+            self.string_cache.debug_values.insert(field, value);
+        }
     }
 
     fn record_i64(&mut self, field: &Field, value: i64) {
+        if self.is_denied(field) {
+            return;
+        }
+
         let name_ref = self.get_string_ref(field.name());
         
         self.arguments.push(ftfrs::Argument::Int64(name_ref, value));
     }
 
     fn record_u64(&mut self, field: &Field, value: u64) {
+        if self.is_denied(field) {
+            return;
+        }
+
         let name_ref = self.get_string_ref(field.name());
         
         self.arguments.push(ftfrs::Argument::UInt64(name_ref, value));
     }
 
     fn record_bool(&mut self, field: &Field, value: bool) {
+        if self.is_denied(field) {
+            return;
+        }
+
         let name_ref = self.get_string_ref(field.name());
         
         self.arguments.push(ftfrs::Argument::Boolean(name_ref, value));
     }
 
     fn record_str(&mut self, field: &Field, value: &str) {
-        let name_ref = self.get_string_ref(field.name());
-        let value_ref = self.get_string_ref(value);
-        
-        self.arguments.push(ftfrs::Argument::Str(name_ref, value_ref));
+        if self.is_denied(field) {
+            return;
+        }
+
+        self.push_str(field.name(), value);
     }
 
     fn record_f64(&mut self, field: &Field, value: f64) {
+        if self.is_denied(field) {
+            return;
+        }
+
         let name_ref = self.get_string_ref(field.name());
         
         self.arguments.push(ftfrs::Argument::Float(name_ref, value));
     }
 
     fn record_i128(&mut self, field: &Field, value: i128) {
-        self.record_debug(field, &value);
+        if self.is_denied(field) {
+            return;
+        }
+
+        match self.config.wide_int_encoding {
+            WideIntEncoding::Decimal => self.push_str(field.name(), &value.to_string()),
+            WideIntEncoding::Hex if value < 0 => {
+                self.push_str(field.name(), &format!("-{:#x}", value.unsigned_abs()))
+            }
+            WideIntEncoding::Hex => self.push_str(field.name(), &format!("{:#x}", value)),
+            WideIntEncoding::Split => self.push_split(field.name(), value as u128),
+        }
     }
 
     fn record_u128(&mut self, field: &Field, value: u128) {
-        self.record_debug(field, &value);
+        if self.is_denied(field) {
+            return;
+        }
+
+        match self.config.wide_int_encoding {
+            WideIntEncoding::Decimal => self.push_str(field.name(), &value.to_string()),
+            WideIntEncoding::Hex => self.push_str(field.name(), &format!("{:#x}", value)),
+            WideIntEncoding::Split => self.push_split(field.name(), value),
+        }
+    }
+
+    fn record_bytes(&mut self, field: &Field, value: &[u8]) {
+        if self.is_denied(field) {
+            return;
+        }
+
+        let max_len = self.config.max_bytes_len.unwrap_or(usize::MAX);
+        let mut encoded = bytes::encode(&value[..value.len().min(max_len)], self.config.bytes_encoding);
+        if value.len() > max_len {
+            encoded.push_str(TRUNCATION_MARKER);
+        }
+        self.push_str(field.name(), &encoded);
     }
 
     fn record_error(&mut self, field: &Field, error: &(dyn std::error::Error + 'static)) {
@@ -205,300 +1175,2060 @@ impl<W: for<'a> MakeWriter<'a>> FtfLayer<W> {
         Self::with_config(writer, FtfLayerConfig::default())
     }
 
-    pub fn with_config(writer: W, config: FtfLayerConfig) -> Self {
-        let writer = Arc::new(RwLock::new(writer));
-        let string_cache = Arc::new(RwLock::new(StringCache::new()));
-        let thread_cache = Arc::new(RwLock::new(ThreadCache::new()));
-        
-        {
-            let writer_guard = writer.write();
-            let mut w = writer_guard.make_writer();
-            
-            let magic = ftfrs::Record::create_magic_number();
-            if let Err(e) = magic.write(&mut w) {
-                eprintln!("Error writing magic number: {}", e);
-            }
-            
-            if let Err(e) = ftfrs::Record::create_provider_info(config.provider_id, config.provider_name)
-                .write(&mut w)
-            {
-                eprintln!("Error writing provider info: {}", e);
-            }
+    pub fn with_config(writer: W, mut config: FtfLayerConfig) -> Self {
+        let trace_id: Arc<str> = trace_id::generate().into();
+        config.metadata.push((TRACE_ID_KEY.to_string(), trace_id.to_string()));
+        if config.unique_provider_id {
+            assign_unique_provider_id(&mut config);
+        }
+        if config.process_metadata {
+            config.metadata.extend(process_info::collect());
         }
+
+        let memory = MemoryBudget::new(config.memory_budget);
+        let main = MainOutput {
+            string_cache: StringCache::new(&config, &memory),
+            thread_cache: ThreadCache::new(&config, &memory),
+            routed_headers: HashMap::new(),
+        };
+        let (string_counters, thread_counters) = (main.string_cache.counters(), main.thread_cache.counters());
+        
+        let overhead = config.measure_overhead.then(|| Arc::new(Overhead::default()));
+        let latency = config.latency_summary.then(|| Arc::new(LatencySummary::default()));
+        let slowest = (config.slowest_spans > 0).then(|| Arc::new(SlowestSpans::new(config.slowest_spans)));
+        let schema = config.schema_record.then(|| Arc::new(Schema::default()));
+
+        let trace_marker = if config.trace_marker {
+            TraceMarker::open().map(Arc::new)
+        } else {
+            None
+        };
+
+        let router = if config.category_files.is_empty() {
+            None
+        } else {
+            Some(Arc::new(CategoryRouter::new(&config, &memory)))
+        };
+
+        let budgets = Arc::new(CategoryBudgets::new(&config.category_budgets));
+        let chunk_checksum = config.chunk_trailers.then(|| Arc::new(ChunkChecksum::default()));
         
         Self {
-            writer,
-            start: Instant::now(),
-            string_cache,
-            thread_cache,
+            writer: Arc::new(writer),
+            main: Arc::new(Mutex::new(main)),
+            start: Clock::new(config.timestamp_epoch),
+            string_counters,
+            thread_counters,
+            router,
+            budgets,
+            category_filters: Arc::new(CategoryFilters::new(&config)),
+            health: Arc::new(WriteHealth::new(
+                config.max_write_failures,
+                config.write_failure_handler.clone(),
+                config.max_trace_bytes,
+                config.chunk_bytes.is_some(),
+            )),
+            config: Arc::new(config),
+            header_written: Arc::new(AtomicBool::new(false)),
+            truncated_debug_values: Arc::new(AtomicU64::new(0)),
+            migrated_spans: Arc::new(AtomicU64::new(0)),
+            long_running_spans: Arc::new(AtomicU64::new(0)),
+            open_spans: Arc::new(Mutex::new(HashMap::new())),
+            subscriber: 0,
+            next_subscriber: Arc::new(AtomicU64::new(1)),
+            overhead,
+            latency,
+            slowest,
+            schema,
+            next_flow_id: Arc::new(AtomicU64::new(1)),
+            next_async_id: Arc::new(AtomicU64::new(1)),
+            next_track_id: Arc::new(AtomicU64::new(0)),
+            link_spans: None,
+            manual_hooks: None,
+            trace_id,
+            rotation_period: Arc::new(AtomicU64::new(0)),
+            chunk_start: Arc::new(AtomicU64::new(0)),
+            chunk_start_bytes: Arc::new(AtomicU64::new(0)),
+            chunk_checksum,
+            memory,
+            trace_marker,
         }
     }
 
-    /// Get the current time as nanoseconds elapsed since layer creation
-    fn now(&self) -> u64 {
-        self.start.elapsed().as_nanos() as u64
+    /// Create another layer value writing to the same output with the same
+    /// intern tables and clock
+    fn share(&self) -> Self {
+        Self {
+            writer: self.writer.clone(),
+            main: self.main.clone(),
+            start: self.start,
+            string_counters: self.string_counters.clone(),
+            thread_counters: self.thread_counters.clone(),
+            router: self.router.clone(),
+            budgets: self.budgets.clone(),
+            category_filters: self.category_filters.clone(),
+            config: self.config.clone(),
+            header_written: self.header_written.clone(),
+            health: self.health.clone(),
+            truncated_debug_values: self.truncated_debug_values.clone(),
+            migrated_spans: self.migrated_spans.clone(),
+            long_running_spans: self.long_running_spans.clone(),
+            open_spans: self.open_spans.clone(),
+            subscriber: self.subscriber,
+            next_subscriber: self.next_subscriber.clone(),
+            overhead: self.overhead.clone(),
+            latency: self.latency.clone(),
+            slowest: self.slowest.clone(),
+            schema: self.schema.clone(),
+            next_flow_id: self.next_flow_id.clone(),
+            next_async_id: self.next_async_id.clone(),
+            next_track_id: self.next_track_id.clone(),
+            link_spans: self.link_spans,
+            manual_hooks: self.manual_hooks,
+            trace_id: self.trace_id.clone(),
+            rotation_period: self.rotation_period.clone(),
+            chunk_start: self.chunk_start.clone(),
+            chunk_start_bytes: self.chunk_start_bytes.clone(),
+            chunk_checksum: self.chunk_checksum.clone(),
+            memory: self.memory.clone(),
+            trace_marker: self.trace_marker.clone(),
+        }
     }
 
-    /// Get the current process ID
-    fn process_id(&self) -> u64 {
-        // Return the process ID from the current environment
-        std::process::id() as u64
+    /// Get the UUID generated for this layer's trace.
+    ///
+    /// It is written to every output as the `trace_id` metadata argument, so
+    /// logs and traces from the same run can be cross-referenced.
+    pub fn trace_id(&self) -> &str {
+        &self.trace_id
     }
 
-    /// Get the current thread ID
-    fn thread_id(&self) -> u64 {
-        thread_local! {
-            static THREAD_ID: u64 = {
-                use std::sync::atomic::AtomicU64;
-                static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
-                NEXT_THREAD_ID.fetch_add(1, Ordering::SeqCst)
-            }
-        }
-        
-        THREAD_ID.with(|id| *id)
-    }
-    
-    /// Get an interned string reference
-    fn get_string_ref(
-        &self, 
-        value: &str
-    ) -> ftfrs::StringRef {
-        let mut string_cache = self.string_cache.write();
-        let writer_guard = self.writer.write();
-        let mut writer = writer_guard.make_writer();
-        
-        match string_cache.get_or_create(value, &mut writer) {
-            Ok(string_ref) => string_ref,
-            Err(_) => {
-                ftfrs::StringRef::Inline(value.to_string())
-            }
-        }
+    /// Get a handle for emitting records directly, which stays usable after
+    /// the layer is added to a subscriber
+    pub fn handle(&self) -> FtfHandle<W> {
+        FtfHandle::new(self.share())
     }
-    
-    /// Get an interned thread reference
-    fn get_thread_ref(&self) -> ftfrs::ThreadRef {
-        let process_id = self.process_id();
-        let thread_id = self.thread_id();
-        
-        let mut thread_cache = self.thread_cache.write();
-        let writer_guard = self.writer.write();
-        let mut writer = writer_guard.make_writer();
-        
-        match thread_cache.get_or_create(process_id, thread_id, &mut writer) {
-            Ok(thread_ref) => thread_ref,
-            Err(_) => {
-                ftfrs::ThreadRef::Inline { 
-                    process_koid: process_id, 
-                    thread_koid: thread_id 
-                }
-            }
-        }
+
+    /// Get another layer writing into this one's trace, for adding to a
+    /// different subscriber, e.g. one built per subsystem
+    pub fn layer_handle(&self) -> FtfLayerHandle<W> {
+        FtfLayerHandle::new(self)
     }
-    
-    /// Write a record to the underlying writer
-    fn write_record(&self, record: ftfrs::Record) {
-        let writer_guard = self.writer.write();
-        let mut writer = writer_guard.make_writer();
-        if let Err(e) = record.write(&mut writer) {
-            eprintln!("Error writing FTF record: {}", e);
-        }
+
+    /// Get a guard that ends open spans and flushes the output when dropped,
+    /// for holding until the end of `main`
+    pub fn flush_guard(&self) -> FlushGuard<W> {
+        FlushGuard::new(self.handle())
     }
 
-    /// Extract arguments from span attributes
-    fn record_attributes(
-        &self, 
-        attrs: &span::Attributes<'_>
-    ) -> Vec<ftfrs::Argument> {
-        let mut string_cache = self.string_cache.write();
-        let writer_guard = self.writer.write();
-        let mut writer = writer_guard.make_writer();
-        
-        let mut visitor = ArgumentVisitor::new(&mut string_cache, &mut writer);
-        
-        attrs.record(&mut visitor);
-        
-        visitor.arguments
+    /// Create a layer, writing the trace header immediately and returning an
+    /// error if the output can't be written
+    pub fn try_new(writer: W) -> Result<Self, FtfLayerError> {
+        Self::try_with_config(writer, FtfLayerConfig::default())
     }
 
-    /// Extract arguments from event fields
-    fn record_event_fields(&self, event: &Event<'_>) -> Vec<ftfrs::Argument> {
-        let mut string_cache = self.string_cache.write();
-        let writer_guard = self.writer.write();
-        let mut writer = writer_guard.make_writer();
-        
-        let mut visitor = ArgumentVisitor::new(&mut string_cache, &mut writer);
-        
-        event.record(&mut visitor);
-        
-        visitor.arguments
+    /// Like [`FtfLayer::try_new`], using the given configuration
+    pub fn try_with_config(writer: W, config: FtfLayerConfig) -> Result<Self, FtfLayerError> {
+        let layer = Self::with_config(writer, config);
+        layer.try_init_now()?;
+        Ok(layer)
     }
-}
 
-/// Filter to check if a span should be included in FTF tracing
-/// and to extract additional metadata like category
-struct FtfFilter {
-    should_record: bool,
-    category: Option<String>,
-}
+    /// Write the trace header now, returning any error instead of printing it.
+    ///
+    /// Calling this after the header has been written has no effect.
+    pub fn try_init_now(&self) -> Result<(), FtfLayerError> {
+        let mut writer = self.writer.make_writer();
+        let mut main = self.main.lock();
+        let main = &mut *main;
+        if self.header_written.load(Ordering::Acquire) {
+            return Ok(());
+        }
 
-impl FtfFilter {
-    fn new() -> Self {
-        Self {
-            should_record: false,
-            category: None,
+        let mut monitored = MonitoredWriter::new(&mut writer, &self.health);
+        let mut checksummed;
+        let writer: &mut dyn io::Write = match &self.chunk_checksum {
+            Some(checksum) => {
+                checksummed = ChecksummedWriter::new(&mut monitored, checksum);
+                &mut checksummed
+            }
+            None => &mut monitored,
+        };
+        try_write_header(
+            &mut Destination::new(
+                &mut main.string_cache,
+                &mut main.thread_cache,
+                RecordOutput::new(writer, &self.config.middleware, &self.config.sinks),
+            ),
+            &self.config,
+        )?;
+        io::Write::flush(writer)?;
+        if let Some(rotation) = self.config.rotation {
+            self.rotation_period.store(rotation.current_period(), Ordering::Release);
         }
+        self.start_chunk(self.now());
+        self.header_written.store(true, Ordering::Release);
+        Ok(())
     }
-}
 
-impl Visit for FtfFilter {
-    fn record_bool(&mut self, field: &Field, value: bool) {
-        if field.name() == "ftf" && value {
-            self.should_record = true;
-        }
+    /// Write the trace header now rather than before the first record.
+    ///
+    /// The header is otherwise deferred so that no output is created if no
+    /// traced code ever runs. Calling this more than once has no effect.
+    pub fn init_now(&self) {
+        self.with_main_destination(|_| {});
     }
 
-    fn record_str(&mut self, field: &Field, value: &str) {
-        if field.name() == "category" {
-            self.category = Some(value.to_string());
+    /// Write the header to the main output unless it has already been written.
+    ///
+    /// `dest` must be the main output, so the header lands before any record.
+    fn write_header_once(&self, dest: &mut Destination<'_>) {
+        let period = self.config.rotation.map(Rotation::current_period);
+        if !self.header_written.swap(true, Ordering::AcqRel) {
+            self.rotation_period.store(period.unwrap_or_default(), Ordering::Release);
+            self.start_chunk(self.now());
+            write_header(dest, &self.config);
+            return;
         }
-    }
 
-    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
-    fn record_i64(&mut self, _field: &Field, _value: i64) {}
-    fn record_u64(&mut self, _field: &Field, _value: u64) {}
-    fn record_f64(&mut self, _field: &Field, _value: f64) {}
-    fn record_i128(&mut self, _field: &Field, _value: i128) {}
-    fn record_u128(&mut self, _field: &Field, _value: u128) {}
-    fn record_error(&mut self, _field: &Field, _error: &(dyn std::error::Error + 'static)) {}
-}
+        let chunk_ended = self.chunk_checksum.as_ref().is_some_and(|checksum| checksum.take_ended());
+        if self.health.take_resync() {
+            self.write_chunk_trailer(dest);
+            self.start_chunk(self.now());
+            if let Err(e) = try_write_rolled_header(dest, &self.config) {
+                eprintln!("Error writing trace header after resuming recording: {}", e);
+            }
+            return;
+        }
 
-impl<W, S> Layer<S> for FtfLayer<W>
-where
-    S: Subscriber + for<'a> LookupSpan<'a>,
-    W: for<'writer> MakeWriter<'writer> + 'static,
-{
-    
-    fn on_event(&self, event: &Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        let mut filter = FtfFilter::new();
+        if let Some(period) = period {
+            if self.rotation_period.swap(period, Ordering::AcqRel) != period {
+                self.write_chunk_trailer(dest);
+                self.start_chunk(self.now());
+                if let Err(e) = try_write_rolled_header(dest, &self.config) {
+                    eprintln!("Error writing trace header after rotation: {}", e);
+                }
+                return;
+            }
+        }
+
+        if chunk_ended {
+            self.start_chunk(self.now());
+            if let Err(e) = try_write_rolled_header(dest, &self.config) {
+                eprintln!("Error writing trace header for new chunk: {}", e);
+            }
+            return;
+        }
+
+        if self.config.chunk_interval.is_none() && self.config.chunk_bytes.is_none() {
+            return;
+        }
+        let now = self.now();
+        let interval_passed = self
+            .config
+            .chunk_interval
+            .is_some_and(|interval| u128::from(now.saturating_sub(self.chunk_start.load(Ordering::Acquire))) >= interval.as_nanos());
+        let bytes_filled = self
+            .config
+            .chunk_bytes
+            .is_some_and(|bytes| self.health.written().saturating_sub(self.chunk_start_bytes.load(Ordering::Acquire)) >= bytes);
+        if interval_passed || bytes_filled {
+            self.write_chunk_trailer(dest);
+            self.start_chunk(now);
+            if let Err(e) = try_write_rolled_header(dest, &self.config) {
+                eprintln!("Error writing trace header for new chunk: {}", e);
+            }
+        }
+    }
+
+    /// End the main output's current chunk with a trailer, if
+    /// [`FtfLayerConfig::chunk_trailers`] is set, returning whether one was
+    /// written.
+    ///
+    /// `dest` must be the main output.
+    fn write_chunk_trailer(&self, dest: &mut Destination<'_>) -> bool {
+        let Some(checksum) = &self.chunk_checksum else {
+            return false;
+        };
+        checksum.write_trailer(dest.output.raw()).unwrap_or_else(|e| {
+            eprintln!("Error writing chunk trailer: {}", e);
+            false
+        })
+    }
+
+    /// End the main output's current chunk with a trailer, so the next
+    /// record starts a new chunk
+    pub(crate) fn end_chunk(&self) {
+        let Some(checksum) = &self.chunk_checksum else {
+            return;
+        };
+        if !self.header_written.load(Ordering::Acquire) || !self.health.is_recording() {
+            return;
+        }
+        self.with_main_destination(|dest| {
+            if self.write_chunk_trailer(dest) {
+                checksum.end();
+            }
+        });
+    }
+
+    /// Note that a new chunk of the main output starts at trace time `now`
+    fn start_chunk(&self, now: u64) {
+        self.chunk_start.store(now, Ordering::Release);
+        self.chunk_start_bytes.store(self.health.written(), Ordering::Release);
+    }
+
+    /// Intern `strings` up front so their first use on a hot path doesn't pay
+    /// for interning and writing a string record.
+    ///
+    /// The strings are pinned as names in the main output's string table.
+    /// Per-category files still intern them on first use.
+    pub fn pre_intern<I, S>(&self, strings: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.with_main_destination(|dest| {
+            for string in strings {
+                if let Err(e) = dest.string_cache.get_or_create(string.as_ref(), &mut dest.output) {
+                    eprintln!("Error writing string record: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Write `payload` to the main output as a blob record named `name`
+    fn write_blob(&self, name: &str, payload: &[u8]) {
+        self.with_main_destination(|dest| {
+            let category_ref = dest.string_ref(BLOB_CATEGORY);
+            let name_ref = dest.string_ref(name);
+            if let Err(e) = blob::write_blob(dest.output.raw(), &category_ref, &name_ref, payload) {
+                eprintln!("Error writing FTF blob record: {}", e);
+            }
+        });
+    }
+
+    /// Flush the main writer without writing the header
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        let mut writer = self.writer.make_writer();
+        let _main = self.main.lock();
+        io::Write::flush(&mut MonitoredWriter::new(&mut writer, &self.health))
+    }
+
+    /// Whether recording is on, i.e. not turned off by
+    /// [`FtfLayerConfig::max_write_failures`] or [`FtfLayerConfig::max_trace_bytes`]
+    pub(crate) fn is_recording(&self) -> bool {
+        self.health.is_recording()
+    }
+
+    /// Turn recording back on after [`FtfLayerConfig::max_write_failures`],
+    /// [`FtfLayerConfig::max_trace_bytes`] or [`FtfHandle::stop_recording`]
+    /// turned it off
+    pub(crate) fn resume_recording(&self) {
+        self.health.resume();
+    }
+
+    /// Turn recording off until it is resumed
+    pub(crate) fn stop_recording(&self) {
+        self.health.stop();
+    }
+
+    /// Add a category or glob pattern to the deny-list
+    pub(crate) fn deny_category(&self, category: &str) {
+        self.category_filters.denied.write().insert(category, ());
+    }
+
+    /// Remove a category or glob pattern from the deny-list, returning
+    /// whether it was on it
+    pub(crate) fn allow_category(&self, category: &str) -> bool {
+        self.category_filters.denied.write().remove(category)
+    }
+
+    /// The categories and patterns on the deny-list, sorted
+    pub(crate) fn denied_categories(&self) -> Vec<String> {
+        self.category_filters.denied.read().keys()
+    }
+
+    /// Set or lift the record budget of `category`
+    pub(crate) fn set_category_budget(&self, category: &str, per_second: Option<u32>) {
+        self.budgets.set(category, per_second);
+    }
+
+    /// Each category's record budget per second, sorted by category
+    pub(crate) fn category_budgets(&self) -> Vec<(String, u32)> {
+        self.budgets.budgets()
+    }
+
+    /// Write an end record, with a `truncated = true` argument, for every
+    /// recorded span that is still open, then flush the main writer.
+    ///
+    /// Spans held in a sampled tree are left alone. The ended spans write
+    /// nothing more when they eventually close.
+    pub(crate) fn end_open_spans(&self) {
+        let mut ended: Vec<OpenSpan> = {
+            let mut open_spans = self.open_spans.lock();
+            let (ended, sampled) = std::mem::take(&mut *open_spans)
+                .into_iter()
+                .partition(|(_, span)| !span.sampled);
+            *open_spans = sampled;
+            ended.into_values().collect()
+        };
+        if ended.is_empty() {
+            return;
+        }
+
+        // Spans without a begin record have nothing to end
+        if self.config.span_duration_counters == DurationCounters::InsteadOfSpans {
+            ended.clear();
+        }
+
+        // End the innermost spans first
+        ended.sort_by_key(|span| std::cmp::Reverse(span.origin.timestamp));
+        let now = self.now();
+        for span in &ended {
+            self.with_destination(&span.category, |dest| {
+                let (name_ref, category_ref) = dest.record_refs(span.metadata, span.name.as_deref(), &span.category);
+                let thread_ref = dest.thread_ref(self.process_id(), span.origin.thread_id);
+                let truncated_ref = dest.string_ref(TRUNCATED_ARG);
+
+                dest.write_event(EventRecord::duration_end(
+                    now.max(span.origin.timestamp),
+                    thread_ref,
+                    category_ref,
+                    name_ref,
+                    &[ftfrs::Argument::Boolean(truncated_ref, true)],
+                ));
+            });
+        }
+
+        self.with_main_destination(|dest| {
+            if let Err(e) = dest.output.raw().flush() {
+                eprintln!("Error flushing FTF output: {}", e);
+            }
+        });
+    }
+
+    /// Report spans open for longer than
+    /// [`FtfLayerConfig::long_running_span_threshold`] that haven't been
+    /// reported yet, or not for [`FtfLayerConfig::long_running_span_repeat`],
+    /// returning how many there were
+    pub(crate) fn check_open_spans(&self) -> usize {
+        let Some(threshold) = self.config.long_running_span_threshold else {
+            return 0;
+        };
+
+        let now = self.now();
+        let due = |span: &OpenSpan| match (span.reported_at, self.config.long_running_span_repeat) {
+            (None, _) => u128::from(now.saturating_sub(span.origin.timestamp)) >= threshold.as_nanos(),
+            (Some(reported_at), Some(repeat)) => u128::from(now.saturating_sub(reported_at)) >= repeat.as_nanos(),
+            (Some(_), None) => false,
+        };
+        let mut first_reports = 0;
+        let long_running: Vec<OpenSpan> = self
+            .open_spans
+            .lock()
+            .values_mut()
+            .filter(|span| due(span))
+            .map(|span| {
+                if span.reported_at.is_none() {
+                    first_reports += 1;
+                }
+                span.reported_at = Some(now);
+                span.clone()
+            })
+            .collect();
+        self.long_running_spans.fetch_add(first_reports, Ordering::Relaxed);
+
+        if self.config.long_running_span_events {
+            for span in &long_running {
+                self.write_long_running_span(span, now, now.saturating_sub(span.origin.timestamp));
+            }
+        }
+        long_running.len()
+    }
+
+    /// Write the instant event reporting a long-running span
+    fn write_long_running_span(&self, span: &OpenSpan, now: u64, open_ns: u64) {
+        let category = self.alias_category(WATCHDOG_CATEGORY.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(LONG_RUNNING_SPAN_NAME);
+            let thread_ref = dest.thread_ref(self.process_id(), span.origin.thread_id);
+            let arguments = self.arguments(dest, |visitor| {
+                visitor.push_arg("span", &ArgValue::Str(span.name()));
+                visitor.push_arg("category", &ArgValue::Str(&span.category));
+                visitor.push_arg("open_ns", &ArgValue::U64(open_ns));
+            });
+
+            dest.write_event(EventRecord::instant(now, thread_ref, category_ref, name_ref, &arguments));
+        });
+    }
+
+    /// Write the instant event marking where [`FtfLayerConfig::max_trace_bytes`]
+    /// stopped the trace
+    fn write_budget_exceeded(&self, dest: &mut Destination<'_>) {
+        let category_ref = dest.category_ref(OVERHEAD_CATEGORY);
+        let name_ref = dest.string_ref(BUDGET_EXCEEDED_NAME);
+        let thread_ref = dest.thread_ref(self.process_id(), self.thread_id());
+        let max_trace_bytes = self.config.max_trace_bytes.unwrap_or_default();
+        let arguments = self.arguments(dest, |visitor| {
+            visitor.push_arg("max_trace_bytes", &ArgValue::U64(max_trace_bytes));
+        });
+
+        dest.write_event(EventRecord::instant(self.now(), thread_ref, category_ref, name_ref, &arguments));
+        if let Err(e) = dest.output.raw().flush() {
+            eprintln!("Error flushing FTF output: {}", e);
+        }
+    }
+
+    /// Write an instant event summarizing each span name's durations since
+    /// the last summary, if [`FtfLayerConfig::latency_summary`] is set
+    pub(crate) fn write_latency_summary(&self) {
+        let Some(latency) = &self.latency else {
+            return;
+        };
+        let category = self.alias_category(OVERHEAD_CATEGORY.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+
+        let summaries = latency.take();
+        if summaries.is_empty() {
+            return;
+        }
+        let now = self.now();
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(LATENCY_SUMMARY_NAME);
+            for summary in &summaries {
+                let arguments = self.arguments(dest, |visitor| {
+                    visitor.push_arg("span", &ArgValue::Str(&summary.name));
+                    visitor.push_arg("count", &ArgValue::U64(summary.count));
+                    visitor.push_arg("min_ns", &ArgValue::U64(summary.min_ns));
+                    visitor.push_arg("mean_ns", &ArgValue::U64(summary.mean_ns));
+                    visitor.push_arg("p50_ns", &ArgValue::U64(summary.p50_ns));
+                    visitor.push_arg("p95_ns", &ArgValue::U64(summary.p95_ns));
+                    visitor.push_arg("p99_ns", &ArgValue::U64(summary.p99_ns));
+                    visitor.push_arg("max_ns", &ArgValue::U64(summary.max_ns));
+                });
+
+                dest.write_event(EventRecord::instant(
+                    now,
+                    self.process_thread_ref(),
+                    category_ref.clone(),
+                    name_ref.clone(),
+                    &arguments,
+                ));
+            }
+        });
+    }
+
+    /// Write an instant event for each of the slowest spans since the last
+    /// report, if [`FtfLayerConfig::slowest_spans`] is set.
+    ///
+    /// Each is placed at its span's start on the span's thread, so it marks
+    /// the span in the timeline.
+    pub(crate) fn write_slowest_spans(&self) {
+        let Some(slowest) = &self.slowest else {
+            return;
+        };
+        let category = self.alias_category(OVERHEAD_CATEGORY.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+
+        let spans = slowest.take();
+        if spans.is_empty() {
+            return;
+        }
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(SLOWEST_SPAN_NAME);
+            for (rank, span) in spans.iter().enumerate() {
+                let thread_ref = dest.thread_ref(self.process_id(), span.thread_id);
+                let arguments = self.arguments(dest, |visitor| {
+                    visitor.push_arg("rank", &ArgValue::U64(rank as u64 + 1));
+                    visitor.push_arg("span", &ArgValue::Str(&span.name));
+                    visitor.push_arg("category", &ArgValue::Str(&span.category));
+                    visitor.push_arg("duration_ns", &ArgValue::U64(span.duration_ns));
+                });
+
+                dest.write_event(EventRecord::instant(
+                    span.start,
+                    thread_ref,
+                    category_ref.clone(),
+                    name_ref.clone(),
+                    &arguments,
+                ));
+            }
+        });
+    }
+
+    /// Write the categories and record names seen so far as a blob, if
+    /// [`FtfLayerConfig::schema_record`] is set
+    pub(crate) fn write_schema(&self) {
+        if let Some(schema) = &self.schema {
+            self.write_blob(SCHEMA_BLOB_NAME, schema.to_json().as_bytes());
+        }
+    }
+
+    /// Remember that a `kind` record named `name` was written in `category`,
+    /// if [`FtfLayerConfig::schema_record`] is set
+    fn note_schema(&self, kind: SchemaKind, category: &str, name: &str) {
+        if let Some(schema) = &self.schema {
+            schema.note(kind, category, name);
+        }
+    }
+
+    /// Start timing `hook`, if overhead is measured
+    fn hook_timer(&self, hook: Hook) -> Option<HookTimer<'_, W>> {
+        self.overhead.as_ref()?;
+        Some(HookTimer {
+            layer: self,
+            hook,
+            started: self.overhead_now(),
+        })
+    }
+
+    /// Run `f`, adding the time it takes to `phase` if overhead is measured
+    fn timed<R>(&self, phase: Phase, f: impl FnOnce() -> R) -> R {
+        let Some(overhead) = &self.overhead else {
+            return f();
+        };
+        let started = self.overhead_now();
+        let result = f();
+        overhead.add_phase(phase, self.overhead_now().saturating_sub(started));
+        result
+    }
+
+    /// Write the overhead measured over the last interval as a counter
+    /// record, if [`FtfLayerConfig::overhead_counter_interval`] has passed
+    fn write_overhead_counters(&self, overhead: &Overhead) {
+        let Some(interval) = self.config.overhead_counter_interval else {
+            return;
+        };
+        let now = self.now();
+        let Some(stats) = overhead.take_interval(now, interval.as_nanos() as u64) else {
+            return;
+        };
+
+        let category = self.alias_category(OVERHEAD_CATEGORY.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+        self.note_schema(SchemaKind::Counter, &category, OVERHEAD_NAME);
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(OVERHEAD_NAME);
+            let arguments = self.arguments(dest, |visitor| {
+                visitor.push_arg("new_span_ns", &ArgValue::U64(stats.new_span.mean_ns()));
+                visitor.push_arg("event_ns", &ArgValue::U64(stats.event.mean_ns()));
+                visitor.push_arg("close_ns", &ArgValue::U64(stats.close.mean_ns()));
+                visitor.push_arg("intern_ns", &ArgValue::U64(stats.intern_ns));
+                visitor.push_arg("serialize_ns", &ArgValue::U64(stats.serialize_ns));
+                visitor.push_arg("write_ns", &ArgValue::U64(stats.write_ns));
+            });
+
+            dest.write_event(EventRecord::counter(
+                now,
+                self.process_thread_ref(),
+                category_ref,
+                name_ref,
+                &arguments,
+                0,
+            ));
+        });
+    }
+
+    /// Write what changed between two scheduling samples of the process's
+    /// threads: a context switch when a thread starts or stops running, and
+    /// a counter with the CPU time, wait time and switches since the last
+    /// sample for each thread that was scheduled
+    #[cfg(all(feature = "sched", any(target_os = "linux", target_os = "android")))]
+    fn write_sched_samples(&self, previous: &HashMap<u64, ThreadSample>, current: &HashMap<u64, ThreadSample>) {
+        let category = self.alias_category(SCHED_CATEGORY.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+        let now = self.now();
+        self.note_schema(SchemaKind::Counter, &category, SCHED_NAME);
+        self.with_destination(&category, |dest| {
+            for (&thread_id, sample) in current {
+                let Some(last) = previous.get(&thread_id) else {
+                    continue;
+                };
+                let running = sample.state == ThreadState::Running;
+                if running != (last.state == ThreadState::Running) {
+                    if running {
+                        dest.write_context_switch(now, sample.cpu, ThreadState::Running, sched::IDLE_KOID, thread_id);
+                    } else {
+                        dest.write_context_switch(now, last.cpu, sample.state, thread_id, sched::IDLE_KOID);
+                    }
+                }
+
+                if sample.slices != last.slices {
+                    let category_ref = dest.category_ref(&category);
+                    let name_ref = dest.string_ref(SCHED_NAME);
+                    let thread_ref = dest.thread_ref(self.process_id(), thread_id);
+                    let arguments = self.arguments(dest, |visitor| {
+                        visitor.push_arg("cpu_ns", &ArgValue::U64(sample.cpu_ns.saturating_sub(last.cpu_ns)));
+                        visitor.push_arg("wait_ns", &ArgValue::U64(sample.wait_ns.saturating_sub(last.wait_ns)));
+                        visitor.push_arg("switches", &ArgValue::U64(sample.slices.saturating_sub(last.slices)));
+                    });
+                    dest.write_event(EventRecord::counter(now, thread_ref, category_ref, name_ref, &arguments, thread_id));
+                }
+            }
+
+            // Threads that exited while running
+            for (&thread_id, last) in previous {
+                if !current.contains_key(&thread_id) && last.state == ThreadState::Running {
+                    dest.write_context_switch(now, last.cpu, ThreadState::Dead, thread_id, sched::IDLE_KOID);
+                }
+            }
+        });
+    }
+
+    /// Write an I/O counter for the process with its open file descriptors
+    /// and the bytes read and written between two samples
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn write_io_sample(&self, previous: &IoSample, current: &IoSample) {
+        let category = self.alias_category(IO_CATEGORY.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+        let now = self.now();
+        self.note_schema(SchemaKind::Counter, &category, IO_NAME);
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(IO_NAME);
+            let arguments = self.arguments(dest, |visitor| {
+                visitor.push_arg("open_fds", &ArgValue::U64(current.open_fds));
+                visitor.push_arg("read_chars", &ArgValue::U64(current.read_chars.saturating_sub(previous.read_chars)));
+                visitor.push_arg("written_chars", &ArgValue::U64(current.written_chars.saturating_sub(previous.written_chars)));
+                visitor.push_arg("read_bytes", &ArgValue::U64(current.read_bytes.saturating_sub(previous.read_bytes)));
+                visitor.push_arg("write_bytes", &ArgValue::U64(current.write_bytes.saturating_sub(previous.write_bytes)));
+            });
+
+            dest.write_event(EventRecord::counter(
+                now,
+                self.process_thread_ref(),
+                category_ref,
+                name_ref,
+                &arguments,
+                0,
+            ));
+        });
+    }
+
+    /// Write a counter named after `interface` with its traffic since the
+    /// last sample
+    #[cfg(feature = "net-sampler")]
+    fn write_net_sample(&self, interface: &str, delta: &NetCounters) {
+        let category = self.alias_category(NET_CATEGORY.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+        let now = self.now();
+        self.note_schema(SchemaKind::Counter, &category, interface);
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(interface);
+            let arguments = self.arguments(dest, |visitor| {
+                visitor.push_arg("rx_bytes", &ArgValue::U64(delta.rx_bytes));
+                visitor.push_arg("tx_bytes", &ArgValue::U64(delta.tx_bytes));
+                visitor.push_arg("rx_packets", &ArgValue::U64(delta.rx_packets));
+                visitor.push_arg("tx_packets", &ArgValue::U64(delta.tx_packets));
+            });
+
+            dest.write_event(EventRecord::counter(
+                now,
+                self.process_thread_ref(),
+                category_ref,
+                name_ref,
+                &arguments,
+                0,
+            ));
+        });
+    }
+
+    /// Write a stack sample of thread `thread_id`, given innermost frame
+    /// first, as an instant event with a `stack` argument listing the frames
+    /// outermost first, separated by `;` as in collapsed flamegraph stacks
+    #[cfg(all(feature = "profiler", target_os = "linux", target_env = "gnu"))]
+    fn write_stack_sample(&self, timestamp: u64, thread_id: u64, frames: &[&str]) {
+        let category = self.alias_category(PROFILER_CATEGORY.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(PROFILER_SAMPLE_NAME);
+            let thread_ref = dest.thread_ref(self.process_id(), thread_id);
+            // Events hold too few arguments for a frame each. Stacks repeat
+            // across samples, so they are always interned.
+            let stack = frames.iter().rev().copied().collect::<Vec<_>>().join(";");
+            let arguments = [ftfrs::Argument::Str(dest.string_ref(PROFILER_STACK_ARG), dest.string_ref(&stack))];
+            dest.write_event(EventRecord::instant(timestamp, thread_ref, category_ref, name_ref, &arguments));
+        });
+    }
+
+    /// Write the calling thread's allocations since its last counter, if
+    /// [`FtfLayerConfig::alloc_counter_interval`] has passed
+    #[cfg(feature = "alloc-track")]
+    fn write_alloc_counters(&self) {
+        let Some(interval) = self.config.alloc_counter_interval else {
+            return;
+        };
+        let now = self.now();
+        let Some(stats) = alloc_track::take_thread_interval(now, interval.as_nanos() as u64) else {
+            return;
+        };
+
+        let category = self.alias_category(OVERHEAD_CATEGORY.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+        let live_bytes = alloc_track::process_alloc_stats().live_bytes();
+        self.note_schema(SchemaKind::Counter, &category, ALLOC_COUNTER_NAME);
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(ALLOC_COUNTER_NAME);
+            let thread_ref = dest.thread_ref(self.process_id(), self.thread_id());
+            let arguments = self.arguments(dest, |visitor| {
+                visitor.push_arg("alloc_count", &ArgValue::U64(stats.allocations));
+                visitor.push_arg("alloc_bytes", &ArgValue::U64(stats.allocated_bytes));
+                visitor.push_arg("freed_bytes", &ArgValue::U64(stats.freed_bytes));
+                visitor.push_arg("process_live_bytes", &ArgValue::I64(live_bytes));
+            });
+
+            dest.write_event(EventRecord::counter(
+                now,
+                thread_ref,
+                category_ref,
+                name_ref,
+                &arguments,
+                self.thread_id(),
+            ));
+        });
+    }
+
+    /// Get a snapshot of the intern table counters
+    pub fn stats(&self) -> FtfLayerStats {
+        let mut stats = FtfLayerStats {
+            strings: self.string_counters.stats(),
+            sanitized_strings: self.string_counters.sanitized(),
+            threads: self.thread_counters.stats(),
+            truncated_debug_values: self.truncated_debug_values.load(Ordering::Relaxed),
+            migrated_spans: self.migrated_spans.load(Ordering::Relaxed),
+            long_running_spans: self.long_running_spans.load(Ordering::Relaxed),
+            throttled_records: self.budgets.throttled(),
+            overhead: self.overhead.as_ref().map(|overhead| overhead.stats()).unwrap_or_default(),
+            memory: self.memory.stats(),
+        };
+        if let Some(router) = &self.router {
+            router.add_stats(&mut stats);
+        }
+        stats
+    }
+
+    /// Get the current trace time: the [`FtfLayerConfig::clock`]'s reading,
+    /// or nanoseconds elapsed since the [`FtfLayerConfig::timestamp_epoch`]
+    fn now(&self) -> u64 {
+        match &self.config.clock {
+            Some(clock) => clock.now_ns(),
+            None => self.start.elapsed_nanos(),
+        }
+    }
+
+    /// Get a monotonic time for measuring the layer's own overhead, which
+    /// never follows [`FtfLayerConfig::clock`]
+    fn overhead_now(&self) -> u64 {
+        self.start.elapsed_nanos()
+    }
+
+    /// Get the current process ID
+    fn process_id(&self) -> u64 {
+        platform::process_id() as u64
+    }
+
+    /// Get an inline thread reference standing for the process as a whole
+    fn process_thread_ref(&self) -> ftfrs::ThreadRef {
+        ftfrs::ThreadRef::Inline {
+            process_koid: self.process_id(),
+            thread_koid: 0,
+        }
+    }
+
+    /// Get the current thread ID
+    fn thread_id(&self) -> u64 {
+        thread_id::current()
+    }
+    
+    /// Category for a record that sets none and has no parent span setting one
+    fn default_category<'c>(&'c self, metadata: &'c Metadata<'_>) -> Cow<'c, str> {
+        match &self.config.default_category {
+            DefaultCategory::Fixed(category) => category.into(),
+            DefaultCategory::Target => metadata.target().into(),
+            DefaultCategory::TargetCrate => metadata.target().split("::").next().unwrap_or_default().into(),
+        }
+    }
+
+    /// Category of a span created by [`FtfLayer::record_new_span`]
+    fn span_category(&self, span: &impl SpanView) -> String {
+        let category = match span.get::<String>() {
+            Some(category) => category.into(),
+            None => self.default_category(span.metadata()),
+        };
+        self.alias_category(category).into_owned()
+    }
+
+    /// Draw a flow from the start of `from` to the start of `to`, if both are recorded
+    fn link<V: SpanView>(&self, from: &V, to: &V) {
+        let from_origin = from.get::<SpanOrigin>();
+        let to_origin = to.get::<SpanOrigin>();
+        let (Some(from_origin), Some(to_origin)) = (from_origin, to_origin) else {
+            return;
+        };
+
+        let category = self.span_category(from);
+        let flow_id = self.next_flow_id.fetch_add(1, Ordering::Relaxed);
+        self.with_destination(&category, |dest| {
+            let (name_ref, category_ref) = dest.callsite_refs(from.metadata(), &category);
+
+            let thread_ref = dest.thread_ref(self.process_id(), from_origin.thread_id);
+            dest.write_flow(FlowPhase::Begin, from_origin.timestamp, &thread_ref, &category_ref, &name_ref, flow_id);
+
+            let thread_ref = dest.thread_ref(self.process_id(), to_origin.thread_id);
+            dest.write_flow(FlowPhase::End, to_origin.timestamp, &thread_ref, &category_ref, &name_ref, flow_id);
+        });
+    }
+
+    /// Write a flow event at the current time on the calling thread, bound to
+    /// the recorded span `span`, starting a new flow unless `flow_id` is
+    /// given. Returns the flow's id, or `None` if `span` isn't recorded.
+    fn write_flow_now(&self, span: &impl SpanView, phase: FlowPhase, flow_id: Option<u64>) -> Option<u64> {
+        span.get::<SpanOrigin>()?;
+
+        let category = self.span_category(span);
+        let flow_id = flow_id.unwrap_or_else(|| self.next_flow_id.fetch_add(1, Ordering::Relaxed));
+        let now = self.now();
+        self.with_destination(&category, |dest| {
+            let (name_ref, category_ref) = dest.callsite_refs(span.metadata(), &category);
+            let thread_ref = dest.thread_ref(self.process_id(), self.thread_id());
+            dest.write_flow(phase, now, &thread_ref, &category_ref, &name_ref, flow_id);
+        });
+        Some(flow_id)
+    }
+
+    /// Write an async event for a traced future at the current time on the
+    /// calling thread
+    fn write_async_event(&self, phase: AsyncPhase, category: &str, name: &str, async_id: u64) {
+        let category = self.alias_category(category.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+
+        let now = self.now();
+        self.note_schema(SchemaKind::Span, &category, name);
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(name);
+            let thread_ref = dest.thread_ref(self.process_id(), self.thread_id());
+            dest.write_async(phase, now, &thread_ref, &category_ref, &name_ref, async_id);
+        });
+    }
+
+    /// Write one poll of a traced future, from `start` to the current time,
+    /// as a duration on the calling thread
+    fn write_poll(&self, category: &str, name: &str, start: u64) {
+        let category = self.alias_category(category.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+
+        let now = self.now();
+        self.note_schema(SchemaKind::Span, &category, name);
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(name);
+            let thread_ref = dest.thread_ref(self.process_id(), self.thread_id());
+            dest.write_event(EventRecord::duration_complete(
+                start,
+                thread_ref,
+                category_ref,
+                name_ref,
+                &[],
+                now.max(start),
+            ));
+        });
+    }
+
+    /// Write an item of a traced stream or sink as an instant on the calling
+    /// thread
+    fn write_stream_item(&self, category: &str, name: &str, index: u64) {
+        let category = self.alias_category(category.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+
+        let now = self.now();
+        self.note_schema(SchemaKind::Event, &category, name);
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(name);
+            let thread_ref = dest.thread_ref(self.process_id(), self.thread_id());
+            let arguments = self.arguments(dest, |visitor| visitor.push_arg("item", &ArgValue::U64(index)));
+            dest.write_event(EventRecord::instant(now, thread_ref, category_ref, name_ref, &arguments));
+        });
+    }
+
+    /// Write a blocking section from `start` to the current time, warning if
+    /// it took longer than [`FtfLayerConfig::blocking_threshold`]
+    fn write_blocking_section(&self, reason: &str, start: u64) {
+        let end = self.now().max(start);
+        if let Some(threshold) = self.config.blocking_threshold {
+            if u128::from(end - start) >= threshold.as_nanos() {
+                eprintln!("Blocking section {:?} took {:?}", reason, Duration::from_nanos(end - start));
+            }
+        }
+
+        let category = self.alias_category(BLOCKING_CATEGORY.into());
+        if self.is_category_denied(&category) {
+            return;
+        }
+
+        self.note_schema(SchemaKind::Span, &category, reason);
+        self.with_destination(&category, |dest| {
+            let category_ref = dest.category_ref(&category);
+            let name_ref = dest.string_ref(reason);
+            let thread_ref = dest.thread_ref(self.process_id(), self.thread_id());
+            dest.write_event(EventRecord::duration_complete(
+                start,
+                thread_ref,
+                category_ref,
+                name_ref,
+                &[],
+                end,
+            ));
+        });
+    }
+
+    /// The tree a record under `span` is buffered in, if tail sampling is on
+    fn sampled_tree<V: SpanView>(&self, span: Option<&V>) -> Option<Arc<Mutex<SpanTreeBuffer>>> {
+        if !self.config.tail_sampling.is_enabled() {
+            return None;
+        }
+        let span = span?;
+        span.get::<Arc<Mutex<SpanTreeBuffer>>>().or_else(|| self.sampled_tree(span.parent().as_ref()))
+    }
+
+    /// Run `f` against the destination for a record in `category`, which is
+    /// `tree`'s buffer when the record is part of a sampled tree
+    fn with_record_destination<R>(
+        &self,
+        category: &str,
+        tree: Option<&Mutex<SpanTreeBuffer>>,
+        metadata: &Metadata<'_>,
+        f: impl FnOnce(&mut Destination<'_>) -> R,
+    ) -> R {
+        if let Some(tree) = tree {
+            let mut tree = tree.lock();
+            if self.config.tail_sampling.keeps(metadata) {
+                tree.mark_keep();
+            }
+            if !self.config.category_files.contains_key(category) {
+                return tree.with_destination(f);
+            }
+        }
+        self.with_destination_for(category, Some(metadata), f)
+    }
+
+    /// Rewrite `category` through the configured alias table
+    fn alias_category<'c>(&'c self, category: Cow<'c, str>) -> Cow<'c, str> {
+        match self.config.category_aliases.get(category.as_ref()) {
+            Some(alias) => alias.into(),
+            None => category,
+        }
+    }
+
+    /// Whether `category` is in [`FtfLayerConfig::denied_categories`] or
+    /// matches a pattern there
+    pub(crate) fn is_category_denied(&self, category: &str) -> bool {
+        self.category_filters.denied.read().contains(category)
+    }
+
+    /// Whether a record at `level` in `category` passes the deny-list and
+    /// per-category level thresholds
+    fn is_category_enabled(&self, category: &str, level: &Level) -> bool {
+        if self.is_category_denied(category) {
+            return false;
+        }
+
+        if self.config.max_level.is_some_and(|max_level| *level > max_level) {
+            return false;
+        }
+
+        match self.category_filters.levels.get(category) {
+            Some(max_level) => level <= max_level,
+            None => true,
+        }
+    }
+
+    /// Whether `category` has budget left for another record, using it up if so
+    fn within_budget(&self, category: &str) -> bool {
+        !self.budgets.is_active() || self.budgets.allow(category, self.overhead_now())
+    }
+
+    /// Extract arguments from span attributes or event fields
+    fn arguments(
+        &self,
+        dest: &mut Destination<'_>,
+        record: impl FnOnce(&mut ArgumentVisitor<'_>),
+    ) -> Arguments {
+        let mut visitor = ArgumentVisitor::new(dest.string_cache, dest.output.reborrow(), &self.config);
+
+        self.timed(Phase::Serialize, || record(&mut visitor));
+
+        if visitor.truncated_debug_values > 0 {
+            self.truncated_debug_values.fetch_add(visitor.truncated_debug_values, Ordering::Relaxed);
+        }
+
+        let mut arguments = visitor.arguments;
+        arguments.truncate(limits::fitting_arguments(&arguments));
+        arguments
+    }
+
+    /// Run `f` against the destination that records in `category` go to
+    fn with_destination<R>(
+        &self,
+        category: &str,
+        f: impl FnOnce(&mut Destination<'_>) -> R,
+    ) -> R {
+        self.with_destination_for(category, None, f)
+    }
+
+    /// Like [`FtfLayer::with_destination`], for a record with `metadata`
+    fn with_destination_for<R>(
+        &self,
+        category: &str,
+        metadata: Option<&Metadata<'_>>,
+        f: impl FnOnce(&mut Destination<'_>) -> R,
+    ) -> R {
+        if let Some(output) = self.router.as_ref().and_then(|router| router.output_for(category)) {
+            let mut output = output.lock();
+            return f(&mut output.destination(&self.config));
+        }
+
+        self.with_main_destination_for(metadata, f)
+    }
+
+    /// Run `f` against the main output, writing the header first if needed
+    fn with_main_destination<R>(&self, f: impl FnOnce(&mut Destination<'_>) -> R) -> R {
+        self.with_main_destination_for(None, f)
+    }
+
+    /// Like [`FtfLayer::with_main_destination`], for a record with `metadata`
+    fn with_main_destination_for<R>(
+        &self,
+        metadata: Option<&Metadata<'_>>,
+        f: impl FnOnce(&mut Destination<'_>) -> R,
+    ) -> R {
+        if let Some(metadata) = metadata.filter(|_| self.config.route_by_metadata) {
+            return self.with_routed_destination(metadata, f);
+        }
+
+        let mut writer = match metadata {
+            Some(metadata) => self.writer.make_writer_for(metadata),
+            None => self.writer.make_writer(),
+        };
+        let mut main = self.main.lock();
+        let main = &mut *main;
+
+        // While recording is off, records are still interned and built so
+        // that `f` runs, but go nowhere; the string table is written again
+        // once recording resumes
+        let recording = self.health.is_recording();
+        let mut discard = io::sink();
+        let mut monitored = MonitoredWriter::new(&mut writer, &self.health);
+        let mut checksummed;
+        let output = match &self.chunk_checksum {
+            _ if !recording => RecordOutput::new(&mut discard, &[], &[]),
+            Some(checksum) => {
+                checksummed = ChecksummedWriter::new(&mut monitored, checksum);
+                RecordOutput::new(&mut checksummed, &self.config.middleware, &self.config.sinks)
+            }
+            None => RecordOutput::new(&mut monitored, &self.config.middleware, &self.config.sinks),
+        };
+        let mut dest = Destination::new(&mut main.string_cache, &mut main.thread_cache, output);
+        if recording {
+            self.write_header_once(&mut dest);
+        }
+        let result = f(&mut dest);
+        if recording && self.health.take_budget_exceeded() {
+            self.write_budget_exceeded(&mut dest);
+        }
+
+        // Dropped before unlocking, so writers that send what they buffered
+        // when dropped keep records in the order they were interned in
+        drop(writer);
+        result
+    }
+
+    /// Run `f` against the writer made for `metadata`, with fresh intern
+    /// tables so the record reads correctly whichever output it is routed to
+    fn with_routed_destination<R>(&self, metadata: &Metadata<'_>, f: impl FnOnce(&mut Destination<'_>) -> R) -> R {
+        let mut writer = self.writer.make_writer_for(metadata);
+        let mut main = self.main.lock();
+
+        let recording = self.health.is_recording();
+        let mut discard = io::sink();
+        let mut monitored = MonitoredWriter::new(&mut writer, &self.health);
+        let output = if recording {
+            RecordOutput::new(&mut monitored, &self.config.middleware, &self.config.sinks)
+        } else {
+            RecordOutput::new(&mut discard, &[], &[])
+        };
+        let mut string_cache = StringCache::new(&self.config, &self.memory);
+        let mut thread_cache = ThreadCache::new(&self.config, &self.memory);
+        let mut dest = Destination::new(&mut string_cache, &mut thread_cache, output);
+        let targets = main.routed_headers.entry(*metadata.level()).or_default();
+        if recording && !targets.contains(metadata.target()) {
+            targets.insert(metadata.target().to_string());
+            write_header(&mut dest, &self.config);
+        }
+        let result = f(&mut dest);
+
+        drop(writer);
+        result
+    }
+}
+
+/// The intern tables that are valid for the main writer, behind one lock so
+/// that interning and writing a record is a single critical section
+#[derive(Debug)]
+struct MainOutput {
+    string_cache: StringCache,
+    thread_cache: ThreadCache,
+    /// Levels and targets whose records have had a header written before
+    /// them, with [`FtfLayerConfig::route_by_metadata`]
+    routed_headers: HashMap<Level, HashSet<String>>,
+}
+
+/// An output together with the intern tables that are valid for it.
+struct Destination<'a> {
+    string_cache: &'a mut StringCache,
+    thread_cache: &'a mut ThreadCache,
+    output: RecordOutput<'a>,
+}
+
+impl<'a> Destination<'a> {
+    /// Tables and output for the records `f` writes next, which must not
+    /// evict the strings each other refer to
+    fn new(string_cache: &'a mut StringCache, thread_cache: &'a mut ThreadCache, output: RecordOutput<'a>) -> Self {
+        string_cache.begin_record();
+        Self {
+            string_cache,
+            thread_cache,
+            output,
+        }
+    }
+
+    /// Get an interned string reference
+    fn string_ref(&mut self, value: &str) -> ftfrs::StringRef {
+        match self.string_cache.get_or_create(value, &mut self.output) {
+            Ok(string_ref) => string_ref,
+            Err(_) => limits::inline_name(value),
+        }
+    }
+
+    /// Get an interned reference to a category. Categories are names, so
+    /// once interned they stay pinned in the string table.
+    fn category_ref(&mut self, category: &str) -> ftfrs::StringRef {
+        self.string_ref(category)
+    }
+
+    /// Get the `(name, category)` references for a callsite, resolving and
+    /// caching them on first use
+    fn callsite_refs(
+        &mut self,
+        metadata: &'static Metadata<'static>,
+        category: &str,
+    ) -> (ftfrs::StringRef, ftfrs::StringRef) {
+        if let Some((name_id, category_id)) = self.string_cache.callsites.get(metadata, category) {
+            return (ftfrs::StringRef::Ref(name_id), ftfrs::StringRef::Ref(category_id));
+        }
+
+        let name_ref = self.string_ref(metadata.name());
+        let category_ref = self.category_ref(category);
+        if let (ftfrs::StringRef::Ref(name_id), ftfrs::StringRef::Ref(category_id)) = (&name_ref, &category_ref) {
+            self.string_cache.callsites.insert(metadata, category, *name_id, *category_id);
+        }
+
+        (name_ref, category_ref)
+    }
+
+    /// Get the `(name, category)` references for a record, whose name is
+    /// `name` if it was set at runtime rather than its callsite's
+    fn record_refs(
+        &mut self,
+        metadata: &'static Metadata<'static>,
+        name: Option<&str>,
+        category: &str,
+    ) -> (ftfrs::StringRef, ftfrs::StringRef) {
+        let Some(name) = name else {
+            return self.callsite_refs(metadata, category);
+        };
+
+        let name_ref = match self.string_cache.get_or_create_dynamic_name(name, &mut self.output) {
+            Ok(name_ref) => name_ref,
+            Err(_) => limits::inline_name(name),
+        };
+        (name_ref, self.category_ref(category))
+    }
+
+    /// Get an interned thread reference
+    fn thread_ref(&mut self, process_id: u64, thread_id: u64) -> ftfrs::ThreadRef {
+        match self.thread_cache.get_or_create(process_id, thread_id, &mut self.output) {
+            Ok(thread_ref) => thread_ref,
+            Err(_) => {
+                ftfrs::ThreadRef::Inline {
+                    process_koid: process_id,
+                    thread_koid: thread_id
+                }
+            }
+        }
+    }
+
+    /// Get an interned reference to a synthetic thread, writing a record that
+    /// names it whenever it is newly interned
+    fn named_thread_ref(&mut self, process_id: u64, thread_id: u64, name: &str) -> ftfrs::ThreadRef {
+        let named = self.thread_cache.contains(process_id, thread_id);
+        let thread_ref = self.thread_ref(process_id, thread_id);
+        if !named {
+            let name_ref = self.string_ref(name);
+            let process_arg = self.string_ref("process");
+            if let Err(e) = kernel_object::write_thread_name(self.output.raw(), process_id, thread_id, &name_ref, &process_arg) {
+                eprintln!("Error writing FTF thread name record: {}", e);
+            }
+        }
+        thread_ref
+    }
+
+    /// Write an event record to the output and its sinks
+    fn write_event(&mut self, event: EventRecord<'_>) {
+        if let Err(e) = self.output.write_event(event) {
+            eprintln!("Error writing FTF record: {}", e);
+        }
+    }
+
+    /// Write a context switch record to the underlying writer
+    #[cfg(all(feature = "sched", any(target_os = "linux", target_os = "android")))]
+    fn write_context_switch(&mut self, timestamp: u64, cpu: u16, outgoing_state: ThreadState, outgoing_koid: u64, incoming_koid: u64) {
+        if let Err(e) = sched::write_context_switch(self.output.raw(), timestamp, cpu, outgoing_state, outgoing_koid, incoming_koid) {
+            eprintln!("Error writing FTF context switch record: {}", e);
+        }
+    }
+
+    /// Write an async event record to the underlying writer
+    fn write_async(
+        &mut self,
+        phase: AsyncPhase,
+        timestamp: u64,
+        thread_ref: &ftfrs::ThreadRef,
+        category_ref: &ftfrs::StringRef,
+        name_ref: &ftfrs::StringRef,
+        async_id: u64,
+    ) {
+        if let Err(e) = flow::write_async_event(self.output.raw(), phase, timestamp, thread_ref, category_ref, name_ref, async_id) {
+            eprintln!("Error writing FTF async record: {}", e);
+        }
+    }
+
+    /// Write a flow event record to the underlying writer
+    fn write_flow(
+        &mut self,
+        phase: FlowPhase,
+        timestamp: u64,
+        thread_ref: &ftfrs::ThreadRef,
+        category_ref: &ftfrs::StringRef,
+        name_ref: &ftfrs::StringRef,
+        flow_id: u64,
+    ) {
+        if let Err(e) = flow::write_flow_event(self.output.raw(), phase, timestamp, thread_ref, category_ref, name_ref, flow_id) {
+            eprintln!("Error writing FTF flow record: {}", e);
+        }
+    }
+}
+
+/// Replace the provider id with a unique one and record it as metadata, for
+/// [`FtfLayerConfig::unique_provider_id`]. The flag is cleared, so a config
+/// passed on to [`FtfLayer::with_config`] keeps the id assigned here.
+fn assign_unique_provider_id(config: &mut FtfLayerConfig) {
+    config.unique_provider_id = false;
+    config.provider_id = trace_id::generate_provider_id();
+    config.metadata.push((PROVIDER_ID_KEY.to_string(), config.provider_id.to_string()));
+}
+
+/// Write the magic number and provider info records that start every trace,
+/// followed by the configured [`FtfLayerConfig::metadata`]
+fn try_write_header(dest: &mut Destination<'_>, config: &FtfLayerConfig) -> Result<(), ftfrs::FtfError> {
+    try_write_provider(dest, config)?;
+    try_write_metadata(dest, config)?;
+
+    // Most records use the default category, so resolve it up front
+    if let DefaultCategory::Fixed(category) = &config.default_category {
+        let category = config.category_aliases.get(category).unwrap_or(category);
+        dest.category_ref(category);
+    }
+    Ok(())
+}
+
+/// Write the magic number and provider info records
+fn try_write_provider(dest: &mut Destination<'_>, config: &FtfLayerConfig) -> Result<(), ftfrs::FtfError> {
+    dest.output.write(ftfrs::Record::create_magic_number())?;
+    let provider_name = limits::truncate(&config.provider_name, MAX_PROVIDER_NAME_BYTES);
+    dest.output.write(ftfrs::Record::create_provider_info(config.provider_id, provider_name.to_string()))
+}
+
+/// Write the instant events carrying [`FtfLayerConfig::metadata`]
+fn try_write_metadata(dest: &mut Destination<'_>, config: &FtfLayerConfig) -> Result<(), ftfrs::FtfError> {
+    if config.metadata.is_empty() {
+        return Ok(());
+    }
+
+    let category_ref = dest.string_ref(METADATA_CATEGORY);
+    let name_ref = dest.string_ref(METADATA_NAME);
+    let mut visitor = ArgumentVisitor::new(dest.string_cache, dest.output.reborrow(), config);
+    for (key, value) in &config.metadata {
+        visitor.push_arg(key, &ArgValue::Str(value));
+    }
+
+    // Metadata that doesn't fit in one record is spread over several
+    let mut remaining = visitor.arguments.into_vec();
+    while !remaining.is_empty() {
+        let mut arguments = remaining.clone();
+        limits::fit_arguments(&mut arguments);
+        if arguments.is_empty() {
+            break;
+        }
+        remaining.drain(..arguments.len());
+
+        let thread_ref = ftfrs::ThreadRef::Inline {
+            process_koid: platform::process_id() as u64,
+            thread_koid: 0,
+        };
+        let record = ftfrs::Record::create_instant_event(0, thread_ref, category_ref.clone(), name_ref.clone(), arguments);
+        dest.output.write(record)?;
+    }
+    Ok(())
+}
+
+/// Write the trace header, reporting rather than returning any error
+fn write_header(dest: &mut Destination<'_>, config: &FtfLayerConfig) {
+    if let Err(e) = try_write_header(dest, config) {
+        eprintln!("Error writing trace header: {}", e);
+    }
+}
+
+/// Start a new file after the writer rolled over, or a new chunk of a
+/// stream: write the header again, re-emit the string table so cached
+/// references stay valid, and forget the thread table so threads and tracks
+/// are re-interned and renamed on next use
+fn try_write_rolled_header(dest: &mut Destination<'_>, config: &FtfLayerConfig) -> Result<(), ftfrs::FtfError> {
+    try_write_provider(dest, config)?;
+    dest.string_cache.rewrite(&mut dest.output)?;
+    dest.thread_cache.clear();
+    try_write_metadata(dest, config)
+}
+
+impl<T: io::Write + Send + 'static> FtfLayer<OwnedWriter<T>> {
+    /// Create a layer writing to any [`io::Write`], e.g. a `File` or a
+    /// `BufWriter`, without needing to know about [`MakeWriter`].
+    ///
+    /// The writer is owned by the layer and locked for each record. This is
+    /// the same as [`FtfLayer::with_writer`].
+    pub fn from_write(writer: T) -> Self {
+        Self::with_writer(writer)
+    }
+
+    /// Create a layer that owns `writer` for its whole lifetime.
+    ///
+    /// Unlike [`FtfLayer::new`], this does not require a [`MakeWriter`] and
+    /// never re-creates the underlying writer between records.
+    pub fn with_writer(writer: T) -> Self {
+        Self::with_writer_and_config(writer, FtfLayerConfig::default())
+    }
+
+    /// Create a layer that owns `writer`, using the given configuration
+    pub fn with_writer_and_config(writer: T, config: FtfLayerConfig) -> Self {
+        Self::with_config(OwnedWriter::new(writer), config)
+    }
+}
+
+impl FtfLayer<OwnedWriter<File>> {
+    /// Get a layer writing to the file at `path`, shared with every other
+    /// layer in the process opened this way for the same file.
+    ///
+    /// The first call creates the file and a layer with `config`; later calls
+    /// return a clone of that layer, ignoring their `config`, so independent
+    /// components writing to one path share its header and intern tables
+    /// instead of corrupting each other's records. The file stays open for
+    /// the rest of the process.
+    pub fn shared_file(path: impl AsRef<Path>, config: FtfLayerConfig) -> Result<Self, FtfLayerError> {
+        Ok(shared_file::layer_for(path.as_ref(), config)?)
+    }
+}
+
+/// Filter to check if a span should be included in FTF tracing
+/// and to extract additional metadata like category
+struct FtfFilter {
+    should_record: bool,
+    category: Option<String>,
+    /// Timestamp set through [`TIMESTAMP_FIELD`]
+    timestamp: Option<u64>,
+    /// Name set through [`NAME_FIELD`]
+    name: Option<String>,
+}
+
+impl FtfFilter {
+    fn new() -> Self {
+        Self {
+            should_record: false,
+            category: None,
+            timestamp: None,
+            name: None,
+        }
+    }
+}
+
+impl Visit for FtfFilter {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "ftf" && value {
+            self.should_record = true;
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "category" {
+            self.category = Some(value.to_string());
+        } else if field.name() == NAME_FIELD {
+            self.name = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        // Also reached for `ftf.name = %value`
+        if field.name() == NAME_FIELD {
+            self.name = Some(format!("{:?}", value));
+        }
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        // Negative timestamps can't be encoded, so the layer clock is used instead
+        if field.name() == TIMESTAMP_FIELD && value >= 0 {
+            self.timestamp = Some(value as u64);
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == TIMESTAMP_FIELD {
+            self.timestamp = Some(value);
+        }
+    }
+
+    fn record_f64(&mut self, _field: &Field, _value: f64) {}
+    fn record_i128(&mut self, _field: &Field, _value: i128) {}
+    fn record_u128(&mut self, _field: &Field, _value: u128) {}
+    fn record_error(&mut self, _field: &Field, _error: &(dyn std::error::Error + 'static)) {}
+}
+
+/// Resolve both spans through the subscriber `S` and link them with the
+/// `FtfLayer<W>` registered in `dispatch`
+fn link_spans<W, S>(dispatch: &Dispatch, from: &span::Id, to: &span::Id)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    let (Some(layer), Some(subscriber)) = (dispatch.downcast_ref::<FtfLayer<W>>(), dispatch.downcast_ref::<S>()) else {
+        return;
+    };
+    if let (Some(from), Some(to)) = (subscriber.span(from), subscriber.span(to)) {
+        layer.link(&from, &to);
+    }
+}
+
+fn begin_flow<W, S>(dispatch: &Dispatch, id: &span::Id) -> Option<u64>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    let (layer, subscriber) = (dispatch.downcast_ref::<FtfLayer<W>>()?, dispatch.downcast_ref::<S>()?);
+    layer.write_flow_now(&subscriber.span(id)?, FlowPhase::Begin, None)
+}
+
+fn end_flow<W, S>(dispatch: &Dispatch, id: &span::Id, flow_id: u64)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    let (Some(layer), Some(subscriber)) = (dispatch.downcast_ref::<FtfLayer<W>>(), dispatch.downcast_ref::<S>()) else {
+        return;
+    };
+    if let Some(span) = subscriber.span(id) {
+        layer.write_flow_now(&span, FlowPhase::End, Some(flow_id));
+    }
+}
+
+fn future_now<W>(dispatch: &Dispatch) -> u64
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    dispatch.downcast_ref::<FtfLayer<W>>().map_or(0, FtfLayer::now)
+}
+
+fn future_begin<W>(dispatch: &Dispatch, category: &str, name: &str) -> u64
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    let Some(layer) = dispatch.downcast_ref::<FtfLayer<W>>() else {
+        return 0;
+    };
+    let async_id = layer.next_async_id.fetch_add(1, Ordering::Relaxed);
+    layer.write_async_event(AsyncPhase::Begin, category, name, async_id);
+    async_id
+}
+
+fn future_poll<W>(dispatch: &Dispatch, category: &str, name: &str, start: u64)
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    if let Some(layer) = dispatch.downcast_ref::<FtfLayer<W>>() {
+        layer.write_poll(category, name, start);
+    }
+}
+
+fn future_end<W>(dispatch: &Dispatch, category: &str, name: &str, async_id: u64)
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    if let Some(layer) = dispatch.downcast_ref::<FtfLayer<W>>() {
+        layer.write_async_event(AsyncPhase::End, category, name, async_id);
+    }
+}
+
+fn stream_item<W>(dispatch: &Dispatch, category: &str, name: &str, index: u64)
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    if let Some(layer) = dispatch.downcast_ref::<FtfLayer<W>>() {
+        layer.write_stream_item(category, name, index);
+    }
+}
+
+fn blocking_ended<W>(dispatch: &Dispatch, reason: &str, start: u64)
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    if let Some(layer) = dispatch.downcast_ref::<FtfLayer<W>>() {
+        layer.write_blocking_section(reason, start);
+    }
+}
+
+impl<W, S> Layer<S> for FtfLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    fn on_layer(&mut self, _subscriber: &mut S) {
+        self.set_hooks(LinkSpans {
+            link: link_spans::<W, S>,
+            begin_flow: begin_flow::<W, S>,
+            end_flow: end_flow::<W, S>,
+        });
+    }
+
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else if id == TypeId::of::<LinkSpans>() {
+            self.link_spans.as_ref().map(|link_spans| link_spans as *const LinkSpans as *const ())
+        } else if id == TypeId::of::<ManualHooks>() {
+            self.manual_hooks.as_ref().map(|hooks| hooks as *const ManualHooks as *const ())
+        } else {
+            None
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.config.max_level.map(LevelFilter::from_level)
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let current = ctx.current_span().id().and_then(|id| ctx.span(id));
+        self.record_event(event, current, ctx.event_span(event));
+    }
+
+    #[cfg(feature = "alloc-track")]
+    fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            self.record_enter(&span);
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            self.record_exit(&span);
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            self.record_close(&id, &span);
+        }
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let span = ctx.span(id).expect("span should exist");
+        self.record_new_span(attrs, id, &span);
+    }
+}
+
+/// Recording of spans and events, over whichever store holds the spans
+impl<W: for<'writer> MakeWriter<'writer> + 'static> FtfLayer<W> {
+    /// Install the hooks found by downcasting the dispatcher, with
+    /// `link_spans` looking spans up in the subscriber the layer is part of
+    fn set_hooks(&mut self, link_spans: LinkSpans) {
+        self.link_spans = Some(link_spans);
+        self.manual_hooks = Some(ManualHooks {
+            now: future_now::<W>,
+            begin: future_begin::<W>,
+            poll: future_poll::<W>,
+            end: future_end::<W>,
+            item: stream_item::<W>,
+            blocking: blocking_ended::<W>,
+        });
+    }
+
+    /// Record `event`, given the current span and the span it belongs to
+    fn record_event<V: SpanView>(&self, event: &Event<'_>, current: Option<V>, parent: Option<V>) {
+        if !self.config.record_events || !self.health.is_recording() {
+            return;
+        }
+        let _timer = self.hook_timer(Hook::Event);
+        #[cfg(feature = "alloc-track")]
+        self.write_alloc_counters();
+        let mut filter = FtfFilter::new();
         event.record(&mut filter);
         
-        let parent_span_active = if !filter.should_record {
-            if let Some(current_span) = ctx.current_span().id() {
-                if let Some(span) = ctx.span(current_span) {
-                    span.extensions().get::<bool>().copied().unwrap_or(false)
-                } else {
-                    false
-                }
-            } else {
-                false
-            }
-        } else {
-            false
-        };
+        let parent_span_active = !filter.should_record
+            && current.as_ref().is_some_and(|span| span.get::<bool>().unwrap_or(false));
         
         if !filter.should_record && !parent_span_active {
             return;
         }
 
-        let category = if let Some(cat) = filter.category {
-            cat
-        } else if let Some(current_span) = ctx.current_span().id() {
-            if let Some(span) = ctx.span(current_span) {
-                span.extensions().get::<String>().cloned().unwrap_or_else(|| "default".to_string())
-            } else {
-                "default".to_string()
-            }
-        } else {
-            "default".to_string()
+        let category = match (filter.category, current.as_ref().and_then(|span| span.get::<String>())) {
+            (Some(category), _) | (None, Some(category)) => category.into(),
+            (None, None) => self.default_category(event.metadata()),
         };
-        
-        let category_ref = self.get_string_ref(&category);
-        let name_ref = self.get_string_ref(event.metadata().name());
-        let thread_ref = self.get_thread_ref();
-        
-        let arguments = self.record_event_fields(event);
-        
-        let record = ftfrs::Record::create_instant_event(
-            self.now(),
-            thread_ref,
-            category_ref,
-            name_ref,
-            arguments,
-        );
-        
-        self.write_record(record);
+        let category = self.alias_category(category);
+
+        if !self.is_category_enabled(&category, event.metadata().level()) || !self.within_budget(&category) {
+            return;
+        }
+
+        self.note_schema(SchemaKind::Event, &category, filter.name.as_deref().unwrap_or(event.metadata().name()));
+
+        let tree = self.sampled_tree(parent.as_ref());
+        self.with_record_destination(&category, tree.as_deref(), event.metadata(), |dest| {
+            let ((name_ref, category_ref), thread_ref) = self.timed(Phase::Intern, || {
+                let refs = dest.record_refs(event.metadata(), filter.name.as_deref(), &category);
+                let thread_ref = if self.category_filters.process.contains(&category) {
+                    self.process_thread_ref()
+                } else {
+                    dest.thread_ref(self.process_id(), self.thread_id())
+                };
+                (refs, thread_ref)
+            });
+
+            let arguments = self.arguments(dest, |visitor| event.record(visitor));
+
+            let record = EventRecord::instant(
+                filter.timestamp.unwrap_or_else(|| self.now()),
+                thread_ref,
+                category_ref,
+                name_ref,
+                &arguments,
+            );
+
+            self.timed(Phase::Write, || dest.write_event(record));
+        });
     }
 
-    fn on_close(
-        &self,
-        id: span::Id,
-        ctx: tracing_subscriber::layer::Context<'_, S>,
-    ) {
-        let span = match ctx.span(&id) {
-            Some(span) => span,
-            None => return, 
-        };
+    #[cfg(feature = "alloc-track")]
+    fn record_enter(&self, span: &impl SpanView) {
+        if !self.config.span_alloc_args || !self.config.record_spans {
+            return;
+        }
+
+        if span.get::<bool>().unwrap_or(false) {
+            if span.with(|_: &SpanAllocs| ()).is_none() {
+                span.insert(SpanAllocs::default());
+            }
+            // Snapshot after inserting, so the extension's own allocation isn't counted
+            span.with_mut(SpanAllocs::enter);
+        }
+    }
+
+    fn record_exit(&self, span: &impl SpanView) {
+        #[cfg(feature = "alloc-track")]
+        if self.config.span_alloc_args {
+            span.with_mut(SpanAllocs::exit);
+        }
+
+        if self.config.span_end != SpanEnd::LastExit || !self.config.record_spans {
+            return;
+        }
+
+        if span.get::<bool>().unwrap_or(false) {
+            span.insert(LastExit(self.now()));
+        }
+    }
+
+    /// Record the close of span `id`, once every handle to it is gone
+    fn record_close(&self, id: &span::Id, span: &impl SpanView) {
+        if !self.config.record_spans {
+            return;
+        }
+        let _timer = self.hook_timer(Hook::Close);
+        #[cfg(feature = "alloc-track")]
+        self.write_alloc_counters();
         
-        if !span.extensions().get::<bool>().copied().unwrap_or(false) {
+        if !span.get::<bool>().unwrap_or(false) {
             return; 
         }
+        // Already ended by `end_open_spans`
+        let Some(open_span) = self.open_spans.lock().remove(&(self.subscriber, id.clone())) else {
+            return;
+        };
 
-        let category = span.extensions().get::<String>().cloned().unwrap_or_else(|| "default".to_string());
-        let category_ref = self.get_string_ref(&category);
-        
-        let name_ref = self.get_string_ref(span.name());
-        let thread_ref = self.get_thread_ref();
-
-        let event = ftfrs::Record::create_duration_end_event(
-            self.now(),
-            thread_ref,
-            category_ref,
-            name_ref,
-            Vec::new(),
-        );
-        
-        self.write_record(event);
+        let category = self.span_category(span);
+        let end = match span.get::<LastExit>() {
+            Some(LastExit(timestamp)) => timestamp,
+            None => self.now(),
+        };
+        // A span whose start was overridden with a later timestamp must not end before it starts
+        let origin = span.get::<SpanOrigin>();
+        let timestamp = match origin {
+            Some(origin) => end.max(origin.timestamp),
+            None => end,
+        };
+        // Spans moved between threads (e.g. by an async runtime) end on the
+        // thread they began on, so every thread's begins and ends pair up
+        let thread_id = match origin {
+            Some(origin) => {
+                if origin.thread_id != self.thread_id() {
+                    self.migrated_spans.fetch_add(1, Ordering::Relaxed);
+                }
+                origin.thread_id
+            }
+            None => self.thread_id(),
+        };
+        let duration_ns = timestamp.saturating_sub(open_span.origin.timestamp);
+        if let Some(latency) = &self.latency {
+            latency.record(open_span.name(), duration_ns);
+        }
+        if let Some(slowest) = &self.slowest {
+            slowest.offer(duration_ns, || SlowSpan {
+                duration_ns,
+                start: open_span.origin.timestamp,
+                thread_id: open_span.origin.thread_id,
+                name: open_span.name().to_string(),
+                category: category.clone(),
+            });
+        }
+        let tree = self.sampled_tree(Some(span));
+        self.with_record_destination(&category, tree.as_deref(), span.metadata(), |dest| {
+            let ((name_ref, category_ref), thread_ref) = self.timed(Phase::Intern, || {
+                let refs = dest.record_refs(span.metadata(), open_span.name.as_deref(), &category);
+                (refs, dest.thread_ref(self.process_id(), thread_id))
+            });
+
+            if self.config.span_duration_counters != DurationCounters::InsteadOfSpans {
+                #[cfg(feature = "alloc-track")]
+                let arguments = match span.with(|allocs: &SpanAllocs| allocs.total) {
+                    Some(total) => self.arguments(dest, |visitor| {
+                        visitor.push_arg("alloc_count", &ArgValue::U64(total.allocations));
+                        visitor.push_arg("alloc_bytes", &ArgValue::U64(total.allocated_bytes));
+                    }),
+                    None => Arguments::new(),
+                };
+                #[cfg(not(feature = "alloc-track"))]
+                let arguments = Arguments::new();
+
+                let event = EventRecord::duration_end(
+                    timestamp,
+                    thread_ref,
+                    category_ref.clone(),
+                    name_ref.clone(),
+                    &arguments,
+                );
+
+                self.timed(Phase::Write, || dest.write_event(event));
+            }
+
+            if self.config.span_duration_counters != DurationCounters::Off {
+                let arguments = self.arguments(dest, |visitor| {
+                    visitor.push_arg(DURATION_ARG, &ArgValue::U64(duration_ns));
+                });
+                let counter = EventRecord::counter(
+                    timestamp,
+                    self.process_thread_ref(),
+                    category_ref,
+                    name_ref,
+                    &arguments,
+                    0,
+                );
+
+                self.timed(Phase::Write, || dest.write_event(counter));
+            }
+        });
+
+        // The root of a sampled tree decides its fate once the whole tree is done
+        let tree = span.remove::<Arc<Mutex<SpanTreeBuffer>>>();
+        if let Some(tree) = tree {
+            let mut tree = tree.lock();
+            let duration = origin.map(|origin| timestamp - origin.timestamp);
+            if duration.is_some_and(|duration| self.config.tail_sampling.keeps_root(duration)) {
+                tree.mark_keep();
+            }
+            if tree.keep() {
+                self.with_main_destination(|dest| {
+                    if let Err(e) = tree.flush(dest) {
+                        eprintln!("Error writing sampled span tree: {}", e);
+                    }
+                });
+            }
+        }
+
+        if let Some(marker) = &self.trace_marker {
+            marker.end(self.process_id());
+        }
     }
     
-    fn on_new_span(
-        &self,
-        attrs: &span::Attributes<'_>,
-        id: &span::Id,
-        ctx: tracing_subscriber::layer::Context<'_, S>,
-    ) {
+    /// Record the new span `id`, stored as `span`
+    fn record_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, span: &impl SpanView) {
+        let _timer = self.hook_timer(Hook::NewSpan);
+        #[cfg(feature = "alloc-track")]
+        self.write_alloc_counters();
         let mut filter = FtfFilter::new();
         attrs.record(&mut filter);
+        if !filter.should_record && self.category_filters.span_names.contains(attrs.metadata()) {
+            filter.should_record = true;
+        }
+
+        let category = match filter.category.clone() {
+            Some(category) => category.into(),
+            None => self.default_category(attrs.metadata()),
+        };
+        let category = self.alias_category(category).into_owned();
+
+        if filter.should_record
+            && (!self.health.is_recording()
+                || !self.is_category_enabled(&category, attrs.metadata().level()) || !self.within_budget(&category))
+        {
+            filter.should_record = false;
+        }
         
-        if let Some(span) = ctx.span(id) {
-            span.extensions_mut().insert(filter.should_record);
-            if let Some(category) = filter.category.as_ref() {
-                span.extensions_mut().insert(category.clone());
-            }
+        span.insert(filter.should_record);
+        if let Some(category) = filter.category.as_ref() {
+            span.insert(category.clone());
         }
         
-        if !filter.should_record {
+        if !filter.should_record || !self.config.record_spans {
             return; 
         }
 
-        let span = ctx.span(id).expect("span should exist");
-        
-        let category = filter.category.unwrap_or_else(|| "default".to_string());
-        let category_ref = self.get_string_ref(&category);
-        
-        let name_ref = self.get_string_ref(span.name());
-        let thread_ref = self.get_thread_ref();
-
-        let arguments = self.record_attributes(attrs);
-
-        let event = ftfrs::Record::create_duration_begin_event(
-            self.now(),
-            thread_ref,
-            category_ref,
-            name_ref,
-            arguments,
-        );
-        
-        self.write_record(event);
+        let origin = SpanOrigin {
+            timestamp: filter.timestamp.unwrap_or_else(|| self.now()),
+            thread_id: self.thread_id(),
+        };
+        span.insert(origin);
+
+        let tree = if self.config.tail_sampling.is_enabled() {
+            let tree = self.sampled_tree(span.parent().as_ref()).unwrap_or_else(|| {
+                let tree = Arc::new(Mutex::new(SpanTreeBuffer::new(&self.config, &self.memory)));
+                span.insert(tree.clone());
+                tree
+            });
+            Some(tree)
+        } else {
+            None
+        };
+        self.open_spans.lock().insert((self.subscriber, id.clone()), OpenSpan {
+            metadata: attrs.metadata(),
+            name: filter.name.clone(),
+            category: category.clone(),
+            origin,
+            sampled: tree.is_some(),
+            reported_at: None,
+        });
+
+        let write_begin = self.config.span_duration_counters != DurationCounters::InsteadOfSpans;
+        let span_name = filter.name.as_deref().unwrap_or(attrs.metadata().name());
+        if write_begin {
+            self.note_schema(SchemaKind::Span, &category, span_name);
+        }
+        if self.config.span_duration_counters != DurationCounters::Off {
+            self.note_schema(SchemaKind::Counter, &category, span_name);
+        }
+        self.with_record_destination(&category, tree.as_deref(), attrs.metadata(), |dest| {
+            if !write_begin {
+                return;
+            }
+            let ((name_ref, category_ref), thread_ref) = self.timed(Phase::Intern, || {
+                let refs = dest.record_refs(span.metadata(), filter.name.as_deref(), &category);
+                (refs, dest.thread_ref(self.process_id(), origin.thread_id))
+            });
+
+            let arguments = self.arguments(dest, |visitor| attrs.record(visitor));
+
+            let event = EventRecord::duration_begin(
+                origin.timestamp,
+                thread_ref,
+                category_ref,
+                name_ref,
+                &arguments,
+            );
+
+            self.timed(Phase::Write, || dest.write_event(event));
+        });
+
+        if let Some(marker) = &self.trace_marker {
+            marker.begin(self.process_id(), filter.name.as_deref().unwrap_or(span.metadata().name()));
+        }
+    }
+}
+
+/// A clone writes to the same output with the same intern tables, clock and
+/// counters, so subscribers built separately, e.g. one per subsystem, can
+/// all feed one trace. See [`FtfLayer::layer_handle`].
+///
+/// Span ids are only unique within one subscriber, so each clone tracks its
+/// open spans apart from the others' in the shared table, and
+/// [`FtfHandle::end_open_spans`] and [`FtfHandle::check_open_spans`] see
+/// the spans of every subscriber.
+impl<W: for<'a> MakeWriter<'a>> Clone for FtfLayer<W> {
+    fn clone(&self) -> Self {
+        Self {
+            subscriber: self.next_subscriber.fetch_add(1, Ordering::Relaxed),
+            link_spans: None,
+            manual_hooks: None,
+            ..self.share()
+        }
     }
 }
 
@@ -506,4 +3236,137 @@ impl<W: for<'a> MakeWriter<'a>> fmt::Display for FtfLayer<W> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "FtfLayer")
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    /// Collects the records written to an output
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<ftfrs::Record>>);
+
+    impl RecordSink for Collect {
+        fn write_record(&self, record: &ftfrs::Record) {
+            self.0.lock().push(record.clone());
+        }
+    }
+
+    impl Collect {
+        /// Instant events written. ftfrs doesn't export its event types, so
+        /// they are told apart by their debug output.
+        fn instants(&self) -> usize {
+            self.0
+                .lock()
+                .iter()
+                .filter(|record| format!("{:?}", record).starts_with("Event(Instant("))
+                .count()
+        }
+    }
+
+    type TestLayer = FtfLayer<fn() -> io::Sink>;
+
+    /// A layer built from `config` whose records after the header are
+    /// collected, with trace time standing still
+    fn layer(config: FtfLayerConfigBuilder) -> (TestLayer, Arc<Collect>) {
+        let collect = Arc::new(Collect::default());
+        let config = config.sink(collect.clone()).clock(Arc::new(|| 0)).build();
+        let layer = FtfLayer::try_with_config(io::sink as fn() -> io::Sink, config).unwrap();
+        collect.0.lock().clear();
+        (layer, collect)
+    }
+
+    #[test]
+    fn denied_categories_are_never_recorded() {
+        let (layer, collect) = layer(FtfLayerConfig::builder().denied_categories(["gfx*"]));
+        let subscriber = tracing_subscriber::registry().with(layer.layer_handle());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(ftf = true, category = "gfx:detail", "denied");
+            tracing::info!(ftf = true, category = "network", "recorded");
+            tracing::info_span!("span", ftf = true, category = "gfx").in_scope(|| tracing::info!("inside"));
+        });
+        assert_eq!(collect.instants(), 1);
+    }
+
+    #[test]
+    fn categories_over_budget_are_throttled() {
+        let (layer, collect) = layer(FtfLayerConfig::builder().category_budgets([("network", 3)]));
+        let subscriber = tracing_subscriber::registry().with(layer.layer_handle());
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..5 {
+                tracing::info!(ftf = true, category = "network", "request");
+                tracing::info!(ftf = true, category = "db", "query");
+            }
+        });
+        assert_eq!(collect.instants(), 3 + 5);
+        assert_eq!(layer.stats().throttled_records, 2);
+    }
+
+    #[test]
+    fn recording_stops_once_the_trace_is_too_large() {
+        let (layer, collect) = layer(FtfLayerConfig::builder().max_trace_bytes(1024));
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer.layer_handle());
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..100 {
+                tracing::info!(ftf = true, i, "event");
+            }
+            assert!(!handle.is_recording());
+            let written = collect.0.lock().len();
+            tracing::info!(ftf = true, "dropped");
+            assert_eq!(collect.0.lock().len(), written);
+
+            handle.resume_recording();
+            tracing::info!(ftf = true, "recorded");
+            assert!(collect.0.lock().len() > written);
+        });
+    }
+
+    #[test]
+    fn long_running_spans_are_counted_once() {
+        let now = Arc::new(AtomicU64::new(0));
+        let clock = now.clone();
+        let collect = Arc::new(Collect::default());
+        let config = FtfLayerConfig::builder()
+            .long_running_span_threshold(Duration::from_secs(1))
+            .long_running_span_events(true)
+            .sink(collect.clone())
+            .clock(Arc::new(move || clock.load(Ordering::Relaxed)))
+            .build();
+        let layer = FtfLayer::try_with_config(io::sink as fn() -> io::Sink, config).unwrap();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer.layer_handle());
+        tracing::subscriber::with_default(subscriber, || {
+            let _spans: Vec<_> = (0..3).map(|i| tracing::info_span!("leaked", ftf = true, i)).collect();
+            assert_eq!(handle.check_open_spans(), 0);
+
+            now.store(2_000_000_000, Ordering::Relaxed);
+            collect.0.lock().clear();
+            assert_eq!(handle.check_open_spans(), 3);
+            assert_eq!(handle.check_open_spans(), 0);
+        });
+        assert_eq!(layer.stats().long_running_spans, 3);
+        assert_eq!(collect.instants(), 3);
+    }
+
+    #[cfg(feature = "alloc-track")]
+    #[test]
+    fn events_with_few_fields_are_written_without_allocating() {
+        let config = FtfLayerConfig::builder().clock(Arc::new(|| 0)).build();
+        let layer = FtfLayer::try_with_config(io::sink as fn() -> io::Sink, config).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer.layer_handle());
+        tracing::subscriber::with_default(subscriber, || {
+            let event = |count: u64| tracing::info!(ftf = true, count, ok = true);
+            // The first event interns the names and fills the caches
+            event(0);
+
+            let before = alloc_track::thread_alloc_stats();
+            for count in 1..4 {
+                event(count);
+            }
+            assert_eq!(alloc_track::thread_alloc_stats().since(&before).allocations, 0);
+        });
+    }
+}
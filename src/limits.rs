@@ -0,0 +1,198 @@
+//! Size limits that keep every record within what ftfrs encodes correctly.
+//!
+//! ftfrs has no large-record support and stores record sizes in a `u8`, so a
+//! record may span at most 255 words. It also sizes inline string argument
+//! values as a single word. Rather than emit corrupt records, the layer
+//! interns values too long to inline, splits argument values longer than a
+//! string record into numbered chunks, truncates oversized names and drops
+//! trailing arguments that don't fit.
+
+use std::borrow::Cow;
+use std::fmt;
+
+/// Largest record, in 64-bit words, whose size ftfrs encodes correctly
+pub(crate) const MAX_RECORD_WORDS: usize = 255;
+
+/// Longest string that fits in a single string record
+pub(crate) const MAX_STRING_BYTES: usize = (MAX_RECORD_WORDS - 1) * 8;
+
+/// Longest string argument value ftfrs can write inline
+pub(crate) const MAX_INLINE_VALUE_BYTES: usize = 8;
+
+/// Longest provider name, whose length the provider info record stores in a byte
+pub(crate) const MAX_PROVIDER_NAME_BYTES: usize = 255;
+
+/// Longest name written inline in an event when it can't be interned
+pub(crate) const MAX_INLINE_NAME_BYTES: usize = 256;
+
+/// Most arguments an event record can carry
+pub(crate) const MAX_ARGUMENTS: usize = 15;
+
+/// Words an event can need besides its arguments: header, timestamp, an
+/// inline thread, inline category and name, and one trailing word
+const MAX_FIXED_EVENT_WORDS: usize = 2 + 2 + 2 * (MAX_INLINE_NAME_BYTES / 8) + 1;
+
+/// Replacement for NUL characters, which some readers take as the end of a string
+const NUL_ESCAPE: &str = "\\0";
+
+/// Escape characters that some readers reject, returning the value unchanged
+/// if there are none
+pub(crate) fn sanitize(value: &str) -> Cow<'_, str> {
+    if value.contains('\0') {
+        Cow::Owned(value.replace('\0', NUL_ESCAPE))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Format `value` with `Debug`, stopping once `max_len` bytes have been
+/// written. Returns the formatted string and whether it was cut short.
+pub(crate) fn debug_string(value: &dyn fmt::Debug, max_len: usize) -> (String, bool) {
+    let mut writer = BoundedString {
+        value: String::new(),
+        max_len,
+        truncated: false,
+    };
+    // An error here only means the bound was hit, or that the Debug impl
+    // failed, in which case whatever it wrote so far is kept
+    let _ = fmt::write(&mut writer, format_args!("{:?}", value));
+    (writer.value, writer.truncated)
+}
+
+/// A string that refuses writes past `max_len` bytes
+struct BoundedString {
+    value: String,
+    max_len: usize,
+    truncated: bool,
+}
+
+impl fmt::Write for BoundedString {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.truncated {
+            return Err(fmt::Error);
+        }
+
+        let remaining = self.max_len - self.value.len();
+        if s.len() <= remaining {
+            self.value.push_str(s);
+            return Ok(());
+        }
+
+        self.value.push_str(truncate(s, remaining));
+        self.truncated = true;
+        Err(fmt::Error)
+    }
+}
+
+/// Truncate `value` to at most `max_len` bytes on a char boundary
+pub(crate) fn truncate(value: &str, max_len: usize) -> &str {
+    if value.len() <= max_len {
+        return value;
+    }
+
+    let mut end = max_len;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// Split `value` into pieces of at most `max_len` bytes on char boundaries
+pub(crate) fn chunks(mut value: &str, max_len: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    while !value.is_empty() {
+        let chunk = truncate(value, max_len);
+        chunks.push(chunk);
+        value = &value[chunk.len()..];
+    }
+    chunks
+}
+
+/// An inline reference to a name, truncated to fit in an event
+pub(crate) fn inline_name(value: &str) -> ftfrs::StringRef {
+    ftfrs::StringRef::Inline(truncate(value, MAX_INLINE_NAME_BYTES).to_string())
+}
+
+/// An inline reference to an argument value, truncated so ftfrs sizes it correctly
+pub(crate) fn inline_value(value: &str) -> ftfrs::StringRef {
+    ftfrs::StringRef::Inline(truncate(value, MAX_INLINE_VALUE_BYTES).to_string())
+}
+
+/// Drop trailing arguments beyond what an event record can hold
+pub(crate) fn fit_arguments(arguments: &mut Vec<ftfrs::Argument>) {
+    arguments.truncate(fitting_arguments(arguments));
+}
+
+/// How many leading arguments an event record can hold
+pub(crate) fn fitting_arguments(arguments: &[ftfrs::Argument]) -> usize {
+    let mut words = MAX_FIXED_EVENT_WORDS;
+    arguments
+        .iter()
+        .take(MAX_ARGUMENTS)
+        .take_while(|argument| {
+            words += argument_words(argument);
+            words <= MAX_RECORD_WORDS
+        })
+        .count()
+}
+
+fn string_words(string_ref: &ftfrs::StringRef) -> usize {
+    match string_ref {
+        ftfrs::StringRef::Inline(s) => s.len().div_ceil(8),
+        ftfrs::StringRef::Ref(_) => 0,
+    }
+}
+
+/// Words an argument takes up in an event record, header included
+pub(crate) fn argument_words(argument: &ftfrs::Argument) -> usize {
+    use ftfrs::Argument::*;
+
+    match argument {
+        Null(name) | Int32(name, _) | UInt32(name, _) | Boolean(name, _) => 1 + string_words(name),
+        Int64(name, _) | UInt64(name, _) | Float(name, _) | Pointer(name, _) | KernelObjectId(name, _) => {
+            2 + string_words(name)
+        }
+        Str(name, value) => 1 + string_words(name) + string_words(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_cut_on_char_boundaries() {
+        assert_eq!(truncate("héllo", 2), "h");
+        assert_eq!(truncate("héllo", 3), "hé");
+        assert_eq!(truncate("héllo", 10), "héllo");
+        assert_eq!(chunks("aébc", 2), ["a", "é", "bc"]);
+        assert!(chunks("", 2).is_empty());
+        assert_eq!(sanitize("a\0b"), "a\\0b");
+        assert!(matches!(sanitize("ab"), Cow::Borrowed("ab")));
+    }
+
+    #[test]
+    fn debug_values_stop_at_the_limit() {
+        assert_eq!(debug_string(&"abc", 10), ("\"abc\"".to_string(), false));
+        assert_eq!(debug_string(&[1, 2, 3], 4), ("[1, ".to_string(), true));
+        assert_eq!(debug_string(&"é", 2), ("\"".to_string(), true));
+    }
+
+    #[test]
+    fn arguments_that_overflow_the_record_are_dropped() {
+        let name = || ftfrs::StringRef::Ref(1);
+        let mut small: Vec<_> = (0..20).map(|i| ftfrs::Argument::UInt64(name(), i)).collect();
+        fit_arguments(&mut small);
+        assert_eq!(small.len(), MAX_ARGUMENTS);
+
+        // Each of these takes 1 word for the header, 1 for the name and 100
+        // for the value
+        let long = ftfrs::StringRef::Inline("x".repeat(800));
+        let mut large: Vec<_> = (0..3)
+            .map(|_| ftfrs::Argument::Str(ftfrs::StringRef::Inline("name".to_string()), long.clone()))
+            .collect();
+        assert_eq!(fitting_arguments(&large), 1, "{} fixed words leave room for one", MAX_FIXED_EVENT_WORDS);
+        fit_arguments(&mut large);
+        assert_eq!(large.len(), 1);
+    }
+}
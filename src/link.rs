@@ -0,0 +1,57 @@
+use tracing::Span;
+use tracing_core::{span, Dispatch};
+
+/// Extension trait for linking [`Span`]s recorded by an
+/// [`FtfLayer`](crate::FtfLayer) after they have been created.
+pub trait FtfExt {
+    /// Draw a flow arrow from this span to `to`, for causal links discovered
+    /// after both spans were created.
+    ///
+    /// The arrow runs from the start of this span to the start of `to`. Both
+    /// spans must still be open and recorded by an `FtfLayer` in the current
+    /// subscriber; otherwise this does nothing.
+    fn link_spans(&self, to: &Span);
+}
+
+impl FtfExt for Span {
+    fn link_spans(&self, to: &Span) {
+        let Some(to) = to.id() else {
+            return;
+        };
+
+        self.with_subscriber(|(from, dispatch)| {
+            if let Some(link) = dispatch.downcast_ref::<LinkSpans>() {
+                (link.link)(dispatch, from, &to);
+            }
+        });
+    }
+}
+
+/// Write a flow begin at the current time on the calling thread, inside
+/// `span`, returning the flow's id if `span` is recorded
+pub(crate) fn begin_flow(span: &Span) -> Option<u64> {
+    span.with_subscriber(|(id, dispatch)| {
+        let link = dispatch.downcast_ref::<LinkSpans>()?;
+        (link.begin_flow)(dispatch, id)
+    })
+    .flatten()
+}
+
+/// Write the end of flow `flow_id` at the current time on the calling
+/// thread, inside `span`
+pub(crate) fn end_flow(span: &Span, flow_id: u64) {
+    span.with_subscriber(|(id, dispatch)| {
+        if let Some(link) = dispatch.downcast_ref::<LinkSpans>() {
+            (link.end_flow)(dispatch, id, flow_id);
+        }
+    });
+}
+
+/// Entry points exposed through `Layer::downcast_raw` so a [`Span`] can
+/// reach the layer without knowing its writer or subscriber types
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LinkSpans {
+    pub(crate) link: fn(&Dispatch, &span::Id, &span::Id),
+    pub(crate) begin_flow: fn(&Dispatch, &span::Id) -> Option<u64>,
+    pub(crate) end_flow: fn(&Dispatch, &span::Id, u64),
+}
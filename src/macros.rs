@@ -0,0 +1,66 @@
+/// Create a span recorded by [`FtfLayer`](crate::FtfLayer) in the given
+/// category, enter it, and return the guard that exits it when dropped.
+///
+/// `ftf_span!("db", "query", table = "users")` is shorthand for
+/// `trace_span!("query", ftf = true, category = "db", table = "users").entered()`.
+/// The name must be a string literal, as with any `tracing` span.
+///
+/// ```
+/// let _guard = ftfrs_tracing::ftf_span!("db", "query", table = "users");
+/// ```
+#[macro_export]
+macro_rules! ftf_span {
+    ($category:expr, $name:literal $(,)?) => {
+        $crate::__tracing::trace_span!($name, ftf = true, category = $category).entered()
+    };
+    ($category:expr, $name:literal, $($fields:tt)+) => {
+        $crate::__tracing::trace_span!($name, ftf = true, category = $category, $($fields)+).entered()
+    };
+}
+
+/// Run a closure as a blocking section, written as a duration named after
+/// the reason in the `blocking` category.
+///
+/// Wrap calls that block the thread, such as file I/O or lock waits in async
+/// code, to see where an executor stalls. If
+/// [`FtfLayerConfig::blocking_threshold`](crate::FtfLayerConfig::blocking_threshold)
+/// is set, sections running longer print a warning.
+///
+/// ```
+/// let contents = ftfrs_tracing::ftf_blocking!("read config", || std::fs::read_to_string("Cargo.toml"));
+/// ```
+#[macro_export]
+macro_rules! ftf_blocking {
+    ($reason:expr, $f:expr $(,)?) => {
+        $crate::blocking_section($reason, $f)
+    };
+}
+
+/// Collect build information about the calling crate as
+/// [`FtfLayerConfig::metadata`](crate::FtfLayerConfig::metadata) entries, so
+/// performance regressions can be tied to builds.
+///
+/// Records the package name and version, the `GIT_HASH` environment variable
+/// if the application sets it at build time (e.g. from its build script with
+/// `cargo:rustc-env=GIT_HASH=...`), and the rustc version. Requires the
+/// `build-info` feature.
+///
+/// ```
+/// let mut config = ftfrs_tracing::FtfLayerConfig::default();
+/// config.metadata.extend(ftfrs_tracing::build_info!());
+/// ```
+#[cfg(feature = "build-info")]
+#[macro_export]
+macro_rules! build_info {
+    () => {{
+        let mut info = ::std::vec![
+            (::std::string::String::from("build.package"), ::std::string::String::from(env!("CARGO_PKG_NAME"))),
+            (::std::string::String::from("build.version"), ::std::string::String::from(env!("CARGO_PKG_VERSION"))),
+            (::std::string::String::from("build.rustc"), ::std::string::String::from($crate::__RUSTC_VERSION)),
+        ];
+        if let ::std::option::Option::Some(git_hash) = option_env!("GIT_HASH") {
+            info.push((::std::string::String::from("build.git_hash"), ::std::string::String::from(git_hash)));
+        }
+        info
+    }};
+}
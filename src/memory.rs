@@ -0,0 +1,162 @@
+//! The memory budget shared by a layer's intern tables and buffers.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::MemoryStats;
+
+/// Rough bookkeeping cost of one table entry beyond its string's bytes:
+/// the map slot, the `String` itself and its tick or index
+pub(crate) const ENTRY_OVERHEAD: usize = 48;
+
+/// Bytes held by every intern table and buffer of a layer, against
+/// [`FtfLayerConfig::memory_budget`](crate::FtfLayerConfig::memory_budget).
+///
+/// Nothing is counted without a budget, so the tables pay only for a branch.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryBudget {
+    limit: Option<usize>,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    /// Names, values and threads written inline because the budget was full
+    inlined: AtomicU64,
+    /// Sampled span trees dropped because the budget was full
+    dropped_trees: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            ..Self::default()
+        })
+    }
+
+    pub(crate) fn is_limited(&self) -> bool {
+        self.limit.is_some()
+    }
+
+    /// Take `bytes` from the budget, unless that would go over it
+    fn try_take(&self, bytes: usize) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+        let taken = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| used.checked_add(bytes).filter(|&used| used <= limit));
+        match taken {
+            Ok(used) => {
+                self.peak.fetch_max(used + bytes, Ordering::Relaxed);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn give_back(&self, bytes: usize) {
+        if self.limit.is_some() {
+            self.used.fetch_sub(bytes, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn count_inlined(&self) {
+        self.inlined.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_dropped_tree(&self) {
+        self.dropped_trees.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            used_bytes: self.used.load(Ordering::Relaxed) as u64,
+            peak_bytes: self.peak.load(Ordering::Relaxed) as u64,
+            inlined_entries: self.inlined.load(Ordering::Relaxed),
+            dropped_trees: self.dropped_trees.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Bytes one table or buffer holds against a [`MemoryBudget`], given back
+/// when it is dropped
+#[derive(Debug)]
+pub(crate) struct MemoryCharge {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl MemoryCharge {
+    pub(crate) fn new(budget: &Arc<MemoryBudget>) -> Self {
+        Self {
+            budget: budget.clone(),
+            bytes: 0,
+        }
+    }
+
+    pub(crate) fn budget(&self) -> &MemoryBudget {
+        &self.budget
+    }
+
+    /// Charge `bytes` more, returning false and charging nothing if the
+    /// budget can't cover them
+    pub(crate) fn try_add(&mut self, bytes: usize) -> bool {
+        if !self.budget.try_take(bytes) {
+            return false;
+        }
+        self.bytes += bytes;
+        true
+    }
+
+    /// Stop charging `bytes` that were freed
+    pub(crate) fn remove(&mut self, bytes: usize) {
+        let bytes = bytes.min(self.bytes);
+        self.bytes -= bytes;
+        self.budget.give_back(bytes);
+    }
+
+    /// Stop charging anything, once everything held was freed
+    pub(crate) fn clear(&mut self) {
+        self.remove(self.bytes);
+    }
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charges_are_capped_and_given_back() {
+        let budget = MemoryBudget::new(Some(100));
+        let mut first = MemoryCharge::new(&budget);
+        let mut second = MemoryCharge::new(&budget);
+        assert!(first.try_add(60));
+        assert!(!second.try_add(41), "only 40 bytes are left");
+        assert!(second.try_add(40));
+
+        // Removing more than was charged gives back only what was
+        first.remove(100);
+        assert_eq!(budget.stats().used_bytes, 40);
+        drop(second);
+        assert_eq!(budget.stats(), MemoryStats {
+            used_bytes: 0,
+            peak_bytes: 100,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn nothing_is_counted_without_a_budget() {
+        let budget = MemoryBudget::new(None);
+        let mut charge = MemoryCharge::new(&budget);
+        assert!(!budget.is_limited());
+        assert!(charge.try_add(usize::MAX));
+        charge.clear();
+        assert_eq!(budget.stats(), MemoryStats::default());
+    }
+}
@@ -0,0 +1,249 @@
+//! Appending to one trace file from several processes.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use parking_lot::{Mutex, MutexGuard};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{FtfLayer, FtfLayerConfig, FtfLayerError};
+
+/// Bytes buffered before they are appended to the file
+const DEFAULT_CHUNK_BYTES: usize = 64 * 1024;
+
+/// A file that processes append their traces to together, for
+/// process-per-request servers that would otherwise need a merge step.
+///
+/// Records are buffered and appended in batches of whole records under an
+/// exclusive advisory lock (`flock`), so batches from different processes
+/// never interleave. Each batch starts with a provider section record, which
+/// tells readers whose string and thread tables the records that follow use.
+/// Get a layer writing to one with [`FtfLayer::multi_process_file`], which
+/// gives each process a unique provider id. Call
+/// [`MultiProcessFile::append`] (or flush the layer) to append what is
+/// buffered. A batch cut short by a write error keeps only the bytes that
+/// weren't written for the next append.
+#[derive(Debug)]
+pub struct MultiProcessFile {
+    file: File,
+    provider_id: u32,
+    buffer: Mutex<Vec<u8>>,
+    chunk_bytes: usize,
+}
+
+impl MultiProcessFile {
+    fn open(path: &Path, provider_id: u32, chunk_bytes: usize) -> io::Result<Self> {
+        Ok(Self {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            provider_id,
+            buffer: Mutex::new(Vec::with_capacity(chunk_bytes)),
+            chunk_bytes,
+        })
+    }
+
+    /// Append everything buffered so far
+    pub fn append(&self) -> io::Result<()> {
+        append_locked(&self.file, self.provider_id, &mut self.buffer.lock())
+    }
+}
+
+impl Drop for MultiProcessFile {
+    fn drop(&mut self) {
+        if let Err(e) = append_locked(&self.file, self.provider_id, self.buffer.get_mut()) {
+            eprintln!("Error appending to shared FTF file: {}", e);
+        }
+    }
+}
+
+/// Append `buffer` to `file` as one batch of `provider_id`'s records,
+/// holding the file's lock for the whole batch
+fn append_locked(file: &File, provider_id: u32, buffer: &mut Vec<u8>) -> io::Result<()> {
+    if buffer.is_empty() {
+        return Ok(());
+    }
+
+    let mut section = Vec::new();
+    ftfrs::Record::create_provider_section(provider_id)
+        .write(&mut section)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    let _lock = FileLock::exclusive(file)?;
+    let mut file = file;
+    file.write_all(&section)?;
+    write_draining(&mut file, buffer)
+}
+
+/// Write `buffer` to `writer`, removing whatever was written from `buffer`
+/// even if an error cuts the write short, so a retry doesn't repeat records
+fn write_draining(writer: &mut impl Write, buffer: &mut Vec<u8>) -> io::Result<()> {
+    let mut written = 0;
+    let result = loop {
+        if written == buffer.len() {
+            break Ok(());
+        }
+        match writer.write(&buffer[written..]) {
+            Ok(0) => break Err(io::Error::new(io::ErrorKind::WriteZero, "shared FTF file took no bytes")),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => break Err(e),
+        }
+    };
+    buffer.drain(..written);
+    result
+}
+
+/// An exclusive `flock` on a file, released when dropped
+struct FileLock<'a> {
+    file: &'a File,
+}
+
+impl<'a> FileLock<'a> {
+    fn exclusive(file: &'a File) -> io::Result<Self> {
+        loop {
+            // SAFETY: the descriptor is borrowed from `file`, which outlives
+            // the lock, and `flock` touches no memory
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                return Ok(Self { file });
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+    }
+}
+
+impl Drop for FileLock<'_> {
+    fn drop(&mut self) {
+        // SAFETY: as in `exclusive`, `file` is still open while it is borrowed
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Writer handed out by [`MultiProcessFile`], holding its buffer for one record.
+#[derive(Debug)]
+pub struct MultiProcessFileGuard<'a> {
+    file: &'a MultiProcessFile,
+    buffer: MutexGuard<'a, Vec<u8>>,
+}
+
+impl io::Write for MultiProcessFileGuard<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        append_locked(&self.file.file, self.file.provider_id, &mut self.buffer)
+    }
+}
+
+impl Drop for MultiProcessFileGuard<'_> {
+    fn drop(&mut self) {
+        if self.buffer.len() >= self.file.chunk_bytes {
+            if let Err(e) = append_locked(&self.file.file, self.file.provider_id, &mut self.buffer) {
+                eprintln!("Error appending to shared FTF file: {}", e);
+            }
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for MultiProcessFile {
+    type Writer = MultiProcessFileGuard<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        MultiProcessFileGuard {
+            file: self,
+            buffer: self.buffer.lock(),
+        }
+    }
+}
+
+impl FtfLayer<MultiProcessFile> {
+    /// Get a layer appending to the file at `path` alongside other processes
+    /// doing the same, creating it if needed.
+    ///
+    /// The layer gets a unique provider id, as with
+    /// [`FtfLayerConfig::unique_provider_id`], and its records are appended
+    /// in batches of about 64 KiB. Hold a [`FlushGuard`](crate::FlushGuard)
+    /// so the last batch is appended before the process exits.
+    pub fn multi_process_file(path: impl AsRef<Path>, mut config: FtfLayerConfig) -> Result<Self, FtfLayerError> {
+        crate::assign_unique_provider_id(&mut config);
+        let file = MultiProcessFile::open(path.as_ref(), config.provider_id, DEFAULT_CHUNK_BYTES)?;
+        Ok(Self::with_config(file, config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Takes `limit` bytes, then fails every write
+    struct Failing {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let room = self.limit - self.written.len();
+            if room == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            let n = buf.len().min(room).min(3);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bytes_written_before_an_error_are_not_written_again() {
+        let mut writer = Failing { written: Vec::new(), limit: 7 };
+        let mut buffer = b"0123456789".to_vec();
+        assert!(write_draining(&mut writer, &mut buffer).is_err());
+        assert_eq!(writer.written, b"0123456");
+        assert_eq!(buffer, b"789");
+
+        writer.limit = 10;
+        write_draining(&mut writer, &mut buffer).unwrap();
+        assert_eq!(writer.written, b"0123456789");
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn batches_from_each_writer_follow_their_provider_section() {
+        let path = std::env::temp_dir().join(format!("ftfrs-tracing-multi-process-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let first = MultiProcessFile::open(&path, 1, DEFAULT_CHUNK_BYTES).unwrap();
+        let second = MultiProcessFile::open(&path, 2, DEFAULT_CHUNK_BYTES).unwrap();
+
+        let record = |id, name: &str| {
+            let mut bytes = Vec::new();
+            ftfrs::Record::create_string(id, name.to_string()).write(&mut bytes).unwrap();
+            bytes
+        };
+        first.make_writer().write_all(&record(1, "first")).unwrap();
+        second.make_writer().write_all(&record(1, "second")).unwrap();
+        first.make_writer().write_all(&record(2, "again")).unwrap();
+        second.append().unwrap();
+        first.append().unwrap();
+        assert!(first.buffer.lock().is_empty());
+
+        // Nothing is left to append, so dropping adds nothing
+        drop((first, second));
+        let trace = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut expected = Vec::new();
+        for (provider_id, records) in [(2, vec![record(1, "second")]), (1, vec![record(1, "first"), record(2, "again")])] {
+            ftfrs::Record::create_provider_section(provider_id).write(&mut expected).unwrap();
+            expected.extend(records.concat());
+        }
+        assert_eq!(trace, expected);
+    }
+}
@@ -0,0 +1,212 @@
+//! Network throughput samples, per interface.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use parking_lot::{Condvar, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::FtfHandle;
+
+/// Running totals for one network interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetCounters {
+    /// Bytes received
+    pub rx_bytes: u64,
+    /// Bytes sent
+    pub tx_bytes: u64,
+    /// Packets received
+    pub rx_packets: u64,
+    /// Packets sent
+    pub tx_packets: u64,
+}
+
+impl NetCounters {
+    /// Counts accumulated since `earlier`, treating a counter that went
+    /// backwards (e.g. a reset interface) as starting over
+    pub(crate) fn since(&self, earlier: &Self) -> Self {
+        let delta = |now: u64, then: u64| now.checked_sub(then).unwrap_or(now);
+        Self {
+            rx_bytes: delta(self.rx_bytes, earlier.rx_bytes),
+            tx_bytes: delta(self.tx_bytes, earlier.tx_bytes),
+            rx_packets: delta(self.rx_packets, earlier.rx_packets),
+            tx_packets: delta(self.tx_packets, earlier.tx_packets),
+        }
+    }
+}
+
+/// Where a network sampler gets its totals from, by interface name
+pub(crate) type NetSource = Box<dyn FnMut() -> io::Result<Vec<(String, NetCounters)>> + Send>;
+
+/// Read the totals of every interface in the process's network namespace
+/// from `/proc/self/net/dev`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn read_proc_net_dev() -> io::Result<Vec<(String, NetCounters)>> {
+    let dev = std::fs::read_to_string("/proc/self/net/dev")?;
+    // Two header lines, then `name: rx_bytes rx_packets ... tx_bytes tx_packets ...`
+    let interfaces = dev
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let (name, fields) = line.split_once(':')?;
+            let fields: Vec<u64> = fields.split_whitespace().map(|field| field.parse().ok()).collect::<Option<_>>()?;
+            let counters = NetCounters {
+                rx_bytes: *fields.first()?,
+                rx_packets: *fields.get(1)?,
+                tx_bytes: *fields.get(8)?,
+                tx_packets: *fields.get(9)?,
+            };
+            Some((name.trim().to_string(), counters))
+        })
+        .collect();
+    Ok(interfaces)
+}
+
+/// Keeps a sampler started by [`FtfHandle::spawn_net_sampler`] or
+/// [`FtfHandle::spawn_net_sampler_with`] running.
+///
+/// Dropping the guard stops the sampler and joins its thread.
+#[must_use = "dropping the guard immediately stops the sampler"]
+#[derive(Debug)]
+pub struct NetSamplerGuard {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl NetSamplerGuard {
+    pub(crate) fn spawn<W>(handle: FtfHandle<W>, interval: Duration, mut source: NetSource) -> Self
+    where
+        W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let spawned = std::thread::Builder::new()
+            .name("ftfrs-tracing-net".to_string())
+            .spawn(move || {
+                let mut previous: HashMap<String, NetCounters> = HashMap::new();
+                let (stopped, condvar) = &*thread_stop;
+                let mut stopped = stopped.lock();
+                while !*stopped {
+                    match source() {
+                        Ok(current) => {
+                            for (interface, counters) in current {
+                                if let Some(last) = previous.get(&interface) {
+                                    handle.write_net_sample(&interface, &counters.since(last));
+                                }
+                                previous.insert(interface, counters);
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("Error reading network stats: {}", e);
+                            return;
+                        }
+                    }
+                    condvar.wait_for(&mut stopped, interval);
+                }
+            });
+
+        let handle = match spawned {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                eprintln!("Error starting FTF network sampling thread: {}", e);
+                None
+            }
+        };
+        Self { stop, handle }
+    }
+}
+
+impl Drop for NetSamplerGuard {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock() = true;
+        condvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                eprintln!("FTF network sampling thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Instant;
+
+    use crate::{FtfLayer, FtfLayerConfig, RecordSink};
+
+    use super::*;
+
+    /// Collects the records written to an output
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<ftfrs::Record>>);
+
+    impl RecordSink for Collect {
+        fn write_record(&self, record: &ftfrs::Record) {
+            self.0.lock().push(record.clone());
+        }
+    }
+
+    fn counters(rx_bytes: u64, tx_bytes: u64) -> NetCounters {
+        NetCounters {
+            rx_bytes,
+            tx_bytes,
+            rx_packets: rx_bytes / 10,
+            tx_packets: tx_bytes / 10,
+        }
+    }
+
+    #[test]
+    fn deltas_start_over_when_a_counter_goes_backwards() {
+        assert_eq!(counters(150, 300).since(&counters(100, 200)), counters(50, 100));
+        // The interface was reset: everything counted since is new
+        assert_eq!(counters(30, 300).since(&counters(100, 200)), NetCounters {
+            rx_bytes: 30,
+            tx_bytes: 100,
+            rx_packets: 3,
+            tx_packets: 10,
+        });
+    }
+
+    #[test]
+    fn each_interface_gets_a_counter_of_its_traffic_since_the_last_sample() {
+        let collect = Arc::new(Collect::default());
+        let layer = FtfLayer::with_config(io::sink, FtfLayerConfig {
+            sinks: vec![collect.clone()],
+            ..Default::default()
+        });
+
+        let mut samples = 0;
+        let guard = layer.handle().spawn_net_sampler_with(Duration::from_millis(1), move || {
+            samples += 1;
+            Ok(vec![("eth0".to_string(), counters(1000 * samples, 0))])
+        });
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let counter_count = || collect.0.lock().iter().filter(|record| format!("{:?}", record).starts_with("Event(Counter(")).count();
+        while counter_count() < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        drop(guard);
+
+        let records = collect.0.lock();
+        let counters: Vec<String> = records
+            .iter()
+            .map(|record| format!("{:?}", record))
+            .filter(|debug| debug.starts_with("Event(Counter("))
+            .collect();
+        assert!(counters.len() >= 2, "{:?}", *records);
+        // Every sample after the first adds 1000 received bytes in 100 packets
+        assert!(counters.iter().all(|debug| debug.contains(", 1000), ") && debug.contains(", 100), ")), "{:?}", counters);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn proc_net_dev_lists_the_loopback_interface() {
+        let interfaces = read_proc_net_dev().unwrap();
+        assert!(interfaces.iter().any(|(name, _)| name == "lo"), "{:?}", interfaces);
+    }
+}
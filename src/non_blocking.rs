@@ -0,0 +1,414 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use parking_lot::{Condvar, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Default number of records the background queue holds before the
+/// backpressure policy kicks in.
+const DEFAULT_BUFFERED_RECORDS: usize = 128_000;
+
+/// What a producer does when the background writer's queue is full.
+///
+/// Records are queued whole, so no policy ever tears a record in half. Note
+/// that the dropping policies may discard string or thread records that later
+/// events reference, which leaves those references unresolved in the trace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Block the instrumented thread until the writer frees up space.
+    #[default]
+    Block,
+    /// Discard the record that was about to be queued.
+    DropNewest,
+    /// Discard the oldest queued record to make room for the new one.
+    DropOldest,
+}
+
+/// A snapshot of the background writer's counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BackpressureMetrics {
+    /// Records accepted into the queue
+    pub enqueued: u64,
+    /// Records the underlying writer took
+    pub written: u64,
+    /// Records the underlying writer failed to take, which are lost
+    pub write_errors: u64,
+    /// Times a producer had to wait under [`BackpressurePolicy::Block`]
+    pub blocked: u64,
+    /// Records discarded under [`BackpressurePolicy::DropNewest`]
+    pub dropped_newest: u64,
+    /// Records discarded under [`BackpressurePolicy::DropOldest`]
+    pub dropped_oldest: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    enqueued: AtomicU64,
+    written: AtomicU64,
+    write_errors: AtomicU64,
+    blocked: AtomicU64,
+    dropped_newest: AtomicU64,
+    dropped_oldest: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Queue {
+    records: VecDeque<Vec<u8>>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<Queue>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: BackpressurePolicy,
+    counters: Counters,
+}
+
+impl Shared {
+    fn enqueue(&self, record: Vec<u8>) {
+        let mut queue = self.queue.lock();
+        if queue.closed {
+            return;
+        }
+
+        if queue.records.len() >= self.capacity {
+            match self.policy {
+                BackpressurePolicy::Block => {
+                    self.counters.blocked.fetch_add(1, Ordering::Relaxed);
+                    while queue.records.len() >= self.capacity && !queue.closed {
+                        self.not_full.wait(&mut queue);
+                    }
+                    if queue.closed {
+                        return;
+                    }
+                }
+                BackpressurePolicy::DropNewest => {
+                    self.counters.dropped_newest.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                BackpressurePolicy::DropOldest => {
+                    queue.records.pop_front();
+                    self.counters.dropped_oldest.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        queue.records.push_back(record);
+        self.counters.enqueued.fetch_add(1, Ordering::Relaxed);
+        self.not_empty.notify_one();
+    }
+
+    fn close(&self) {
+        let mut queue = self.queue.lock();
+        queue.closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    fn run<W: io::Write>(&self, mut writer: W) {
+        let mut batch = Vec::new();
+        loop {
+            let closed = {
+                let mut queue = self.queue.lock();
+                while queue.records.is_empty() && !queue.closed {
+                    self.not_empty.wait(&mut queue);
+                }
+                batch.extend(queue.records.drain(..));
+                self.not_full.notify_all();
+                queue.closed
+            };
+
+            for record in batch.drain(..) {
+                match writer.write_all(&record) {
+                    Ok(()) => self.counters.written.fetch_add(1, Ordering::Relaxed),
+                    Err(e) => {
+                        eprintln!("Error writing FTF record in background writer: {}", e);
+                        self.counters.write_errors.fetch_add(1, Ordering::Relaxed)
+                    }
+                };
+            }
+            if let Err(e) = writer.flush() {
+                eprintln!("Error flushing background writer: {}", e);
+            }
+
+            if closed {
+                return;
+            }
+        }
+    }
+
+    fn metrics(&self) -> BackpressureMetrics {
+        BackpressureMetrics {
+            enqueued: self.counters.enqueued.load(Ordering::Relaxed),
+            written: self.counters.written.load(Ordering::Relaxed),
+            write_errors: self.counters.write_errors.load(Ordering::Relaxed),
+            blocked: self.counters.blocked.load(Ordering::Relaxed),
+            dropped_newest: self.counters.dropped_newest.load(Ordering::Relaxed),
+            dropped_oldest: self.counters.dropped_oldest.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A [`MakeWriter`] that hands finished records to a background thread.
+///
+/// Each writer returned by [`MakeWriter::make_writer`] buffers what it is
+/// given and queues it as a single record when dropped or flushed.
+#[derive(Debug, Clone)]
+pub struct NonBlocking {
+    shared: Arc<Shared>,
+}
+
+impl NonBlocking {
+    /// Get a snapshot of the queue and backpressure counters
+    pub fn metrics(&self) -> BackpressureMetrics {
+        self.shared.metrics()
+    }
+
+    /// The backpressure policy this writer was built with
+    pub fn policy(&self) -> BackpressurePolicy {
+        self.shared.policy
+    }
+}
+
+/// Per-record writer handed out by [`NonBlocking`].
+#[derive(Debug)]
+pub struct NonBlockingWriter<'a> {
+    shared: &'a Shared,
+    buffer: Vec<u8>,
+}
+
+impl io::Write for NonBlockingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.shared.enqueue(std::mem::take(&mut self.buffer));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NonBlockingWriter<'_> {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            self.shared.enqueue(std::mem::take(&mut self.buffer));
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for NonBlocking {
+    type Writer = NonBlockingWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        NonBlockingWriter {
+            shared: &self.shared,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Keeps the background writer alive.
+///
+/// Dropping the guard drains any queued records to the underlying writer and
+/// joins the background thread, so hold on to it until tracing is done.
+#[must_use = "dropping the guard immediately stops the background writer"]
+#[derive(Debug)]
+pub struct WorkerGuard {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.shared.close();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                eprintln!("Background FTF writer thread panicked");
+            }
+        }
+    }
+}
+
+/// Builder for a [`NonBlocking`] writer.
+#[derive(Debug, Clone)]
+pub struct NonBlockingBuilder {
+    buffered_records: usize,
+    policy: BackpressurePolicy,
+    thread_name: String,
+}
+
+impl Default for NonBlockingBuilder {
+    fn default() -> Self {
+        Self {
+            buffered_records: DEFAULT_BUFFERED_RECORDS,
+            policy: BackpressurePolicy::default(),
+            thread_name: "ftfrs-tracing-writer".to_string(),
+        }
+    }
+}
+
+impl NonBlockingBuilder {
+    /// Set how many records may wait in the queue before backpressure applies
+    pub fn buffered_records(mut self, buffered_records: usize) -> Self {
+        self.buffered_records = buffered_records.max(1);
+        self
+    }
+
+    /// Set what happens when the queue is full
+    pub fn backpressure(mut self, policy: BackpressurePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Set the name of the background thread
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// Spawn the background thread writing to `writer`, failing if the
+    /// thread can't be spawned
+    pub fn finish<W: io::Write + Send + 'static>(self, writer: W) -> io::Result<(NonBlocking, WorkerGuard)> {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                records: VecDeque::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity: self.buffered_records,
+            policy: self.policy,
+            counters: Counters::default(),
+        });
+
+        let worker = Arc::clone(&shared);
+        let handle = std::thread::Builder::new()
+            .name(self.thread_name)
+            .spawn(move || worker.run(writer))?;
+
+        Ok((
+            NonBlocking {
+                shared: Arc::clone(&shared),
+            },
+            WorkerGuard {
+                shared,
+                handle: Some(handle),
+            },
+        ))
+    }
+}
+
+/// Create a [`NonBlocking`] writer with the default [`BackpressurePolicy`].
+pub fn non_blocking<W: io::Write + Send + 'static>(writer: W) -> io::Result<(NonBlocking, WorkerGuard)> {
+    NonBlockingBuilder::default().finish(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::mpsc;
+
+    use super::*;
+
+    /// A writer that collects what it is given, or fails every write
+    #[derive(Clone, Default)]
+    struct Collect {
+        written: Arc<Mutex<Vec<u8>>>,
+        fail: bool,
+    }
+
+    impl io::Write for Collect {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.fail {
+                return Err(io::Error::other("disk full"));
+            }
+            self.written.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn write_records(writer: &NonBlocking, count: u8) {
+        for i in 0..count {
+            writer.make_writer().write_all(&[i; 8]).unwrap();
+        }
+    }
+
+    #[test]
+    fn records_reach_the_writer_in_order() {
+        let collect = Collect::default();
+        let (writer, guard) = non_blocking(collect.clone()).unwrap();
+        write_records(&writer, 10);
+        drop(guard);
+
+        let expected: Vec<u8> = (0..10).flat_map(|i| [i; 8]).collect();
+        assert_eq!(*collect.written.lock(), expected);
+        let metrics = writer.metrics();
+        assert_eq!((metrics.enqueued, metrics.written, metrics.write_errors), (10, 10, 0));
+    }
+
+    #[test]
+    fn failed_writes_are_not_counted_as_written() {
+        let collect = Collect {
+            fail: true,
+            ..Collect::default()
+        };
+        let (writer, guard) = non_blocking(collect).unwrap();
+        write_records(&writer, 3);
+        drop(guard);
+
+        let metrics = writer.metrics();
+        assert_eq!((metrics.enqueued, metrics.written, metrics.write_errors), (3, 0, 3));
+    }
+
+    /// A writer that waits for a go-ahead before each write
+    struct Gated(mpsc::Receiver<()>);
+
+    impl io::Write for Gated {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.0.recv();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn full_queue_drops_by_policy() {
+        for policy in [BackpressurePolicy::DropNewest, BackpressurePolicy::DropOldest] {
+            let (go, gate) = mpsc::channel();
+            let (writer, guard) = NonBlockingBuilder::default()
+                .buffered_records(2)
+                .backpressure(policy)
+                .finish(Gated(gate))
+                .unwrap();
+            // Hold the writer thread on the first record so the rest queue up
+            write_records(&writer, 1);
+            while !writer.shared.queue.lock().records.is_empty() {
+                std::thread::yield_now();
+            }
+            write_records(&writer, 5);
+            drop(go);
+            drop(guard);
+
+            let metrics = writer.metrics();
+            let dropped = metrics.dropped_newest + metrics.dropped_oldest;
+            assert_eq!(dropped, 3, "{:?}", policy);
+            assert_eq!(metrics.written, 3, "{:?}", policy);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+
+/// Layer hooks whose latency is measured
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Hook {
+    NewSpan,
+    Event,
+    Close,
+}
+
+/// Parts of a hook whose latency is measured
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Phase {
+    /// Resolving name, category and thread references
+    Intern,
+    /// Recording fields as arguments
+    Serialize,
+    /// Writing the finished record
+    Write,
+}
+
+/// Calls to one hook and the time spent in them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HookStats {
+    /// Times the hook ran
+    pub calls: u64,
+    /// Nanoseconds spent in the hook
+    pub total_ns: u64,
+}
+
+impl HookStats {
+    /// Mean nanoseconds per call, or 0 if the hook never ran
+    pub fn mean_ns(&self) -> u64 {
+        self.total_ns.checked_div(self.calls).unwrap_or(0)
+    }
+}
+
+/// Time the layer spent on its own work, reported by
+/// [`FtfLayer::stats`](crate::FtfLayer::stats) when
+/// [`FtfLayerConfig::measure_overhead`](crate::FtfLayerConfig::measure_overhead) is set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OverheadStats {
+    /// Span creation, in `on_new_span`
+    pub new_span: HookStats,
+    /// Events, in `on_event`
+    pub event: HookStats,
+    /// Span ends, in `on_close`
+    pub close: HookStats,
+    /// Nanoseconds spent resolving name, category and thread references
+    pub intern_ns: u64,
+    /// Nanoseconds spent recording fields as arguments
+    pub serialize_ns: u64,
+    /// Nanoseconds spent writing finished records
+    pub write_ns: u64,
+}
+
+impl OverheadStats {
+    /// Counters accumulated since `earlier` was taken
+    pub(crate) fn since(&self, earlier: &Self) -> Self {
+        let hook = |now: HookStats, then: HookStats| HookStats {
+            calls: now.calls - then.calls,
+            total_ns: now.total_ns - then.total_ns,
+        };
+        Self {
+            new_span: hook(self.new_span, earlier.new_span),
+            event: hook(self.event, earlier.event),
+            close: hook(self.close, earlier.close),
+            intern_ns: self.intern_ns - earlier.intern_ns,
+            serialize_ns: self.serialize_ns - earlier.serialize_ns,
+            write_ns: self.write_ns - earlier.write_ns,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct HookCounters {
+    calls: AtomicU64,
+    total_ns: AtomicU64,
+}
+
+impl HookCounters {
+    fn snapshot(&self) -> HookStats {
+        HookStats {
+            calls: self.calls.load(Ordering::Relaxed),
+            total_ns: self.total_ns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Running totals behind [`OverheadStats`]
+#[derive(Debug, Default)]
+pub(crate) struct Overhead {
+    hooks: [HookCounters; 3],
+    phases: [AtomicU64; 3],
+    /// When counters were last written, and the totals at that time
+    last_written: Mutex<(u64, OverheadStats)>,
+}
+
+impl Overhead {
+    pub(crate) fn add_hook(&self, hook: Hook, ns: u64) {
+        let counters = &self.hooks[hook as usize];
+        counters.calls.fetch_add(1, Ordering::Relaxed);
+        counters.total_ns.fetch_add(ns, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_phase(&self, phase: Phase, ns: u64) {
+        self.phases[phase as usize].fetch_add(ns, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> OverheadStats {
+        OverheadStats {
+            new_span: self.hooks[Hook::NewSpan as usize].snapshot(),
+            event: self.hooks[Hook::Event as usize].snapshot(),
+            close: self.hooks[Hook::Close as usize].snapshot(),
+            intern_ns: self.phases[Phase::Intern as usize].load(Ordering::Relaxed),
+            serialize_ns: self.phases[Phase::Serialize as usize].load(Ordering::Relaxed),
+            write_ns: self.phases[Phase::Write as usize].load(Ordering::Relaxed),
+        }
+    }
+
+    /// If at least `interval_ns` passed since counters were last written,
+    /// claim the next write and return what accumulated in between
+    pub(crate) fn take_interval(&self, now: u64, interval_ns: u64) -> Option<OverheadStats> {
+        let mut last_written = self.last_written.try_lock()?;
+        if now.saturating_sub(last_written.0) < interval_ns {
+            return None;
+        }
+        let stats = self.stats();
+        let interval = stats.since(&last_written.1);
+        *last_written = (now, stats);
+        Some(interval)
+    }
+}
@@ -0,0 +1,121 @@
+//! Clock and process identity.
+//!
+//! Native targets use the standard library. With the `wasm` feature on
+//! `wasm32`, where `Instant`, `SystemTime` and `std::process::id` are
+//! unavailable, they come from the host page instead (see [`crate::wasm`]).
+
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+mod imp {
+    use std::sync::OnceLock;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+    use crate::TimestampEpoch;
+
+    /// Monotonic clock that trace timestamps are measured on
+    #[derive(Debug, Clone, Copy)]
+    pub(crate) struct Clock {
+        start: Instant,
+    }
+
+    impl Clock {
+        pub(crate) fn new(epoch: TimestampEpoch) -> Self {
+            let start = match epoch {
+                TimestampEpoch::LayerCreation => Instant::now(),
+                TimestampEpoch::ProcessStart => process_start(),
+                TimestampEpoch::Instant(start) => start,
+            };
+            Self { start }
+        }
+
+        /// Nanoseconds elapsed since the clock was started
+        pub(crate) fn elapsed_nanos(&self) -> u64 {
+            self.start.elapsed().as_nanos() as u64
+        }
+    }
+
+    /// Wall-clock time as nanoseconds since the Unix epoch
+    pub(crate) fn unix_nanos() -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn process_id() -> u32 {
+        std::process::id()
+    }
+
+    /// When the process started, or failing that when this was first called
+    fn process_start() -> Instant {
+        static START: OnceLock<Instant> = OnceLock::new();
+        *START.get_or_init(|| {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            if let Some(start) = process_age().and_then(|age| Instant::now().checked_sub(age)) {
+                return start;
+            }
+            Instant::now()
+        })
+    }
+
+    /// How long ago the process started, from its start time in
+    /// `/proc/self/stat` and the time since boot
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn process_age() -> Option<std::time::Duration> {
+        let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+        // The command name may contain spaces and parentheses, so fields are
+        // counted from after its closing parenthesis, starting at field 3
+        let start_ticks: u64 = stat.rsplit_once(')')?.1.split_whitespace().nth(22 - 3)?.parse().ok()?;
+
+        // SAFETY: `sysconf` and `clock_gettime` only write to `now`, which
+        // outlives the call
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        if ticks_per_second <= 0 || unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut now) } != 0 {
+            return None;
+        }
+
+        let since_boot = std::time::Duration::new(now.tv_sec as u64, now.tv_nsec as u32);
+        let started_after_boot = std::time::Duration::from_nanos(start_ticks * 1_000_000_000 / ticks_per_second as u64);
+        since_boot.checked_sub(started_after_boot)
+    }
+}
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+mod imp {
+    use crate::wasm::host;
+    use crate::TimestampEpoch;
+
+    /// Monotonic clock that trace timestamps are measured on, backed by
+    /// `performance.now()`
+    #[derive(Debug, Clone, Copy)]
+    pub(crate) struct Clock {
+        start_ms: f64,
+    }
+
+    impl Clock {
+        pub(crate) fn new(epoch: TimestampEpoch) -> Self {
+            match epoch {
+                // `performance.now()` already counts from the page's time origin
+                TimestampEpoch::ProcessStart => Self { start_ms: 0.0 },
+                TimestampEpoch::LayerCreation | TimestampEpoch::Instant(_) => Self { start_ms: host::now() },
+            }
+        }
+
+        /// Nanoseconds elapsed since the clock was started
+        pub(crate) fn elapsed_nanos(&self) -> u64 {
+            ((host::now() - self.start_ms) * 1_000_000.0) as u64
+        }
+    }
+
+    /// Wall-clock time as nanoseconds since the Unix epoch, from `Date.now()`
+    pub(crate) fn unix_nanos() -> u128 {
+        (host::date_now() * 1_000_000.0) as u128
+    }
+
+    /// A browser has no process ids; every trace uses the same one
+    pub(crate) fn process_id() -> u32 {
+        1
+    }
+}
+
+pub(crate) use imp::{process_id, unix_nanos, Clock};
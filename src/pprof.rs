@@ -0,0 +1,381 @@
+//! CPU profiles in pprof's format.
+//!
+//! [`CpuProfile`] counts the stacks the profiler samples and encodes them as
+//! a gzipped `profile.proto` message, which `go tool pprof` and other pprof
+//! readers load directly. Only a handful of its fields are needed, so they
+//! are encoded by hand rather than through a protobuf library.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+
+use crate::gzip;
+use crate::profiler::{capture, locate, ProfilerGuard};
+
+/// Samples taken of each stack, innermost frame first
+type Stacks = HashMap<Vec<usize>, u64>;
+
+/// Counts sampled stacks from [`CpuProfile::start`] until
+/// [`CpuProfile::finish`]
+pub(crate) struct CpuProfile {
+    guard: ProfilerGuard,
+    stacks: Arc<Mutex<Stacks>>,
+    interval: Duration,
+    start: SystemTime,
+    started: Instant,
+}
+
+impl CpuProfile {
+    /// Sample each running thread every `interval`
+    pub(crate) fn start(interval: Duration) -> Self {
+        let stacks = Arc::new(Mutex::new(Stacks::new()));
+        let sampled = stacks.clone();
+        let guard = ProfilerGuard::spawn_with(interval, move |thread_id| {
+            if let Some(addresses) = capture(thread_id) {
+                *sampled.lock().entry(addresses).or_insert(0) += 1;
+            }
+        });
+        Self {
+            guard,
+            stacks,
+            interval,
+            start: SystemTime::now(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Stop sampling and encode the profile, gzipped
+    pub(crate) fn finish(self) -> Vec<u8> {
+        let duration = self.started.elapsed();
+        drop(self.guard);
+        let stacks = mem::take(&mut *self.stacks.lock());
+        let start = self.start.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let profile = encode(&stacks, self.interval, start, duration, |address| match locate(address) {
+            Some((name, _)) => name,
+            None => format!("{:#x}", address),
+        });
+        gzip::compress(&profile)
+    }
+}
+
+/// Encode `stacks` as a `Profile` message, each sample standing for
+/// `interval` of CPU time, naming each frame's function with `function`
+fn encode(
+    stacks: &Stacks,
+    interval: Duration,
+    start: Duration,
+    duration: Duration,
+    mut function: impl FnMut(usize) -> String,
+) -> Vec<u8> {
+    let mut strings = StringTable::default();
+    let mut profile = Vec::new();
+    let period = interval.as_nanos() as u64;
+
+    for (kind, unit) in [("samples", "count"), ("cpu", "nanoseconds")] {
+        let value_type = value_type(strings.index(kind), strings.index(unit));
+        bytes(&mut profile, 1, &value_type);
+    }
+
+    // Functions and locations are numbered from 1 in order of appearance
+    let mut locations: HashMap<usize, u64> = HashMap::new();
+    let mut functions: HashMap<String, u64> = HashMap::new();
+    let mut location_messages = Vec::new();
+    let mut function_messages = Vec::new();
+    let mut sorted: Vec<_> = stacks.iter().collect();
+    sorted.sort();
+    for (stack, &count) in sorted {
+        let mut ids = Vec::with_capacity(stack.len());
+        for &address in stack {
+            let next_id = locations.len() as u64 + 1;
+            let id = *locations.entry(address).or_insert_with(|| {
+                let name = function(address);
+                let next_function = functions.len() as u64 + 1;
+                let function_id = *functions.entry(name.clone()).or_insert_with(|| {
+                    let name = strings.index(&name);
+                    let mut message = Vec::new();
+                    uint(&mut message, 1, next_function);
+                    uint(&mut message, 2, name);
+                    uint(&mut message, 3, name);
+                    function_messages.push(message);
+                    next_function
+                });
+                let mut line = Vec::new();
+                uint(&mut line, 1, function_id);
+                let mut message = Vec::new();
+                uint(&mut message, 1, next_id);
+                uint(&mut message, 3, address as u64);
+                bytes(&mut message, 4, &line);
+                location_messages.push(message);
+                next_id
+            });
+            ids.push(id);
+        }
+
+        // Stacks are already innermost first, as pprof wants them
+        let mut sample = Vec::new();
+        packed(&mut sample, 1, &ids);
+        packed(&mut sample, 2, &[count, count * period]);
+        bytes(&mut profile, 2, &sample);
+    }
+
+    for message in &location_messages {
+        bytes(&mut profile, 4, message);
+    }
+    for message in &function_messages {
+        bytes(&mut profile, 5, message);
+    }
+    let period_type = value_type(strings.index("cpu"), strings.index("nanoseconds"));
+    for string in &strings.strings {
+        bytes(&mut profile, 6, string.as_bytes());
+    }
+    uint(&mut profile, 9, start.as_nanos() as u64);
+    uint(&mut profile, 10, duration.as_nanos() as u64);
+    bytes(&mut profile, 11, &period_type);
+    uint(&mut profile, 12, period);
+    profile
+}
+
+/// The string table, whose first entry must be empty
+struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, u64>,
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        Self {
+            strings: vec![String::new()],
+            indices: HashMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl StringTable {
+    fn index(&mut self, string: &str) -> u64 {
+        if let Some(&index) = self.indices.get(string) {
+            return index;
+        }
+        let index = self.strings.len() as u64;
+        self.strings.push(string.to_string());
+        self.indices.insert(string.to_string(), index);
+        index
+    }
+}
+
+/// A `ValueType` message
+fn value_type(kind: u64, unit: u64) -> Vec<u8> {
+    let mut message = Vec::new();
+    uint(&mut message, 1, kind);
+    uint(&mut message, 2, unit);
+    message
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Write a varint field, which also encodes non-negative `int64`s
+fn uint(out: &mut Vec<u8>, field: u64, value: u64) {
+    varint(out, field << 3);
+    varint(out, value);
+}
+
+/// Write a length-delimited field
+fn bytes(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    varint(out, field << 3 | 2);
+    varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+/// Write a packed repeated varint field
+fn packed(out: &mut Vec<u8>, field: u64, values: &[u64]) {
+    let mut body = Vec::new();
+    for &value in values {
+        varint(&mut body, value);
+    }
+    bytes(out, field, &body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Varint(u64),
+        Bytes(Vec<u8>),
+    }
+
+    fn read_varint(data: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = data[0];
+            *data = &data[1..];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    /// Split a message into its fields
+    fn fields(mut data: &[u8]) -> Vec<(u64, Value)> {
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let key = read_varint(&mut data);
+            let value = match key & 7 {
+                0 => Value::Varint(read_varint(&mut data)),
+                2 => {
+                    let len = read_varint(&mut data) as usize;
+                    let (value, rest) = data.split_at(len);
+                    data = rest;
+                    Value::Bytes(value.to_vec())
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    fn varints(fields: &[(u64, Value)], field: u64) -> Vec<u64> {
+        fields
+            .iter()
+            .filter(|(number, _)| *number == field)
+            .map(|(_, value)| match value {
+                Value::Varint(value) => *value,
+                Value::Bytes(_) => panic!("field {} is not a varint", field),
+            })
+            .collect()
+    }
+
+    fn messages(fields: &[(u64, Value)], field: u64) -> Vec<Vec<u8>> {
+        fields
+            .iter()
+            .filter(|(number, _)| *number == field)
+            .map(|(_, value)| match value {
+                Value::Bytes(value) => value.clone(),
+                Value::Varint(_) => panic!("field {} is not length-delimited", field),
+            })
+            .collect()
+    }
+
+    /// A sample's locations, as addresses and function names, and values
+    type Sample<'a> = (Vec<(u64, &'a str)>, Vec<u64>);
+
+    fn unpack(mut data: &[u8]) -> Vec<u64> {
+        let mut values = Vec::new();
+        while !data.is_empty() {
+            values.push(read_varint(&mut data));
+        }
+        values
+    }
+
+    #[test]
+    fn varints_use_seven_bits_per_byte() {
+        let mut out = Vec::new();
+        varint(&mut out, 0);
+        varint(&mut out, 127);
+        varint(&mut out, 300);
+        varint(&mut out, u64::MAX);
+        assert_eq!(out[..4], [0x00, 0x7f, 0xac, 0x02]);
+        assert_eq!(out.len(), 4 + 10);
+        assert_eq!(read_varint(&mut &out[4..]), u64::MAX);
+    }
+
+    #[test]
+    fn profile_shares_locations_and_functions() {
+        // Two addresses in `inner`, each called from `main`
+        let names = HashMap::from([(0x10, "inner"), (0x14, "inner"), (0x20, "main")]);
+        let stacks = Stacks::from([(vec![0x10, 0x20], 3), (vec![0x14, 0x20], 2)]);
+        let profile = encode(
+            &stacks,
+            Duration::from_millis(10),
+            Duration::from_secs(1),
+            Duration::from_millis(50),
+            |address| names[&address].to_string(),
+        );
+        let profile = fields(&profile);
+
+        let strings: Vec<String> =
+            messages(&profile, 6).into_iter().map(|string| String::from_utf8(string).unwrap()).collect();
+        assert_eq!(strings[0], "");
+        let string = |index: u64| strings[index as usize].as_str();
+
+        let sample_types: Vec<(&str, &str)> = messages(&profile, 1)
+            .iter()
+            .map(|message| {
+                let fields = fields(message);
+                (string(varints(&fields, 1)[0]), string(varints(&fields, 2)[0]))
+            })
+            .collect();
+        assert_eq!(sample_types, [("samples", "count"), ("cpu", "nanoseconds")]);
+
+        let functions: HashMap<u64, &str> = messages(&profile, 5)
+            .iter()
+            .map(|message| {
+                let fields = fields(message);
+                (varints(&fields, 1)[0], string(varints(&fields, 2)[0]))
+            })
+            .collect();
+        assert_eq!(functions.len(), 2);
+
+        let locations: HashMap<u64, (u64, &str)> = messages(&profile, 4)
+            .iter()
+            .map(|message| {
+                let fields = fields(message);
+                let line = line_function(&fields);
+                (varints(&fields, 1)[0], (varints(&fields, 3)[0], functions[&line]))
+            })
+            .collect();
+        assert_eq!(locations.len(), 3);
+        assert!(!locations.contains_key(&0), "location ids start at 1");
+
+        let mut samples: Vec<Sample<'_>> = messages(&profile, 2)
+            .iter()
+            .map(|message| {
+                let fields = fields(message);
+                let stack = unpack(&messages(&fields, 1)[0]).iter().map(|id| locations[id]).collect();
+                (stack, unpack(&messages(&fields, 2)[0]))
+            })
+            .collect();
+        samples.sort();
+        assert_eq!(
+            samples,
+            [
+                (vec![(0x10, "inner"), (0x20, "main")], vec![3, 30_000_000]),
+                (vec![(0x14, "inner"), (0x20, "main")], vec![2, 20_000_000]),
+            ]
+        );
+
+        assert_eq!(varints(&profile, 9), [1_000_000_000]);
+        assert_eq!(varints(&profile, 10), [50_000_000]);
+        assert_eq!(varints(&profile, 12), [10_000_000]);
+        let period_type = fields(&messages(&profile, 11)[0]);
+        assert_eq!((string(varints(&period_type, 1)[0]), string(varints(&period_type, 2)[0])), ("cpu", "nanoseconds"));
+    }
+
+    /// The function id of a location's only line
+    fn line_function(location: &[(u64, Value)]) -> u64 {
+        let lines = messages(location, 4);
+        assert_eq!(lines.len(), 1);
+        varints(&fields(&lines[0]), 1)[0]
+    }
+
+    #[test]
+    fn empty_profile_still_describes_its_samples() {
+        let profile = encode(&Stacks::new(), Duration::from_millis(1), Duration::ZERO, Duration::ZERO, |_| unreachable!());
+        let profile = fields(&profile);
+        assert!(messages(&profile, 2).is_empty());
+        assert_eq!(messages(&profile, 1).len(), 2);
+        assert_eq!(varints(&profile, 12), [1_000_000]);
+    }
+}
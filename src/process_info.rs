@@ -0,0 +1,26 @@
+use std::{env, fs};
+
+/// Collect metadata identifying this process: hostname, pid, executable path
+/// and command-line arguments. Anything that can't be determined is left out.
+pub(crate) fn collect() -> Vec<(String, String)> {
+    let mut info = Vec::new();
+    if let Some(hostname) = hostname() {
+        info.push(("process.hostname".to_string(), hostname));
+    }
+    info.push(("process.pid".to_string(), crate::platform::process_id().to_string()));
+    if let Ok(executable) = env::current_exe() {
+        info.push(("process.executable".to_string(), executable.display().to_string()));
+    }
+    let args: Vec<String> = env::args_os().map(|arg| arg.to_string_lossy().into_owned()).collect();
+    info.push(("process.args".to_string(), args.join(" ")));
+    info
+}
+
+fn hostname() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| env::var("HOSTNAME").ok())
+        .or_else(|| env::var("COMPUTERNAME").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+}
@@ -0,0 +1,410 @@
+//! Signal-based sampling profiler.
+//!
+//! A sampler thread picks the process's running threads from procfs and
+//! sends each one `SIGPROF` in turn. The handler captures the interrupted
+//! thread's stack with glibc's `backtrace` into a static slot, which the
+//! sampler then symbolizes with `dladdr` and writes as an instant event on
+//! that thread, or adds to a [`CpuProfile`](crate::pprof::CpuProfile). Only
+//! one sample is in flight at a time, so the slot needs no allocation or
+//! locking inside the handler.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::{fs, io, mem, ptr};
+
+use parking_lot::{Condvar, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::FtfHandle;
+
+/// Most frames captured per sample
+const MAX_FRAMES: usize = 64;
+
+/// Frames at the top of each capture belonging to the handler itself and
+/// the kernel's signal return trampoline
+const HANDLER_FRAMES: usize = 2;
+
+/// How long the sampler waits for a thread to run its signal handler
+const SAMPLE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// States of [`SLOT`], in the low bits of its request word
+const IDLE: u64 = 0;
+const REQUESTED: u64 = 1;
+const WRITING: u64 = 2;
+const CAPTURED: u64 = 3;
+const STATE_BITS: u32 = 2;
+
+/// Hand-off between the sampler thread and the signal handler
+struct Slot {
+    /// The thread the current request is for, packed with its state. A
+    /// handler running late on a thread given up on sees another thread's
+    /// request, or fails to swap it, so it can't answer for that thread.
+    request: AtomicU64,
+    len: AtomicUsize,
+    frames: [AtomicUsize; MAX_FRAMES],
+}
+
+static SLOT: Slot = Slot {
+    request: AtomicU64::new(IDLE),
+    len: AtomicUsize::new(0),
+    frames: [const { AtomicUsize::new(0) }; MAX_FRAMES],
+};
+
+/// The request word for thread `thread_id` in `state`
+fn request(thread_id: u64, state: u64) -> u64 {
+    thread_id << STATE_BITS | state
+}
+
+/// Set while a profiler is running, since the handler and slot are global
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigprof(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // SAFETY: gettid takes no arguments and cannot fail
+    let thread_id = unsafe { libc::syscall(libc::SYS_gettid) as u64 };
+    let requested = request(thread_id, REQUESTED);
+    if SLOT
+        .request
+        .compare_exchange(requested, request(thread_id, WRITING), Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+
+    // SAFETY: errno is thread-local and restored before returning; backtrace
+    // was already called once on the sampler thread, so it doesn't load
+    // libgcc (which isn't async-signal-safe) here
+    unsafe {
+        let errno = *libc::__errno_location();
+        let mut frames = [ptr::null_mut::<libc::c_void>(); MAX_FRAMES];
+        let len = libc::backtrace(frames.as_mut_ptr(), MAX_FRAMES as libc::c_int).max(0) as usize;
+        for (slot, frame) in SLOT.frames.iter().zip(&frames[..len]) {
+            slot.store(*frame as usize, Ordering::Relaxed);
+        }
+        SLOT.len.store(len, Ordering::Relaxed);
+        SLOT.request.store(request(thread_id, CAPTURED), Ordering::Release);
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Installs [`handle_sigprof`], restoring the previous action when dropped
+struct Handler {
+    previous: libc::sigaction,
+}
+
+impl Handler {
+    fn install() -> io::Result<Self> {
+        // Load libgcc now rather than in the first signal handler
+        let mut frames = [ptr::null_mut::<libc::c_void>(); 1];
+        // SAFETY: the buffer holds one frame, as passed; the action is fully
+        // initialized before use
+        unsafe {
+            libc::backtrace(frames.as_mut_ptr(), 1);
+
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle_sigprof as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            let mut previous: libc::sigaction = mem::zeroed();
+            if libc::sigaction(libc::SIGPROF, &action, &mut previous) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { previous })
+        }
+    }
+}
+
+impl Drop for Handler {
+    fn drop(&mut self) {
+        // SAFETY: the ignoring action is fully initialized before use, and
+        // the restored one is the action saved by `install`
+        unsafe {
+            // A signal sent for a capture that timed out may still be pending
+            // on a thread that has it blocked. Ignoring SIGPROF discards it,
+            // rather than leaving it to the previous action, which by default
+            // terminates the process.
+            let mut ignore: libc::sigaction = mem::zeroed();
+            ignore.sa_sigaction = libc::SIG_IGN;
+            libc::sigemptyset(&mut ignore.sa_mask);
+            libc::sigaction(libc::SIGPROF, &ignore, ptr::null_mut());
+
+            libc::sigaction(libc::SIGPROF, &self.previous, ptr::null_mut());
+        }
+    }
+}
+
+/// OS ids of this process's threads that are currently running
+fn running_threads() -> io::Result<Vec<u64>> {
+    let mut threads = Vec::new();
+    for entry in fs::read_dir("/proc/self/task")? {
+        let entry = entry?;
+        let Some(tid) = entry.file_name().to_str().and_then(|name| name.parse().ok()) else {
+            continue;
+        };
+        let Ok(stat) = fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        // The state follows the command name, which may contain parentheses
+        let state = stat.rsplit_once(')').and_then(|(_, fields)| fields.trim_start().chars().next());
+        if state == Some('R') {
+            threads.push(tid);
+        }
+    }
+    Ok(threads)
+}
+
+/// Capture the stack of thread `thread_id`, innermost frame first, or `None`
+/// if it didn't run the handler in time
+pub(crate) fn capture(thread_id: u64) -> Option<Vec<usize>> {
+    let requested = request(thread_id, REQUESTED);
+    SLOT.request.store(requested, Ordering::Release);
+    // SAFETY: tgkill only sends a signal; a thread that has since exited is
+    // reported as an error
+    let sent = unsafe {
+        libc::syscall(libc::SYS_tgkill, libc::getpid(), thread_id as libc::pid_t, libc::SIGPROF) == 0
+    };
+
+    let deadline = Instant::now() + SAMPLE_TIMEOUT;
+    let captured = loop {
+        if SLOT.request.load(Ordering::Acquire) == request(thread_id, CAPTURED) {
+            break true;
+        }
+        // Give up unless the handler has already started writing
+        if (!sent || Instant::now() >= deadline)
+            && SLOT.request.compare_exchange(requested, IDLE, Ordering::AcqRel, Ordering::Acquire).is_ok()
+        {
+            break false;
+        }
+        std::thread::yield_now();
+    };
+
+    let frames = captured.then(|| {
+        let len = SLOT.len.load(Ordering::Relaxed);
+        SLOT.frames[HANDLER_FRAMES.min(len)..len].iter().map(|frame| frame.load(Ordering::Relaxed)).collect()
+    });
+    SLOT.request.store(IDLE, Ordering::Release);
+    frames
+}
+
+/// Name `address` as `symbol+0xoffset`, falling back to the file it is
+/// mapped from, or the bare address if `dladdr` knows neither
+fn symbolize(address: usize) -> String {
+    match locate(address) {
+        Some((name, offset)) => format!("{}+{:#x}", name, offset),
+        None => format!("{:#x}", address),
+    }
+}
+
+/// The symbol containing `address` and its offset into it, falling back to
+/// the file it is mapped from
+pub(crate) fn locate(address: usize) -> Option<(String, usize)> {
+    // SAFETY: dladdr only reads the address; the strings it returns live as
+    // long as the object stays loaded, and are copied out immediately
+    unsafe {
+        let mut info: libc::Dl_info = mem::zeroed();
+        if libc::dladdr(address as *const libc::c_void, &mut info) == 0 {
+            return None;
+        }
+        if !info.dli_sname.is_null() {
+            let name = CStr::from_ptr(info.dli_sname).to_string_lossy();
+            return Some((name.into_owned(), address - info.dli_saddr as usize));
+        }
+        if !info.dli_fname.is_null() {
+            let file = CStr::from_ptr(info.dli_fname).to_string_lossy();
+            let file = file.rsplit('/').next().unwrap_or_default();
+            return Some((file.to_string(), address - info.dli_fbase as usize));
+        }
+        None
+    }
+}
+
+/// Keeps a profiler started by [`FtfHandle::spawn_profiler`] running.
+///
+/// Dropping the guard stops the profiler, joins its thread and restores the
+/// previous `SIGPROF` action.
+#[must_use = "dropping the guard immediately stops the profiler"]
+#[derive(Debug)]
+pub struct ProfilerGuard {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ProfilerGuard {
+    pub(crate) fn spawn<W>(handle: FtfHandle<W>, interval: Duration) -> Self
+    where
+        W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        let mut symbols: HashMap<usize, String> = HashMap::new();
+        Self::spawn_with(interval, move |thread_id| {
+            let timestamp = handle.now();
+            let Some(addresses) = capture(thread_id) else {
+                return;
+            };
+            for &address in &addresses {
+                symbols.entry(address).or_insert_with(|| symbolize(address));
+            }
+            let frames: Vec<&str> = addresses.iter().map(|address| symbols[address].as_str()).collect();
+            handle.write_stack_sample(timestamp, thread_id, &frames);
+        })
+    }
+
+    /// Call `sample` with each running thread other than the sampler's own
+    /// every `interval`, for it to [`capture`]
+    pub(crate) fn spawn_with<F>(interval: Duration, mut sample: F) -> Self
+    where
+        F: FnMut(u64) + Send + 'static,
+    {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        if RUNNING.swap(true, Ordering::AcqRel) {
+            eprintln!("Error starting FTF profiler: a profiler is already running");
+            return Self { stop, handle: None };
+        }
+
+        let thread_stop = stop.clone();
+        let spawned = std::thread::Builder::new()
+            .name("ftfrs-tracing-profiler".to_string())
+            .spawn(move || {
+                let _handler = match Handler::install() {
+                    Ok(handler) => handler,
+                    Err(e) => {
+                        eprintln!("Error installing FTF profiler signal handler: {}", e);
+                        RUNNING.store(false, Ordering::Release);
+                        return;
+                    }
+                };
+
+                let own_id = crate::thread_id::current();
+                let (stopped, condvar) = &*thread_stop;
+                // Sampled without the lock held, so stopping doesn't wait out a sweep
+                while !*stopped.lock() {
+                    let threads = match running_threads() {
+                        Ok(threads) => threads,
+                        Err(e) => {
+                            eprintln!("Error listing threads to profile: {}", e);
+                            break;
+                        }
+                    };
+                    for thread_id in threads.into_iter().filter(|&id| id != own_id) {
+                        if *stopped.lock() {
+                            break;
+                        }
+                        sample(thread_id);
+                    }
+                    let mut stopped = stopped.lock();
+                    if !*stopped {
+                        condvar.wait_for(&mut stopped, interval);
+                    }
+                }
+                RUNNING.store(false, Ordering::Release);
+            });
+
+        let handle = match spawned {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                eprintln!("Error starting FTF profiler thread: {}", e);
+                RUNNING.store(false, Ordering::Release);
+                None
+            }
+        };
+        Self { stop, handle }
+    }
+}
+
+impl Drop for ProfilerGuard {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock() = true;
+        condvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                eprintln!("FTF profiler thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    fn current() -> u64 {
+        // SAFETY: gettid takes no arguments and cannot fail
+        unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+    }
+
+    #[test]
+    fn handler_answers_only_its_own_request() {
+        if RUNNING.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let own_id = current();
+
+        // A handler running late after the sampler moved on to another thread
+        let other = request(own_id + 1, REQUESTED);
+        SLOT.request.store(other, Ordering::Release);
+        handle_sigprof(0, ptr::null_mut(), ptr::null_mut());
+        assert_eq!(SLOT.request.load(Ordering::Acquire), other);
+
+        // A request the sampler has given up on
+        SLOT.request.store(IDLE, Ordering::Release);
+        handle_sigprof(0, ptr::null_mut(), ptr::null_mut());
+        assert_eq!(SLOT.request.load(Ordering::Acquire), IDLE);
+
+        SLOT.request.store(request(own_id, REQUESTED), Ordering::Release);
+        handle_sigprof(0, ptr::null_mut(), ptr::null_mut());
+        assert_eq!(SLOT.request.load(Ordering::Acquire), request(own_id, CAPTURED));
+        assert!(SLOT.len.load(Ordering::Relaxed) > 0);
+
+        SLOT.request.store(IDLE, Ordering::Release);
+        RUNNING.store(false, Ordering::Release);
+    }
+
+    #[test]
+    fn signals_left_pending_by_a_timed_out_capture_are_discarded() {
+        if RUNNING.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let handler = Handler::install().unwrap();
+
+        let (blocked_tx, blocked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            // SAFETY: the set is initialized by sigemptyset before use, and
+            // only this thread's mask changes
+            unsafe {
+                let mut set: libc::sigset_t = mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, libc::SIGPROF);
+                libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+                blocked_tx.send(current()).unwrap();
+                release_rx.recv().unwrap();
+                // Takes a still pending SIGPROF under the default action,
+                // terminating the test process
+                libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, ptr::null_mut());
+            }
+        });
+
+        let thread_id = blocked_rx.recv().unwrap();
+        assert_eq!(capture(thread_id), None, "the thread has SIGPROF blocked");
+        drop(handler);
+        release_tx.send(()).unwrap();
+        thread.join().unwrap();
+        RUNNING.store(false, Ordering::Release);
+    }
+
+    #[test]
+    fn request_keeps_thread_and_state_apart() {
+        let thread_id = u64::from(u32::MAX);
+        for state in [IDLE, REQUESTED, WRITING, CAPTURED] {
+            let word = request(thread_id, state);
+            assert_eq!(word >> STATE_BITS, thread_id);
+            assert_eq!(word & ((1 << STATE_BITS) - 1), state);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Helpers for hand-encoding records that ftfrs doesn't write.
+
+use std::io;
+
+/// Header field for a string reference: the index, or the inline flag and length
+pub(crate) fn string_field(string_ref: &ftfrs::StringRef) -> u64 {
+    match string_ref {
+        ftfrs::StringRef::Ref(id) => *id as u64,
+        ftfrs::StringRef::Inline(s) => 0x8000 | s.len() as u64,
+    }
+}
+
+/// Words a string reference takes up after the header
+pub(crate) fn string_words(string_ref: &ftfrs::StringRef) -> usize {
+    match string_ref {
+        ftfrs::StringRef::Ref(_) => 0,
+        ftfrs::StringRef::Inline(s) => s.len().div_ceil(8),
+    }
+}
+
+/// Write an inline string zero-padded to a whole number of words
+pub(crate) fn write_inline_string(writer: &mut dyn io::Write, string_ref: &ftfrs::StringRef) -> io::Result<()> {
+    if let ftfrs::StringRef::Inline(s) = string_ref {
+        writer.write_all(s.as_bytes())?;
+        let padding = s.len().div_ceil(8) * 8 - s.len();
+        writer.write_all(&[0; 8][..padding])?;
+    }
+    Ok(())
+}
+
+/// Length the header at the start of `bytes` gives its record, or `None` if
+/// the header is cut short
+pub(crate) fn declared_record_len(bytes: &[u8]) -> Option<usize> {
+    let header = u64::from_le_bytes(bytes.get(..8)?.try_into().expect("8 bytes"));
+    let words = if header & 0xF == 15 { (header >> 4) & 0xFFFF_FFFF } else { (header >> 4) & 0xFFF } as usize;
+    Some(words * 8)
+}
+
+/// Length of the record at the start of `bytes`, or all of `bytes` if its
+/// header is cut short or doesn't fit
+pub(crate) fn record_len(bytes: &[u8]) -> usize {
+    match declared_record_len(bytes) {
+        None | Some(0) => bytes.len(),
+        Some(len) => len.min(bytes.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_strings_are_padded_to_words() {
+        let inline = ftfrs::StringRef::Inline("nine byte".to_string());
+        assert_eq!(string_field(&inline), 0x8000 | 9);
+        assert_eq!(string_words(&inline), 2);
+        let mut bytes = Vec::new();
+        write_inline_string(&mut bytes, &inline).unwrap();
+        assert_eq!(bytes, b"nine byte\0\0\0\0\0\0\0");
+
+        let reference = ftfrs::StringRef::Ref(7);
+        assert_eq!((string_field(&reference), string_words(&reference)), (7, 0));
+        bytes.clear();
+        write_inline_string(&mut bytes, &reference).unwrap();
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn record_len_stops_at_the_end_of_the_bytes() {
+        let mut bytes = Vec::new();
+        ftfrs::Record::create_string(1, "a string".to_string()).write(&mut bytes).unwrap();
+        let len = bytes.len();
+        bytes.extend_from_slice(&[0xFF; 8]);
+        assert_eq!(record_len(&bytes), len);
+        assert_eq!(record_len(&bytes[..len - 1]), len - 1);
+        assert_eq!(record_len(&bytes[..4]), 4);
+        assert_eq!(record_len(&[0; 8]), 8, "a record of no words takes the rest");
+    }
+}
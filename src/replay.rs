@@ -0,0 +1,133 @@
+//! Catching up readers that join a trace part way through.
+//!
+//! The layer writes its header and each string and thread once, so a reader
+//! attaching later couldn't decode what follows. [`Replay`] watches the
+//! records going out, remembers the latest provider, string, thread and
+//! kernel object records, and encodes them for each new reader to get first.
+
+use std::collections::BTreeMap;
+
+/// FTF record types replayed to a new reader
+const METADATA_RECORD_TYPE: u64 = 0;
+const STRING_RECORD_TYPE: u64 = 2;
+const THREAD_RECORD_TYPE: u64 = 3;
+const KERNEL_OBJECT_RECORD_TYPE: u64 = 7;
+
+/// Large records carry a 32-bit size in words instead of a 12-bit one
+const LARGE_RECORD_TYPE: u64 = 15;
+
+/// Metadata type of provider info records
+const PROVIDER_INFO_TYPE: u64 = 1;
+
+/// The latest records a new reader needs before it can decode the trace
+#[derive(Debug, Default)]
+pub(crate) struct Replay {
+    provider_info: Option<Vec<u8>>,
+    strings: BTreeMap<u64, Vec<u8>>,
+    threads: BTreeMap<u64, Vec<u8>>,
+    kernel_objects: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Replay {
+    /// Remember the records in `bytes` that a new reader needs
+    pub(crate) fn observe(&mut self, mut bytes: &[u8]) {
+        while bytes.len() >= 8 {
+            let header = u64::from_le_bytes(bytes[..8].try_into().expect("8 bytes"));
+            let record_type = header & 0xF;
+            let words = if record_type == LARGE_RECORD_TYPE {
+                (header >> 4) & 0xFFFF_FFFF
+            } else {
+                (header >> 4) & 0xFFF
+            } as usize;
+            let len = words * 8;
+            if len == 0 || len > bytes.len() {
+                return;
+            }
+
+            let record = &bytes[..len];
+            match record_type {
+                METADATA_RECORD_TYPE if (header >> 16) & 0xF == PROVIDER_INFO_TYPE => {
+                    self.provider_info = Some(record.to_vec());
+                }
+                STRING_RECORD_TYPE => {
+                    self.strings.insert((header >> 16) & 0x7FFF, record.to_vec());
+                }
+                THREAD_RECORD_TYPE => {
+                    self.threads.insert((header >> 16) & 0xFF, record.to_vec());
+                }
+                KERNEL_OBJECT_RECORD_TYPE if len >= 16 => {
+                    let koid = u64::from_le_bytes(record[8..16].try_into().expect("8 bytes"));
+                    self.kernel_objects.insert(koid, record.to_vec());
+                }
+                _ => {}
+            }
+            bytes = &bytes[len..];
+        }
+    }
+
+    /// The header and tables, as records to write ahead of anything else
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if let Err(e) = ftfrs::Record::create_magic_number().write(&mut bytes) {
+            eprintln!("Error encoding FTF magic number: {}", e);
+        }
+        if let Some(provider_info) = &self.provider_info {
+            bytes.extend_from_slice(provider_info);
+        }
+        // Kernel objects name threads by koid, so they can follow the strings
+        // their names refer to
+        for record in self.strings.values().chain(self.threads.values()).chain(self.kernel_objects.values()) {
+            bytes.extend_from_slice(record);
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(records: &[ftfrs::Record]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for record in records {
+            record.write(&mut bytes).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn latest_tables_are_replayed_after_the_header() {
+        let provider = ftfrs::Record::create_provider_info(1, "trace".to_string());
+        let thread = ftfrs::Record::create_thread(1, 10, 11);
+        let mut replay = Replay::default();
+        replay.observe(&encode(&[
+            ftfrs::Record::create_magic_number(),
+            provider.clone(),
+            ftfrs::Record::create_initialization(1_000_000_000),
+            ftfrs::Record::create_string(1, "first".to_string()),
+            thread.clone(),
+        ]));
+        // Later records replace earlier ones with the same index, and may
+        // arrive in any order
+        replay.observe(&encode(&[
+            ftfrs::Record::create_string(2, "second".to_string()),
+            ftfrs::Record::create_string(1, "replaced".to_string()),
+        ]));
+
+        assert_eq!(replay.encode(), encode(&[
+            ftfrs::Record::create_magic_number(),
+            provider,
+            ftfrs::Record::create_string(1, "replaced".to_string()),
+            ftfrs::Record::create_string(2, "second".to_string()),
+            thread,
+        ]));
+    }
+
+    #[test]
+    fn records_cut_short_are_ignored() {
+        let string = encode(&[ftfrs::Record::create_string(1, "cut short".to_string())]);
+        let mut replay = Replay::default();
+        replay.observe(&string[..string.len() - 8]);
+        assert_eq!(replay.encode(), encode(&[ftfrs::Record::create_magic_number()]));
+    }
+}
@@ -0,0 +1,389 @@
+//! Rolling trace files, handed on for archiving once finished.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{trailer, Rotation, MAGIC_NUMBER_RECORD};
+
+/// Called with the path of each finished trace file
+type FileCompleteHandler = Arc<dyn Fn(&Path) + Send + Sync>;
+
+#[derive(Debug)]
+struct CurrentFile {
+    period: u64,
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+#[derive(Debug)]
+struct FileState {
+    dir: PathBuf,
+    prefix: String,
+    rotation: Rotation,
+    current: Option<CurrentFile>,
+    /// Bytes of the record being written
+    pending: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Completed {
+    paths: VecDeque<PathBuf>,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<FileState>,
+    completed: Mutex<Completed>,
+    not_empty: Condvar,
+    on_complete: Option<FileCompleteHandler>,
+}
+
+impl std::fmt::Debug for Shared {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Shared")
+            .field("state", &self.state)
+            .field("completed", &self.completed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Shared {
+    /// Write the records in `state.pending` to the current period's file,
+    /// starting a new file when they begin a trace header in a later period
+    fn deliver(&self, state: &mut FileState) {
+        let records = std::mem::take(&mut state.pending);
+        let period = state.rotation.current_period();
+        // A trailer ending the last chunk stays in that chunk's file
+        let trailer = trailer::leading_trailer_len(&records);
+        let roll = match &state.current {
+            Some(current) => current.period != period && records[trailer..].starts_with(&MAGIC_NUMBER_RECORD),
+            None => true,
+        };
+        let mut records = &records[..];
+        if roll {
+            write_records(state, &records[..trailer]);
+            records = &records[trailer..];
+            self.finish_file(state);
+            match open_file(state, period) {
+                Ok(current) => state.current = Some(current),
+                Err(e) => eprintln!("Error opening rolled FTF file: {}", e),
+            }
+        }
+        write_records(state, records);
+    }
+
+    /// Close the current file and queue it for the completion handler
+    fn finish_file(&self, state: &mut FileState) {
+        let Some(mut current) = state.current.take() else {
+            return;
+        };
+        if let Err(e) = current.file.flush().and_then(|()| current.file.get_ref().sync_all()) {
+            eprintln!("Error finishing FTF file {}: {}", current.path.display(), e);
+        }
+        if self.on_complete.is_some() {
+            self.completed.lock().paths.push_back(current.path);
+            self.not_empty.notify_one();
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock();
+        if !state.pending.is_empty() {
+            self.deliver(&mut state);
+        }
+        self.finish_file(&mut state);
+        drop(state);
+
+        self.completed.lock().closed = true;
+        self.not_empty.notify_all();
+    }
+
+    fn run(&self, on_complete: &FileCompleteHandler) {
+        loop {
+            let next = {
+                let mut completed = self.completed.lock();
+                while completed.paths.is_empty() && !completed.closed {
+                    self.not_empty.wait(&mut completed);
+                }
+                completed.paths.pop_front()
+            };
+            match next {
+                Some(path) => on_complete(&path),
+                None => return,
+            }
+        }
+    }
+}
+
+fn write_records(state: &mut FileState, records: &[u8]) {
+    if let Some(current) = &mut state.current {
+        if let Err(e) = current.file.write_all(records) {
+            eprintln!("Error writing to FTF file {}: {}", current.path.display(), e);
+        }
+    }
+}
+
+/// Open the file for `period`, appending if a trace was already written to
+/// it, e.g. by an earlier run
+fn open_file(state: &FileState, period: u64) -> io::Result<CurrentFile> {
+    let path = state
+        .dir
+        .join(format!("{}.{}.ftf", state.prefix, period_suffix(state.rotation, period)));
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    Ok(CurrentFile {
+        period,
+        path,
+        file: BufWriter::new(file),
+    })
+}
+
+/// The date and time `period` starts at, as `tracing_appender` formats it in
+/// file names
+fn period_suffix(rotation: Rotation, period: u64) -> String {
+    let seconds = period * rotation.period_seconds();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let (hour, minute) = (seconds % 86_400 / 3600, seconds % 3600 / 60);
+    match rotation {
+        Rotation::Minutely => format!("{:04}-{:02}-{:02}-{:02}-{:02}", year, month, day, hour, minute),
+        Rotation::Hourly => format!("{:04}-{:02}-{:02}-{:02}", year, month, day, hour),
+        Rotation::Daily => format!("{:04}-{:02}-{:02}", year, month, day),
+    }
+}
+
+/// The UTC date `days` days after the Unix epoch
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's algorithm, counting from 0000-03-01 so leap days end
+    // each 400-year era
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// A [`MakeWriter`] that writes the trace to a new file each period and
+/// hands every finished file to a handler, so traces are archived as they
+/// complete.
+///
+/// Files are named `<prefix>.<date>.ftf`, with the date formatted as
+/// `tracing_appender` does for the same [`Rotation`]. Set
+/// [`FtfLayerConfig::rotation`](crate::FtfLayerConfig::rotation) to the same
+/// schedule: the writer starts the next file exactly where the layer writes
+/// the next period's trace header, so no record lands in the wrong file.
+#[derive(Debug, Clone)]
+pub struct RollingFile {
+    shared: Arc<Shared>,
+}
+
+/// Writer handed out by [`RollingFile`], holding its file for one record.
+pub struct RollingFileWriter<'a> {
+    shared: &'a Shared,
+    state: MutexGuard<'a, FileState>,
+}
+
+impl std::fmt::Debug for RollingFileWriter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RollingFileWriter").field("state", &*self.state).finish_non_exhaustive()
+    }
+}
+
+impl io::Write for RollingFileWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.state.pending.is_empty() {
+            self.shared.deliver(&mut self.state);
+        }
+        match &mut self.state.current {
+            Some(current) => current.file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RollingFileWriter<'_> {
+    fn drop(&mut self) {
+        if !self.state.pending.is_empty() {
+            self.shared.deliver(&mut self.state);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for RollingFile {
+    type Writer = RollingFileWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RollingFileWriter {
+            shared: &self.shared,
+            state: self.shared.state.lock(),
+        }
+    }
+}
+
+/// Finishes the last file.
+///
+/// Dropping the guard closes the current file, passes it to the completion
+/// handler and waits for the handler to finish with every file, so hold on
+/// to it until tracing is done.
+#[must_use = "dropping the guard immediately finishes the current file"]
+#[derive(Debug)]
+pub struct RollingFileGuard {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for RollingFileGuard {
+    fn drop(&mut self) {
+        self.shared.close();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                eprintln!("FTF file completion thread panicked");
+            }
+        }
+    }
+}
+
+/// Builder for a [`RollingFile`].
+pub struct RollingFileBuilder {
+    dir: PathBuf,
+    prefix: String,
+    rotation: Rotation,
+    on_complete: Option<FileCompleteHandler>,
+    thread_name: String,
+}
+
+impl std::fmt::Debug for RollingFileBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RollingFileBuilder")
+            .field("dir", &self.dir)
+            .field("prefix", &self.prefix)
+            .field("rotation", &self.rotation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RollingFileBuilder {
+    /// Write files named after `prefix` to `dir`, starting a new one each
+    /// `rotation` period
+    pub fn new(dir: impl AsRef<Path>, prefix: impl Into<String>, rotation: Rotation) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            prefix: prefix.into(),
+            rotation,
+            on_complete: None,
+            thread_name: "ftfrs-tracing-archive".to_string(),
+        }
+    }
+
+    /// Call `handler` with each finished file, on a background thread, so it
+    /// may block, e.g. on an upload. To run async code, hand the path to a
+    /// runtime, e.g. with `tokio::runtime::Handle::block_on`.
+    pub fn on_complete(mut self, handler: impl Fn(&Path) + Send + Sync + 'static) -> Self {
+        self.on_complete = Some(Arc::new(handler));
+        self
+    }
+
+    /// Set the name of the thread running the completion handler
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// Create the directory if needed and start the completion thread
+    pub fn finish(self) -> io::Result<(RollingFile, RollingFileGuard)> {
+        fs::create_dir_all(&self.dir)?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(FileState {
+                dir: self.dir,
+                prefix: self.prefix,
+                rotation: self.rotation,
+                current: None,
+                pending: Vec::new(),
+            }),
+            completed: Mutex::new(Completed {
+                paths: VecDeque::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            on_complete: self.on_complete,
+        });
+
+        let handle = match shared.on_complete.clone() {
+            Some(on_complete) => {
+                let worker = Arc::clone(&shared);
+                Some(
+                    std::thread::Builder::new()
+                        .name(self.thread_name)
+                        .spawn(move || worker.run(&on_complete))?,
+                )
+            }
+            None => None,
+        };
+
+        Ok((
+            RollingFile {
+                shared: Arc::clone(&shared),
+            },
+            RollingFileGuard { shared, handle },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_named_like_tracing_appender() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+
+        let hours = 19_782 * 24 + 13;
+        assert_eq!(period_suffix(Rotation::Hourly, hours), "2024-02-29-13");
+        assert_eq!(period_suffix(Rotation::Minutely, hours * 60 + 5), "2024-02-29-13-05");
+        assert_eq!(period_suffix(Rotation::Daily, 19_782), "2024-02-29");
+    }
+
+    #[test]
+    fn finished_files_are_handed_on() {
+        let dir = std::env::temp_dir().join(format!("ftfrs-tracing-rolling-{}", std::process::id()));
+        let completed = Arc::new(Mutex::new(Vec::new()));
+        let (file, guard) = RollingFileBuilder::new(&dir, "trace", Rotation::Daily)
+            .on_complete({
+                let completed = completed.clone();
+                move |path| completed.lock().push(path.to_path_buf())
+            })
+            .finish()
+            .unwrap();
+
+        let mut trace = MAGIC_NUMBER_RECORD.to_vec();
+        ftfrs::Record::create_string(1, "name".to_string()).write(&mut trace).unwrap();
+        let mut writer = file.make_writer();
+        writer.write_all(&trace).unwrap();
+        drop(writer);
+        assert!(completed.lock().is_empty(), "the file is still being written");
+        drop(guard);
+
+        let completed = completed.lock();
+        assert_eq!(completed.len(), 1);
+        assert!(completed[0].file_name().unwrap().to_str().unwrap().starts_with("trace."));
+        assert_eq!(fs::read(&completed[0]).unwrap(), trace);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
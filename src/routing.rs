@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::memory::MemoryBudget;
+use crate::sink::RecordOutput;
+use crate::{write_header, Destination, FtfLayerConfig, FtfLayerStats, StringCache, ThreadCache};
+
+/// Routes records in selected categories to their own output files.
+///
+/// Files are opened lazily on the first record in their category. Each one
+/// starts with its own header and has its own intern tables.
+#[derive(Debug)]
+pub(crate) struct CategoryRouter {
+    /// Opened outputs by category; `None` records a file that failed to open
+    outputs: Mutex<HashMap<String, Option<Arc<Mutex<RoutedOutput>>>>>,
+    config: FtfLayerConfig,
+    memory: Arc<MemoryBudget>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RoutedOutput {
+    writer: File,
+    string_cache: StringCache,
+    thread_cache: ThreadCache,
+}
+
+impl RoutedOutput {
+    pub(crate) fn destination<'a>(&'a mut self, config: &'a FtfLayerConfig) -> Destination<'a> {
+        Destination::new(
+            &mut self.string_cache,
+            &mut self.thread_cache,
+            RecordOutput::new(&mut self.writer, &config.middleware, &[]),
+        )
+    }
+}
+
+impl CategoryRouter {
+    pub(crate) fn new(config: &FtfLayerConfig, memory: &Arc<MemoryBudget>) -> Self {
+        Self {
+            outputs: Mutex::new(HashMap::new()),
+            config: config.clone(),
+            memory: memory.clone(),
+        }
+    }
+
+    /// Get the output for `category`, or `None` if it goes to the main writer
+    pub(crate) fn output_for(&self, category: &str) -> Option<Arc<Mutex<RoutedOutput>>> {
+        let path = self.config.category_files.get(category)?;
+
+        let mut outputs = self.outputs.lock();
+        if let Some(output) = outputs.get(category) {
+            return output.clone();
+        }
+
+        let output = match File::create(path) {
+            Ok(writer) => {
+                let mut output = RoutedOutput {
+                    writer,
+                    string_cache: StringCache::new(&self.config, &self.memory),
+                    thread_cache: ThreadCache::new(&self.config, &self.memory),
+                };
+                write_header(&mut output.destination(&self.config), &self.config);
+                Some(Arc::new(Mutex::new(output)))
+            }
+            Err(e) => {
+                eprintln!(
+                    "Error opening FTF output for category {:?} at {}: {}",
+                    category,
+                    path.display(),
+                    e
+                );
+                None
+            }
+        };
+        outputs.insert(category.to_string(), output.clone());
+
+        output
+    }
+
+    /// Add the counters of every opened output to `stats`
+    pub(crate) fn add_stats(&self, stats: &mut FtfLayerStats) {
+        for output in self.outputs.lock().values().flatten() {
+            let output = output.lock();
+            let (strings, threads) = (output.string_cache.counters(), output.thread_cache.counters());
+            stats.strings += strings.stats();
+            stats.sanitized_strings += strings.sanitized();
+            stats.threads += threads.stats();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{self, Write};
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::{FtfLayer, FtfLayerConfig};
+
+    /// The main output, shared with the test
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn contains(bytes: &[u8], text: &str) -> bool {
+        bytes.windows(text.len()).any(|window| window == text.as_bytes())
+    }
+
+    #[test]
+    fn routed_categories_get_files_of_their_own() {
+        let dir = std::env::temp_dir().join(format!("ftfrs-tracing-routing-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.ftf");
+        let config = FtfLayerConfig {
+            category_files: [
+                ("db".to_string(), path.clone()),
+                ("cache".to_string(), dir.join("missing").join("cache.ftf")),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let output = Output::default();
+        let layer = FtfLayer::with_config(
+            {
+                let output = output.clone();
+                move || output.clone()
+            },
+            config,
+        );
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(ftf = true, category = "network", "request sent");
+            tracing::info!(ftf = true, category = "db", "query run");
+            // A file that can't be created sends its category to the main output
+            tracing::info!(ftf = true, category = "cache", "cache hit");
+        });
+
+        let mut header = Vec::new();
+        ftfrs::Record::create_magic_number().write(&mut header).unwrap();
+        let routed = fs::read(&path).unwrap();
+        assert!(routed.starts_with(&header));
+        assert!(contains(&routed, "query run"));
+        assert!(!contains(&routed, "request sent"));
+
+        let main = output.0.lock();
+        assert!(contains(&main, "request sent"));
+        assert!(contains(&main, "cache hit"));
+        assert!(!contains(&main, "query run"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing_core::{Level, Metadata};
+
+/// Whether span trees are buffered and written only if they turn out to be
+/// interesting. See [`FtfLayerConfig::tail_sampling`](crate::FtfLayerConfig::tail_sampling).
+#[derive(Clone, Default)]
+pub enum TailSampling {
+    /// Write every record as it happens
+    #[default]
+    Off,
+    /// Keep a tree if any span or event recorded in it is at `ERROR` level
+    KeepOnError,
+    /// Keep a tree if the predicate holds for any span or event recorded in it
+    KeepIf(Arc<dyn Fn(&Metadata<'_>) -> bool + Send + Sync>),
+    /// Keep a tree if its root span was open for at least this long
+    KeepIfLongerThan(Duration),
+}
+
+impl TailSampling {
+    pub(crate) fn is_enabled(&self) -> bool {
+        !matches!(self, Self::Off)
+    }
+
+    /// Whether a record with `metadata` makes its tree worth keeping
+    pub(crate) fn keeps(&self, metadata: &Metadata<'_>) -> bool {
+        match self {
+            Self::Off => true,
+            Self::KeepOnError => *metadata.level() == Level::ERROR,
+            Self::KeepIf(predicate) => predicate(metadata),
+            Self::KeepIfLongerThan(_) => false,
+        }
+    }
+
+    /// Whether a root span open for `duration_ns` makes its tree worth keeping
+    pub(crate) fn keeps_root(&self, duration_ns: u64) -> bool {
+        match self {
+            Self::KeepIfLongerThan(threshold) => u128::from(duration_ns) >= threshold.as_nanos(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for TailSampling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Off => f.write_str("Off"),
+            Self::KeepOnError => f.write_str("KeepOnError"),
+            Self::KeepIf(_) => f.write_str("KeepIf(..)"),
+            Self::KeepIfLongerThan(threshold) => f.debug_tuple("KeepIfLongerThan").field(threshold).finish(),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Scheduling samples for the process's threads, read from procfs, and the
+//! context switch records they are written as, which ftfrs doesn't write.
+//!
+//! Context switch records follow the FTF scheduling record layout: header
+//! (with the CPU and outgoing thread state), timestamp, outgoing thread koid
+//! and incoming thread koid, with no arguments.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::{fs, io};
+
+use parking_lot::{Condvar, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::FtfHandle;
+
+/// FTF record type of scheduling records
+const SCHEDULING_RECORD_TYPE: u64 = 8;
+
+/// Scheduling record type of context switch records
+const CONTEXT_SWITCH_TYPE: u64 = 1;
+
+/// Koid standing for no thread, i.e. the CPU going idle or an unknown thread
+pub(crate) const IDLE_KOID: u64 = 0;
+
+/// Zircon thread states, as encoded in a context switch record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ThreadState {
+    Running = 1,
+    Suspended = 2,
+    Blocked = 3,
+    Dead = 5,
+}
+
+impl ThreadState {
+    /// Map a `/proc/<pid>/task/<tid>/stat` state letter
+    fn from_proc(state: char) -> Self {
+        match state {
+            'R' => Self::Running,
+            'T' | 't' => Self::Suspended,
+            'Z' | 'X' | 'x' => Self::Dead,
+            _ => Self::Blocked,
+        }
+    }
+}
+
+/// Scheduling counters and state of one thread at one sample
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ThreadSample {
+    /// Nanoseconds spent on a CPU
+    pub(crate) cpu_ns: u64,
+    /// Nanoseconds spent runnable but waiting for a CPU
+    pub(crate) wait_ns: u64,
+    /// Times the thread was scheduled onto a CPU
+    pub(crate) slices: u64,
+    pub(crate) state: ThreadState,
+    /// CPU the thread last ran on
+    pub(crate) cpu: u16,
+}
+
+/// Sample every thread of this process, by OS thread id
+pub(crate) fn read_threads() -> io::Result<HashMap<u64, ThreadSample>> {
+    let mut threads = HashMap::new();
+    for entry in fs::read_dir("/proc/self/task")? {
+        let entry = entry?;
+        let Some(tid) = entry.file_name().to_str().and_then(|name| name.parse().ok()) else {
+            continue;
+        };
+        // Threads exiting while we read are simply skipped
+        if let Ok(sample) = read_thread(&entry.path()) {
+            threads.insert(tid, sample);
+        }
+    }
+    Ok(threads)
+}
+
+fn read_thread(task: &std::path::Path) -> io::Result<ThreadSample> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "unexpected procfs format");
+
+    let schedstat = fs::read_to_string(task.join("schedstat"))?;
+    let mut counters = schedstat.split_whitespace().map(str::parse::<u64>);
+    let mut counter = || counters.next().and_then(Result::ok).ok_or_else(invalid);
+    let (cpu_ns, wait_ns, slices) = (counter()?, counter()?, counter()?);
+
+    // The command name may contain spaces and parentheses, so fields are
+    // counted from the last `)`: the state is field 3 and the CPU field 39
+    let stat = fs::read_to_string(task.join("stat"))?;
+    let fields = stat.rsplit_once(')').ok_or_else(invalid)?.1;
+    let mut fields = fields.split_whitespace();
+    let state = fields.next().and_then(|state| state.chars().next()).ok_or_else(invalid)?;
+    let cpu = fields.nth(35).and_then(|cpu| cpu.parse().ok()).ok_or_else(invalid)?;
+
+    Ok(ThreadSample {
+        cpu_ns,
+        wait_ns,
+        slices,
+        state: ThreadState::from_proc(state),
+        cpu,
+    })
+}
+
+/// Write a context switch record for `cpu` at `timestamp`
+pub(crate) fn write_context_switch(
+    writer: &mut dyn io::Write,
+    timestamp: u64,
+    cpu: u16,
+    outgoing_state: ThreadState,
+    outgoing_koid: u64,
+    incoming_koid: u64,
+) -> io::Result<()> {
+    let header = SCHEDULING_RECORD_TYPE
+        | 4 << 4
+        | (cpu as u64) << 20
+        | (outgoing_state as u64) << 36
+        | CONTEXT_SWITCH_TYPE << 60;
+    writer.write_all(&header.to_le_bytes())?;
+    writer.write_all(&timestamp.to_le_bytes())?;
+    writer.write_all(&outgoing_koid.to_le_bytes())?;
+    writer.write_all(&incoming_koid.to_le_bytes())
+}
+
+/// Keeps a sampler started by [`FtfHandle::spawn_sched_sampler`] running.
+///
+/// Dropping the guard stops the sampler and joins its thread.
+#[must_use = "dropping the guard immediately stops the sampler"]
+#[derive(Debug)]
+pub struct SchedSamplerGuard {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SchedSamplerGuard {
+    pub(crate) fn spawn<W>(handle: FtfHandle<W>, interval: Duration) -> Self
+    where
+        W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let spawned = std::thread::Builder::new()
+            .name("ftfrs-tracing-sched".to_string())
+            .spawn(move || {
+                let mut previous = HashMap::new();
+                let (stopped, condvar) = &*thread_stop;
+                let mut stopped = stopped.lock();
+                while !*stopped {
+                    match read_threads() {
+                        Ok(current) => {
+                            handle.write_sched_samples(&previous, &current);
+                            previous = current;
+                        }
+                        Err(e) => {
+                            eprintln!("Error reading thread scheduling stats: {}", e);
+                            return;
+                        }
+                    }
+                    condvar.wait_for(&mut stopped, interval);
+                }
+            });
+
+        let handle = match spawned {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                eprintln!("Error starting FTF scheduler sampling thread: {}", e);
+                None
+            }
+        };
+        Self { stop, handle }
+    }
+}
+
+impl Drop for SchedSamplerGuard {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock() = true;
+        condvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                eprintln!("FTF scheduler sampling thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_switches_follow_the_scheduling_record_layout() {
+        let mut bytes = Vec::new();
+        write_context_switch(&mut bytes, 1_000, 3, ThreadState::Blocked, 41, IDLE_KOID).unwrap();
+        let words: Vec<u64> = bytes.chunks(8).map(|word| u64::from_le_bytes(word.try_into().unwrap())).collect();
+        assert_eq!(words.len(), 4);
+
+        let header = words[0];
+        assert_eq!(header & 0xF, SCHEDULING_RECORD_TYPE);
+        assert_eq!(header >> 4 & 0xFFF, 4, "the size counts every word");
+        assert_eq!(header >> 20 & 0xFFFF, 3);
+        assert_eq!(header >> 36 & 0xF, ThreadState::Blocked as u64);
+        assert_eq!(header >> 60, CONTEXT_SWITCH_TYPE);
+        assert_eq!(words[1..], [1_000, 41, IDLE_KOID]);
+    }
+
+    #[test]
+    fn proc_states_map_to_zircon_states() {
+        assert_eq!(ThreadState::from_proc('R'), ThreadState::Running);
+        assert_eq!(ThreadState::from_proc('S'), ThreadState::Blocked);
+        assert_eq!(ThreadState::from_proc('D'), ThreadState::Blocked);
+        assert_eq!(ThreadState::from_proc('t'), ThreadState::Suspended);
+        assert_eq!(ThreadState::from_proc('Z'), ThreadState::Dead);
+    }
+
+    #[test]
+    fn the_sampling_thread_is_seen_running() {
+        let threads = read_threads().unwrap();
+        let sample = threads.get(&crate::thread_id::current()).expect("the calling thread is sampled");
+        assert_eq!(sample.state, ThreadState::Running);
+        assert!(sample.slices > 0);
+    }
+}
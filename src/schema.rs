@@ -0,0 +1,94 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use parking_lot::Mutex;
+
+/// Names of each kind remembered before further names are left out
+const MAX_SCHEMA_NAMES: usize = 4096;
+
+/// Kind of record a name was seen on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SchemaKind {
+    Span,
+    Event,
+    Counter,
+}
+
+/// Categories and record names seen, for
+/// [`FtfLayerConfig::schema_record`](crate::FtfLayerConfig::schema_record)
+#[derive(Debug, Default)]
+pub(crate) struct Schema {
+    seen: Mutex<Seen>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    categories: BTreeSet<String>,
+    spans: BTreeSet<String>,
+    events: BTreeSet<String>,
+    counters: BTreeSet<String>,
+}
+
+impl Schema {
+    pub(crate) fn note(&self, kind: SchemaKind, category: &str, name: &str) {
+        let mut seen = self.seen.lock();
+        insert(&mut seen.categories, category);
+        let names = match kind {
+            SchemaKind::Span => &mut seen.spans,
+            SchemaKind::Event => &mut seen.events,
+            SchemaKind::Counter => &mut seen.counters,
+        };
+        insert(names, name);
+    }
+
+    /// Everything seen so far as a JSON object of sorted name lists
+    pub(crate) fn to_json(&self) -> String {
+        let seen = self.seen.lock();
+        let mut json = String::from("{");
+        let lists = [
+            ("categories", &seen.categories),
+            ("spans", &seen.spans),
+            ("events", &seen.events),
+            ("counters", &seen.counters),
+        ];
+        for (index, (key, names)) in lists.into_iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            let _ = write!(json, "\"{}\":[", key);
+            for (index, name) in names.iter().enumerate() {
+                if index > 0 {
+                    json.push(',');
+                }
+                push_json_string(&mut json, name);
+            }
+            json.push(']');
+        }
+        json.push('}');
+        json
+    }
+}
+
+fn insert(names: &mut BTreeSet<String>, name: &str) {
+    if names.len() < MAX_SCHEMA_NAMES && !names.contains(name) {
+        names.insert(name.to_string());
+    }
+}
+
+fn push_json_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
@@ -0,0 +1,71 @@
+//! Scoped threads whose root span is tied to the spawning span.
+
+use std::thread::{Scope, ScopedJoinHandle};
+
+use tracing::{dispatcher, Dispatch, Span};
+
+use crate::link::{begin_flow, end_flow};
+
+/// A wrapper around a [`std::thread::Scope`] whose threads start in a root
+/// `thread` span, linked by a flow arrow to the span they were spawned from.
+///
+/// ```
+/// use ftfrs_tracing::FtfScope;
+///
+/// let _request = tracing::info_span!("request", ftf = true).entered();
+/// std::thread::scope(|scope| {
+///     let scope = FtfScope::new(scope);
+///     scope.spawn(|| tracing::info!(ftf = true, "working"));
+/// });
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct FtfScope<'scope, 'env> {
+    scope: &'scope Scope<'scope, 'env>,
+}
+
+impl<'scope, 'env> FtfScope<'scope, 'env> {
+    /// Wrap `scope`
+    pub fn new(scope: &'scope Scope<'scope, 'env>) -> Self {
+        Self { scope }
+    }
+
+    /// Spawn a scoped thread running `f` in a root `thread` span, as
+    /// [`Scope::spawn`] does.
+    ///
+    /// A flow begins now, in the current span, and ends when the thread
+    /// starts. The thread also uses the current dispatcher.
+    pub fn spawn<F, T>(&self, f: F) -> ScopedJoinHandle<'scope, T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        self.scope.spawn(in_thread_span(f))
+    }
+}
+
+/// Wrap a closure that runs as the body of a new thread, such as one spawned
+/// in a crossbeam scope, so that it runs in a root `thread` span linked to
+/// the current span as [`FtfScope::spawn`] does.
+///
+/// ```
+/// let body = ftfrs_tracing::in_thread_span(|| 2 + 2);
+/// assert_eq!(std::thread::spawn(body).join().unwrap(), 4);
+/// ```
+pub fn in_thread_span<F, T>(f: F) -> impl FnOnce() -> T + Send
+where
+    F: FnOnce() -> T + Send,
+{
+    let dispatch = dispatcher::get_default(Dispatch::clone);
+    let flow_id = begin_flow(&Span::current());
+
+    move || {
+        dispatcher::with_default(&dispatch, || {
+            let thread = tracing::info_span!(parent: None, "thread", ftf = true);
+            let _entered = thread.enter();
+            if let Some(flow_id) = flow_id {
+                end_flow(&thread, flow_id);
+            }
+            f()
+        })
+    }
+}
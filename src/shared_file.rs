@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use parking_lot::Mutex;
+
+use crate::{FtfLayer, FtfLayerConfig, OwnedWriter};
+
+/// A layer owning the file it writes to
+pub(crate) type FileLayer = FtfLayer<OwnedWriter<File>>;
+
+/// Layers opened through [`FtfLayer::shared_file`], by canonical output path
+static SHARED_FILES: OnceLock<Mutex<HashMap<PathBuf, FileLayer>>> = OnceLock::new();
+
+/// Get a layer writing to `path`, creating the file with `config` if no
+/// layer in this process writes to it yet, and sharing the existing layer's
+/// output otherwise
+pub(crate) fn layer_for(path: &Path, config: FtfLayerConfig) -> io::Result<FileLayer> {
+    let path = canonical_path(path)?;
+    let mut layers = SHARED_FILES.get_or_init(Default::default).lock();
+    if let Some(layer) = layers.get(&path) {
+        return Ok(layer.clone());
+    }
+
+    let layer = FtfLayer::with_writer_and_config(File::create(&path)?, config);
+    layers.insert(path, layer.clone());
+    Ok(layer)
+}
+
+/// Resolve `path` so that different spellings of it share a key. Only the
+/// directory has to exist, since the file may not have been created yet.
+fn canonical_path(path: &Path) -> io::Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "FTF output path has no file name"))?;
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    Ok(directory.canonicalize()?.join(file_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    fn occurs_once(bytes: &[u8], text: &[u8]) -> bool {
+        bytes.windows(text.len()).filter(|window| *window == text).count() == 1
+    }
+
+    #[test]
+    fn layers_for_one_path_share_its_output() {
+        let directory = std::env::temp_dir().join(format!("ftfrs-tracing-shared-file-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("trace.ftf");
+
+        let first = FtfLayer::shared_file(&path, FtfLayerConfig::default()).unwrap();
+        let second = FtfLayer::shared_file(directory.join(".").join("trace.ftf"), FtfLayerConfig::default()).unwrap();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(first), || {
+            tracing::info!(ftf = true, "from the first component");
+        });
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(second), || {
+            tracing::info!(ftf = true, "from the second component");
+        });
+
+        let written = fs::read(&path).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        let mut header = Vec::new();
+        ftfrs::Record::create_magic_number().write(&mut header).unwrap();
+        assert!(written.starts_with(&header));
+        assert!(occurs_once(&written, &header), "the file has a single header");
+        assert!(occurs_once(&written, b"from the first component"));
+        assert!(occurs_once(&written, b"from the second component"));
+    }
+
+    #[test]
+    fn paths_need_a_file_name() {
+        let error = canonical_path(Path::new("/")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(canonical_path(Path::new("trace.ftf")).unwrap(), std::env::current_dir().unwrap().join("trace.ftf"));
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use crate::event::EventRecord;
+
+/// A consumer of the records written to the main output, plugged in
+/// through [`FtfLayerConfig::sinks`](crate::FtfLayerConfig::sinks).
+///
+/// Sinks see every record the FTF file gets, in the same order and after it
+/// was written: the header, string and thread records, then events. Records
+/// refer to strings and threads by the indices those earlier records assign,
+/// so a sink that resolves references must track them too. Flow and kernel
+/// object records have no [`ftfrs::Record`] form and are not passed on, and
+/// neither are records routed to [`FtfLayerConfig::category_files`](crate::FtfLayerConfig::category_files).
+///
+/// `write_record` runs on the instrumented thread with the output locked, so
+/// it should be quick; hand anything slow off to another thread.
+pub trait RecordSink: Send + Sync {
+    fn write_record(&self, record: &ftfrs::Record);
+}
+
+impl fmt::Debug for dyn RecordSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RecordSink")
+    }
+}
+
+/// A step in [`FtfLayerConfig::middleware`](crate::FtfLayerConfig::middleware)
+/// that sees each record before it is written, and may change it or drop it
+/// by returning `None`.
+///
+/// Middleware applies to every output, including per-category files, and to
+/// string and thread records as well as events. Rewriting a string record's
+/// text is a cheap way to scrub every use of it; dropping a string or thread
+/// record leaves the records that refer to it unresolved.
+///
+/// Closures of the right shape are middleware:
+///
+/// ```
+/// use ftfrs_tracing::RecordMiddleware;
+///
+/// fn drop_metadata() -> impl RecordMiddleware {
+///     |record: ftfrs::Record| match record {
+///         ftfrs::Record::Metadata(_) => None,
+///         record => Some(record),
+///     }
+/// }
+/// ```
+pub trait RecordMiddleware: Send + Sync {
+    fn process(&self, record: ftfrs::Record) -> Option<ftfrs::Record>;
+}
+
+impl<F> RecordMiddleware for F
+where
+    F: Fn(ftfrs::Record) -> Option<ftfrs::Record> + Send + Sync,
+{
+    fn process(&self, record: ftfrs::Record) -> Option<ftfrs::Record> {
+        self(record)
+    }
+}
+
+impl fmt::Debug for dyn RecordMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RecordMiddleware")
+    }
+}
+
+/// An output's writer together with the middleware its records pass through
+/// and the sinks they are also passed to
+pub(crate) struct RecordOutput<'a> {
+    writer: &'a mut dyn io::Write,
+    /// Where records ftfrs can't represent go instead of `writer`, if set
+    raw: Option<&'a mut dyn io::Write>,
+    middleware: &'a [Arc<dyn RecordMiddleware>],
+    sinks: &'a [Arc<dyn RecordSink>],
+}
+
+impl<'a> RecordOutput<'a> {
+    pub(crate) fn new(
+        writer: &'a mut dyn io::Write,
+        middleware: &'a [Arc<dyn RecordMiddleware>],
+        sinks: &'a [Arc<dyn RecordSink>],
+    ) -> Self {
+        Self {
+            writer,
+            raw: None,
+            middleware,
+            sinks,
+        }
+    }
+
+    /// Send records ftfrs can't represent to `raw` rather than the writer
+    pub(crate) fn with_raw(self, raw: &'a mut dyn io::Write) -> Self {
+        Self { raw: Some(raw), ..self }
+    }
+
+    /// Borrow this output for a shorter lifetime
+    pub(crate) fn reborrow(&mut self) -> RecordOutput<'_> {
+        RecordOutput {
+            writer: &mut *self.writer,
+            raw: match &mut self.raw {
+                Some(raw) => Some(&mut **raw),
+                None => None,
+            },
+            middleware: self.middleware,
+            sinks: self.sinks,
+        }
+    }
+
+    /// Run a record through the middleware, then write what is left and
+    /// pass it to every sink
+    pub(crate) fn write(&mut self, mut record: ftfrs::Record) -> Result<(), ftfrs::FtfError> {
+        for middleware in self.middleware {
+            record = match middleware.process(record) {
+                Some(record) => record,
+                None => return Ok(()),
+            };
+        }
+
+        record.write(&mut self.writer)?;
+        for sink in self.sinks {
+            sink.write_record(&record);
+        }
+        Ok(())
+    }
+
+    /// Write an event record, encoding it by hand unless middleware or sinks
+    /// need it as an [`ftfrs::Record`]
+    pub(crate) fn write_event(&mut self, event: EventRecord<'_>) -> Result<(), ftfrs::FtfError> {
+        if self.middleware.is_empty() && self.sinks.is_empty() {
+            return Ok(event.write(&mut *self.writer)?);
+        }
+        self.write(event.into_record())
+    }
+
+    /// The writer for records ftfrs can't represent
+    pub(crate) fn raw(&mut self) -> &mut dyn io::Write {
+        match &mut self.raw {
+            Some(raw) => &mut **raw,
+            None => &mut *self.writer,
+        }
+    }
+}
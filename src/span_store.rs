@@ -0,0 +1,298 @@
+//! Per-span state, wherever the spans are stored.
+//!
+//! The layer keeps a few values of its own on each span and walks up to its
+//! parents, which [`SpanView`] abstracts over. Under a registry those are
+//! the registry's spans; [`FtfSubscriber`](crate::FtfSubscriber) keeps its
+//! spans in a [`SpanStore`] instead, which holds only what the layer and the
+//! subscriber need: metadata, parents, reference counts, the layer's values
+//! and a stack of entered spans per thread, without storing fields or
+//! filtering per layer.
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use parking_lot::{Mutex, RwLock};
+use tracing_core::span::{Attributes, Id};
+use tracing_core::Metadata;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+/// A span the layer records to, with the values it attaches to it
+pub(crate) trait SpanView: Sized {
+    fn metadata(&self) -> &'static Metadata<'static>;
+
+    fn parent(&self) -> Option<Self>;
+
+    /// Run `f` on the value of type `T` attached to the span, if any
+    fn with<T: Send + Sync + 'static, R>(&self, f: impl FnOnce(&T) -> R) -> Option<R>;
+
+    // Only allocation tracking updates values in place
+    #[cfg_attr(not(feature = "alloc-track"), allow(dead_code))]
+    fn with_mut<T: Send + Sync + 'static, R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R>;
+
+    /// Attach `value`, replacing any value of the same type
+    fn insert<T: Send + Sync + 'static>(&self, value: T);
+
+    fn remove<T: Send + Sync + 'static>(&self) -> Option<T>;
+
+    fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.with(T::clone)
+    }
+}
+
+impl<'a, S: LookupSpan<'a>> SpanView for SpanRef<'a, S> {
+    fn metadata(&self) -> &'static Metadata<'static> {
+        SpanRef::metadata(self)
+    }
+
+    fn parent(&self) -> Option<Self> {
+        SpanRef::parent(self)
+    }
+
+    fn with<T: Send + Sync + 'static, R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.extensions().get::<T>().map(f)
+    }
+
+    fn with_mut<T: Send + Sync + 'static, R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.extensions_mut().get_mut::<T>().map(f)
+    }
+
+    fn insert<T: Send + Sync + 'static>(&self, value: T) {
+        self.extensions_mut().replace(value);
+    }
+
+    fn remove<T: Send + Sync + 'static>(&self) -> Option<T> {
+        self.extensions_mut().remove::<T>()
+    }
+}
+
+/// Gives each store its own stacks of entered spans
+static NEXT_STORE: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Spans entered on this thread, innermost last, per store
+    static ENTERED: RefCell<Vec<(u64, Vec<Id>)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Spans of one subscriber, by id
+pub(crate) struct SpanStore {
+    store: u64,
+    spans: RwLock<HashMap<u64, Arc<StoredSpan>>>,
+    next_id: AtomicU64,
+}
+
+struct StoredSpan {
+    metadata: &'static Metadata<'static>,
+    parent: Option<Id>,
+    /// Handles to the span, plus one for each open child
+    refs: AtomicUsize,
+    values: Mutex<HashMap<TypeId, Box<dyn Any + Send + Sync>>>,
+}
+
+/// A span in a [`SpanStore`]
+pub(crate) struct StoredSpanRef<'a> {
+    store: &'a SpanStore,
+    span: Arc<StoredSpan>,
+}
+
+impl SpanStore {
+    pub(crate) fn new() -> Self {
+        Self {
+            store: NEXT_STORE.fetch_add(1, Ordering::Relaxed),
+            spans: RwLock::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Store a new span, holding on to its parent until it closes
+    pub(crate) fn create(&self, attrs: &Attributes<'_>) -> Id {
+        let parent = if let Some(parent) = attrs.parent() {
+            Some(parent.clone())
+        } else if attrs.is_contextual() {
+            self.current()
+        } else {
+            None
+        };
+        let parent = parent.filter(|parent| self.clone_span(parent));
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.spans.write().insert(id, Arc::new(StoredSpan {
+            metadata: attrs.metadata(),
+            parent,
+            refs: AtomicUsize::new(1),
+            values: Mutex::new(HashMap::new()),
+        }));
+        Id::from_u64(id)
+    }
+
+    pub(crate) fn span(&self, id: &Id) -> Option<StoredSpanRef<'_>> {
+        let span = self.spans.read().get(&id.into_u64())?.clone();
+        Some(StoredSpanRef { store: self, span })
+    }
+
+    /// Count another handle to span `id`, returning whether it exists
+    pub(crate) fn clone_span(&self, id: &Id) -> bool {
+        let spans = self.spans.read();
+        let Some(span) = spans.get(&id.into_u64()) else {
+            return false;
+        };
+        span.refs.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Drop a handle to span `id`, calling `close` with the span before
+    /// removing it if that was the last one, and then doing the same for
+    /// its parent. Returns whether span `id` closed.
+    pub(crate) fn try_close(&self, id: &Id, close: &dyn Fn(&Id, StoredSpanRef<'_>)) -> bool {
+        let Some(span) = self.span(id) else {
+            return false;
+        };
+        if span.span.refs.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return false;
+        }
+
+        let parent = span.span.parent.clone();
+        close(id, span);
+        self.spans.write().remove(&id.into_u64());
+        if let Some(parent) = parent {
+            self.try_close(&parent, close);
+        }
+        true
+    }
+
+    pub(crate) fn enter(&self, id: &Id) {
+        self.with_entered(|entered| entered.push(id.clone()));
+    }
+
+    pub(crate) fn exit(&self, id: &Id) {
+        self.with_entered(|entered| {
+            if let Some(position) = entered.iter().rposition(|entered| entered == id) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    /// The span most recently entered on this thread and not yet exited
+    pub(crate) fn current(&self) -> Option<Id> {
+        self.with_entered(|entered| entered.last().cloned())
+    }
+
+    fn with_entered<R>(&self, f: impl FnOnce(&mut Vec<Id>) -> R) -> R {
+        ENTERED.with(|stacks| {
+            let mut stacks = stacks.borrow_mut();
+            let index = match stacks.iter().position(|(store, _)| *store == self.store) {
+                Some(index) => index,
+                None => {
+                    stacks.push((self.store, Vec::new()));
+                    stacks.len() - 1
+                }
+            };
+            f(&mut stacks[index].1)
+        })
+    }
+}
+
+impl fmt::Debug for SpanStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpanStore").field("spans", &self.spans.read().len()).finish()
+    }
+}
+
+impl SpanView for StoredSpanRef<'_> {
+    fn metadata(&self) -> &'static Metadata<'static> {
+        self.span.metadata
+    }
+
+    fn parent(&self) -> Option<Self> {
+        self.store.span(self.span.parent.as_ref()?)
+    }
+
+    fn with<T: Send + Sync + 'static, R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.span.values.lock().get(&TypeId::of::<T>())?.downcast_ref().map(f)
+    }
+
+    fn with_mut<T: Send + Sync + 'static, R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.span.values.lock().get_mut(&TypeId::of::<T>())?.downcast_mut().map(f)
+    }
+
+    fn insert<T: Send + Sync + 'static>(&self, value: T) {
+        self.span.values.lock().insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    fn remove<T: Send + Sync + 'static>(&self) -> Option<T> {
+        let value = self.span.values.lock().remove(&TypeId::of::<T>())?;
+        value.downcast().ok().map(|value| *value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a span in `store` as a child of `parent`, or as a root
+    fn create(store: &SpanStore, parent: Option<&Id>) -> Id {
+        let metadata = tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+            tracing::info_span!("span").metadata().expect("span has metadata")
+        });
+        let values = metadata.fields().value_set(&[]);
+        let attrs = match parent {
+            Some(parent) => Attributes::child_of(parent.clone(), metadata, &values),
+            None => Attributes::new_root(metadata, &values),
+        };
+        store.create(&attrs)
+    }
+
+    #[test]
+    fn parents_close_after_their_children() {
+        let store = SpanStore::new();
+        let parent = create(&store, None);
+        let child = create(&store, Some(&parent));
+        assert!(store.span(&child).unwrap().parent().is_some());
+
+        let closed = RefCell::new(Vec::new());
+        let close = |id: &Id, _: StoredSpanRef<'_>| closed.borrow_mut().push(id.clone());
+        assert!(!store.try_close(&parent, &close), "the child still holds its parent");
+        assert!(store.clone_span(&child));
+        assert!(!store.try_close(&child, &close));
+        assert!(store.try_close(&child, &close));
+        assert_eq!(*closed.borrow(), [child.clone(), parent.clone()]);
+        assert!(store.span(&parent).is_none());
+        assert!(!store.try_close(&child, &close));
+    }
+
+    #[test]
+    fn values_are_kept_per_type() {
+        let store = SpanStore::new();
+        let span = store.span(&create(&store, None)).unwrap();
+        span.insert(7u64);
+        span.insert(String::from("category"));
+        span.insert(8u64);
+        assert_eq!(span.get::<u64>(), Some(8));
+        span.with_mut(|value: &mut u64| *value += 1);
+        assert_eq!(span.remove::<u64>(), Some(9));
+        assert_eq!(span.get::<u64>(), None);
+        assert_eq!(span.get::<String>().as_deref(), Some("category"));
+    }
+
+    #[test]
+    fn entered_spans_are_per_store_and_thread() {
+        let (first, second) = (SpanStore::new(), SpanStore::new());
+        let (outer, inner) = (create(&first, None), create(&first, None));
+        first.enter(&outer);
+        first.enter(&inner);
+        assert_eq!(first.current(), Some(inner.clone()));
+        assert_eq!(second.current(), None);
+        std::thread::scope(|scope| {
+            scope.spawn(|| assert_eq!(first.current(), None));
+        });
+
+        // Exiting out of order leaves the other span current
+        first.exit(&outer);
+        assert_eq!(first.current(), Some(inner.clone()));
+        first.exit(&inner);
+        assert_eq!(first.current(), None);
+    }
+}
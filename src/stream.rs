@@ -0,0 +1,128 @@
+//! Instrumentation for stream and sink implementations.
+//!
+//! This crate doesn't depend on `futures`, so rather than adapters
+//! implementing `Stream` and `Sink`, [`FtfStreamTracer`] wraps the calls an
+//! adapter forwards to the inner stream or sink.
+
+use std::task::Poll;
+
+use tracing::{dispatcher, Dispatch};
+
+use crate::future::ManualHooks;
+
+/// Traces the polls and items of one stream or sink into an
+/// [`FtfLayer`](crate::FtfLayer).
+///
+/// Every `poll_next` or `poll_ready` is written as a duration on the polling
+/// thread, so a sink holding back a producer shows up as a run of short
+/// pending polls. Items, or every `n`th item with
+/// [`sample_items`](Self::sample_items), are written as instant events with
+/// their index. Nothing is written unless the current subscriber has an
+/// `FtfLayer`.
+///
+/// ```
+/// use std::pin::Pin;
+/// use std::task::{Context, Poll};
+///
+/// use ftfrs_tracing::FtfStreamTracer;
+///
+/// struct Traced<S> {
+///     inner: S,
+///     tracer: FtfStreamTracer,
+/// }
+///
+/// impl<S: Unpin> Traced<S> {
+///     // The body of `futures::Stream::poll_next` for an `S: Stream`
+///     fn poll_next<T>(
+///         &mut self,
+///         cx: &mut Context<'_>,
+///         poll: impl FnOnce(Pin<&mut S>, &mut Context<'_>) -> Poll<Option<T>>,
+///     ) -> Poll<Option<T>> {
+///         let inner = Pin::new(&mut self.inner);
+///         self.tracer.poll_next(|| poll(inner, cx))
+///     }
+/// }
+///
+/// let tracer = FtfStreamTracer::new("pipeline", "decode").sample_items(100);
+/// ```
+#[derive(Debug, Clone)]
+pub struct FtfStreamTracer {
+    category: &'static str,
+    name: &'static str,
+    sample_every: u64,
+    items: u64,
+}
+
+impl FtfStreamTracer {
+    /// Trace a stream or sink as `name` in `category`, writing every item
+    pub fn new(category: &'static str, name: &'static str) -> Self {
+        Self {
+            category,
+            name,
+            sample_every: 1,
+            items: 0,
+        }
+    }
+
+    /// Write only every `every`th item, starting with the first. Polls are
+    /// still all written.
+    pub fn sample_items(mut self, every: u64) -> Self {
+        self.sample_every = every.max(1);
+        self
+    }
+
+    /// Number of items seen so far
+    pub fn items(&self) -> u64 {
+        self.items
+    }
+
+    /// Trace one `Stream::poll_next` of the inner stream, made by `poll`
+    pub fn poll_next<T>(&mut self, poll: impl FnOnce() -> Poll<Option<T>>) -> Poll<Option<T>> {
+        let polled = self.traced_poll(poll);
+        if let Poll::Ready(Some(_)) = polled {
+            self.item();
+        }
+        polled
+    }
+
+    /// Trace one `Sink::poll_ready` of the inner sink, made by `poll`
+    pub fn poll_ready<R>(&mut self, poll: impl FnOnce() -> Poll<R>) -> Poll<R> {
+        self.traced_poll(poll)
+    }
+
+    /// Trace one `Sink::start_send` of the inner sink, made by `send`
+    pub fn start_send<R>(&mut self, send: impl FnOnce() -> R) -> R {
+        let sent = send();
+        self.item();
+        sent
+    }
+
+    /// Run `poll`, writing it as a duration
+    fn traced_poll<R>(&self, poll: impl FnOnce() -> R) -> R {
+        // Cloned so that the inner stream can use the dispatcher while polled
+        let dispatch = dispatcher::get_default(Dispatch::clone);
+        let Some(hooks) = dispatch.downcast_ref::<ManualHooks>() else {
+            return poll();
+        };
+
+        let start = (hooks.now)(&dispatch);
+        let polled = poll();
+        (hooks.poll)(&dispatch, self.category, self.name, start);
+        polled
+    }
+
+    /// Count an item, writing it if it is sampled
+    fn item(&mut self) {
+        let index = self.items;
+        self.items += 1;
+        if !index.is_multiple_of(self.sample_every) {
+            return;
+        }
+
+        dispatcher::get_default(|dispatch| {
+            if let Some(hooks) = dispatch.downcast_ref::<ManualHooks>() {
+                (hooks.item)(dispatch, self.category, self.name, index);
+            }
+        });
+    }
+}
@@ -0,0 +1,488 @@
+//! Streaming records to live viewers over a socket.
+//!
+//! The stream is a sequence of frames, each a little-endian `u32` payload
+//! length, a one-byte [`StreamFrame`] kind and the payload:
+//!
+//! | Kind | Payload |
+//! |------|---------|
+//! | 1, records | Whole FTF records, in the order they were written |
+//! | 2, snapshot | The magic number, provider info, and every string and thread record currently interned |
+//!
+//! A viewer starts with a snapshot and replaces its string and thread
+//! tables with each one it receives. Snapshots are repeated periodically
+//! and after records were dropped, so a viewer that fell behind recovers.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::replay::Replay;
+use crate::RecordSink;
+
+const RECORDS_FRAME: u8 = 1;
+const SNAPSHOT_FRAME: u8 = 2;
+
+const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_BUFFERED_BYTES: usize = 16 * 1024 * 1024;
+
+/// How often the sender checks for new viewers while no records arrive
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a viewer may take to accept a frame before it is disconnected
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A frame of the live stream, as read by a viewer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamFrame {
+    /// Whole FTF records, in the order they were written
+    Records(Vec<u8>),
+    /// The magic number, provider info, and the string and thread records
+    /// currently interned, replacing the viewer's tables
+    Snapshot(Vec<u8>),
+}
+
+impl StreamFrame {
+    /// Read the next frame, or `None` at the end of the stream
+    pub fn read(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut header = [0; 5];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_le_bytes(header[..4].try_into().expect("4 bytes")) as usize;
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        match header[4] {
+            RECORDS_FRAME => Ok(Some(Self::Records(payload))),
+            SNAPSHOT_FRAME => Ok(Some(Self::Snapshot(payload))),
+            kind => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown stream frame kind {}", kind))),
+        }
+    }
+
+    /// The frame's FTF records
+    pub fn records(&self) -> &[u8] {
+        match self {
+            Self::Records(records) | Self::Snapshot(records) => records,
+        }
+    }
+
+    fn write(kind: u8, payload: &[u8], writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+        writer.write_all(&[kind])?;
+        writer.write_all(payload)
+    }
+}
+
+/// What the layer handed over, waiting to be sent
+#[derive(Debug)]
+enum Queued {
+    Records(Vec<u8>),
+    Snapshot(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct Queue {
+    items: VecDeque<Queued>,
+    bytes: usize,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Producer {
+    replay: Replay,
+    last_snapshot: Option<Instant>,
+    /// Set when records were dropped, so viewers get a snapshot before the
+    /// next records
+    resync: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    producer: Mutex<Producer>,
+    queue: Mutex<Queue>,
+    not_empty: Condvar,
+    /// Set by the sender when a viewer connects, so the next record is
+    /// preceded by a snapshot it can start from
+    snapshot_requested: AtomicBool,
+    snapshot_interval: Duration,
+    buffered_bytes: usize,
+    viewers: AtomicUsize,
+    dropped_records: AtomicU64,
+}
+
+/// A [`RecordSink`] that streams records to live viewers connected over TCP
+/// or a Unix socket, for rendering the trace as it happens.
+///
+/// Records are framed as described in [`StreamFrame`]. Each viewer starts
+/// with a snapshot of the header and intern tables, sent along with the
+/// next record written after it connects, and then gets every record.
+/// Snapshots repeat every [`StreamingSinkBuilder::snapshot_interval`].
+/// Records are sent from a background thread; while viewers fall behind,
+/// up to [`StreamingSinkBuilder::buffered_bytes`] of them are buffered and
+/// later ones dropped, and a viewer that doesn't keep up for a second is
+/// disconnected.
+///
+/// ```no_run
+/// use ftfrs_tracing::{FtfLayer, FtfLayerConfig, MemoryWriter, StreamingSinkBuilder};
+///
+/// let (sink, _guard) = StreamingSinkBuilder::default().listen_tcp("127.0.0.1:7879").unwrap();
+/// let layer = FtfLayer::with_config(MemoryWriter::new(), FtfLayerConfig::builder().sink(sink).build());
+/// ```
+#[derive(Debug)]
+pub struct StreamingSink {
+    shared: Arc<Shared>,
+}
+
+impl StreamingSink {
+    /// How many viewers are connected
+    pub fn viewers(&self) -> usize {
+        self.shared.viewers.load(Ordering::Relaxed)
+    }
+
+    /// Records dropped because viewers fell behind
+    pub fn dropped_records(&self) -> u64 {
+        self.shared.dropped_records.load(Ordering::Relaxed)
+    }
+}
+
+impl RecordSink for StreamingSink {
+    fn write_record(&self, record: &ftfrs::Record) {
+        let mut bytes = Vec::new();
+        if let Err(e) = record.write(&mut bytes) {
+            eprintln!("Error encoding FTF record for streaming: {}", e);
+            return;
+        }
+
+        let mut producer = self.shared.producer.lock();
+        producer.replay.observe(&bytes);
+        if self.shared.viewers.load(Ordering::Relaxed) == 0 && !self.shared.snapshot_requested.load(Ordering::Acquire) {
+            return;
+        }
+
+        let now = Instant::now();
+        let snapshot_due = producer.resync
+            || self.shared.snapshot_requested.swap(false, Ordering::AcqRel)
+            || producer
+                .last_snapshot
+                .is_none_or(|last| now.duration_since(last) >= self.shared.snapshot_interval);
+        let snapshot = snapshot_due.then(|| producer.replay.encode());
+
+        let mut queue = self.shared.queue.lock();
+        let needed = bytes.len() + snapshot.as_ref().map_or(0, Vec::len);
+        // An empty queue takes anything, so a snapshot larger than the
+        // buffer still gets through
+        if !queue.items.is_empty() && queue.bytes + needed > self.shared.buffered_bytes {
+            producer.resync = true;
+            self.shared.dropped_records.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if let Some(snapshot) = snapshot {
+            producer.last_snapshot = Some(now);
+            producer.resync = false;
+            queue.bytes += snapshot.len();
+            queue.items.push_back(Queued::Snapshot(snapshot));
+        }
+        queue.bytes += bytes.len();
+        queue.items.push_back(Queued::Records(bytes));
+        self.shared.not_empty.notify_one();
+    }
+}
+
+impl Shared {
+    fn close(&self) {
+        self.queue.lock().closed = true;
+        self.not_empty.notify_all();
+    }
+
+    /// Send queued records to viewers, taking new viewers from `accept`,
+    /// until closed
+    fn run(&self, mut accept: impl FnMut() -> io::Result<Option<Box<dyn Viewer>>>) {
+        let mut viewers: Vec<(Box<dyn Viewer>, bool)> = Vec::new();
+        loop {
+            loop {
+                match accept() {
+                    Ok(Some(viewer)) => {
+                        viewers.push((viewer, false));
+                        self.snapshot_requested.store(true, Ordering::Release);
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("Error accepting FTF stream viewer: {}", e);
+                        break;
+                    }
+                }
+            }
+            self.viewers.store(viewers.len(), Ordering::Relaxed);
+
+            let (items, closed) = {
+                let mut queue = self.queue.lock();
+                if queue.items.is_empty() && !queue.closed {
+                    self.not_empty.wait_for(&mut queue, POLL_INTERVAL);
+                }
+                queue.bytes = 0;
+                (std::mem::take(&mut queue.items), queue.closed)
+            };
+
+            // Consecutive records go out in one frame
+            let mut records = Vec::new();
+            for item in items {
+                match item {
+                    Queued::Records(bytes) => records.extend_from_slice(&bytes),
+                    Queued::Snapshot(snapshot) => {
+                        send(&mut viewers, RECORDS_FRAME, &records, false);
+                        records.clear();
+                        send(&mut viewers, SNAPSHOT_FRAME, &snapshot, true);
+                    }
+                }
+            }
+            send(&mut viewers, RECORDS_FRAME, &records, false);
+            self.viewers.store(viewers.len(), Ordering::Relaxed);
+
+            if closed {
+                return;
+            }
+        }
+    }
+}
+
+/// Send a frame to each viewer that has had a snapshot, or to every viewer
+/// if it is one, disconnecting those that fail
+fn send(viewers: &mut Vec<(Box<dyn Viewer>, bool)>, kind: u8, payload: &[u8], snapshot: bool) {
+    if payload.is_empty() {
+        return;
+    }
+    viewers.retain_mut(|(viewer, synced)| {
+        if !*synced && !snapshot {
+            return true;
+        }
+        *synced = true;
+        StreamFrame::write(kind, payload, viewer).and_then(|()| viewer.flush()).is_ok()
+    });
+}
+
+/// A viewer's connection over either kind of socket
+trait Viewer: Write + Send {}
+
+impl<T: Write + Send> Viewer for T {}
+
+/// Keeps a [`StreamingSink`]'s sender running.
+///
+/// Dropping the guard sends what is queued and joins the sender thread.
+#[must_use = "dropping the guard immediately stops streaming"]
+#[derive(Debug)]
+pub struct StreamingGuard {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    local_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    socket_path: Option<PathBuf>,
+}
+
+impl StreamingGuard {
+    /// The address a TCP stream is listening on
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+}
+
+impl Drop for StreamingGuard {
+    fn drop(&mut self) {
+        self.shared.close();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("FTF streaming thread panicked");
+            }
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Builder for a [`StreamingSink`].
+#[derive(Debug, Clone)]
+pub struct StreamingSinkBuilder {
+    snapshot_interval: Duration,
+    buffered_bytes: usize,
+}
+
+impl Default for StreamingSinkBuilder {
+    fn default() -> Self {
+        Self {
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            buffered_bytes: DEFAULT_BUFFERED_BYTES,
+        }
+    }
+}
+
+impl StreamingSinkBuilder {
+    /// Set how often viewers get a fresh snapshot of the intern tables
+    pub fn snapshot_interval(mut self, interval: Duration) -> Self {
+        self.snapshot_interval = interval;
+        self
+    }
+
+    /// Set how many bytes of records may wait to be sent before later ones
+    /// are dropped
+    pub fn buffered_bytes(mut self, buffered_bytes: usize) -> Self {
+        self.buffered_bytes = buffered_bytes;
+        self
+    }
+
+    /// Listen for viewers on a TCP address, e.g. `127.0.0.1:0` for any free
+    /// port, which the guard's [`StreamingGuard::local_addr`] reports
+    pub fn listen_tcp(self, addr: impl ToSocketAddrs) -> io::Result<(Arc<StreamingSink>, StreamingGuard)> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let (sink, mut guard) = self.spawn(move || match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_nodelay(true)?;
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                Ok(Some(Box::new(stream) as Box<dyn Viewer>))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        })?;
+        guard.local_addr = Some(local_addr);
+        Ok((sink, guard))
+    }
+
+    /// Listen for viewers on a Unix socket at `path`, which is removed when
+    /// the guard is dropped
+    #[cfg(unix)]
+    pub fn listen_unix(self, path: impl AsRef<Path>) -> io::Result<(Arc<StreamingSink>, StreamingGuard)> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        let (sink, mut guard) = self.spawn(move || match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                Ok(Some(Box::new(stream) as Box<dyn Viewer>))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        })?;
+        guard.socket_path = Some(path);
+        Ok((sink, guard))
+    }
+
+    fn spawn<A>(self, accept: A) -> io::Result<(Arc<StreamingSink>, StreamingGuard)>
+    where
+        A: FnMut() -> io::Result<Option<Box<dyn Viewer>>> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            producer: Mutex::new(Producer {
+                replay: Replay::default(),
+                last_snapshot: None,
+                resync: false,
+            }),
+            queue: Mutex::new(Queue {
+                items: VecDeque::new(),
+                bytes: 0,
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            snapshot_requested: AtomicBool::new(false),
+            snapshot_interval: self.snapshot_interval,
+            buffered_bytes: self.buffered_bytes,
+            viewers: AtomicUsize::new(0),
+            dropped_records: AtomicU64::new(0),
+        });
+
+        let worker = Arc::clone(&shared);
+        let thread = std::thread::Builder::new()
+            .name("ftfrs-tracing-stream".to_string())
+            .spawn(move || worker.run(accept))?;
+
+        Ok((
+            Arc::new(StreamingSink {
+                shared: Arc::clone(&shared),
+            }),
+            StreamingGuard {
+                shared,
+                thread: Some(thread),
+                local_addr: None,
+                #[cfg(unix)]
+                socket_path: None,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+
+    fn encode(record: ftfrs::Record) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        record.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn frames_read_back_as_written() {
+        let mut stream = Vec::new();
+        StreamFrame::write(SNAPSHOT_FRAME, b"tables", &mut stream).unwrap();
+        StreamFrame::write(RECORDS_FRAME, b"records", &mut stream).unwrap();
+
+        let mut reader = stream.as_slice();
+        assert_eq!(StreamFrame::read(&mut reader).unwrap(), Some(StreamFrame::Snapshot(b"tables".to_vec())));
+        assert_eq!(StreamFrame::read(&mut reader).unwrap(), Some(StreamFrame::Records(b"records".to_vec())));
+        assert_eq!(StreamFrame::read(&mut reader).unwrap(), None);
+
+        let unknown = [0, 0, 0, 0, 9];
+        let error = StreamFrame::read(&mut unknown.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn viewers_start_from_a_snapshot_of_earlier_records() {
+        let (sink, guard) = StreamingSinkBuilder::default().listen_tcp("127.0.0.1:0").unwrap();
+        let before = encode(ftfrs::Record::create_string(1, "before".to_string()));
+        sink.write_record(&ftfrs::Record::create_string(1, "before".to_string()));
+
+        let mut viewer = TcpStream::connect(guard.local_addr().unwrap()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while sink.viewers() == 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        sink.write_record(&ftfrs::Record::create_string(2, "after".to_string()));
+        drop(guard);
+
+        let Some(StreamFrame::Snapshot(snapshot)) = StreamFrame::read(&mut viewer).unwrap() else {
+            panic!("the first frame is a snapshot");
+        };
+        assert!(snapshot.starts_with(&encode(ftfrs::Record::create_magic_number())));
+        assert!(snapshot.windows(before.len()).any(|window| window == before));
+        let after = encode(ftfrs::Record::create_string(2, "after".to_string()));
+        assert_eq!(StreamFrame::read(&mut viewer).unwrap(), Some(StreamFrame::Records(after)));
+        assert_eq!(StreamFrame::read(&mut viewer).unwrap(), None, "dropping the guard ends the stream");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_unix_socket_is_removed_with_the_guard() {
+        let path = std::env::temp_dir().join(format!("ftfrs-tracing-stream-{}.sock", std::process::id()));
+        let (_sink, guard) = StreamingSinkBuilder::default().listen_unix(&path).unwrap();
+        assert!(path.exists());
+        drop(guard);
+        assert!(!path.exists());
+    }
+}
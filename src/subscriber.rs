@@ -0,0 +1,310 @@
+use std::any::TypeId;
+
+use tracing_core::span::{Attributes, Current, Id, Record};
+use tracing_core::{Dispatch, Event, Interest, LevelFilter, Metadata, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::flow::FlowPhase;
+use crate::future::ManualHooks;
+use crate::link::LinkSpans;
+use crate::span_store::{SpanStore, SpanView, StoredSpanRef};
+use crate::{FlushGuard, FtfHandle, FtfLayer, FtfLayerConfig, FtfLayerError};
+
+/// A subscriber that only records to FTF, for binaries that don't otherwise
+/// compose layers.
+///
+/// It drives an [`FtfLayer`] over a minimal span store of its own instead of
+/// a [`Registry`](tracing_subscriber::Registry): spans keep only their
+/// metadata, parent and the layer's own bookkeeping, and no field values or
+/// per-layer filter state. It can be installed in one line:
+///
+/// ```
+/// use ftfrs_tracing::{FtfSubscriber, MemoryWriter};
+///
+/// let subscriber = FtfSubscriber::new(MemoryWriter::new());
+/// let _guard = subscriber.flush_guard();
+/// tracing::subscriber::set_global_default(subscriber).unwrap();
+/// ```
+///
+/// To add other layers, use [`FtfLayer`] with a registry instead.
+#[derive(Debug)]
+pub struct FtfSubscriber<W: for<'a> MakeWriter<'a> + 'static> {
+    layer: FtfLayer<W>,
+    spans: SpanStore,
+}
+
+impl<W: for<'a> MakeWriter<'a> + 'static> FtfSubscriber<W> {
+    /// Create a subscriber writing to `writer`, using the default configuration
+    pub fn new(writer: W) -> Self {
+        Self::from_layer(FtfLayer::new(writer))
+    }
+
+    /// Create a subscriber writing to `writer`, using the given configuration
+    pub fn with_config(writer: W, config: FtfLayerConfig) -> Self {
+        Self::from_layer(FtfLayer::with_config(writer, config))
+    }
+
+    /// Like [`FtfSubscriber::with_config`], writing the trace header
+    /// immediately and returning an error if the output can't be written
+    pub fn try_with_config(writer: W, config: FtfLayerConfig) -> Result<Self, FtfLayerError> {
+        Ok(Self::from_layer(FtfLayer::try_with_config(writer, config)?))
+    }
+
+    /// Create a subscriber recording to an existing layer
+    pub fn from_layer(mut layer: FtfLayer<W>) -> Self {
+        layer.set_hooks(LinkSpans {
+            link: link_spans::<W>,
+            begin_flow: begin_flow::<W>,
+            end_flow: end_flow::<W>,
+        });
+        Self {
+            layer,
+            spans: SpanStore::new(),
+        }
+    }
+
+    /// Get a handle for emitting records directly, which stays usable after
+    /// the subscriber is installed
+    pub fn handle(&self) -> FtfHandle<W> {
+        self.layer.handle()
+    }
+
+    /// Get a guard that ends open spans and flushes the output when dropped,
+    /// for holding until the end of `main`
+    pub fn flush_guard(&self) -> FlushGuard<W> {
+        self.layer.flush_guard()
+    }
+
+    fn current(&self) -> Option<StoredSpanRef<'_>> {
+        self.spans.span(&self.spans.current()?)
+    }
+}
+
+impl<W: for<'a> MakeWriter<'a> + 'static> Subscriber for FtfSubscriber<W> {
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        Interest::always()
+    }
+
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.layer.config.max_level.map(LevelFilter::from_level)
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let id = self.spans.create(attrs);
+        if let Some(span) = self.spans.span(&id) {
+            self.layer.record_new_span(attrs, &id, &span);
+        }
+        id
+    }
+
+    // Fields recorded later aren't written, as under a registry
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let parent = if event.is_root() {
+            None
+        } else if let Some(parent) = event.parent() {
+            self.spans.span(parent)
+        } else {
+            self.current()
+        };
+        self.layer.record_event(event, self.current(), parent);
+    }
+
+    fn enter(&self, id: &Id) {
+        self.spans.enter(id);
+        #[cfg(feature = "alloc-track")]
+        if let Some(span) = self.spans.span(id) {
+            self.layer.record_enter(&span);
+        }
+    }
+
+    fn exit(&self, id: &Id) {
+        self.spans.exit(id);
+        if let Some(span) = self.spans.span(id) {
+            self.layer.record_exit(&span);
+        }
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        self.spans.clone_span(id);
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        self.spans.try_close(&id, &|id, span| self.layer.record_close(id, &span))
+    }
+
+    fn current_span(&self) -> Current {
+        let Some(id) = self.spans.current() else {
+            return Current::none();
+        };
+        match self.spans.span(&id) {
+            Some(span) => Current::new(id, span.metadata()),
+            None => Current::none(),
+        }
+    }
+
+    // The layer and its hooks are looked up through the dispatcher, e.g. to
+    // link spans, so they must stay reachable by downcasting
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else if id == TypeId::of::<FtfLayer<W>>() {
+            Some(&self.layer as *const FtfLayer<W> as *const ())
+        } else if id == TypeId::of::<LinkSpans>() {
+            self.layer.link_spans.as_ref().map(|link_spans| link_spans as *const LinkSpans as *const ())
+        } else if id == TypeId::of::<ManualHooks>() {
+            self.layer.manual_hooks.as_ref().map(|hooks| hooks as *const ManualHooks as *const ())
+        } else {
+            None
+        }
+    }
+}
+
+fn link_spans<W>(dispatch: &Dispatch, from: &Id, to: &Id)
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    let Some(subscriber) = dispatch.downcast_ref::<FtfSubscriber<W>>() else {
+        return;
+    };
+    if let (Some(from), Some(to)) = (subscriber.spans.span(from), subscriber.spans.span(to)) {
+        subscriber.layer.link(&from, &to);
+    }
+}
+
+fn begin_flow<W>(dispatch: &Dispatch, id: &Id) -> Option<u64>
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    let subscriber = dispatch.downcast_ref::<FtfSubscriber<W>>()?;
+    subscriber.layer.write_flow_now(&subscriber.spans.span(id)?, FlowPhase::Begin, None)
+}
+
+fn end_flow<W>(dispatch: &Dispatch, id: &Id, flow_id: u64)
+where
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    let Some(subscriber) = dispatch.downcast_ref::<FtfSubscriber<W>>() else {
+        return;
+    };
+    if let Some(span) = subscriber.spans.span(id) {
+        subscriber.layer.write_flow_now(&span, FlowPhase::End, Some(flow_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use crate::{RecordSink, TailSampling};
+
+    use super::*;
+
+    /// Collects the records written to an output
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<ftfrs::Record>>);
+
+    impl RecordSink for Collect {
+        fn write_record(&self, record: &ftfrs::Record) {
+            self.0.lock().push(record.clone());
+        }
+    }
+
+    impl Collect {
+        /// The kinds of events written, in order. ftfrs doesn't export its
+        /// event types, so they are told apart by their debug output.
+        fn events(&self) -> Vec<&'static str> {
+            self.0
+                .lock()
+                .iter()
+                .filter_map(|record| {
+                    let debug = format!("{:?}", record);
+                    ["DurationBegin", "DurationEnd", "Instant"]
+                        .into_iter()
+                        .find(|kind| debug.starts_with(&format!("Event({}(", kind)))
+                })
+                .collect()
+        }
+    }
+
+    type TestSubscriber = FtfSubscriber<fn() -> io::Sink>;
+
+    /// A subscriber with `config` whose records after the header are collected
+    fn subscriber(config: FtfLayerConfig) -> (TestSubscriber, Arc<Collect>) {
+        let collect = Arc::new(Collect::default());
+        let config = FtfLayerConfig {
+            sinks: vec![collect.clone()],
+            ..config
+        };
+        let subscriber = FtfSubscriber::try_with_config(io::sink as fn() -> io::Sink, config).unwrap();
+        collect.0.lock().clear();
+        (subscriber, collect)
+    }
+
+    #[test]
+    fn parents_end_after_their_children() {
+        let (subscriber, collect) = subscriber(FtfLayerConfig::default());
+        tracing::subscriber::with_default(subscriber, || {
+            let parent = tracing::info_span!("parent", ftf = true);
+            let child = tracing::info_span!(parent: &parent, "child", ftf = true);
+            drop(parent);
+            let _entered = child.enter();
+            tracing::info!(ftf = true, "inside");
+            assert_eq!(tracing::Span::current().metadata().map(|metadata| metadata.name()), Some("child"));
+        });
+        assert_eq!(collect.events(), ["DurationBegin", "DurationBegin", "Instant", "DurationEnd", "DurationEnd"]);
+    }
+
+    #[test]
+    fn events_in_recorded_spans_are_recorded() {
+        let (subscriber, collect) = subscriber(FtfLayerConfig::default());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("outside");
+            tracing::info_span!("span", ftf = true).in_scope(|| tracing::info!("inside"));
+            tracing::info_span!("unrecorded").in_scope(|| tracing::info!("ignored"));
+        });
+        assert_eq!(collect.events(), ["DurationBegin", "Instant", "DurationEnd"]);
+    }
+
+    #[test]
+    fn flows_find_spans_in_the_store() {
+        let (subscriber, _) = subscriber(FtfLayerConfig::default());
+        tracing::subscriber::with_default(subscriber, || {
+            let recorded = tracing::info_span!("recorded", ftf = true);
+            let flow_id = crate::link::begin_flow(&recorded).expect("flow from a recorded span");
+            crate::link::end_flow(&recorded, flow_id);
+            assert_eq!(crate::link::begin_flow(&tracing::info_span!("unrecorded")), None);
+        });
+    }
+
+    #[test]
+    fn tail_sampling_buffers_whole_trees() {
+        let (subscriber, collect) = subscriber(FtfLayerConfig {
+            tail_sampling: TailSampling::KeepOnError,
+            ..Default::default()
+        });
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("dropped", ftf = true).in_scope(|| {
+                tracing::info_span!("child", ftf = true).in_scope(|| tracing::info!(ftf = true, "fine"));
+            });
+            tracing::info_span!("kept", ftf = true).in_scope(|| {
+                tracing::info_span!("child", ftf = true).in_scope(|| tracing::error!(ftf = true, "failed"));
+            });
+        });
+        assert_eq!(
+            collect.events(),
+            ["DurationBegin", "DurationBegin", "Instant", "DurationEnd", "DurationEnd"]
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! A buffered trace file synced to disk on a chosen schedule, with disk
+//! space optionally reserved ahead of the trace.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, MutexGuard};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::FtfLayerConfig;
+
+/// How often a [`SyncedFile`] syncs what it has written to disk, trading
+/// throughput for how much of the trace survives a power loss or kernel
+/// crash. A crash of the process alone loses only what is still buffered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave writing back to the OS. A [`FlushGuard`](crate::FlushGuard)
+    /// made with [`FlushGuard::syncing`](crate::FlushGuard::syncing) still
+    /// syncs once at shutdown.
+    #[default]
+    Never,
+    /// Sync whenever the output is flushed, e.g. by
+    /// [`FtfHandle::flush`](crate::FtfHandle::flush)
+    OnFlush,
+    /// Sync when a record is written at least this long after the last sync,
+    /// and whenever the output is flushed
+    Interval(Duration),
+    /// Sync after every record, the slowest and most durable choice
+    EveryRecord,
+}
+
+/// How much disk space a [`SyncedFile`] reserves ahead of the trace, so the
+/// file is laid out in few extents and writes don't stall allocating blocks.
+///
+/// Space is reserved with `fallocate` without changing the file's size, so
+/// the file still ends where the trace does. What is left over is released
+/// when the [`SyncedFile`] is dropped. Elsewhere than on Linux, and on file
+/// systems without `fallocate`, nothing is reserved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Preallocation {
+    /// Bytes reserved when the file is opened
+    pub initial: u64,
+    /// Bytes reserved beyond the last reservation each time the trace
+    /// outgrows it
+    pub growth: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    file: BufWriter<File>,
+    policy: SyncPolicy,
+    last_sync: Instant,
+    preallocation: Option<Preallocation>,
+    /// Bytes in the file, counting those still buffered
+    len: u64,
+    /// Offset up to which space is reserved
+    reserved: u64,
+}
+
+impl Inner {
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Make sure space is reserved for `additional` more bytes
+    fn reserve(&mut self, additional: usize) {
+        let Some(preallocation) = self.preallocation else {
+            return;
+        };
+        let end = self.len + additional as u64;
+        if end <= self.reserved {
+            return;
+        }
+        let target = end.max(self.reserved.saturating_add(preallocation.growth));
+        match allocate(self.file.get_ref(), self.reserved, target - self.reserved) {
+            Ok(()) => self.reserved = target,
+            Err(e) => {
+                eprintln!("Error preallocating FTF file, no longer preallocating: {}", e);
+                self.preallocation = None;
+            }
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if self.reserved <= self.len {
+            return;
+        }
+        // Truncating to the current size releases the space reserved past it
+        if let Err(e) = self.file.flush().and_then(|()| self.file.get_ref().set_len(self.len)) {
+            eprintln!("Error releasing preallocated FTF file space: {}", e);
+        }
+    }
+}
+
+/// Reserve `len` bytes of `file` from `offset` on, without changing its size
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn allocate(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        // SAFETY: the descriptor is borrowed from `file`, which stays open for
+        // the call, and fallocate only reserves disk space without touching
+        // any memory of ours
+        let result = unsafe {
+            libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, offset as libc::off_t, len as libc::off_t)
+        };
+        if result == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EINTR) => {}
+            Some(libc::EOPNOTSUPP) => return Err(io::Error::new(io::ErrorKind::Unsupported, error)),
+            _ => return Err(error),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn allocate(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// A [`MakeWriter`] that buffers the trace in a file and syncs it to disk as
+/// its [`SyncPolicy`] says.
+///
+/// [`init`](crate::init) writes to one, with
+/// [`FtfLayerConfig::sync_policy`](crate::FtfLayerConfig::sync_policy) and
+/// [`FtfLayerConfig::preallocation`](crate::FtfLayerConfig::preallocation).
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use ftfrs_tracing::{FtfLayer, SyncPolicy, SyncedFile};
+///
+/// let file = SyncedFile::create("trace.ftf", SyncPolicy::Interval(Duration::from_secs(1))).unwrap();
+/// let layer = FtfLayer::new(file);
+/// ```
+#[derive(Debug)]
+pub struct SyncedFile {
+    inner: Mutex<Inner>,
+}
+
+impl SyncedFile {
+    /// Write to `file`, syncing it as `policy` says
+    pub fn new(file: File, policy: SyncPolicy) -> Self {
+        let len = file.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+        Self {
+            inner: Mutex::new(Inner {
+                file: BufWriter::new(file),
+                policy,
+                last_sync: Instant::now(),
+                preallocation: None,
+                len,
+                reserved: len,
+            }),
+        }
+    }
+
+    /// Create the file at `path`, truncating any existing one
+    pub fn create(path: impl AsRef<Path>, policy: SyncPolicy) -> io::Result<Self> {
+        Ok(Self::new(File::create(path)?, policy))
+    }
+
+    /// Reserve disk space ahead of the trace as `preallocation` says,
+    /// starting with its initial reservation now.
+    ///
+    /// Returns an error if the initial reservation fails, other than because
+    /// the file system doesn't support it.
+    pub fn preallocate(self, preallocation: Preallocation) -> io::Result<Self> {
+        {
+            let mut inner = self.inner.lock();
+            let start = inner.len;
+            match allocate(inner.file.get_ref(), start, preallocation.initial) {
+                Ok(()) => {
+                    inner.reserved = start + preallocation.initial;
+                    inner.preallocation = Some(preallocation);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self)
+    }
+
+    /// Write to `file` as `config` says for the files the layer's own setup
+    /// functions open
+    pub(crate) fn for_config(file: File, config: &FtfLayerConfig) -> io::Result<Self> {
+        let file = Self::new(file, config.sync_policy);
+        match config.preallocation {
+            Some(preallocation) => file.preallocate(preallocation),
+            None => Ok(file),
+        }
+    }
+}
+
+/// Writer handed out by [`SyncedFile`], holding its file for one record.
+#[derive(Debug)]
+pub struct SyncedFileWriter<'a> {
+    inner: MutexGuard<'a, Inner>,
+}
+
+impl io::Write for SyncedFileWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.reserve(buf.len());
+        let written = self.inner.file.write(buf)?;
+        self.inner.len += written as u64;
+        Ok(written)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.reserve(buf.len());
+        self.inner.file.write_all(buf)?;
+        self.inner.len += buf.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.policy {
+            SyncPolicy::Never => self.inner.file.flush(),
+            _ => self.inner.sync(),
+        }
+    }
+}
+
+impl Drop for SyncedFileWriter<'_> {
+    fn drop(&mut self) {
+        let due = match self.inner.policy {
+            SyncPolicy::Never | SyncPolicy::OnFlush => false,
+            SyncPolicy::Interval(interval) => self.inner.last_sync.elapsed() >= interval,
+            SyncPolicy::EveryRecord => true,
+        };
+        if due {
+            if let Err(e) = self.inner.sync() {
+                eprintln!("Error syncing FTF file: {}", e);
+            }
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyncedFile {
+    type Writer = SyncedFileWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyncedFileWriter {
+            inner: self.inner.lock(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ftfrs-tracing-synced-{}-{}", name, std::process::id()))
+    }
+
+    /// Write `bytes` as one record, returning what the file holds afterwards
+    fn write_record(file: &SyncedFile, path: &Path, bytes: &[u8]) -> Vec<u8> {
+        file.make_writer().write_all(bytes).unwrap();
+        fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn records_stay_buffered_until_flushed_unless_synced_each_time() {
+        for policy in [SyncPolicy::Never, SyncPolicy::OnFlush, SyncPolicy::Interval(Duration::from_secs(3600))] {
+            let path = temp_path("buffered");
+            let file = SyncedFile::create(&path, policy).unwrap();
+            assert_eq!(write_record(&file, &path, b"record"), b"", "{:?}", policy);
+            file.make_writer().flush().unwrap();
+            assert_eq!(fs::read(&path).unwrap(), b"record", "{:?}", policy);
+            fs::remove_file(&path).unwrap();
+        }
+
+        let path = temp_path("every-record");
+        let file = SyncedFile::create(&path, SyncPolicy::EveryRecord).unwrap();
+        assert_eq!(write_record(&file, &path, b"record"), b"record");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn intervals_sync_the_first_record_written_once_they_pass() {
+        let path = temp_path("interval");
+        let file = SyncedFile::create(&path, SyncPolicy::Interval(Duration::from_millis(10))).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(write_record(&file, &path, b"first"), b"first");
+        assert_eq!(write_record(&file, &path, b"second"), b"first");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn space_is_reserved_ahead_of_the_trace_and_released_after() {
+        let path = temp_path("preallocated");
+        let preallocation = Preallocation {
+            initial: 1 << 20,
+            growth: 1 << 20,
+        };
+        let file = SyncedFile::create(&path, SyncPolicy::Never).unwrap().preallocate(preallocation).unwrap();
+        if file.inner.lock().preallocation.is_none() {
+            // The file system can't reserve space
+            fs::remove_file(&path).unwrap();
+            return;
+        }
+        assert_eq!(file.inner.lock().reserved, 1 << 20);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0, "reserving keeps the file's size");
+
+        file.make_writer().write_all(&[0; 100]).unwrap();
+        assert_eq!(file.inner.lock().reserved, 1 << 20);
+        file.make_writer().write_all(&vec![0; 1 << 20]).unwrap();
+        assert_eq!(file.inner.lock().reserved, 2 << 20, "outgrowing the reservation extends it by the growth");
+
+        drop(file);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 100 + (1 << 20));
+        fs::remove_file(&path).unwrap();
+    }
+}
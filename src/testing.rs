@@ -0,0 +1,88 @@
+//! Capturing a trace per test.
+
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use tracing::subscriber::DefaultGuard;
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::{FlushGuard, FtfLayer, FtfLayerConfig, InitWriter, SyncedFile};
+
+/// Traces the current test until dropped, returned by [`per_test`].
+///
+/// The subscriber is the default only on the test's thread. Dropping this
+/// ends the spans still open and flushes the file.
+#[must_use = "dropping the guard immediately stops capturing the test's trace"]
+#[derive(Debug)]
+pub struct TestTrace {
+    path: PathBuf,
+    _default: DefaultGuard,
+    _flush: FlushGuard<InitWriter>,
+}
+
+impl TestTrace {
+    /// The file the trace is written to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Trace the current test to `target/traces/<test_name>.ftf`, using the
+/// default configuration.
+///
+/// See [`per_test_with_config`].
+///
+/// ```no_run
+/// #[test]
+/// fn parses_header() {
+///     let _trace = ftfrs_tracing::testing::per_test();
+///     // ...
+/// }
+/// ```
+pub fn per_test() -> TestTrace {
+    per_test_with_config(FtfLayerConfig::default())
+}
+
+/// Trace the current test to `target/traces/<test_name>.ftf`.
+///
+/// The test's name is that of the current thread, which the test harness
+/// names after the test, e.g. `parser::tests::parses_header`. Characters
+/// that aren't safe in a file name, such as `:`, become `_`. The traces
+/// directory is under `CARGO_TARGET_DIR` if set.
+///
+/// # Panics
+///
+/// If the trace file can't be created, so a test never passes silently
+/// without its trace.
+pub fn per_test_with_config(config: FtfLayerConfig) -> TestTrace {
+    let dir = env::var_os("CARGO_TARGET_DIR").map_or_else(|| PathBuf::from("target"), PathBuf::from).join("traces");
+    let test_name = thread::current().name().unwrap_or("unnamed").to_string();
+    let path = dir.join(format!("{}.ftf", file_stem(&test_name)));
+
+    let create = || -> std::io::Result<(SyncedFile, File)> {
+        fs::create_dir_all(&dir)?;
+        let file = File::create(&path)?;
+        let sync_file = file.try_clone()?;
+        Ok((SyncedFile::for_config(file, &config)?, sync_file))
+    };
+    let (file, sync_file) = create().unwrap_or_else(|e| panic!("failed to create trace file {}: {}", path.display(), e));
+    let layer = FtfLayer::try_with_config(file, config)
+        .unwrap_or_else(|e| panic!("failed to start trace {}: {}", path.display(), e));
+    let flush = layer.flush_guard().syncing(sync_file);
+    let default = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+    TestTrace {
+        path,
+        _default: default,
+        _flush: flush,
+    }
+}
+
+/// `name` with every character unsafe in a file name replaced by `_`
+pub(crate) fn file_stem(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect()
+}
@@ -0,0 +1,40 @@
+//! Operating system ids for the current thread.
+//!
+//! Thread records carry the id the OS itself uses, so traces line up with
+//! other tools looking at the same process: `gettid` on Linux and Android,
+//! `GetCurrentThreadId` on Windows. Elsewhere each thread gets a small
+//! process-unique number instead.
+
+/// Get the id of the calling thread
+pub(crate) fn current() -> u64 {
+    thread_local! {
+        static THREAD_ID: u64 = native();
+    }
+
+    THREAD_ID.with(|id| *id)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn native() -> u64 {
+    // SAFETY: gettid takes no arguments and cannot fail
+    unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+}
+
+#[cfg(windows)]
+fn native() -> u64 {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+    }
+
+    // SAFETY: GetCurrentThreadId takes no arguments and cannot fail
+    u64::from(unsafe { GetCurrentThreadId() })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+fn native() -> u64 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed)
+}
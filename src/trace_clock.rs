@@ -0,0 +1,39 @@
+use std::fmt;
+
+/// A source of trace time, plugged in through
+/// [`FtfLayerConfig::clock`](crate::FtfLayerConfig::clock), for simulators
+/// and games whose traces should follow the application's own timeline.
+///
+/// Every record is stamped with the clock's reading, in nanoseconds, as is.
+/// It should never go backwards, or spans may end before they begin.
+///
+/// Any `Fn() -> u64` closure is a clock:
+///
+/// ```
+/// use std::sync::atomic::{AtomicU64, Ordering};
+/// use std::sync::Arc;
+///
+/// static SIM_TIME_NS: AtomicU64 = AtomicU64::new(0);
+///
+/// let config = ftfrs_tracing::FtfLayerConfig::builder()
+///     .clock(Arc::new(|| SIM_TIME_NS.load(Ordering::Relaxed)))
+///     .build();
+/// ```
+pub trait TraceClock: Send + Sync {
+    fn now_ns(&self) -> u64;
+}
+
+impl<F> TraceClock for F
+where
+    F: Fn() -> u64 + Send + Sync,
+{
+    fn now_ns(&self) -> u64 {
+        self()
+    }
+}
+
+impl fmt::Debug for dyn TraceClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TraceClock")
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::platform;
+
+/// Generate a random (version 4) UUID identifying one layer's trace.
+///
+/// The randomness comes from the per-process keys the standard library seeds
+/// `RandomState` with, mixed with the time, process id and a counter so that
+/// layers created in the same process still get distinct ids.
+pub(crate) fn generate() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = platform::unix_nanos();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    let mut halves = [0u64; 2];
+    for half in &mut halves {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_u32(platform::process_id());
+        hasher.write_u64(count);
+        *half = hasher.finish();
+    }
+
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&halves[0].to_be_bytes());
+    bytes[8..].copy_from_slice(&halves[1].to_be_bytes());
+    bytes[6] = (bytes[6] & 0x0F) | 0x40;
+    bytes[8] = (bytes[8] & 0x3F) | 0x80;
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Generate a provider id for [`FtfLayerConfig::unique_provider_id`](crate::FtfLayerConfig::unique_provider_id).
+///
+/// The process id goes in the low bits, so processes alive at the same time
+/// on one machine never collide, and random bits fill the rest, so ids from
+/// different machines or reused process ids rarely do. It is never 0.
+pub(crate) fn generate_provider_id() -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(platform::unix_nanos());
+    hasher.write_u32(platform::process_id());
+    let random = hasher.finish() as u32;
+
+    let pid = platform::process_id();
+    let pid_bits = (32 - pid.leading_zeros()).max(1);
+    let id = if pid_bits >= 32 { pid } else { (random << pid_bits) | pid };
+    id.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_ids_are_distinct_version_4_uuids() {
+        let (first, second) = (generate(), generate());
+        assert_ne!(first, second);
+        let groups: Vec<_> = first.split('-').map(str::len).collect();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert_eq!(&first[14..15], "4");
+        assert!(matches!(&first[19..20], "8" | "9" | "a" | "b"));
+    }
+
+    #[test]
+    fn provider_ids_keep_the_process_id() {
+        let pid = platform::process_id();
+        let id = generate_provider_id();
+        let pid_bits = (32 - pid.leading_zeros()).max(1);
+        assert_ne!(id, 0);
+        if pid_bits < 32 {
+            assert_eq!(id & ((1 << pid_bits) - 1), pid);
+        }
+    }
+}
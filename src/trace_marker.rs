@@ -0,0 +1,56 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+
+/// Where tracefs is mounted on current kernels, and where older ones expose it
+const TRACE_MARKER_PATHS: [&str; 2] = [
+    "/sys/kernel/tracing/trace_marker",
+    "/sys/kernel/debug/tracing/trace_marker",
+];
+
+/// Mirrors span begin and end events into ftrace's `trace_marker`.
+///
+/// Lines use the atrace format (`B|pid|name` and `E|pid`) that perfetto and
+/// systrace turn into slices on the writing thread.
+#[derive(Debug)]
+pub(crate) struct TraceMarker {
+    file: File,
+}
+
+impl TraceMarker {
+    /// Open the first available `trace_marker`, reporting if none can be opened
+    pub(crate) fn open() -> Option<Self> {
+        let mut last_error = None;
+        for path in TRACE_MARKER_PATHS {
+            match OpenOptions::new().write(true).open(path) {
+                Ok(file) => return Some(Self { file }),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        if let Some(e) = last_error {
+            eprintln!("Error opening trace_marker: {}", e);
+        }
+        None
+    }
+
+    pub(crate) fn begin(&self, process_id: u64, name: &str) {
+        let name: String = name.chars().filter(|c| *c != '\n').collect();
+        self.write(&format!("B|{}|{}", process_id, name));
+    }
+
+    pub(crate) fn end(&self, process_id: u64) {
+        self.write(&format!("E|{}", process_id));
+    }
+
+    /// Write one marker, which the kernel only accepts as a single write
+    fn write(&self, line: &str) {
+        if let Err(e) = (&self.file).write(line.as_bytes()).and_then(|written| {
+            if written == line.len() {
+                Ok(())
+            } else {
+                Err(io::Error::new(io::ErrorKind::WriteZero, "trace marker was cut short"))
+            }
+        }) {
+            eprintln!("Error writing trace_marker: {}", e);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::handle::Scope;
+use crate::{ArgValue, FtfHandle};
+
+/// A named virtual track, created by [`FtfHandle::create_track`].
+///
+/// Events emitted to a track appear on their own row in trace viewers, apart
+/// from the threads that emitted them.
+#[derive(Debug)]
+pub struct FtfTrack<W: for<'a> MakeWriter<'a>> {
+    handle: FtfHandle<W>,
+    koid: u64,
+    name: String,
+}
+
+impl<W: for<'a> MakeWriter<'a>> Clone for FtfTrack<W> {
+    fn clone(&self) -> Self {
+        Self::new(self.handle.clone(), self.koid, self.name.clone())
+    }
+}
+
+impl<W: for<'a> MakeWriter<'a>> FtfTrack<W> {
+    pub(crate) fn new(handle: FtfHandle<W>, koid: u64, name: String) -> Self {
+        Self { handle, koid, name }
+    }
+
+    /// Get the track's name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Write an instant event on this track at `ts_ns`, in nanoseconds since
+    /// the timestamp epoch
+    pub fn emit_instant_at(&self, ts_ns: u64, category: &str, name: &str, args: &[(&str, ArgValue<'_>)]) {
+        self.handle.emit_instant(self.scope(), ts_ns, category, name, args);
+    }
+
+    /// Write a complete duration event on this track from `start_ns` to
+    /// `end_ns`, in nanoseconds since the timestamp epoch.
+    ///
+    /// An `end_ns` before `start_ns` is clamped so the slice has zero length.
+    pub fn emit_duration_at(
+        &self,
+        start_ns: u64,
+        end_ns: u64,
+        category: &str,
+        name: &str,
+        args: &[(&str, ArgValue<'_>)],
+    ) {
+        self.handle.emit_duration(self.scope(), start_ns, end_ns, category, name, args);
+    }
+
+    fn scope(&self) -> Scope<'_> {
+        Scope::Track {
+            koid: self.koid,
+            name: &self.name,
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Chunk trailers, which let readers find where a torn write cut the trace
+//! short.
+//!
+//! A trailer is two trace info metadata records, which ftfrs reads and
+//! viewers skip: the chunk's length in bytes, then its CRC-32, both covering
+//! everything from the chunk's magic number record up to the trailer.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use crate::crc32;
+use crate::raw::record_len;
+
+/// FTF record type and metadata type of trace info records
+const METADATA_RECORD_TYPE: u64 = 0;
+const TRACE_INFO_METADATA_TYPE: u64 = 4;
+
+/// Trace info types of the trailer's records, from the reserved range
+const CHUNK_LENGTH_INFO: u64 = 14;
+const CHUNK_CHECKSUM_INFO: u64 = 15;
+
+/// Bits of a trace info record's data field
+const DATA_MASK: u64 = (1 << 40) - 1;
+
+const TRAILER_BYTES: usize = 16;
+
+/// Header of a one-word trace info record of `info_type` carrying `data`
+fn trace_info(info_type: u64, data: u64) -> u64 {
+    METADATA_RECORD_TYPE | 1 << 4 | TRACE_INFO_METADATA_TYPE << 16 | info_type << 20 | (data & DATA_MASK) << 24
+}
+
+/// Encode the trailer of a chunk of `len` bytes with checksum `crc`
+fn encode(len: u64, crc: u32) -> [u8; TRAILER_BYTES] {
+    let mut trailer = [0; TRAILER_BYTES];
+    trailer[..8].copy_from_slice(&trace_info(CHUNK_LENGTH_INFO, len).to_le_bytes());
+    trailer[8..].copy_from_slice(&trace_info(CHUNK_CHECKSUM_INFO, u64::from(crc)).to_le_bytes());
+    trailer
+}
+
+/// The length and checksum of the trailer at the start of `bytes`, if there
+/// is one
+fn decode(bytes: &[u8]) -> Option<(u64, u32)> {
+    if bytes.len() < TRAILER_BYTES {
+        return None;
+    }
+    // Everything below the data field identifies the record
+    let data = |index: usize, info_type: u64| {
+        let header = u64::from_le_bytes(bytes[index * 8..index * 8 + 8].try_into().expect("8 bytes"));
+        (header & 0xFF_FFFF == trace_info(info_type, 0)).then_some(header >> 24)
+    };
+    let crc = u32::try_from(data(1, CHUNK_CHECKSUM_INFO)?).ok()?;
+    Some((data(0, CHUNK_LENGTH_INFO)?, crc))
+}
+
+/// Length of the trailer at the start of `bytes`, or 0 if they don't start
+/// with one
+pub(crate) fn leading_trailer_len(bytes: &[u8]) -> usize {
+    if decode(bytes).is_some() {
+        TRAILER_BYTES
+    } else {
+        0
+    }
+}
+
+/// Length of the longest prefix of `trace` made of chunks whose trailers
+/// check out, written with
+/// [`FtfLayerConfig::chunk_trailers`](crate::FtfLayerConfig::chunk_trailers).
+///
+/// After a crash the last chunk may be cut short or hold garbage where a
+/// write was torn, failing the whole file's parse. Reading only this prefix
+/// skips it:
+///
+/// ```no_run
+/// let trace = std::fs::read("trace.ftf").unwrap();
+/// let intact = &trace[..ftfrs_tracing::verified_trace_len(&trace)];
+/// let archive = ftfrs::Archive::read(intact).unwrap();
+/// ```
+///
+/// A trace written without trailers has no verified prefix, so this returns 0.
+pub fn verified_trace_len(trace: &[u8]) -> usize {
+    let (mut verified, mut chunk_start, mut pos) = (0, 0, 0);
+    while pos < trace.len() {
+        if let Some((len, crc)) = decode(&trace[pos..]) {
+            if len != (pos - chunk_start) as u64 || crc != crc32::crc32(&trace[chunk_start..pos]) {
+                break;
+            }
+            pos += TRAILER_BYTES;
+            verified = pos;
+            chunk_start = pos;
+            continue;
+        }
+        pos += record_len(&trace[pos..]);
+    }
+    verified
+}
+
+/// Running length and checksum of the main output's current chunk.
+///
+/// Only updated with the main output locked, so relaxed loads and stores
+/// are enough.
+#[derive(Debug, Default)]
+pub(crate) struct ChunkChecksum {
+    len: AtomicU64,
+    crc: AtomicU32,
+    /// Set once a trailer ends the chunk outside of a chunk boundary, so the
+    /// next record starts a new chunk
+    ended: AtomicBool,
+}
+
+impl ChunkChecksum {
+    fn update(&self, bytes: &[u8]) {
+        self.len.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        let crc = crc32::update(self.crc.load(Ordering::Relaxed), bytes);
+        self.crc.store(crc, Ordering::Relaxed);
+    }
+
+    /// Start over for the next chunk, returning the length and checksum of
+    /// the current one
+    fn reset(&self) -> (u64, u32) {
+        (self.len.swap(0, Ordering::Relaxed), self.crc.swap(0, Ordering::Relaxed))
+    }
+
+    /// Write the current chunk's trailer to `writer`, which must be the
+    /// main output, returning whether there was a chunk to end
+    pub(crate) fn write_trailer(&self, writer: &mut dyn io::Write) -> io::Result<bool> {
+        let (len, crc) = self.reset();
+        if len == 0 {
+            return Ok(false);
+        }
+        let result = writer.write_all(&encode(len, crc));
+        // The trailer belongs to neither chunk
+        self.reset();
+        result.map(|()| true)
+    }
+
+    /// Note that a trailer was written between chunk boundaries
+    pub(crate) fn end(&self) {
+        self.ended.store(true, Ordering::Release);
+    }
+
+    /// Whether the chunk was ended since the last call
+    pub(crate) fn take_ended(&self) -> bool {
+        self.ended.load(Ordering::Acquire) && self.ended.swap(false, Ordering::AcqRel)
+    }
+}
+
+/// A writer that adds everything written through it to a [`ChunkChecksum`]
+pub(crate) struct ChecksummedWriter<'a> {
+    inner: &'a mut dyn io::Write,
+    checksum: &'a ChunkChecksum,
+}
+
+impl<'a> ChecksummedWriter<'a> {
+    pub(crate) fn new(inner: &'a mut dyn io::Write, checksum: &'a ChunkChecksum) -> Self {
+        Self { inner, checksum }
+    }
+}
+
+impl io::Write for ChecksummedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.checksum.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.checksum.update(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::MAGIC_NUMBER_RECORD;
+
+    /// Append a chunk of a few string records to `trace`, followed by its
+    /// trailer if `trailer` is set
+    fn write_chunk(trace: &mut Vec<u8>, checksum: &ChunkChecksum, first: u16, trailer: bool) {
+        let mut writer = ChecksummedWriter::new(trace, checksum);
+        writer.write_all(&MAGIC_NUMBER_RECORD).unwrap();
+        for id in first..first + 3 {
+            ftfrs::Record::create_string(id, format!("string {}", id)).write(&mut writer).unwrap();
+        }
+        if trailer {
+            assert!(checksum.write_trailer(trace).unwrap());
+        }
+    }
+
+    /// A trace of two chunks with trailers and a third without, with the
+    /// offsets the first and second chunks end at
+    fn trace() -> (Vec<u8>, usize, usize) {
+        let checksum = ChunkChecksum::default();
+        let mut trace = Vec::new();
+        write_chunk(&mut trace, &checksum, 1, true);
+        let first = trace.len();
+        write_chunk(&mut trace, &checksum, 4, true);
+        let second = trace.len();
+        write_chunk(&mut trace, &checksum, 7, false);
+        (trace, first, second)
+    }
+
+    #[test]
+    fn whole_chunks_verify() {
+        let (trace, _, second) = trace();
+        assert_eq!(verified_trace_len(&trace), second);
+        assert_eq!(verified_trace_len(&trace[..second]), second);
+        let archive = ftfrs::Archive::read(&trace[..second]).unwrap();
+        assert_eq!(archive.records.len(), 2 * (1 + 3 + 2));
+    }
+
+    #[test]
+    fn torn_chunk_is_cut() {
+        let (trace, first, second) = trace();
+        for len in first + 1..second {
+            assert_eq!(verified_trace_len(&trace[..len]), first, "cut at {}", len);
+        }
+        assert_eq!(verified_trace_len(&trace[..first - 1]), 0);
+    }
+
+    #[test]
+    fn corrupted_chunk_is_cut() {
+        let (trace, first, second) = trace();
+        for pos in [first, first + 12, second - TRAILER_BYTES - 1, second - 1] {
+            let mut corrupted = trace.clone();
+            corrupted[pos] ^= 0x40;
+            assert_eq!(verified_trace_len(&corrupted), first, "corrupted at {}", pos);
+        }
+    }
+
+    #[test]
+    fn trace_without_trailers_is_not_verified() {
+        let checksum = ChunkChecksum::default();
+        let mut trace = Vec::new();
+        write_chunk(&mut trace, &checksum, 1, false);
+        assert_eq!(verified_trace_len(&trace), 0);
+        assert_eq!(verified_trace_len(&[]), 0);
+    }
+
+    #[test]
+    fn empty_chunk_has_no_trailer() {
+        let checksum = ChunkChecksum::default();
+        let mut trace = Vec::new();
+        assert!(!checksum.write_trailer(&mut trace).unwrap());
+        assert!(trace.is_empty());
+    }
+
+    #[test]
+    fn leading_trailer() {
+        let (trace, first, _) = trace();
+        assert_eq!(leading_trailer_len(&trace[first - TRAILER_BYTES..]), TRAILER_BYTES);
+        assert_eq!(leading_trailer_len(&trace), 0);
+    }
+}
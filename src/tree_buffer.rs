@@ -0,0 +1,281 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::memory::{MemoryBudget, MemoryCharge};
+use crate::sink::{RecordOutput, RecordSink};
+use crate::{Destination, FtfLayerConfig, StringCache, ThreadCache};
+
+/// Records of one span tree held back until its root closes.
+///
+/// The tree interns strings and threads in tables of its own, so its records
+/// are self-contained and can be written out, or dropped, as a unit. Whoever
+/// owns the buffer decides which, with [`SpanTreeBuffer::mark_keep`]. A tree
+/// whose records outgrow the layer's memory budget is dropped whatever is
+/// decided.
+#[derive(Debug)]
+pub(crate) struct SpanTreeBuffer {
+    string_cache: StringCache,
+    thread_cache: ThreadCache,
+    records: Arc<BufferedRecords>,
+    keep: bool,
+}
+
+/// Collects the records written to a [`SpanTreeBuffer`]
+#[derive(Debug)]
+struct BufferedRecords(Mutex<Buffered>);
+
+/// A record held back in a [`SpanTreeBuffer`]
+#[derive(Debug)]
+enum BufferedRecord {
+    Record(ftfrs::Record),
+    /// Encoded records ftfrs can't represent, such as flows and kernel
+    /// objects, written since the last [`ftfrs::Record`]
+    Raw(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct Buffered {
+    records: Vec<BufferedRecord>,
+    /// String and thread indices the tree's records assign
+    string_ids: BTreeSet<u16>,
+    thread_ids: BTreeSet<u8>,
+    memory: MemoryCharge,
+    /// Set once the records outgrew the memory budget and were dropped
+    overflowed: bool,
+}
+
+impl Buffered {
+    /// Charge `bytes` more to the memory budget, dropping the whole tree
+    /// and returning false if the budget can't cover them
+    fn charge(&mut self, bytes: usize) -> bool {
+        if self.overflowed {
+            return false;
+        }
+        if self.memory.budget().is_limited() && !self.memory.try_add(bytes) {
+            self.overflowed = true;
+            self.records = Vec::new();
+            self.memory.clear();
+            self.memory.budget().count_dropped_tree();
+            return false;
+        }
+        true
+    }
+}
+
+impl RecordSink for BufferedRecords {
+    fn write_record(&self, record: &ftfrs::Record) {
+        let mut buffered = self.0.lock();
+        if !buffered.charge(record_size(record)) {
+            return;
+        }
+        match record {
+            ftfrs::Record::String(string) => {
+                buffered.string_ids.insert(string.index());
+            }
+            ftfrs::Record::Thread(thread) => {
+                buffered.thread_ids.insert(thread.index());
+            }
+            _ => {}
+        }
+        buffered.records.push(BufferedRecord::Record(record.clone()));
+    }
+}
+
+impl io::Write for &BufferedRecords {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buffered = self.0.lock();
+        if !buffered.charge(buf.len()) {
+            return Ok(buf.len());
+        }
+        match buffered.records.last_mut() {
+            Some(BufferedRecord::Raw(bytes)) => bytes.extend_from_slice(buf),
+            _ => buffered.records.push(BufferedRecord::Raw(buf.to_vec())),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Bytes `record` takes up while buffered, roughly: the record itself and
+/// its encoding, which stands in for the strings and arguments it owns
+fn record_size(record: &ftfrs::Record) -> usize {
+    struct Count(usize);
+
+    impl io::Write for Count {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut count = Count(0);
+    let _ = record.write(&mut count);
+    std::mem::size_of::<ftfrs::Record>() + count.0
+}
+
+impl SpanTreeBuffer {
+    pub(crate) fn new(config: &FtfLayerConfig, memory: &Arc<MemoryBudget>) -> Self {
+        Self {
+            string_cache: StringCache::new(config, memory),
+            thread_cache: ThreadCache::new(config, memory),
+            records: Arc::new(BufferedRecords(Mutex::new(Buffered {
+                records: Vec::new(),
+                string_ids: BTreeSet::new(),
+                thread_ids: BTreeSet::new(),
+                memory: MemoryCharge::new(memory),
+                overflowed: false,
+            }))),
+            keep: false,
+        }
+    }
+
+    /// Write the tree out when its root closes rather than dropping it
+    pub(crate) fn mark_keep(&mut self) {
+        self.keep = true;
+    }
+
+    /// Whether the tree is to be written out, having been marked to keep
+    /// and not dropped for the memory budget
+    pub(crate) fn keep(&self) -> bool {
+        self.keep && !self.records.0.lock().overflowed
+    }
+
+    /// Run `f` against the tree's buffer, which keeps [`ftfrs::Record`]s
+    /// and the encoded flow, async and kernel object records alike
+    pub(crate) fn with_destination<R>(&mut self, f: impl FnOnce(&mut Destination<'_>) -> R) -> R {
+        let sinks: [Arc<dyn RecordSink>; 1] = [self.records.clone()];
+        let mut writer = io::sink();
+        let mut raw = &*self.records;
+        let mut dest = Destination::new(
+            &mut self.string_cache,
+            &mut self.thread_cache,
+            RecordOutput::new(&mut writer, &[], &sinks).with_raw(&mut raw),
+        );
+        f(&mut dest)
+    }
+
+    /// Write the buffered records to `dest` in the order they were
+    /// recorded, then restore the references `dest`'s own tables handed out
+    /// that the tree's records reassigned
+    pub(crate) fn flush(&self, dest: &mut Destination<'_>) -> Result<(), ftfrs::FtfError> {
+        let (records, string_ids, thread_ids) = {
+            let mut buffered = self.records.0.lock();
+            buffered.memory.clear();
+            (
+                std::mem::take(&mut buffered.records),
+                std::mem::take(&mut buffered.string_ids),
+                std::mem::take(&mut buffered.thread_ids),
+            )
+        };
+        for record in records {
+            match record {
+                BufferedRecord::Record(record) => dest.output.write(record)?,
+                BufferedRecord::Raw(bytes) => dest.output.raw().write_all(&bytes)?,
+            }
+        }
+        dest.string_cache.restore(&string_ids, &mut dest.output)?;
+        dest.thread_cache.restore(&thread_ids, &mut dest.output)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects the records written to an output
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<ftfrs::Record>>);
+
+    impl RecordSink for Collect {
+        fn write_record(&self, record: &ftfrs::Record) {
+            self.0.lock().push(record.clone());
+        }
+    }
+
+    fn write_tree(tree: &mut SpanTreeBuffer) {
+        tree.with_destination(|dest| {
+            dest.string_ref("tree");
+            dest.thread_ref(1, 2);
+            dest.output.raw().write_all(&[0xAB; 8]).unwrap();
+        });
+    }
+
+    /// Flush `tree` into tables already holding three names and a thread,
+    /// returning the records and bytes written
+    fn flush(tree: &SpanTreeBuffer) -> (Vec<ftfrs::Record>, Vec<u8>) {
+        let config = FtfLayerConfig::default();
+        let memory = MemoryBudget::new(None);
+        let mut string_cache = StringCache::new(&config, &memory);
+        let mut thread_cache = ThreadCache::new(&config, &memory);
+        let collect = Arc::new(Collect::default());
+        let sinks: [Arc<dyn RecordSink>; 1] = [collect.clone()];
+        let mut bytes = Vec::new();
+        {
+            let mut dest = Destination::new(&mut string_cache, &mut thread_cache, RecordOutput::new(&mut bytes, &[], &sinks));
+            for name in ["a", "b", "c"] {
+                dest.string_ref(name);
+            }
+            dest.thread_ref(1, 1);
+            collect.0.lock().clear();
+            tree.flush(&mut dest).unwrap();
+        }
+        let records = std::mem::take(&mut *collect.0.lock());
+        (records, bytes)
+    }
+
+    #[test]
+    fn flush_restores_only_reassigned_indices() {
+        let mut tree = SpanTreeBuffer::new(&FtfLayerConfig::default(), &MemoryBudget::new(None));
+        write_tree(&mut tree);
+        tree.mark_keep();
+        assert!(tree.keep());
+
+        let (records, _) = flush(&tree);
+        let strings: Vec<_> = records
+            .iter()
+            .filter_map(|record| match record {
+                ftfrs::Record::String(string) => Some((string.index(), string.value().as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(strings, [(1, "tree"), (1, "a")]);
+        let threads: Vec<_> = records
+            .iter()
+            .filter_map(|record| match record {
+                ftfrs::Record::Thread(thread) => Some((thread.index(), thread.thread_koid())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(threads, [(1, 2), (1, 1)]);
+    }
+
+    #[test]
+    fn flush_writes_raw_records() {
+        let mut tree = SpanTreeBuffer::new(&FtfLayerConfig::default(), &MemoryBudget::new(None));
+        write_tree(&mut tree);
+
+        let (_, bytes) = flush(&tree);
+        assert!(bytes.windows(8).any(|window| window == [0xAB; 8]));
+    }
+
+    #[test]
+    fn tree_over_budget_is_dropped() {
+        let memory = MemoryBudget::new(Some(64));
+        let mut tree = SpanTreeBuffer::new(&FtfLayerConfig::default(), &memory);
+        write_tree(&mut tree);
+        tree.mark_keep();
+        assert!(!tree.keep());
+        assert_eq!(memory.stats().dropped_trees, 1);
+    }
+}
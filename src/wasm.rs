@@ -0,0 +1,144 @@
+//! Support for producing traces from WebAssembly in the browser.
+//!
+//! Nothing here depends on `wasm-bindgen`. Instead the module imports a few
+//! functions from the `ftfrs_tracing` import module, which the page supplies
+//! when instantiating the WebAssembly module:
+//!
+//! ```js
+//! const { instance } = await WebAssembly.instantiateStreaming(fetch("app.wasm"), {
+//!   ftfrs_tracing: {
+//!     now: () => performance.now(),
+//!     date_now: () => Date.now(),
+//!     post_message: (ptr, len) => {
+//!       const bytes = new Uint8Array(instance.exports.memory.buffer, ptr, len);
+//!       postMessage(bytes.slice());
+//!     },
+//!   },
+//! });
+//! ```
+
+use std::io;
+
+use parking_lot::{Mutex, MutexGuard};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Bytes buffered by [`PostMessageWriter`] before they are posted
+const DEFAULT_CHUNK_BYTES: usize = 64 * 1024;
+
+pub(crate) mod host {
+    #[link(wasm_import_module = "ftfrs_tracing")]
+    extern "C" {
+        #[link_name = "now"]
+        fn host_now() -> f64;
+        #[link_name = "date_now"]
+        fn host_date_now() -> f64;
+        #[link_name = "post_message"]
+        fn host_post_message(ptr: *const u8, len: usize);
+    }
+
+    /// `performance.now()`, in milliseconds
+    pub(crate) fn now() -> f64 {
+        // SAFETY: the import takes no arguments and only reads the clock
+        unsafe { host_now() }
+    }
+
+    /// `Date.now()`, in milliseconds since the Unix epoch
+    pub(crate) fn date_now() -> f64 {
+        // SAFETY: the import takes no arguments and only reads the clock
+        unsafe { host_date_now() }
+    }
+
+    /// Hand `bytes` to the page, which copies them out before returning
+    pub(crate) fn post_message(bytes: &[u8]) {
+        // SAFETY: the pointer and length describe a live slice for the
+        // duration of the call
+        unsafe { host_post_message(bytes.as_ptr(), bytes.len()) }
+    }
+}
+
+/// A writer that passes the trace to the page through its `post_message` import.
+///
+/// Records are buffered and posted in chunks of whole records, so a receiver
+/// that concatenates the chunks in order gets a complete FTF file. Call
+/// [`PostMessageWriter::post`] (or flush the layer) to send what is buffered.
+#[derive(Debug)]
+pub struct PostMessageWriter {
+    buffer: Mutex<Vec<u8>>,
+    chunk_bytes: usize,
+}
+
+impl PostMessageWriter {
+    pub fn new() -> Self {
+        Self::with_chunk_bytes(DEFAULT_CHUNK_BYTES)
+    }
+
+    /// Post once at least `chunk_bytes` are buffered
+    pub fn with_chunk_bytes(chunk_bytes: usize) -> Self {
+        Self {
+            buffer: Mutex::new(Vec::with_capacity(chunk_bytes)),
+            chunk_bytes,
+        }
+    }
+
+    /// Post everything buffered so far
+    pub fn post(&self) {
+        post_buffer(&mut self.buffer.lock());
+    }
+}
+
+impl Default for PostMessageWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PostMessageWriter {
+    fn drop(&mut self) {
+        post_buffer(self.buffer.get_mut());
+    }
+}
+
+fn post_buffer(buffer: &mut Vec<u8>) {
+    if !buffer.is_empty() {
+        host::post_message(buffer);
+        buffer.clear();
+    }
+}
+
+/// Writer handed out by [`PostMessageWriter`], holding its buffer for one record.
+#[derive(Debug)]
+pub struct PostMessageWriterGuard<'a> {
+    buffer: MutexGuard<'a, Vec<u8>>,
+    chunk_bytes: usize,
+}
+
+impl io::Write for PostMessageWriterGuard<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        post_buffer(&mut self.buffer);
+        Ok(())
+    }
+}
+
+impl Drop for PostMessageWriterGuard<'_> {
+    fn drop(&mut self) {
+        if self.buffer.len() >= self.chunk_bytes {
+            post_buffer(&mut self.buffer);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for PostMessageWriter {
+    type Writer = PostMessageWriterGuard<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        PostMessageWriterGuard {
+            buffer: self.buffer.lock(),
+            chunk_bytes: self.chunk_bytes,
+        }
+    }
+}
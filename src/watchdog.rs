@@ -0,0 +1,61 @@
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use parking_lot::{Condvar, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::FtfHandle;
+
+/// Keeps a watchdog started by [`FtfHandle::spawn_watchdog`] running.
+///
+/// Dropping the guard stops the watchdog and joins its thread.
+#[must_use = "dropping the guard immediately stops the watchdog"]
+#[derive(Debug)]
+pub struct WatchdogGuard {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl WatchdogGuard {
+    pub(crate) fn spawn<W>(handle: FtfHandle<W>, interval: Duration) -> Self
+    where
+        W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let spawned = std::thread::Builder::new()
+            .name("ftfrs-tracing-watchdog".to_string())
+            .spawn(move || {
+                let (stopped, condvar) = &*thread_stop;
+                let mut stopped = stopped.lock();
+                while !*stopped {
+                    if condvar.wait_for(&mut stopped, interval).timed_out() {
+                        handle.check_open_spans();
+                    }
+                }
+            });
+
+        let handle = match spawned {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                eprintln!("Error starting FTF watchdog thread: {}", e);
+                None
+            }
+        };
+        Self { stop, handle }
+    }
+}
+
+impl Drop for WatchdogGuard {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock() = true;
+        condvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                eprintln!("FTF watchdog thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::io;
+use std::sync::Arc;
+
+use parking_lot::{Mutex, MutexGuard};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A [`MakeWriter`] that owns a single [`io::Write`] for its whole lifetime.
+///
+/// Every call to [`MakeWriter::make_writer`] hands out the same underlying
+/// writer, so writers that open a new resource per call are never re-created.
+#[derive(Debug)]
+pub struct OwnedWriter<W> {
+    inner: Mutex<W>,
+}
+
+impl<W: io::Write> OwnedWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            inner: Mutex::new(writer),
+        }
+    }
+
+    /// Consume the adapter and return the wrapped writer
+    pub fn into_inner(self) -> W {
+        self.inner.into_inner()
+    }
+}
+
+/// Writer handed out by [`OwnedWriter`], holding its lock for one record.
+#[derive(Debug)]
+pub struct OwnedWriterGuard<'a, W> {
+    guard: MutexGuard<'a, W>,
+}
+
+impl<W: io::Write> io::Write for OwnedWriterGuard<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.guard.write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.guard.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.guard.flush()
+    }
+}
+
+impl<'a, W: io::Write + 'a> MakeWriter<'a> for OwnedWriter<W> {
+    type Writer = OwnedWriterGuard<'a, W>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        OwnedWriterGuard {
+            guard: self.inner.lock(),
+        }
+    }
+}
+
+/// A [`MakeWriter`] that collects the trace in memory.
+///
+/// Clones share the same buffer, so keep one to read the trace back with
+/// [`MemoryWriter::contents`] or [`MemoryWriter::take`] while the layer owns
+/// another. Useful where there is no file system, such as in the browser.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryWriter {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemoryWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy of everything written so far
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.lock().clone()
+    }
+
+    /// Take everything written so far, leaving the buffer empty
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.buffer.lock())
+    }
+}
+
+/// Writer handed out by [`MemoryWriter`], holding its buffer for one record.
+#[derive(Debug)]
+pub struct MemoryWriterGuard<'a> {
+    buffer: MutexGuard<'a, Vec<u8>>,
+}
+
+impl io::Write for MemoryWriterGuard<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for MemoryWriter {
+    type Writer = MemoryWriterGuard<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        MemoryWriterGuard {
+            buffer: self.buffer.lock(),
+        }
+    }
+}